@@ -0,0 +1,234 @@
+// Standard libraries
+use std::collections::HashSet;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+// 3rd party crates
+use serde::Deserialize;
+
+// My crates
+use crate::cli::{client_secret, Client};
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::OAuth2TokenGrantFlow;
+
+const ACCOUNTS_DIRECTORY: &str = "accounts";
+
+/// One app registration listed in the `--accounts-file`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Account {
+    /// Names the account in the report and its token sub-directory.
+    pub name: String,
+    pub grant_type: OAuth2TokenGrantFlow,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub recipient_email: String,
+    pub recipient_name: String,
+}
+
+impl Account {
+    /// Reads the JSON array of accounts, which needs at least one account and
+    /// unique names usable as a directory.
+    pub fn load(path: &Path) -> OAuth2Result<Vec<Self>> {
+        let accounts: Vec<Self> = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if accounts.is_empty() {
+            return Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!("There are no accounts in {}.", path.display()),
+            ));
+        }
+
+        let mut names = HashSet::new();
+        for account in &accounts {
+            let name = account.name.as_str();
+            if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+                return Err(OAuth2Error::new(
+                    ErrorCodes::ConfigurationError,
+                    format!("The account name {name:?} is not a valid directory name."),
+                ));
+            }
+            if !names.insert(name) {
+                return Err(OAuth2Error::new(
+                    ErrorCodes::ConfigurationError,
+                    format!("The account name {name:?} is used more than once."),
+                ));
+            }
+        }
+        Ok(accounts)
+    }
+
+    pub fn client(&self) -> Client {
+        Client {
+            grant_type: self.grant_type.clone(),
+            client_id: self.client_id.clone(),
+            client_secret: client_secret(self.client_secret.as_deref()),
+            tenant: self.tenant.clone().unwrap_or_else(|| "common".to_string()),
+            access_token: None,
+        }
+    }
+
+    /// Each account keeps its token files apart from the other accounts.
+    pub fn token_directory(&self, directory: &Path) -> PathBuf {
+        directory.join(ACCOUNTS_DIRECTORY).join(&self.name)
+    }
+}
+
+/// The outcome of the run for one account.
+#[derive(Debug)]
+pub struct AccountResult {
+    pub name: String,
+    pub result: OAuth2Result<()>,
+}
+
+/// Runs `run` for each account in turn, carrying on after the failed ones.
+pub async fn run_accounts<F, Fut>(accounts: &[Account], mut run: F) -> Vec<AccountResult>
+where
+    F: FnMut(Account) -> Fut,
+    Fut: Future<Output = OAuth2Result<()>>,
+{
+    let mut results = Vec::new();
+    for account in accounts {
+        log::info!("Account {}: starting....", account.name);
+        let result = run(account.clone()).await;
+        results.push(AccountResult {
+            name: account.name.clone(),
+            result,
+        });
+    }
+    results
+}
+
+/// Logs the pass/fail report and fails if any of the accounts failed.
+pub fn report(results: &[AccountResult]) -> OAuth2Result<()> {
+    let mut failed = 0;
+    for account in results {
+        match &account.result {
+            Ok(()) => log::info!("PASS {}", account.name),
+            Err(e) => {
+                failed += 1;
+                log::error!("FAIL {}: {:?}", account.name, e);
+            }
+        }
+    }
+    log::info!("{} passed, {} failed.", results.len() - failed, failed);
+
+    if failed > 0 {
+        return Err(OAuth2Error::new(
+            ErrorCodes::OtherError,
+            format!("{failed} of {} accounts failed.", results.len()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::path::Path;
+
+    use super::{report, run_accounts, Account};
+    use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+    use crate::OAuth2TokenGrantFlow;
+
+    const ACCOUNTS: &str = r#"[
+        {
+            "name": "contoso",
+            "grant_type": "DeviceCodeFlow",
+            "client_id": "contoso-client",
+            "tenant": "contoso.onmicrosoft.com",
+            "recipient_email": "recipient@contoso.com",
+            "recipient_name": "Contoso"
+        },
+        {
+            "name": "fabrikam",
+            "grant_type": "AuthorizationCodeGrant",
+            "client_id": "fabrikam-client",
+            "client_secret": "secret",
+            "recipient_email": "recipient@fabrikam.com",
+            "recipient_name": "Fabrikam"
+        }
+    ]"#;
+
+    fn load(json: &str) -> OAuth2Result<Vec<Account>> {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("accounts.json");
+        fs::write(&path, json).unwrap();
+        Account::load(&path)
+    }
+
+    #[test]
+    fn test_load_accounts() {
+        let accounts = load(ACCOUNTS).unwrap();
+        assert_eq!(accounts.len(), 2);
+
+        let client = accounts[0].client();
+        assert_eq!(client.grant_type, OAuth2TokenGrantFlow::DeviceCodeFlow);
+        assert_eq!(client.tenant, "contoso.onmicrosoft.com");
+        assert!(client.client_secret.is_none());
+
+        let client = accounts[1].client();
+        assert_eq!(client.client_id, "fabrikam-client");
+        assert_eq!(client.tenant, "common");
+        assert_eq!(client.client_secret.unwrap().secret(), "secret");
+
+        assert_eq!(
+            accounts[1].token_directory(Path::new("/tokens")),
+            Path::new("/tokens/accounts/fabrikam")
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_bad_names() {
+        let duplicate = ACCOUNTS.replace("fabrikam\"", "contoso\"");
+        assert_eq!(
+            load(&duplicate).unwrap_err().error_code,
+            ErrorCodes::ConfigurationError
+        );
+        let traversal = ACCOUNTS.replace("\"fabrikam\"", "\"../fabrikam\"");
+        assert_eq!(
+            load(&traversal).unwrap_err().error_code,
+            ErrorCodes::ConfigurationError
+        );
+        assert_eq!(
+            load("[]").unwrap_err().error_code,
+            ErrorCodes::ConfigurationError
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_account_does_not_abort_the_others() {
+        let accounts = load(ACCOUNTS).unwrap();
+        let sent = RefCell::new(Vec::new());
+
+        let results = run_accounts(&accounts, |account| {
+            sent.borrow_mut().push(account.recipient_email.clone());
+            async move {
+                if account.name == "contoso" {
+                    Err(OAuth2Error::new(
+                        ErrorCodes::SmtpAuthenticationFailed,
+                        "Authentication unsuccessful".into(),
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(
+            *sent.borrow(),
+            ["recipient@contoso.com", "recipient@fabrikam.com"]
+        );
+        assert!(results[0].result.is_err());
+        assert!(results[1].result.is_ok());
+        assert_eq!(
+            report(&results).unwrap_err().error_code_desc,
+            "1 of 2 accounts failed."
+        );
+        assert!(report(&results[1..]).is_ok());
+    }
+}
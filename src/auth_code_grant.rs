@@ -1,28 +1,48 @@
 // Standard libraries
-use std::io::{BufRead, BufReader, Write};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
 use std::net::TcpListener;
 use std::path::PathBuf;
 use std::{future::Future, path::Path};
 
 // 3rd party crates
 use async_trait::async_trait;
-use directories::UserDirs;
 use oauth2::{
-    basic::BasicClient, url::Url, AuthUrl, ClientId, ClientSecret, CsrfToken, HttpRequest,
-    HttpResponse, RedirectUrl, Scope, TokenUrl,
+    basic::{
+        BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+        BasicTokenType,
+    },
+    url::{form_urlencoded, Url},
+    AuthUrl, Client, ClientId, ClientSecret, CsrfToken, HttpRequest, HttpResponse, RedirectUrl,
+    Scope, StandardRevocableToken, TokenUrl,
 };
 use oauth2::{AccessToken, AuthorizationCode};
+use serde::{Deserialize, Serialize};
 
 use crate::curl::Curl;
 // My crates
+use crate::cli::LoginArgs;
+use crate::cloud::{Authority, Cloud, OAuthVersion};
 use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
-use crate::TokenKeeper;
+use crate::report::OutputFormat;
+use crate::token_keeper::{no_token_to_refresh, numeric_durations, IdTokenResponse, TokenKeeper};
+
+/// The OAuth2 client of the flow, keeping the `id_token` of the token responses.
+type IdTokenClient = Client<
+    BasicErrorResponse,
+    IdTokenResponse,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
 
 #[async_trait]
 pub trait AuthCodeGrantTrait {
     async fn generate_authorization_url(
         &self,
         scopes: Vec<Scope>,
+        extra_params: &[(String, String)],
     ) -> OAuth2Result<(Url, CsrfToken)>;
 
     async fn exchange_auth_code<
@@ -45,6 +65,19 @@ pub trait AuthCodeGrantTrait {
         &self,
         file_directory: &Path,
         file_name: &Path,
+        force_refresh: bool,
+        async_http_callback: T,
+    ) -> OAuth2Result<TokenKeeper>;
+
+    async fn refresh_access_token<
+        F: Future<Output = Result<HttpResponse, RE>> + Send,
+        RE: std::error::Error + 'static + Send,
+        T: Fn(HttpRequest) -> F + Send + Sync,
+    >(
+        &self,
+        file_directory: &Path,
+        file_name: &Path,
+        token_keeper: TokenKeeper,
         async_http_callback: T,
     ) -> OAuth2Result<TokenKeeper>;
 }
@@ -54,6 +87,8 @@ pub struct AuthCodeGrant {
     client_secret: Option<ClientSecret>,
     auth_endpoint: AuthUrl,
     token_endpoint: TokenUrl,
+    /// Added to the token requests, e.g. the resource of the v1 endpoints.
+    token_params: Vec<(String, String)>,
 }
 
 #[async_trait]
@@ -61,16 +96,20 @@ impl AuthCodeGrantTrait for AuthCodeGrant {
     async fn generate_authorization_url(
         &self,
         scopes: Vec<Scope>,
+        extra_params: &[(String, String)],
     ) -> OAuth2Result<(Url, CsrfToken)> {
         log::info!("There is no Access token, please login.");
         let client = self.create_client()?.set_redirect_uri(
             RedirectUrl::new("http://localhost:8080".to_string()).expect("Invalid redirect URL"),
         );
 
-        let (authorize_url, csrf_state) = client
+        let mut request = client
             .authorize_url(CsrfToken::new_random)
-            .add_scopes(scopes)
-            .url();
+            .add_scopes(scopes);
+        for (key, value) in extra_params {
+            request = request.add_extra_param(key, value);
+        }
+        let (authorize_url, csrf_state) = request.url();
 
         Ok((authorize_url, csrf_state))
     }
@@ -89,9 +128,15 @@ impl AuthCodeGrantTrait for AuthCodeGrant {
         let client = self.create_client()?.set_redirect_uri(
             RedirectUrl::new("http://localhost:8080".to_string()).expect("Invalid redirect URL"),
         );
-        let token_res = client
-            .exchange_code(auth_code)
-            .request_async(async_http_callback)
+        let mut request = client.exchange_code(auth_code);
+        for (key, value) in &self.token_params {
+            request = request.add_extra_param(key, value);
+        }
+        let token_res = request
+            .request_async(|request| {
+                let response = async_http_callback(request);
+                async move { response.await.map(numeric_durations) }
+            })
             .await?;
         let mut token_keeper = TokenKeeper::from(token_res);
         token_keeper.set_directory(file_directory.to_path_buf());
@@ -107,53 +152,81 @@ impl AuthCodeGrantTrait for AuthCodeGrant {
         &self,
         file_directory: &Path,
         file_name: &Path,
+        force_refresh: bool,
         async_http_callback: T,
     ) -> OAuth2Result<TokenKeeper> {
         let mut token_keeper = TokenKeeper::new(file_directory.to_path_buf());
         token_keeper.read(file_name)?;
 
+        if force_refresh {
+            log::info!("Refreshing the access token even if it has not expired, --force-refresh was given.");
+            return self
+                .refresh_access_token(file_directory, file_name, token_keeper, async_http_callback)
+                .await;
+        }
         if token_keeper.has_access_token_expired() {
-            match token_keeper.refresh_token {
-                Some(ref_token) => {
-                    log::info!(
-                        "Access token has expired, contacting endpoint to get a new access token."
-                    );
-                    let response = self
-                        .create_client()?
-                        .exchange_refresh_token(&ref_token)
-                        .request_async(async_http_callback)
-                        .await;
-
-                    match response {
-                        Ok(res) => {
-                            token_keeper = TokenKeeper::from(res);
-                            token_keeper.set_directory(file_directory.to_path_buf());
-                            token_keeper.save(file_name)?;
-                            Ok(token_keeper)
-                        }
-                        Err(e) => {
-                            let error = OAuth2Error::from(e);
-                            if error.error_code == ErrorCodes::InvalidGrant {
-                                let file = TokenKeeper::new(file_directory.to_path_buf());
-                                file.delete(file_name).unwrap()
-                            }
-                            Err(error)
-                        }
-                    }
-                }
-                None => {
-                    log::info!("Access token has expired but there is no refresh token, please login again.");
-                    token_keeper.delete(file_name)?;
-                    Err(OAuth2Error::new(
-                        ErrorCodes::NoToken,
-                        "There is no refresh token.".into(),
-                    ))
-                }
+            if token_keeper.refresh_token.is_none() {
+                log::info!(
+                    "Access token has expired but there is no refresh token, please login again."
+                );
+                token_keeper.delete(file_name)?;
+                return Err(OAuth2Error::new(
+                    ErrorCodes::NoToken,
+                    "There is no refresh token.".into(),
+                ));
             }
+            log::info!("Access token has expired, contacting endpoint to get a new access token.");
+            self.refresh_access_token(file_directory, file_name, token_keeper, async_http_callback)
+                .await
         } else {
             Ok(token_keeper)
         }
     }
+
+    async fn refresh_access_token<
+        F: Future<Output = Result<HttpResponse, RE>> + Send,
+        RE: std::error::Error + 'static + Send,
+        T: Fn(HttpRequest) -> F + Send + Sync,
+    >(
+        &self,
+        file_directory: &Path,
+        file_name: &Path,
+        token_keeper: TokenKeeper,
+        async_http_callback: T,
+    ) -> OAuth2Result<TokenKeeper> {
+        let ref_token = token_keeper.refresh_token.ok_or(OAuth2Error::new(
+            ErrorCodes::NoToken,
+            "There is no refresh token.".into(),
+        ))?;
+        let client = self.create_client()?;
+        let mut request = client.exchange_refresh_token(&ref_token);
+        for (key, value) in &self.token_params {
+            request = request.add_extra_param(key, value);
+        }
+        let response = request
+            .request_async(|request| {
+                let response = async_http_callback(request);
+                async move { response.await.map(numeric_durations) }
+            })
+            .await;
+
+        match response {
+            Ok(res) => {
+                let mut token_keeper = TokenKeeper::from(res);
+                token_keeper.set_directory(file_directory.to_path_buf());
+                token_keeper.save(file_name)?;
+                Ok(token_keeper)
+            }
+            Err(e) => {
+                let error = OAuth2Error::from(e);
+                if error.error_code == ErrorCodes::InvalidGrant {
+                    let file = TokenKeeper::new(file_directory.to_path_buf());
+                    file.delete(file_name).unwrap()
+                }
+                Err(error)
+            }
+        }
+    }
 }
 
 impl AuthCodeGrant {
@@ -168,11 +241,17 @@ impl AuthCodeGrant {
             client_secret,
             auth_endpoint,
             token_endpoint,
+            token_params: Vec::new(),
         }
     }
 
-    fn create_client(&self) -> OAuth2Result<BasicClient> {
-        Ok(BasicClient::new(
+    pub fn with_token_params(mut self, token_params: Vec<(String, String)>) -> Self {
+        self.token_params = token_params;
+        self
+    }
+
+    fn create_client(&self) -> OAuth2Result<IdTokenClient> {
+        Ok(IdTokenClient::new(
             self.client_id.to_owned(),
             self.client_secret.to_owned(),
             self.auth_endpoint.to_owned(),
@@ -182,38 +261,254 @@ impl AuthCodeGrant {
     }
 }
 
-pub async fn auth_code_grant(
+/// The name of the cached token file for this flow.
+pub fn token_file(client_id: &str) -> PathBuf {
+    PathBuf::from(format!("{}_auth_code_grant.json", client_id))
+}
+
+/// The name of the file keeping the login started by `--print-authorize-url-only`.
+fn pending_login_file(client_id: &str) -> PathBuf {
+    PathBuf::from(format!("{}_auth_code_state.json", client_id))
+}
+
+/// What `--code` needs from the login started by `--print-authorize-url-only`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct PendingLogin {
+    #[serde(default)]
+    cloud: Cloud,
+    tenant: String,
+    state: String,
+    #[serde(default)]
+    oauth_version: OAuthVersion,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resource: Option<String>,
+}
+
+fn microsoft_auth_code_grant(
     client_id: &str,
     client_secret: Option<ClientSecret>,
-    curl: Curl,
-) -> OAuth2Result<AccessToken> {
-    let auth_code_grant = AuthCodeGrant::new(
+    authority: &Authority,
+) -> OAuth2Result<AuthCodeGrant> {
+    Ok(AuthCodeGrant::new(
         ClientId::new(client_id.to_string()),
         client_secret,
-        AuthUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/authorize".to_string())?,
-        TokenUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string())?,
+        AuthUrl::new(authority.endpoint("authorize"))?,
+        TokenUrl::new(authority.endpoint("token"))?,
+    )
+    .with_token_params(authority.token_params()))
+}
+
+/// Exchanges the cached refresh token for a new access token, even if the cached
+/// access token has not expired yet.
+pub async fn refresh_auth_code_grant(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    authority: &Authority,
+    directory: &Path,
+    curl: Curl,
+) -> OAuth2Result<TokenKeeper> {
+    let auth_code_grant = microsoft_auth_code_grant(client_id, client_secret, authority)?;
+    let token_file = token_file(client_id);
+    let mut token_keeper = TokenKeeper::new(directory.to_path_buf());
+    token_keeper.read(&token_file)?;
+
+    auth_code_grant
+        .refresh_access_token(directory, &token_file, token_keeper, |request| async {
+            curl.send(request).await
+        })
+        .await
+}
+
+/// Takes the authorization code, and the state if there is one, out of what was pasted:
+/// either the URL the browser was redirected to, its query, or the bare code.
+fn parse_pasted_code(input: &str) -> OAuth2Result<(AuthorizationCode, Option<CsrfToken>)> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(OAuth2Error::new(
+            ErrorCodes::InvalidRequest,
+            "Nothing was pasted, expected the redirect URL or the code.".into(),
+        ));
+    }
+
+    let query = input.split_once('?').map_or(input, |(_, query)| query);
+    let query = query.split('#').next().unwrap_or_default();
+    let value = |name: &str| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+
+    if let Some(error) = value("error") {
+        return Err(OAuth2Error::new(
+            ErrorCodes::from(error),
+            value("error_description").unwrap_or_default(),
+        ));
+    }
+    match value("code") {
+        Some(code) => Ok((
+            AuthorizationCode::new(code),
+            value("state").map(CsrfToken::new),
+        )),
+        None if input.contains(['?', '=', '&', '/', ' ']) => Err(OAuth2Error::new(
+            ErrorCodes::InvalidRequest,
+            "There is no code in the pasted URL.".into(),
+        )),
+        None => Ok((AuthorizationCode::new(input.to_string()), None)),
+    }
+}
+
+/// Takes the code out of what was pasted, checking that the state, if there is one, is
+/// the one of this login.
+fn check_pasted_code(input: &str, csrf_state: &CsrfToken) -> OAuth2Result<AuthorizationCode> {
+    let (code, state) = parse_pasted_code(input)?;
+    match state {
+        Some(state) if state.secret() != csrf_state.secret() => Err(OAuth2Error::new(
+            ErrorCodes::InvalidRequest,
+            "The state in the pasted URL does not match, it is not the redirect of this login."
+                .into(),
+        )),
+        _ => Ok(code),
+    }
+}
+
+/// Asks for the redirect URL or the code on stdin, for when the browser runs on
+/// another machine and cannot reach the local listener.
+async fn read_pasted_code(csrf_state: &CsrfToken) -> OAuth2Result<AuthorizationCode> {
+    log::info!("Paste the URL of the page you were redirected to, or just the code:");
+    // Off the runtime thread, so that Ctrl-C is still handled while waiting.
+    let input = tokio::task::spawn_blocking(|| {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input).map(|_| input)
+    })
+    .await
+    .unwrap()?;
+    check_pasted_code(&input, csrf_state)
+}
+
+/// What `--print-authorize-url-only` prints: the URL, or with `--output json` the URL
+/// and its state.
+fn authorize_url_output(
+    authorize_url: &Url,
+    csrf_state: &CsrfToken,
+    output: OutputFormat,
+) -> String {
+    match output {
+        OutputFormat::Text => authorize_url.to_string(),
+        OutputFormat::Json => serde_json::json!({
+            "authorize_url": authorize_url.as_str(),
+            "state": csrf_state.secret(),
+        })
+        .to_string(),
+    }
+}
+
+/// The first step of the scripted login: prints the authorize URL and saves its state
+/// for `exchange_code`.
+pub async fn print_authorize_url(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    authority: &Authority,
+    directory: &Path,
+    output: OutputFormat,
+) -> OAuth2Result<()> {
+    let auth_code_grant = microsoft_auth_code_grant(client_id, client_secret, authority)?;
+    let (authorize_url, csrf_state) = auth_code_grant
+        .generate_authorization_url(authority.scopes(), &authority.authorize_url_params())
+        .await?;
+
+    let pending_login = PendingLogin {
+        cloud: authority.cloud,
+        tenant: authority.tenant.clone(),
+        state: csrf_state.secret().to_string(),
+        oauth_version: authority.oauth_version,
+        resource: authority.resource.clone(),
+    };
+    fs::write(
+        directory.join(pending_login_file(client_id)),
+        serde_json::to_string(&pending_login)?,
+    )?;
+    println!(
+        "{}",
+        authorize_url_output(&authorize_url, &csrf_state, output)
     );
-    let scopes = vec![
-        Scope::new("offline_access".to_string()),
-        Scope::new("https://outlook.office.com/SMTP.Send".to_string()),
-        Scope::new("https://outlook.office.com/User.Read".to_string()),
-    ];
-    let directory = UserDirs::new().unwrap();
-    let mut directory = directory.home_dir().to_owned();
+    Ok(())
+}
 
-    directory = directory.join("token");
+/// The second step of the scripted login: exchanges the code, or the URL the browser was
+/// redirected to, for an access token and caches it like the interactive login does.
+pub async fn exchange_code(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    directory: &Path,
+    code: &str,
+    curl: Curl,
+) -> OAuth2Result<AccessToken> {
+    let path = directory.join(pending_login_file(client_id));
+    let pending_login: PendingLogin = match fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                "There is no login to complete, run with --print-authorize-url-only first.".into(),
+            ))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let code = check_pasted_code(code, &CsrfToken::new(pending_login.state))?;
+
+    let authority = Authority::new(pending_login.cloud, &pending_login.tenant)
+        .with_oauth_version(pending_login.oauth_version)
+        .with_resource(pending_login.resource);
+    let auth_code_grant = microsoft_auth_code_grant(client_id, client_secret, &authority)?;
+    let token_keeper = auth_code_grant
+        .exchange_auth_code(directory, &token_file(client_id), code, |request| async {
+            curl.send(request).await
+        })
+        .await?;
+    fs::remove_file(&path)?;
+    Ok(token_keeper.access_token)
+}
 
-    let token_file = PathBuf::from(format!("{}_auth_code_grant.json", client_id));
+pub async fn auth_code_grant(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    authority: &Authority,
+    directory: &Path,
+    curl: Curl,
+    login: LoginArgs,
+) -> OAuth2Result<AccessToken> {
+    let auth_code_grant = microsoft_auth_code_grant(client_id, client_secret, authority)?;
+    let token_file = token_file(client_id);
     let mut token_keeper = TokenKeeper::new(directory.to_path_buf());
 
-    // If there is no exsting token, get it from the cloud
-    if let Err(_err) = token_keeper.read(&token_file) {
-        let (authorize_url, _csrf_state) =
-            auth_code_grant.generate_authorization_url(scopes).await?;
+    // If there is no exsting token, or one with fewer scopes, get it from the cloud
+    if token_keeper.read(&token_file).is_err()
+        || (login.fail_fast_on_scope_downgrade && token_keeper.lacks_scopes(&authority.scopes()))
+    {
+        if login.force_refresh {
+            return Err(no_token_to_refresh());
+        }
+        let (authorize_url, csrf_state) = auth_code_grant
+            .generate_authorization_url(authority.scopes(), &authority.authorize_url_params())
+            .await?;
         log::info!("Open this link: {}", authorize_url.to_string());
 
+        if login.paste_code {
+            let code = read_pasted_code(&csrf_state).await?;
+            token_keeper = auth_code_grant
+                .exchange_auth_code(directory, &token_file, code, |request| async {
+                    curl.send(request).await
+                })
+                .await?;
+            return Ok(token_keeper.access_token);
+        }
+
         let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-        if let Some(mut stream) = listener.incoming().flatten().next() {
+        // Off the runtime thread, so that Ctrl-C is still handled while waiting.
+        let stream = tokio::task::spawn_blocking(move || listener.incoming().flatten().next())
+            .await
+            .unwrap();
+        if let Some(mut stream) = stream {
             let code;
             let _state;
             {
@@ -258,7 +553,7 @@ pub async fn auth_code_grant(
 
             // Exchange the code with a token.
             token_keeper = auth_code_grant
-                .exchange_auth_code(&directory, &token_file, code, |request| async {
+                .exchange_auth_code(directory, &token_file, code, |request| async {
                     curl.send(request).await
                 })
                 .await?;
@@ -267,10 +562,431 @@ pub async fn auth_code_grant(
         }
     } else {
         token_keeper = auth_code_grant
-            .get_access_token(&directory, &token_file, |request| async {
-                curl.send(request).await
-            })
+            .get_access_token(
+                directory,
+                &token_file,
+                login.force_refresh,
+                |request| async { curl.send(request).await },
+            )
             .await?;
     }
     Ok(token_keeper.access_token)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, StatusCode};
+    use oauth2::{
+        basic::BasicTokenResponse, url::Url, AuthorizationCode, CsrfToken, HttpRequest,
+        HttpResponse,
+    };
+
+    use super::{
+        authorize_url_output, exchange_code, microsoft_auth_code_grant, parse_pasted_code,
+        pending_login_file, print_authorize_url, token_file, AuthCodeGrantTrait, PendingLogin,
+    };
+    use crate::cloud::{Authority, Cloud, OAuthVersion};
+    use crate::curl::Curl;
+    use crate::error::ErrorCodes;
+    use crate::get_profile::SenderProfile;
+    use crate::report::OutputFormat;
+    use crate::TokenKeeper;
+
+    fn authority() -> Authority {
+        Authority::new(Cloud::Commercial, "contoso.onmicrosoft.com")
+    }
+
+    fn cached_token(directory: &std::path::Path, json: &str) -> TokenKeeper {
+        let token_response: BasicTokenResponse = serde_json::from_str(json).unwrap();
+        let mut token_keeper = TokenKeeper::from(token_response);
+        token_keeper.set_directory(directory.to_path_buf());
+        token_keeper.save(&token_file("client")).unwrap();
+        token_keeper
+    }
+
+    async fn mock_token_endpoint(request: HttpRequest) -> Result<HttpResponse, std::io::Error> {
+        let body = String::from_utf8(request.body).unwrap();
+        assert_eq!(
+            request.url.as_str(),
+            "https://login.microsoftonline.com/contoso.onmicrosoft.com/oauth2/v2.0/token"
+        );
+        assert!(body.contains("grant_type=refresh_token"));
+        assert!(body.contains("refresh_token=cached-refresh-token"));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Ok(HttpResponse {
+            status_code: StatusCode::OK,
+            headers,
+            body: r#"{"access_token":"refreshed-access-token","token_type":"Bearer","expires_in":3600,"refresh_token":"new-refresh-token"}"#
+                .as_bytes()
+                .to_vec(),
+        })
+    }
+
+    async fn unreachable_token_endpoint(
+        _request: HttpRequest,
+    ) -> Result<HttpResponse, std::io::Error> {
+        panic!("the token endpoint must not be contacted")
+    }
+
+    #[tokio::test]
+    async fn test_refresh_access_token_ignores_expiry() {
+        let directory = tempfile::tempdir().unwrap();
+        let token_keeper = cached_token(
+            directory.path(),
+            r#"{"access_token":"cached-access-token","token_type":"Bearer","expires_in":3600,"refresh_token":"cached-refresh-token"}"#,
+        );
+        assert!(!token_keeper.has_access_token_expired());
+
+        let refreshed = microsoft_auth_code_grant("client", None, &authority())
+            .unwrap()
+            .refresh_access_token(
+                directory.path(),
+                &token_file("client"),
+                token_keeper,
+                mock_token_endpoint,
+            )
+            .await
+            .unwrap();
+        assert_eq!(refreshed.access_token.secret(), "refreshed-access-token");
+
+        let mut saved = TokenKeeper::new(directory.path().to_path_buf());
+        saved.read(&token_file("client")).unwrap();
+        assert_eq!(saved.access_token.secret(), "refreshed-access-token");
+        assert_eq!(saved.refresh_token.unwrap().secret(), "new-refresh-token");
+    }
+
+    #[tokio::test]
+    async fn test_exchange_keeps_the_id_token() {
+        let directory = tempfile::tempdir().unwrap();
+        let id_token = format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"RS256"}"#),
+            URL_SAFE_NO_PAD
+                .encode(r#"{"name":"Jane Doe","preferred_username":"jane@contoso.com"}"#)
+        );
+        let body = format!(
+            r#"{{"access_token":"access-token","token_type":"Bearer","expires_in":3600,"scope":"openid profile https://outlook.office.com/SMTP.Send","id_token":"{id_token}"}}"#
+        );
+
+        let token_keeper = microsoft_auth_code_grant("client", None, &authority())
+            .unwrap()
+            .exchange_auth_code(
+                directory.path(),
+                &token_file("client"),
+                AuthorizationCode::new("code".to_string()),
+                |request| {
+                    let body = body.clone();
+                    async move {
+                        let form = String::from_utf8(request.body).unwrap();
+                        assert!(form.contains("grant_type=authorization_code"));
+                        let mut headers = HeaderMap::new();
+                        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                        Ok::<_, std::io::Error>(HttpResponse {
+                            status_code: StatusCode::OK,
+                            headers,
+                            body: body.into_bytes(),
+                        })
+                    }
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(token_keeper.id_token.as_deref(), Some(id_token.as_str()));
+
+        let mut saved = TokenKeeper::new(directory.path().to_path_buf());
+        saved.read(&token_file("client")).unwrap();
+        let id_token = saved.id_token.unwrap();
+        assert_eq!(
+            SenderProfile::from_id_token(&id_token),
+            Some(("Jane Doe".to_string(), "jane@contoso.com".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_force_refresh_refreshes_a_fresh_token() {
+        let directory = tempfile::tempdir().unwrap();
+        let token_keeper = cached_token(
+            directory.path(),
+            r#"{"access_token":"cached-access-token","token_type":"Bearer","expires_in":3600,"refresh_token":"cached-refresh-token"}"#,
+        );
+        assert!(!token_keeper.has_access_token_expired());
+        let auth_code_grant = microsoft_auth_code_grant("client", None, &authority()).unwrap();
+
+        let cached = auth_code_grant
+            .get_access_token(
+                directory.path(),
+                &token_file("client"),
+                false,
+                unreachable_token_endpoint,
+            )
+            .await
+            .unwrap();
+        assert_eq!(cached.access_token.secret(), "cached-access-token");
+
+        let refreshed = auth_code_grant
+            .get_access_token(
+                directory.path(),
+                &token_file("client"),
+                true,
+                mock_token_endpoint,
+            )
+            .await
+            .unwrap();
+        assert_eq!(refreshed.access_token.secret(), "refreshed-access-token");
+    }
+
+    #[tokio::test]
+    async fn test_force_refresh_without_refresh_token() {
+        let directory = tempfile::tempdir().unwrap();
+        cached_token(
+            directory.path(),
+            r#"{"access_token":"cached-access-token","token_type":"Bearer","expires_in":3600}"#,
+        );
+
+        let error = microsoft_auth_code_grant("client", None, &authority())
+            .unwrap()
+            .get_access_token(
+                directory.path(),
+                &token_file("client"),
+                true,
+                mock_token_endpoint,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::NoToken);
+        // The cached token is kept.
+        assert!(directory.path().join(token_file("client")).exists());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_access_token_without_refresh_token() {
+        let directory = tempfile::tempdir().unwrap();
+        let token_keeper = cached_token(
+            directory.path(),
+            r#"{"access_token":"cached-access-token","token_type":"Bearer","expires_in":3600}"#,
+        );
+
+        let error = microsoft_auth_code_grant("client", None, &authority())
+            .unwrap()
+            .refresh_access_token(
+                directory.path(),
+                &token_file("client"),
+                token_keeper,
+                mock_token_endpoint,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::NoToken);
+    }
+
+    #[test]
+    fn test_parse_pasted_redirect_url() {
+        let (code, state) = parse_pasted_code(
+            "  http://localhost:8080/?code=0.AXoA%2Fcode&state=csrf-state&session_state=abc\n",
+        )
+        .unwrap();
+        assert_eq!(code.secret(), "0.AXoA/code");
+        assert_eq!(state.unwrap().secret(), "csrf-state");
+
+        let (code, state) = parse_pasted_code("?state=csrf-state&code=query-code").unwrap();
+        assert_eq!(code.secret(), "query-code");
+        assert_eq!(state.unwrap().secret(), "csrf-state");
+    }
+
+    #[test]
+    fn test_parse_pasted_bare_code() {
+        let (code, state) = parse_pasted_code("\t0.AXoA-bare_code.123\r\n").unwrap();
+        assert_eq!(code.secret(), "0.AXoA-bare_code.123");
+        assert!(state.is_none());
+    }
+
+    #[test]
+    fn test_parse_pasted_errors() {
+        assert_eq!(
+            parse_pasted_code(" \n").unwrap_err().error_code,
+            ErrorCodes::InvalidRequest
+        );
+        assert_eq!(
+            parse_pasted_code("http://localhost:8080/?state=csrf-state")
+                .unwrap_err()
+                .error_code,
+            ErrorCodes::InvalidRequest
+        );
+        let error = parse_pasted_code(
+            "http://localhost:8080/?error=access_denied&error_description=User+cancelled",
+        )
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::AccessDenied);
+        assert_eq!(error.error_code_desc, "User cancelled");
+    }
+
+    #[tokio::test]
+    async fn test_authorize_url_output() {
+        let (authorize_url, csrf_state) = microsoft_auth_code_grant("client", None, &authority())
+            .unwrap()
+            .generate_authorization_url(Cloud::Commercial.scopes(), &[])
+            .await
+            .unwrap();
+
+        let printed = authorize_url_output(&authorize_url, &csrf_state, OutputFormat::Text);
+        let url = Url::parse(&printed).unwrap();
+        assert_eq!(url.path(), "/contoso.onmicrosoft.com/oauth2/v2.0/authorize");
+        let value = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        assert_eq!(value("client_id").as_deref(), Some("client"));
+        assert_eq!(
+            value("redirect_uri").as_deref(),
+            Some("http://localhost:8080")
+        );
+        assert_eq!(
+            value("scope").as_deref(),
+            Some("offline_access https://outlook.office.com/SMTP.Send https://outlook.office.com/User.Read")
+        );
+        assert_eq!(value("state").as_ref(), Some(csrf_state.secret()));
+
+        let printed = authorize_url_output(&authorize_url, &csrf_state, OutputFormat::Json);
+        let json: serde_json::Value = serde_json::from_str(&printed).unwrap();
+        assert_eq!(json["authorize_url"], authorize_url.as_str());
+        assert_eq!(json["state"], csrf_state.secret().as_str());
+    }
+
+    #[tokio::test]
+    async fn test_authorize_url_extra_params() {
+        let extra_params = [
+            ("domain_hint".to_string(), "contoso.com".to_string()),
+            ("claims".to_string(), r#"{"a":"b c&d"}"#.to_string()),
+        ];
+        let (authorize_url, _) = microsoft_auth_code_grant("client", None, &authority())
+            .unwrap()
+            .generate_authorization_url(Cloud::Commercial.scopes(), &extra_params)
+            .await
+            .unwrap();
+
+        let value = |name: &str| {
+            authorize_url
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        assert_eq!(value("domain_hint").as_deref(), Some("contoso.com"));
+        assert_eq!(value("claims").as_deref(), Some(r#"{"a":"b c&d"}"#));
+        assert_eq!(value("client_id").as_deref(), Some("client"));
+        assert!(authorize_url
+            .query()
+            .unwrap()
+            .contains("claims=%7B%22a%22%3A%22b+c%26d%22%7D"));
+    }
+
+    #[tokio::test]
+    async fn test_v1_login_sends_the_resource() {
+        let authority = authority()
+            .with_oauth_version(OAuthVersion::V1)
+            .with_resource(Some("https://outlook.office365.com".to_string()));
+        let auth_code_grant = microsoft_auth_code_grant("client", None, &authority).unwrap();
+        let (authorize_url, _) = auth_code_grant
+            .generate_authorization_url(authority.scopes(), &authority.authorize_url_params())
+            .await
+            .unwrap();
+        assert_eq!(
+            authorize_url.path(),
+            "/contoso.onmicrosoft.com/oauth2/authorize"
+        );
+        let value = |name: &str| {
+            authorize_url
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        assert_eq!(
+            value("resource").as_deref(),
+            Some("https://outlook.office365.com")
+        );
+        assert_eq!(value("scope"), None);
+
+        let directory = tempfile::tempdir().unwrap();
+        let token_keeper = auth_code_grant
+            .exchange_auth_code(
+                directory.path(),
+                &token_file("client"),
+                AuthorizationCode::new("code".to_string()),
+                |request: HttpRequest| async move {
+                    assert_eq!(
+                        request.url.as_str(),
+                        "https://login.microsoftonline.com/contoso.onmicrosoft.com/oauth2/token"
+                    );
+                    let body = String::from_utf8(request.body).unwrap();
+                    assert!(body.contains("resource=https%3A%2F%2Foutlook.office365.com"));
+                    assert!(!body.contains("scope="));
+                    Ok::<_, std::io::Error>(HttpResponse {
+                        status_code: StatusCode::OK,
+                        headers: HeaderMap::new(),
+                        body: br#"{"access_token":"v1-access-token","token_type":"Bearer","expires_in":"3599","resource":"https://outlook.office365.com"}"#.to_vec(),
+                    })
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(token_keeper.access_token.secret(), "v1-access-token");
+        assert!(!token_keeper.has_access_token_expired());
+    }
+
+    #[tokio::test]
+    async fn test_print_authorize_url_saves_the_pending_login() {
+        let directory = tempfile::tempdir().unwrap();
+        print_authorize_url(
+            "client",
+            None,
+            &authority(),
+            directory.path(),
+            OutputFormat::Text,
+        )
+        .await
+        .unwrap();
+
+        let text = fs::read_to_string(directory.path().join(pending_login_file("client"))).unwrap();
+        let pending_login: PendingLogin = serde_json::from_str(&text).unwrap();
+        assert_eq!(pending_login.tenant, "contoso.onmicrosoft.com");
+        assert!(!pending_login.state.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_exchange_code_checks_the_pending_login() {
+        let directory = tempfile::tempdir().unwrap();
+        let error = exchange_code("client", None, directory.path(), "0.AXoA", Curl::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+
+        let pending_login = PendingLogin {
+            cloud: Cloud::Commercial,
+            tenant: "contoso.onmicrosoft.com".to_string(),
+            state: CsrfToken::new_random().secret().to_string(),
+            oauth_version: OAuthVersion::V2,
+            resource: None,
+        };
+        fs::write(
+            directory.path().join(pending_login_file("client")),
+            serde_json::to_string(&pending_login).unwrap(),
+        )
+        .unwrap();
+        let error = exchange_code(
+            "client",
+            None,
+            directory.path(),
+            "http://localhost:8080/?code=0.AXoA&state=another-login",
+            Curl::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::InvalidRequest);
+        assert!(directory.path().join(pending_login_file("client")).exists());
+    }
+}
@@ -0,0 +1,129 @@
+// Standard libraries
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+// 3rd party crates
+use strum_macros::EnumString;
+
+/// How the waits between the retries are spread, with `--retry-jitter`, so that many
+/// clients failing together do not retry together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Jitter {
+    /// The exponential wait as it is.
+    None,
+    /// A random wait between zero and the exponential wait.
+    #[default]
+    Full,
+    /// Half the exponential wait, plus a random wait up to the other half.
+    Equal,
+}
+
+impl Jitter {
+    /// Spreads the exponential `wait` with `fraction`, a random number in `[0, 1)`.
+    pub fn apply(&self, wait: Duration, fraction: f64) -> Duration {
+        match self {
+            Jitter::None => wait,
+            Jitter::Full => part(wait, fraction),
+            Jitter::Equal => wait / 2 + part(wait / 2, fraction),
+        }
+    }
+}
+
+/// `fraction` of `wait`, which stays below `wait` also when it is too long for the
+/// float precision.
+fn part(wait: Duration, fraction: f64) -> Duration {
+    Duration::try_from_secs_f64(wait.as_secs_f64() * fraction)
+        .unwrap_or(wait)
+        .min(wait)
+}
+
+/// A random number in `[0, 1)`, from the random keys of the standard library hasher,
+/// which is good enough to spread retries.
+fn random_fraction() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// An exponential backoff: the wait before the first retry is `base`, it doubles before
+/// each next one and is spread with the jitter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    base: Duration,
+    jitter: Jitter,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, jitter: Jitter) -> Self {
+        Self { base, jitter }
+    }
+
+    /// The exponential wait before `retry`, 1 for the first, without the jitter.
+    pub fn exponential(&self, retry: u32) -> Duration {
+        2u32.checked_pow(retry.saturating_sub(1))
+            .and_then(|factor| self.base.checked_mul(factor))
+            .unwrap_or(Duration::MAX)
+    }
+
+    /// The wait before `retry`, 1 for the first.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.jitter
+            .apply(self.exponential(retry), random_fraction())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use super::{random_fraction, Backoff, Jitter};
+
+    const BASE: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn test_jitter_names() {
+        assert_eq!(Jitter::from_str("none").unwrap(), Jitter::None);
+        assert_eq!(Jitter::from_str("full").unwrap(), Jitter::Full);
+        assert_eq!(Jitter::from_str("equal").unwrap(), Jitter::Equal);
+        assert_eq!(Jitter::default(), Jitter::Full);
+        assert!(Jitter::from_str("decorrelated").is_err());
+    }
+
+    #[test]
+    fn test_exponential_waits() {
+        let backoff = Backoff::new(BASE, Jitter::None);
+        assert_eq!(backoff.delay(1), BASE);
+        assert_eq!(backoff.delay(2), BASE * 2);
+        assert_eq!(backoff.delay(4), BASE * 8);
+        assert_eq!(backoff.exponential(64), Duration::MAX);
+        assert!(Jitter::Full.apply(Duration::MAX, 0.999) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let wait = Duration::from_secs(8);
+        assert_eq!(Jitter::Full.apply(wait, 0.0), Duration::ZERO);
+        assert_eq!(Jitter::Full.apply(wait, 0.5), Duration::from_secs(4));
+        assert_eq!(Jitter::Equal.apply(wait, 0.0), Duration::from_secs(4));
+        assert_eq!(Jitter::Equal.apply(wait, 0.5), Duration::from_secs(6));
+
+        for retry in 1..=5 {
+            let exponential = BASE * 2u32.pow(retry - 1);
+            for _ in 0..100 {
+                let full = Backoff::new(BASE, Jitter::Full).delay(retry);
+                assert!(full <= exponential, "{full:?} > {exponential:?}");
+                let equal = Backoff::new(BASE, Jitter::Equal).delay(retry);
+                assert!(equal >= exponential / 2 && equal <= exponential);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_fractions() {
+        let fractions: Vec<f64> = (0..100).map(|_| random_fraction()).collect();
+        assert!(fractions.iter().all(|f| (0.0..1.0).contains(f)));
+        assert!(fractions.iter().any(|f| *f != fractions[0]));
+    }
+}
@@ -0,0 +1,104 @@
+// Standard libraries
+use std::time::Duration;
+
+// 3rd party crates
+use serde::Serialize;
+
+// My crates
+use crate::error::OAuth2Result;
+use crate::report::OutputFormat;
+
+/// The handshake latencies of `benchmark-connect`, in milliseconds.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct HandshakeStats {
+    pub count: usize,
+    pub min_ms: f64,
+    pub mean_ms: f64,
+    pub median_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_nanos() as f64 / 1_000_000.0
+}
+
+/// The latency that `percent` percent of the sorted latencies are within, by nearest rank.
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100);
+    sorted[rank.max(1) - 1]
+}
+
+impl HandshakeStats {
+    /// The statistics of the latencies, none when there is none.
+    pub fn of(latencies: &[Duration]) -> Option<Self> {
+        let mut sorted = latencies.to_vec();
+        sorted.sort();
+        let (min, max) = (*sorted.first()?, *sorted.last()?);
+        let total: Duration = sorted.iter().sum();
+        Some(Self {
+            count: sorted.len(),
+            min_ms: millis(min),
+            mean_ms: millis(total) / sorted.len() as f64,
+            median_ms: millis(percentile(&sorted, 50)),
+            p95_ms: millis(percentile(&sorted, 95)),
+            max_ms: millis(max),
+        })
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        vec![
+            format!("Handshakes: {}", self.count),
+            format!("Min: {:.1} ms", self.min_ms),
+            format!("Mean: {:.1} ms", self.mean_ms),
+            format!("Median: {:.1} ms", self.median_ms),
+            format!("95th percentile: {:.1} ms", self.p95_ms),
+            format!("Max: {:.1} ms", self.max_ms),
+        ]
+    }
+
+    pub fn print(&self, output: OutputFormat) -> OAuth2Result<()> {
+        match output {
+            OutputFormat::Text => {
+                for line in self.summary() {
+                    log::info!("{line}");
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string(self)?),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::HandshakeStats;
+
+    #[test]
+    fn test_handshake_stats() {
+        let latencies: Vec<Duration> = [40, 10, 30, 20, 100]
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect();
+        let stats = HandshakeStats::of(&latencies).unwrap();
+        assert_eq!(
+            stats,
+            HandshakeStats {
+                count: 5,
+                min_ms: 10.0,
+                mean_ms: 40.0,
+                median_ms: 30.0,
+                p95_ms: 100.0,
+                max_ms: 100.0,
+            }
+        );
+        assert_eq!(stats.summary()[4], "95th percentile: 100.0 ms");
+
+        let stats = HandshakeStats::of(&[Duration::from_micros(1500)]).unwrap();
+        assert_eq!(stats.median_ms, 1.5);
+        assert_eq!(stats.p95_ms, 1.5);
+        assert!(HandshakeStats::of(&[]).is_none());
+    }
+}
@@ -0,0 +1,144 @@
+// Standard libraries
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// My crates
+use crate::{auth_code_grant, device_code_flow};
+
+/// The temporary files of this run, removed when the run is interrupted.
+static TEMP_FILES: Cleanup = Cleanup::new();
+
+/// Whether the file is a cached token file, which the cleanup never removes.
+fn is_token_file(path: &Path) -> bool {
+    let suffixes = [
+        auth_code_grant::token_file(""),
+        device_code_flow::token_file(""),
+    ];
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| {
+            suffixes
+                .iter()
+                .filter_map(|suffix| suffix.to_str())
+                .any(|suffix| name.ends_with(suffix))
+        })
+}
+
+fn remove(path: &Path) {
+    if is_token_file(path) {
+        log::warn!("Not removing the token file {}.", path.display());
+        return;
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            log::warn!("Unable to remove {}: {e}", path.display())
+        }
+        _ => {}
+    }
+}
+
+/// A registry of temporary files.
+pub struct Cleanup {
+    files: Mutex<Vec<PathBuf>>,
+    done: AtomicBool,
+}
+
+impl Cleanup {
+    const fn new() -> Self {
+        Self {
+            files: Mutex::new(Vec::new()),
+            done: AtomicBool::new(false),
+        }
+    }
+
+    fn files(&self) -> std::sync::MutexGuard<'_, Vec<PathBuf>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Removes the registered files, only the first time it is called.
+    fn remove_all(&self) {
+        if self.done.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for path in self.files().drain(..) {
+            remove(&path);
+        }
+    }
+}
+
+/// A temporary file that is removed when the guard is dropped, or by [`remove_all`]
+/// when the run is interrupted before that.
+pub struct TempFile<'a> {
+    cleanup: &'a Cleanup,
+    path: PathBuf,
+}
+
+impl TempFile<'static> {
+    pub fn new(path: PathBuf) -> Self {
+        TempFile::register(&TEMP_FILES, path)
+    }
+}
+
+impl<'a> TempFile<'a> {
+    fn register(cleanup: &'a Cleanup, path: PathBuf) -> Self {
+        cleanup.files().push(path.clone());
+        Self { cleanup, path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile<'_> {
+    fn drop(&mut self) {
+        self.cleanup.files().retain(|path| *path != self.path);
+        remove(&self.path);
+    }
+}
+
+/// Removes the temporary files of this run, e.g. on Ctrl-C, keeping the token files.
+pub fn remove_all() {
+    TEMP_FILES.remove_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{Cleanup, TempFile};
+
+    #[test]
+    fn test_temp_file_is_removed_on_drop() {
+        let cleanup = Cleanup::new();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("metrics.prom.tmp");
+
+        let temp_file = TempFile::register(&cleanup, path.clone());
+        fs::write(temp_file.path(), "metrics").unwrap();
+        drop(temp_file);
+        assert!(!path.exists());
+        assert!(cleanup.files().is_empty());
+    }
+
+    #[test]
+    fn test_cancellation_keeps_the_token_file() {
+        let cleanup = Cleanup::new();
+        let directory = tempfile::tempdir().unwrap();
+        let temp_path = directory.path().join("metrics.prom.tmp");
+        let token_path = directory.path().join("client_device_code_flow.json");
+        fs::write(&temp_path, "metrics").unwrap();
+        fs::write(&token_path, "{}").unwrap();
+
+        // The guards are still alive, as when Ctrl-C exits the process.
+        let _temp_file = TempFile::register(&cleanup, temp_path.clone());
+        let _token_file = TempFile::register(&cleanup, token_path.clone());
+        cleanup.remove_all();
+
+        assert!(!temp_path.exists());
+        assert!(token_path.exists());
+    }
+}
@@ -0,0 +1,1891 @@
+// Standard libraries
+use std::ffi::OsString;
+use std::fs;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 3rd party crates
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+use oauth2::{url::Url, AccessToken, ClientSecret, Scope};
+use regex::Regex;
+
+// My crates
+use crate::backoff::Jitter;
+use crate::cloud::{Cloud, OAuthVersion};
+use crate::color::ColorChoice;
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::last_run::LastRun;
+use crate::manifest::AppManifest;
+use crate::message::{
+    parse_date, parse_domain, parse_language_tag, BodyParts, DateHeader, Importance, TestScenario,
+};
+use crate::mime::{parse_boundary, BodyEncoding, Charset, MultipartStructure};
+use crate::net::IpVersion;
+use crate::report::OutputFormat;
+use crate::smtp::{
+    self, parse_banner_pattern, parse_ehlo_name, parse_email, parse_smtp_server, parse_timeout,
+    SmtpAuthMode, SmtpAuthStyle, SmtpRoute, SmtpServer,
+};
+use crate::socks5::{parse_socks5, Socks5Proxy};
+use crate::tls::{TlsCipherSuite, TlsPolicy, TlsVersion};
+use crate::token_encryption::TokenEncryption;
+use crate::token_export::ExportedToken;
+use crate::token_keeper::OnRefreshFailure;
+use crate::OAuth2TokenGrantFlow;
+
+/// Test tool for the Microsoft SMTP XOAUTH2 email workflow.
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    // The password of --auth-mode basic replaces the login. Only the run has both the
+    // client and the SMTP arguments, the subcommands have one or the other.
+    mut_arg("grant_type", |arg| arg.required_unless_present("smtp_password")),
+    mut_arg("client_id", |arg| arg.required_unless_present("smtp_password")),
+    mut_arg("smtp_password", |arg| arg.conflicts_with_all(BASIC_AUTH_CONFLICTS))
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub global: GlobalArgs,
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+/// The flags taking the place of the positional arguments of the earlier versions of
+/// this tool, in the order they were read.
+const LEGACY_RUN_FLAGS: [&str; 6] = [
+    "--grant-type",
+    "--client-id",
+    "--client-secret",
+    "--recipient-email",
+    "--recipient-name",
+    "--debug-level",
+];
+const LEGACY_REFRESH_FLAGS: [&str; 4] = [
+    "--grant-type",
+    "--client-id",
+    "--client-secret",
+    "--debug-level",
+];
+
+/// Rewrites an invocation with positional arguments only, the way earlier versions of
+/// this tool were called, into the named flags. Returns `None` when there is any flag,
+/// the arguments are then parsed as they are.
+pub fn legacy_args(args: &[OsString]) -> Option<Vec<OsString>> {
+    let (program, rest) = args.split_first()?;
+    let is_flag = |arg: &OsString| {
+        let arg = arg.to_string_lossy();
+        arg.starts_with("--") || arg == "-h" || arg == "-V"
+    };
+    if rest.is_empty() || rest.iter().any(is_flag) {
+        return None;
+    }
+
+    let (command, positional, flags) = match rest.split_first() {
+        Some((command, positional)) if command == "refresh" => {
+            (Some(command), positional, &LEGACY_REFRESH_FLAGS[..])
+        }
+        _ => (None, rest, &LEGACY_RUN_FLAGS[..]),
+    };
+    if positional.len() > flags.len() {
+        return None;
+    }
+
+    let mut legacy = vec![program.clone()];
+    legacy.extend(command.cloned());
+    for (flag, value) in flags.iter().zip(positional) {
+        legacy.push(flag.into());
+        legacy.push(value.clone());
+    }
+    Some(legacy)
+}
+
+const LOG_LEVELS: [(&str, LevelFilter); 7] = [
+    ("error", LevelFilter::Error),
+    ("err", LevelFilter::Error),
+    ("warn", LevelFilter::Warn),
+    ("warning", LevelFilter::Warn),
+    ("info", LevelFilter::Info),
+    ("debug", LevelFilter::Debug),
+    ("trace", LevelFilter::Trace),
+];
+
+/// Parses the debug log level, also taking `err` and `warning`, so that a typo is an
+/// error instead of silently logging at info.
+pub fn parse_log_level(level: &str) -> Result<LevelFilter, String> {
+    LOG_LEVELS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(level.trim()))
+        .map(|(_, level)| *level)
+        .ok_or_else(|| {
+            format!(
+                "invalid log level {level:?}, expected one of error, warn, info, debug or trace"
+            )
+        })
+}
+
+/// The query parameters the authorize URL already has, which `--authorize-param` cannot set.
+const RESERVED_AUTHORIZE_PARAMS: &[&str] = &[
+    "client_id",
+    "response_type",
+    "redirect_uri",
+    "scope",
+    "state",
+    "code_challenge",
+    "code_challenge_method",
+    "resource",
+];
+
+/// Parses a `key=value` of `--authorize-param`, the value may be empty or contain `=`.
+pub fn parse_authorize_param(param: &str) -> Result<(String, String), String> {
+    let (key, value) = param
+        .split_once('=')
+        .ok_or_else(|| format!("{param:?} is not key=value"))?;
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("{param:?} has no key"));
+    }
+    if RESERVED_AUTHORIZE_PARAMS
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(key))
+    {
+        return Err(format!(
+            "{key} is already set by the login, it cannot be given"
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parses the URI of `--resource`, e.g. https://outlook.office365.com.
+pub fn parse_resource(resource: &str) -> Result<String, String> {
+    Url::parse(resource).map_err(|e| format!("{resource:?} is not a URI: {e}"))?;
+    Ok(resource.to_string())
+}
+
+/// A run id unique to this invocation, from the time and the process id.
+fn generate_run_id() -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    format!("{:x}-{:x}", timestamp.as_nanos(), std::process::id())
+}
+
+/// Checks a `--run-id`: 1 to 64 letters, digits, `-`, `_` or `.`, to keep the log
+/// lines parsable.
+pub fn parse_run_id(run_id: &str) -> Result<String, String> {
+    let valid = (1..=64).contains(&run_id.len())
+        && run_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(run_id.to_string())
+    } else {
+        Err(format!(
+            "{run_id:?} is not a valid run id, use up to 64 letters, digits, -, _ or ."
+        ))
+    }
+}
+
+/// Options shared by all the commands.
+#[derive(Args, Debug)]
+pub struct GlobalArgs {
+    /// Read the flags from this TOML file: those of its [defaults] table, overridden by
+    /// those of the [profiles.<name>] of --config-profile. The command line takes precedence
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Use this profile of the --config file
+    #[arg(long, global = true, value_name = "NAME", requires = "config")]
+    pub config_profile: Option<String>,
+    /// Directory where the token files are stored
+    #[arg(long, global = true)]
+    pub token_dir: Option<PathBuf>,
+    /// Encrypt the tokens in the token files: none, passphrase (asked for or read from XOAUTH2_TOKEN_PASSPHRASE) or os (DPAPI on Windows, a key in the OS keyring elsewhere)
+    #[arg(long, global = true, value_name = "MODE", default_value = "none")]
+    pub token_encryption: TokenEncryption,
+    /// Export the spans of the login, the profile request, the SMTP connection and the send
+    /// over OTLP to this collector, e.g. http://localhost:4317 (needs the otel feature)
+    #[arg(long, global = true, value_name = "URL")]
+    pub otel_endpoint: Option<String>,
+    /// Connect over IPv4 (v4), IPv6 (v6) or either (auto)
+    #[arg(long, global = true, default_value = "auto")]
+    pub ip_version: IpVersion,
+    /// Login, read the profile and send in this cloud: commercial, gcc-high, dod or china
+    #[arg(long, global = true, default_value = "commercial")]
+    pub cloud: Cloud,
+    /// Ask for this scope instead of the default ones, can be repeated
+    #[arg(long, global = true, value_name = "SCOPE")]
+    pub scope: Vec<String>,
+    /// Ask for these space-delimited scopes instead of the default ones, e.g. as listed
+    /// in the Azure portal
+    #[arg(long, global = true, value_name = "SCOPES")]
+    pub scopes: Option<String>,
+    /// Add this query parameter to the authorize URL, e.g. domain_hint=contoso.com, can be repeated
+    #[arg(long, global = true, value_name = "KEY=VALUE", value_parser = parse_authorize_param)]
+    pub authorize_param: Vec<(String, String)>,
+    /// Login with the v1 (Azure AD) endpoints, which ask for a resource, or the v2 ones
+    #[arg(long, global = true, value_name = "VERSION", default_value = "v2")]
+    pub oauth_version: OAuthVersion,
+    /// Ask the v1 endpoints for a token of this resource [default: Exchange Online of the cloud]
+    #[arg(long, global = true, value_name = "URI", value_parser = parse_resource)]
+    pub resource: Option<String>,
+    /// Use HTTP/1.1 for the token and profile requests instead of negotiating HTTP/2
+    #[arg(long, global = true)]
+    pub http1_only: bool,
+    /// Refuse TLS versions older than this on the SMTP and HTTPS connections: 1.2 or 1.3
+    #[arg(long, global = true, value_name = "VERSION", default_value = "1.2")]
+    pub min_tls_version: TlsVersion,
+    /// Offer only these comma-separated cipher suites, by their IANA names, e.g.
+    /// TLS_AES_256_GCM_SHA384
+    #[arg(long, global = true, value_name = "SUITES", value_delimiter = ',')]
+    pub tls_ciphers: Vec<TlsCipherSuite>,
+    /// Connect to the SMTP server and send the HTTP requests through this SOCKS5 proxy,
+    /// with its username and password if it asks for them
+    #[arg(long, global = true, value_name = "[USER:PASSWORD@]HOST:PORT", value_parser = parse_socks5)]
+    pub socks5: Option<Socks5Proxy>,
+    /// Accept a client id that is not a GUID, for providers other than Microsoft
+    #[arg(long, global = true)]
+    pub allow_non_guid_client_id: bool,
+    /// Read the login endpoints from the OpenID discovery document of the tenant
+    #[arg(long, global = true)]
+    pub discover_endpoints: bool,
+    /// Follow at most this many redirects of the token and profile requests
+    #[arg(long, global = true, default_value = "5")]
+    pub max_redirects: u32,
+    /// Send at most this many HTTP requests per minute to each host, delaying the others
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_requests_per_minute: Option<u32>,
+    /// Clear the settings saved for --resume
+    #[arg(long, global = true)]
+    pub forget: bool,
+    /// error, warn, info, debug or trace
+    #[arg(long, global = true, default_value = "info", value_parser = parse_log_level)]
+    pub debug_level: LevelFilter,
+    /// Color the log levels: auto (on a terminal without NO_COLOR), always or never
+    #[arg(long, global = true, default_value = "auto")]
+    pub color: ColorChoice,
+    /// Print the send report as text (logged) or json (on stdout)
+    #[arg(long, global = true, default_value = "text")]
+    pub output: OutputFormat,
+    /// The id of this run in each log line and in the report [default: generated]
+    #[arg(
+        long,
+        global = true,
+        value_name = "ID",
+        default_value_t = generate_run_id(),
+        hide_default_value = true,
+        value_parser = parse_run_id
+    )]
+    pub run_id: String,
+}
+
+impl GlobalArgs {
+    /// The TLS versions and cipher suites of `--min-tls-version` and `--tls-ciphers`.
+    pub fn tls(&self) -> TlsPolicy {
+        TlsPolicy {
+            min_version: self.min_tls_version,
+            cipher_suites: self.tls_ciphers.clone(),
+        }
+    }
+
+    /// How the SMTP server is reached: `--cloud`, `--ip-version`, the TLS limits and
+    /// `--socks5`.
+    pub fn smtp_route(&self) -> SmtpRoute {
+        SmtpRoute {
+            cloud: self.cloud,
+            ip_version: self.ip_version,
+            tls: self.tls(),
+            socks5: self.socks5.clone(),
+        }
+    }
+
+    /// The scopes of `--scope` and `--scopes` together, without empty or repeated
+    /// ones, or none to ask for the default ones.
+    pub fn scopes(&self) -> Option<Vec<Scope>> {
+        let mut scopes: Vec<Scope> = Vec::new();
+        let given = self.scope.iter().map(String::as_str).chain(
+            self.scopes
+                .iter()
+                .flat_map(|scopes| scopes.split_whitespace()),
+        );
+        for scope in given.map(str::trim).filter(|scope| !scope.is_empty()) {
+            let scope = Scope::new(scope.to_string());
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        (!scopes.is_empty()).then_some(scopes)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Exchange the cached refresh token for a new access token and save it
+    Refresh(RefreshArgs),
+    /// Login with the authorization code grant in two steps, without a prompt or a listener
+    Exchange(ExchangeArgs),
+    /// Login with the device code flow in two steps, requesting the code and polling for it
+    Poll(PollArgs),
+    /// Check the integrity of a cached token file without contacting the network
+    ValidateToken(ValidateTokenArgs),
+    /// Time connect, EHLO, STARTTLS and QUIT cycles with the SMTP server, without a login or a send
+    BenchmarkConnect(BenchmarkConnectArgs),
+    /// Report the validity and expiry of the cached token, refreshing it only with --refresh-if-needed
+    Status(StatusArgs),
+}
+
+/// The app registration used to get the access token.
+#[derive(Args, Debug)]
+pub struct ClientArgs {
+    /// AuthorizationCodeGrant, DeviceCodeFlow or PreAcquired (with --access-token)
+    #[arg(long, required_unless_present_any = CLIENT_NOT_REQUIRED)]
+    pub grant_type: Option<OAuth2TokenGrantFlow>,
+    /// The application (client) id of the app registration
+    #[arg(long, required_unless_present_any = CLIENT_ID_NOT_REQUIRED)]
+    pub client_id: Option<String>,
+    /// The client secret, leave it out (or use None) if there is no client secret
+    #[arg(long)]
+    pub client_secret: Option<String>,
+    /// The tenant to login to, e.g. its domain or directory id [default: common]
+    #[arg(long)]
+    pub tenant: Option<String>,
+    /// Reuse the settings of the last successful run, the arguments given still take precedence
+    #[arg(long, conflicts_with = "forget")]
+    pub resume: bool,
+    /// Run with each of the accounts listed in this JSON file instead, see the README
+    #[arg(long, value_name = "PATH", conflicts_with = "resume")]
+    pub accounts_file: Option<PathBuf>,
+    /// Use this access token instead of logging in, it cannot be refreshed
+    #[arg(long, value_name = "TOKEN", conflicts_with_all = ["access_token_file", "accounts_file"])]
+    pub access_token: Option<String>,
+    /// Read the access token to use instead of logging in from this file, e.g. one of --export-token
+    #[arg(long, value_name = "PATH", conflicts_with = "accounts_file")]
+    pub access_token_file: Option<PathBuf>,
+    /// Read the client id from this exported app registration manifest, --client-id takes precedence
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["accounts_file", "access_token", "access_token_file"]
+    )]
+    pub manifest: Option<PathBuf>,
+}
+
+/// The arguments that make the grant type and client id optional.
+const CLIENT_NOT_REQUIRED: [&str; 5] = [
+    "resume",
+    "forget",
+    "accounts_file",
+    "access_token",
+    "access_token_file",
+];
+
+/// The arguments that make the client id optional.
+const CLIENT_ID_NOT_REQUIRED: [&str; 6] = [
+    "resume",
+    "forget",
+    "accounts_file",
+    "access_token",
+    "access_token_file",
+    "manifest",
+];
+
+/// The arguments of the login that `--auth-mode basic` does without.
+const BASIC_AUTH_CONFLICTS: [&str; 4] = [
+    "accounts_file",
+    "access_token",
+    "access_token_file",
+    "export_token",
+];
+
+/// The app registration after applying the settings saved for `--resume`.
+#[derive(Debug)]
+pub struct Client {
+    pub grant_type: OAuth2TokenGrantFlow,
+    pub client_id: String,
+    pub client_secret: Option<ClientSecret>,
+    pub tenant: String,
+    /// The token given with `--access-token` or `--access-token-file`.
+    pub access_token: Option<AccessToken>,
+}
+
+impl Client {
+    /// The client of `--auth-mode basic`, which does not login: it has an empty token
+    /// given instead, which is not sent.
+    pub fn basic_auth() -> Self {
+        Self {
+            grant_type: OAuth2TokenGrantFlow::PreAcquired,
+            client_id: String::new(),
+            client_secret: None,
+            tenant: "common".to_string(),
+            access_token: Some(AccessToken::new(String::new())),
+        }
+    }
+}
+
+/// Takes the argument if it was given, otherwise the saved setting.
+fn resume<T>(given: Option<T>, saved: Option<T>, name: &str) -> OAuth2Result<T> {
+    given.or(saved).ok_or_else(|| {
+        OAuth2Error::new(
+            ErrorCodes::ConfigurationError,
+            format!("The {name} is missing and there are no saved settings to resume."),
+        )
+    })
+}
+
+/// The client secret, where a missing one or `None` means there is no client secret.
+pub fn client_secret(client_secret: Option<&str>) -> Option<ClientSecret> {
+    match client_secret {
+        None | Some("None") => None,
+        Some(client_secret) => Some(ClientSecret::new(client_secret.to_string())),
+    }
+}
+
+/// Whether `id` has the 8-4-4-4-12 hex digits form of a GUID.
+fn is_guid(id: &str) -> bool {
+    let groups: Vec<&str> = id.split('-').collect();
+    groups.iter().map(|group| group.len()).eq([8, 4, 4, 4, 12])
+        && groups
+            .iter()
+            .all(|group| group.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Checks that the client id is a GUID, like the ids of the Microsoft app registrations,
+/// unless `--allow-non-guid-client-id` is given.
+pub fn check_client_id(client_id: &str, allow_non_guid: bool) -> OAuth2Result<()> {
+    if allow_non_guid || is_guid(client_id) {
+        return Ok(());
+    }
+    Err(OAuth2Error::new(
+        ErrorCodes::InvalidClientId,
+        format!(
+            "The client id {client_id:?} is not a GUID like \
+            00000000-0000-0000-0000-000000000000, check it for a copy and paste mistake. \
+            Use --allow-non-guid-client-id if it is not a Microsoft app registration."
+        ),
+    ))
+}
+
+/// Checks the access token given instead of logging in, which may have the `Bearer`
+/// prefix of an Authorization header.
+fn pre_acquired_token(token: &str) -> OAuth2Result<AccessToken> {
+    let token = token.trim();
+    let token = match token.split_once(char::is_whitespace) {
+        Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => token.trim(),
+        _ if token.eq_ignore_ascii_case("Bearer") => "",
+        _ => token,
+    };
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return Err(OAuth2Error::new(
+            ErrorCodes::ConfigurationError,
+            "The access token must be a non-empty Bearer token.".into(),
+        ));
+    }
+    Ok(AccessToken::new(token.to_string()))
+}
+
+impl ClientArgs {
+    /// Whether anything to run with was given, otherwise only `--forget` is done.
+    pub fn is_given(&self) -> bool {
+        self.grant_type.is_some()
+            || self.resume
+            || self.accounts_file.is_some()
+            || self.access_token.is_some()
+            || self.access_token_file.is_some()
+    }
+
+    /// The access token given instead of logging in, if any.
+    fn access_token(&self) -> OAuth2Result<Option<AccessToken>> {
+        let token = match (&self.access_token, &self.access_token_file) {
+            (Some(token), _) => token.clone(),
+            (None, Some(path)) => {
+                let text = fs::read_to_string(path)?;
+                if !text.trim_start().starts_with('{') {
+                    text
+                } else {
+                    let exported = ExportedToken::parse(&text)?;
+                    if exported.has_expired() == Some(true) {
+                        log::warn!("The exported access token has expired.");
+                    }
+                    exported.access_token
+                }
+            }
+            (None, None) => return Ok(None),
+        };
+        pre_acquired_token(&token).map(Some)
+    }
+
+    pub fn resolve(&self, last_run: Option<&LastRun>) -> OAuth2Result<Client> {
+        if let Some(access_token) = self.access_token()? {
+            if let Some(grant_type) = self
+                .grant_type
+                .as_ref()
+                .filter(|grant_type| **grant_type != OAuth2TokenGrantFlow::PreAcquired)
+            {
+                return Err(OAuth2Error::new(
+                    ErrorCodes::ConfigurationError,
+                    format!("An access token cannot be given with the {grant_type:?} grant type."),
+                ));
+            }
+            log::warn!("The given access token is used as it is, it cannot be refreshed.");
+            return Ok(Client {
+                grant_type: OAuth2TokenGrantFlow::PreAcquired,
+                client_id: self.client_id.clone().unwrap_or_default(),
+                client_secret: None,
+                tenant: self.tenant.clone().unwrap_or_else(|| "common".to_string()),
+                access_token: Some(access_token),
+            });
+        }
+        if self.grant_type == Some(OAuth2TokenGrantFlow::PreAcquired) {
+            return Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                "The PreAcquired grant type needs --access-token or --access-token-file.".into(),
+            ));
+        }
+
+        let manifest = self
+            .manifest
+            .as_deref()
+            .map(AppManifest::load)
+            .transpose()?;
+        if let Some(manifest) = &manifest {
+            manifest.log();
+            if self.client_id.is_some() {
+                log::info!("Using the given --client-id instead of the one of the manifest.");
+            }
+        }
+
+        Ok(Client {
+            grant_type: resume(
+                self.grant_type.clone(),
+                last_run.map(|last_run| last_run.grant_type.clone()),
+                "grant type",
+            )?,
+            client_id: resume(
+                self.client_id
+                    .clone()
+                    .or(manifest.map(|manifest| manifest.client_id)),
+                last_run.map(|last_run| last_run.client_id.clone()),
+                "client id",
+            )?,
+            client_secret: client_secret(self.client_secret.as_deref()),
+            tenant: self
+                .tenant
+                .clone()
+                .or(last_run.map(|last_run| last_run.tenant.clone()))
+                .unwrap_or_else(|| "common".to_string()),
+            access_token: None,
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    /// The e-mail address of the recipient
+    #[arg(
+        long,
+        required_unless_present_any = ["resume", "forget", "accounts_file", "to_self", "recipients_stdin", "exec"]
+    )]
+    pub recipient_email: Option<String>,
+    /// The display name of the recipient
+    #[arg(
+        long,
+        required_unless_present_any = ["resume", "forget", "accounts_file", "to_self", "recipients_stdin", "exec"]
+    )]
+    pub recipient_name: Option<String>,
+    /// Send the email to the sender instead of --recipient-email, as a quick smoke test
+    #[arg(long, conflicts_with_all = ["recipient_email", "recipient_name", "accounts_file"])]
+    pub to_self: bool,
+    /// Read the recipients from stdin instead of --recipient-email, one name:email per line
+    #[arg(
+        long,
+        conflicts_with_all = ["recipient_email", "recipient_name", "accounts_file", "to_self"]
+    )]
+    pub recipients_stdin: bool,
+    /// Send to at most this many recipients per message, splitting the recipients into messages sent one after the other [default: 500, the Office 365 limit]
+    #[arg(
+        long,
+        value_name = "N",
+        default_value = "500",
+        hide_default_value = true,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub batch_size: u32,
+    /// Delete the cached token and login again once if the SMTP server rejects it
+    #[arg(long)]
+    pub auto_relogin: bool,
+    /// Get the sender profile from this URL [default: https://outlook.office.com/api/v2.0/me/ or the one of --cloud]
+    #[arg(long, value_name = "URL")]
+    pub profile_endpoint: Option<Url>,
+    /// Send this language tag in the Accept-Language header of the sender profile request, e.g. de-DE
+    #[arg(long, value_name = "TAG", value_parser = parse_language_tag)]
+    pub accept_language: Option<String>,
+    /// Send as this address without reading the sender profile, for tokens that cannot read it
+    #[arg(long, requires = "sender_name", conflicts_with = "profile_endpoint")]
+    pub sender_email: Option<String>,
+    /// The display name of the sender given with --sender-email
+    #[arg(long, requires = "sender_email")]
+    pub sender_name: Option<String>,
+    /// The display name of the sender when the profile has none [default: the local part of the e-mail address]
+    #[arg(long, value_name = "NAME")]
+    pub default_display_name: Option<String>,
+    /// Write Prometheus metrics of the run to this file, e.g. for the node_exporter textfile collector
+    #[arg(long, value_name = "PATH", conflicts_with = "accounts_file")]
+    pub metrics_file: Option<PathBuf>,
+    /// Write the acquired token, with its refresh token, expiry and scopes, to this file as JSON
+    #[arg(long, value_name = "PATH", conflicts_with = "accounts_file")]
+    pub export_token: Option<PathBuf>,
+    /// Login and build the message but do not send it, e.g. with --dump-message
+    #[arg(long)]
+    pub no_send: bool,
+    /// Check that the token meets the app registration requirements and explain known login errors
+    #[arg(long)]
+    pub first_use_doctor: bool,
+    /// Do not check that the login and SMTP hosts are reachable before the login
+    #[arg(long)]
+    pub skip_preflight: bool,
+    /// Check the arguments and print what the run would do, without any network request
+    #[arg(long, visible_alias = "explain", conflicts_with_all = ["accounts_file", "forget"])]
+    pub dry_run: bool,
+    /// Login, then run this command with the access token in $XOAUTH2_ACCESS_TOKEN instead of sending the email, exiting with its exit code
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["accounts_file", "forget", "dry_run", "no_send", "smtp_password"]
+    )]
+    pub exec: Option<String>,
+    #[command(flatten)]
+    pub login: LoginArgs,
+    #[command(flatten)]
+    pub message: MessageArgs,
+    #[command(flatten)]
+    pub smtp: SmtpArgs,
+}
+
+impl RunArgs {
+    /// The display name and e-mail address of the sender, when both were given, or the
+    /// `--smtp-username` of `--auth-mode basic` without a display name.
+    pub fn sender(&self) -> Option<(String, String)> {
+        match (
+            &self.sender_name,
+            &self.sender_email,
+            &self.smtp.smtp_username,
+        ) {
+            (Some(name), Some(email), _) => Some((name.clone(), email.clone())),
+            (_, _, Some(username)) if self.smtp.auth_mode == SmtpAuthMode::Basic => {
+                Some((String::new(), username.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// The e-mail addresses and display names of the recipients, read from stdin with
+    /// `--recipients-stdin`, none with `--to-self`.
+    pub fn recipients(&self, last_run: Option<&LastRun>) -> OAuth2Result<Vec<(String, String)>> {
+        if self.to_self {
+            return Ok(Vec::new());
+        }
+        if self.recipients_stdin {
+            let recipients = parse_recipients(std::io::stdin().lock())?;
+            log::info!("Loaded {} recipient(s) from stdin.", recipients.len());
+            return Ok(recipients);
+        }
+        Ok(vec![self.recipient(last_run)?])
+    }
+
+    /// The e-mail address and display name of the recipient.
+    pub fn recipient(&self, last_run: Option<&LastRun>) -> OAuth2Result<(String, String)> {
+        Ok((
+            resume(
+                self.recipient_email.clone(),
+                last_run.map(|last_run| last_run.recipient_email.clone()),
+                "recipient email",
+            )?,
+            resume(
+                self.recipient_name.clone(),
+                last_run.map(|last_run| last_run.recipient_name.clone()),
+                "recipient name",
+            )?,
+        ))
+    }
+}
+
+/// The e-mail addresses and display names of the recipients once the sender is known,
+/// the sender itself with `--to-self`.
+pub fn recipients_or_sender<'a>(
+    recipients: &[(&'a str, &'a str)],
+    (sender_name, sender_email): (&'a str, &'a str),
+) -> Vec<(&'a str, &'a str)> {
+    if recipients.is_empty() {
+        return vec![(sender_email, sender_name)];
+    }
+    recipients.to_vec()
+}
+
+/// Parses the `name:email` recipients of `--recipients-stdin`, one per line. Blank lines
+/// and the ones starting with `#` are skipped.
+pub fn parse_recipients(input: impl BufRead) -> OAuth2Result<Vec<(String, String)>> {
+    let mut recipients = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: String| {
+            OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!("Recipient on line {}: {reason}", number + 1),
+            )
+        };
+        let (name, email) = line
+            .rsplit_once(':')
+            .ok_or_else(|| invalid(format!("{line:?} is not name:email")))?;
+        let email = smtp::parse_email(email.trim()).map_err(invalid)?;
+        recipients.push((email, name.trim().to_string()));
+    }
+    if recipients.is_empty() {
+        return Err(OAuth2Error::new(
+            ErrorCodes::ConfigurationError,
+            "There is no recipient on stdin.".into(),
+        ));
+    }
+    Ok(recipients)
+}
+
+/// Options for the interactive login, when there is no cached token.
+#[derive(Args, Clone, Copy, Debug, Default)]
+#[command(next_help_heading = "Login")]
+pub struct LoginArgs {
+    /// Paste the redirect URL or the code on stdin instead of waiting on localhost:8080
+    #[arg(long)]
+    pub paste_code: bool,
+    /// Show the device login link as a QR code
+    #[arg(long)]
+    pub qr: bool,
+    #[command(flatten)]
+    pub poll_limits: PollLimitArgs,
+    /// Refresh the cached token even if it has not expired, to test the refresh
+    #[arg(long, conflicts_with_all = ["access_token", "access_token_file"])]
+    pub force_refresh: bool,
+    /// When the refresh of the cached token is rejected, fail (error) or login again (relogin)
+    #[arg(long, value_name = "ACTION", default_value = "error")]
+    pub on_refresh_failure: OnRefreshFailure,
+    /// Login again instead of using a cached token that was not granted all the requested
+    /// scopes, e.g. one added to the app since
+    #[arg(
+        long,
+        conflicts_with_all = ["force_refresh", "access_token", "access_token_file"]
+    )]
+    pub fail_fast_on_scope_downgrade: bool,
+}
+
+/// Limits of the device code polling, when the server keeps answering slow_down, and how
+/// often the login is recalled meanwhile.
+#[derive(Args, Clone, Copy, Debug)]
+pub struct PollLimitArgs {
+    /// Wait at most this long between two polls of the device code flow
+    #[arg(long, value_name = "SECONDS", default_value = "30", value_parser = parse_timeout)]
+    pub max_poll_interval: Duration,
+    /// Give up the device code flow after this many slow_down answers in a row
+    #[arg(long, value_name = "COUNT", default_value = "10")]
+    pub max_slow_down: u32,
+    /// While waiting for the device login, recall the link, the code and the time left
+    /// this often, 0 to never
+    #[arg(long, value_name = "SECONDS", default_value = "60")]
+    pub reminder_interval: u64,
+}
+
+impl Default for PollLimitArgs {
+    fn default() -> Self {
+        Self {
+            max_poll_interval: Duration::from_secs(30),
+            max_slow_down: 10,
+            reminder_interval: 60,
+        }
+    }
+}
+
+/// Options for the SMTP connection.
+#[derive(Args, Debug, Default)]
+#[command(next_help_heading = "SMTP")]
+pub struct SmtpArgs {
+    /// Send to this SMTP server instead of the one of the cloud, e.g. smtp.example.com:587
+    #[arg(long, value_name = "HOST:PORT", value_parser = parse_smtp_server)]
+    pub smtp_server: Option<SmtpServer>,
+    /// Login with XOAUTH2 (xoauth2) or, without OAuth2, with --smtp-username and --smtp-password (basic)
+    #[arg(long, value_name = "MODE", default_value = "xoauth2")]
+    pub auth_mode: SmtpAuthMode,
+    /// The username of --auth-mode basic, also the sender unless --sender-email is given
+    #[arg(long, value_name = "USER", required_if_eq("auth_mode", "basic"))]
+    pub smtp_username: Option<String>,
+    /// The password of --auth-mode basic
+    #[arg(long, value_name = "PASSWORD", required_if_eq("auth_mode", "basic"))]
+    pub smtp_password: Option<String>,
+    /// Send the password of --auth-mode basic even when the server does not offer STARTTLS
+    #[arg(long)]
+    pub allow_plaintext: bool,
+    /// Authenticate as this user instead of the sender, e.g. to send from a shared mailbox
+    #[arg(long, value_name = "UPN", visible_alias = "sasl-username")]
+    pub smtp_login: Option<String>,
+    /// Allow a sender other than the --smtp-login mailbox, which needs the SendAs permission on it
+    #[arg(long)]
+    pub allow_send_as: bool,
+    /// The MAIL FROM (return path) address, the From header stays the sender's [default: the sender's address]
+    #[arg(long, value_name = "EMAIL", value_parser = parse_email)]
+    pub envelope_from: Option<String>,
+    /// The host name announced in EHLO [default: the name of this machine]
+    #[arg(long, value_name = "HOSTNAME", value_parser = parse_ehlo_name)]
+    pub ehlo_name: Option<String>,
+    /// Send the XOAUTH2 credentials in the AUTH command (initial-response) or after the server's challenge
+    #[arg(long, value_name = "STYLE", default_value = "initial-response")]
+    pub smtp_auth_style: SmtpAuthStyle,
+    /// Fail unless the greeting banner of the SMTP server matches this regex or text, e.g. "Microsoft ESMTP"
+    #[arg(long, value_name = "PATTERN", value_parser = parse_banner_pattern)]
+    pub expect_banner: Option<Regex>,
+    /// Give up connecting (TCP, greeting, EHLO and STARTTLS) after this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    pub smtp_connect_timeout: Option<Duration>,
+    /// Give up waiting for AUTH, MAIL FROM, RCPT TO or DATA after this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    pub smtp_command_timeout: Option<Duration>,
+    /// Send again this many times after a network error or timeout, waiting longer each time
+    #[arg(long, value_name = "COUNT", default_value = "0")]
+    pub send_retries: u32,
+    /// Spread the waits between the send retries: none, full or equal jitter
+    #[arg(long, value_name = "STRATEGY", default_value = "full")]
+    pub retry_jitter: Jitter,
+    /// Enable TCP keepalive on the SMTP connection, probing after this many idle seconds
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    pub tcp_keepalive: Option<Duration>,
+    /// Set TCP_NODELAY on the SMTP connection, sending small writes without delay
+    #[arg(long)]
+    pub tcp_nodelay: bool,
+    /// Log the SMTP commands and replies at debug level, with the credentials redacted
+    #[arg(long)]
+    pub smtp_transcript: bool,
+}
+
+impl SmtpArgs {
+    /// The username and password of `--auth-mode basic`, none with XOAUTH2.
+    pub fn basic_credentials(&self) -> OAuth2Result<Option<(&str, &str)>> {
+        match (self.auth_mode, &self.smtp_username, &self.smtp_password) {
+            (SmtpAuthMode::Basic, Some(username), Some(password)) => Ok(Some((username, password))),
+            (SmtpAuthMode::Basic, _, _) => Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                "Both --smtp-username and --smtp-password are needed with --auth-mode basic."
+                    .into(),
+            )),
+            (SmtpAuthMode::Xoauth2, None, None) => Ok(None),
+            (SmtpAuthMode::Xoauth2, _, _) => Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                "--smtp-username and --smtp-password are only used with --auth-mode basic.".into(),
+            )),
+        }
+    }
+}
+
+/// Options for the test message.
+#[derive(Args, Debug, Default)]
+#[command(next_help_heading = "Message")]
+pub struct MessageArgs {
+    /// Set the Importance and X-Priority headers: high, normal or low
+    #[arg(long)]
+    pub importance: Option<Importance>,
+    /// Set the Content-Language header to this language tag, e.g. en or pt-BR
+    #[arg(long, value_name = "TAG", value_parser = parse_language_tag)]
+    pub content_language: Option<String>,
+    /// Set the Date header: now, none, or an RFC 2822 or RFC 3339 date, e.g. in the future
+    #[arg(long, value_name = "DATE", default_value = "now", value_parser = parse_date)]
+    pub date: DateHeader,
+    /// Encode the body parts as 7bit, 8bit, quoted-printable or base64
+    #[arg(long, value_name = "ENCODING")]
+    pub body_encoding: Option<BodyEncoding>,
+    /// The charset of the text and HTML bodies: utf-8, us-ascii or iso-8859-1
+    #[arg(long, default_value = "utf-8")]
+    pub charset: Charset,
+    /// The domain of the generated Message-ID [default: the sender's domain]
+    #[arg(long, value_name = "DOMAIN", value_parser = parse_domain)]
+    pub message_id_domain: Option<String>,
+    /// Add this iCalendar file as a text/calendar; method=REQUEST alternative of the body
+    #[arg(long, value_name = "PATH")]
+    pub ics_file: Option<PathBuf>,
+    /// Put the alternatives of the body in a multipart/alternative (alternative), a
+    /// multipart/mixed (mixed) or a multipart/alternative in reverse order (reversed)
+    #[arg(long, value_name = "STRUCTURE", default_value = "alternative")]
+    pub multipart_structure: MultipartStructure,
+    /// The boundary of the outermost multipart, the nested ones get a =_<n>_ prefix
+    #[arg(long, value_name = "BOUNDARY", value_parser = parse_boundary)]
+    pub mime_boundary: Option<String>,
+    /// Send both the text and HTML bodies (both), or only one of them (html or text)
+    #[arg(long, value_name = "PARTS", default_value = "both")]
+    pub content_type: BodyParts,
+    /// Append this footer to the text and HTML bodies
+    #[arg(long, value_name = "TEXT", conflicts_with = "footer_file")]
+    pub footer: Option<String>,
+    /// Append the footer in this file to the text and HTML bodies
+    #[arg(long, value_name = "PATH")]
+    pub footer_file: Option<PathBuf>,
+    /// Also write the MIME message, exactly as it is sent, to this file
+    #[arg(long, value_name = "PATH")]
+    pub dump_message: Option<PathBuf>,
+    /// Check that the From header is a valid RFC 5322 mailbox whose display name decodes to the sender name, failing otherwise
+    #[arg(long)]
+    pub check_from_header: bool,
+    /// Warn, before the login, when the estimated size of the message is over this many bytes, e.g. the limit of the mailbox
+    #[arg(long, value_name = "BYTES")]
+    pub max_message_size: Option<u64>,
+    /// Send the headers with an empty text/plain body, to test how gateways handle it
+    #[arg(long, conflicts_with_all = ["content_type", "footer", "footer_file", "ics_file"])]
+    pub empty_body: bool,
+    /// Send a canned test message: simple-text, html-with-inline, large-attachment, calendar
+    /// or high-importance
+    #[arg(
+        long,
+        conflicts_with_all = ["importance", "ics_file", "content_type", "empty_body"]
+    )]
+    pub scenario: Option<TestScenario>,
+}
+
+#[derive(Args, Debug)]
+pub struct RefreshArgs {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    /// Print the new access token to stdout
+    #[arg(long)]
+    pub print_token: bool,
+}
+
+/// The two steps of the scripted login: `--print-authorize-url-only`, then `--code`.
+#[derive(Args, Debug)]
+pub struct ExchangeArgs {
+    /// The application (client) id of the app registration
+    #[arg(long)]
+    pub client_id: String,
+    /// The client secret, leave it out (or use None) if there is no client secret
+    #[arg(long)]
+    pub client_secret: Option<String>,
+    /// The tenant to login to, it is saved for the --code step
+    #[arg(long, default_value = "common")]
+    pub tenant: String,
+    /// Print the authorize URL (and its state with --output json), save the state and exit
+    #[arg(long, required_unless_present = "code", conflicts_with = "code")]
+    pub print_authorize_url_only: bool,
+    /// Exchange this code, or the URL the browser was redirected to, for an access token
+    #[arg(long)]
+    pub code: Option<String>,
+}
+
+/// The arguments of the two steps of the scripted device code flow.
+#[derive(Args, Debug)]
+pub struct PollArgs {
+    /// The application (client) id of the app registration
+    #[arg(long)]
+    pub client_id: String,
+    /// The client secret, leave it out (or use None) if there is no client secret
+    #[arg(long)]
+    pub client_secret: Option<String>,
+    /// The tenant to login to, it is saved for the poll step
+    #[arg(long, default_value = "common")]
+    pub tenant: String,
+    /// Request a device code, show the link and the code to login with, save it and exit
+    #[arg(long)]
+    pub request_only: bool,
+    /// Also show the login link as a QR code in the terminal
+    #[arg(long, requires = "request_only")]
+    pub qr: bool,
+    #[command(flatten)]
+    pub poll_limits: PollLimitArgs,
+}
+
+/// How many handshakes to time, and how to connect.
+#[derive(Args, Debug)]
+pub struct BenchmarkConnectArgs {
+    /// The number of connect, EHLO, STARTTLS and QUIT cycles
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub count: u32,
+    #[command(flatten)]
+    pub smtp: SmtpArgs,
+}
+
+/// The cached token to report on, and what else to do with it.
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+    #[command(flatten)]
+    pub client: ClientArgs,
+    /// Refresh the access token when it has expired, and report the refreshed one
+    #[arg(long)]
+    pub refresh_if_needed: bool,
+    /// Also get the sender profile with the access token, to check it is accepted
+    #[arg(long)]
+    pub with_profile: bool,
+}
+
+/// The token file to check, and who its access token has to be for.
+#[derive(Args, Debug)]
+pub struct ValidateTokenArgs {
+    /// The grant type of the cached token: AuthorizationCodeGrant or DeviceCodeFlow
+    #[arg(long, required_unless_present = "token_file", requires = "client_id")]
+    pub grant_type: Option<OAuth2TokenGrantFlow>,
+    /// The client id the token was cached for, and the one its access token has to be issued to
+    #[arg(long)]
+    pub client_id: Option<String>,
+    /// Check this token file instead of the cached one of --grant-type and --client-id
+    #[arg(long, value_name = "PATH", conflicts_with = "grant_type")]
+    pub token_file: Option<PathBuf>,
+    /// The directory (tenant) id that has to have issued the access token
+    #[arg(long, value_name = "ID")]
+    pub tenant_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use clap::Parser;
+    use log::LevelFilter;
+
+    use super::{
+        check_client_id, legacy_args, parse_authorize_param, parse_log_level, parse_recipients,
+        parse_resource, parse_run_id, recipients_or_sender, Cli, Command,
+    };
+    use crate::cloud::OAuthVersion;
+    use crate::error::ErrorCodes;
+    use crate::last_run::LastRun;
+    use crate::message::TestScenario;
+    use crate::smtp::SmtpAuthMode;
+    use crate::tls::{TlsCipherSuite, TlsPolicy, TlsVersion};
+    use crate::OAuth2TokenGrantFlow;
+
+    fn last_run() -> LastRun {
+        LastRun {
+            grant_type: OAuth2TokenGrantFlow::DeviceCodeFlow,
+            client_id: "saved-client".to_string(),
+            tenant: "saved.onmicrosoft.com".to_string(),
+            recipient_email: "saved@example.com".to_string(),
+            recipient_name: "Saved".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_resume_uses_saved_settings() {
+        let cli = Cli::try_parse_from(["tool", "--resume"]).unwrap();
+        let client = cli.run.client.resolve(Some(&last_run())).unwrap();
+
+        assert_eq!(client.grant_type, OAuth2TokenGrantFlow::DeviceCodeFlow);
+        assert_eq!(client.client_id, "saved-client");
+        assert_eq!(client.tenant, "saved.onmicrosoft.com");
+        assert!(client.client_secret.is_none());
+        assert_eq!(
+            cli.run.recipient(Some(&last_run())).unwrap(),
+            ("saved@example.com".to_string(), "Saved".to_string())
+        );
+    }
+
+    #[test]
+    fn test_arguments_take_precedence_over_saved_settings() {
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--resume",
+            "--tenant",
+            "contoso.onmicrosoft.com",
+            "--grant-type",
+            "AuthorizationCodeGrant",
+            "--client-id",
+            "client",
+            "--client-secret",
+            "secret",
+        ])
+        .unwrap();
+        let client = cli.run.client.resolve(Some(&last_run())).unwrap();
+
+        assert_eq!(
+            client.grant_type,
+            OAuth2TokenGrantFlow::AuthorizationCodeGrant
+        );
+        assert_eq!(client.client_id, "client");
+        assert_eq!(client.tenant, "contoso.onmicrosoft.com");
+        assert_eq!(client.client_secret.unwrap().secret(), "secret");
+        assert_eq!(
+            cli.run.recipient(Some(&last_run())).unwrap().0,
+            "saved@example.com"
+        );
+    }
+
+    #[test]
+    fn test_to_self_sends_to_the_sender() {
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+            "--to-self",
+        ])
+        .unwrap();
+        assert!(cli.run.recipients(Some(&last_run())).unwrap().is_empty());
+        assert_eq!(
+            recipients_or_sender(&[], ("Sender", "sender@contoso.com")),
+            [("sender@contoso.com", "Sender")]
+        );
+        assert_eq!(
+            recipients_or_sender(
+                &[("recipient@example.com", "Recipient")],
+                ("Sender", "sender@contoso.com")
+            ),
+            [("recipient@example.com", "Recipient")]
+        );
+
+        assert!(Cli::try_parse_from([
+            "tool",
+            "--to-self",
+            "--recipient-email",
+            "recipient@example.com"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_recipients_from_stdin() {
+        let stdin = "# Test recipients\n\nAlice Example:alice@example.com\n  Bob: bob@contoso.com  \r\n\n# The end\n";
+        assert_eq!(
+            parse_recipients(stdin.as_bytes()).unwrap(),
+            [
+                ("alice@example.com".to_string(), "Alice Example".to_string()),
+                ("bob@contoso.com".to_string(), "Bob".to_string()),
+            ]
+        );
+
+        let error =
+            parse_recipients("Alice:alice@example.com\nbob@contoso.com\n".as_bytes()).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        assert!(
+            error.error_code_desc.contains("line 2"),
+            "{}",
+            error.error_code_desc
+        );
+        assert!(parse_recipients("Bob:bob\n".as_bytes()).is_err());
+        assert!(parse_recipients("# nobody\n\n".as_bytes()).is_err());
+
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+            "--recipients-stdin",
+        ]);
+        assert!(cli.unwrap().run.recipients_stdin);
+    }
+
+    #[test]
+    fn test_resume_without_saved_settings() {
+        let cli = Cli::try_parse_from(["tool", "--resume"]).unwrap();
+        assert!(cli.run.client.resolve(None).is_err());
+    }
+
+    #[test]
+    fn test_accounts_file_replaces_client_arguments() {
+        let cli = Cli::try_parse_from(["tool", "--accounts-file", "accounts.json"]).unwrap();
+        assert_eq!(
+            cli.run.client.accounts_file.unwrap().to_str(),
+            Some("accounts.json")
+        );
+        assert!(Cli::try_parse_from(["tool", "--accounts-file", "a.json", "--resume"]).is_err());
+    }
+
+    #[test]
+    fn test_log_level_aliases() {
+        assert_eq!(parse_log_level("err"), Ok(LevelFilter::Error));
+        assert_eq!(parse_log_level("warning"), Ok(LevelFilter::Warn));
+        assert_eq!(parse_log_level("WARN"), Ok(LevelFilter::Warn));
+        assert_eq!(parse_log_level(" Debug "), Ok(LevelFilter::Debug));
+        assert_eq!(parse_log_level("trace"), Ok(LevelFilter::Trace));
+    }
+
+    #[test]
+    fn test_invalid_log_level_is_rejected() {
+        let error = parse_log_level("dbug").unwrap_err();
+        assert!(error.contains("\"dbug\""));
+        assert!(error.contains("error, warn, info, debug or trace"));
+
+        let args = [
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ];
+        assert!(Cli::try_parse_from(args.iter().chain(&["--debug-level", "dbug"])).is_err());
+        let cli = Cli::try_parse_from(args.iter().chain(&["--debug-level", "warning"])).unwrap();
+        assert_eq!(cli.global.debug_level, LevelFilter::Warn);
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.global.debug_level, LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_tenant_defaults_to_common() {
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ])
+        .unwrap();
+        let client = cli.run.client.resolve(None).unwrap();
+
+        assert_eq!(client.tenant, "common");
+        assert!(client.client_secret.is_none());
+    }
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    /// Parses the arguments the way `main` does.
+    fn parse(args: &[&str]) -> Cli {
+        let args = os_args(args);
+        Cli::try_parse_from(legacy_args(&args).unwrap_or(args)).unwrap()
+    }
+
+    #[test]
+    fn test_legacy_positional_arguments() {
+        let legacy = parse(&[
+            "tool",
+            "AuthorizationCodeGrant",
+            "client",
+            "secret",
+            "recipient@example.com",
+            "Recipient",
+            "debug",
+        ]);
+        let named = parse(&[
+            "tool",
+            "--grant-type",
+            "AuthorizationCodeGrant",
+            "--client-id",
+            "client",
+            "--client-secret",
+            "secret",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+            "--debug-level",
+            "debug",
+        ]);
+
+        for cli in [&legacy, &named] {
+            let client = cli.run.client.resolve(None).unwrap();
+            assert_eq!(
+                client.grant_type,
+                OAuth2TokenGrantFlow::AuthorizationCodeGrant
+            );
+            assert_eq!(client.client_id, "client");
+            assert_eq!(client.client_secret.unwrap().secret(), "secret");
+            assert_eq!(
+                cli.run.recipient(None).unwrap(),
+                ("recipient@example.com".to_string(), "Recipient".to_string())
+            );
+            assert_eq!(cli.global.debug_level, LevelFilter::Debug);
+        }
+    }
+
+    #[test]
+    fn test_legacy_refresh_arguments() {
+        let legacy = parse(&["tool", "refresh", "DeviceCodeFlow", "client", "None"]);
+        let named = parse(&[
+            "tool",
+            "refresh",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+        ]);
+
+        for cli in [legacy, named] {
+            let Some(Command::Refresh(args)) = cli.command else {
+                panic!("not the refresh command");
+            };
+            let client = args.client.resolve(None).unwrap();
+            assert_eq!(client.grant_type, OAuth2TokenGrantFlow::DeviceCodeFlow);
+            assert_eq!(client.client_id, "client");
+            assert!(client.client_secret.is_none());
+            assert_eq!(cli.global.debug_level, LevelFilter::Info);
+        }
+    }
+
+    #[test]
+    fn test_debug_level_is_global() {
+        for args in [
+            &["tool", "--resume", "--debug-level", "debug"][..],
+            &["tool", "status", "--resume", "--debug-level", "debug"],
+            &[
+                "tool",
+                "benchmark-connect",
+                "--count",
+                "1",
+                "--debug-level",
+                "debug",
+            ],
+        ] {
+            assert_eq!(parse(args).global.debug_level, LevelFilter::Debug);
+        }
+    }
+
+    #[test]
+    fn test_flags_are_not_rewritten() {
+        assert_eq!(legacy_args(&os_args(&["tool", "--resume"])), None);
+        assert_eq!(
+            legacy_args(&os_args(&["tool", "DeviceCodeFlow", "client", "-h"])),
+            None
+        );
+        assert_eq!(legacy_args(&os_args(&["tool"])), None);
+        assert_eq!(
+            legacy_args(&os_args(&["tool", "DeviceCodeFlow", "client"])),
+            Some(os_args(&[
+                "tool",
+                "--grant-type",
+                "DeviceCodeFlow",
+                "--client-id",
+                "client"
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_exchange_needs_one_of_the_two_steps() {
+        let cli = parse(&[
+            "tool",
+            "exchange",
+            "--client-id",
+            "client",
+            "--print-authorize-url-only",
+        ]);
+        let Some(Command::Exchange(args)) = cli.command else {
+            panic!("not the exchange command");
+        };
+        assert!(args.print_authorize_url_only);
+        assert_eq!(args.tenant, "common");
+
+        let cli = parse(&[
+            "tool",
+            "exchange",
+            "--client-id",
+            "client",
+            "--code",
+            "0.AXoA",
+        ]);
+        let Some(Command::Exchange(args)) = cli.command else {
+            panic!("not the exchange command");
+        };
+        assert_eq!(args.code.as_deref(), Some("0.AXoA"));
+
+        assert!(Cli::try_parse_from(["tool", "exchange", "--client-id", "client"]).is_err());
+        assert!(Cli::try_parse_from([
+            "tool",
+            "exchange",
+            "--client-id",
+            "client",
+            "--print-authorize-url-only",
+            "--code",
+            "0.AXoA",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_client_id_must_be_a_guid() {
+        assert!(check_client_id("9e5f94bc-e8a4-4e73-b8be-63364c29d753", false).is_ok());
+        assert!(check_client_id("9E5F94BC-E8A4-4E73-B8BE-63364C29D753", false).is_ok());
+
+        for client_id in [
+            "",
+            "client",
+            "9e5f94bc-e8a4-4e73-b8be-63364c29d75",
+            "9e5f94bc-e8a4-4e73-b8be-63364c29d7533",
+            "9e5f94bce8a44e73b8be63364c29d753",
+            "{9e5f94bc-e8a4-4e73-b8be-63364c29d753}",
+            " 9e5f94bc-e8a4-4e73-b8be-63364c29d753",
+            "9e5f94bc-e8a4-4e73-b8be-63364c29d75g",
+            "9e5f94bc-e8a4-4e73-b8be-63364c-29d753",
+        ] {
+            let error = check_client_id(client_id, false).unwrap_err();
+            assert_eq!(error.error_code, ErrorCodes::InvalidClientId, "{client_id}");
+        }
+    }
+
+    #[test]
+    fn test_allow_non_guid_client_id() {
+        assert!(check_client_id("client", true).is_ok());
+
+        let cli = parse(&["tool", "--resume", "--allow-non-guid-client-id"]);
+        assert!(cli.global.allow_non_guid_client_id);
+        let cli = parse(&["tool", "refresh", "--resume", "--allow-non-guid-client-id"]);
+        assert!(cli.global.allow_non_guid_client_id);
+        assert!(!parse(&["tool", "--resume"]).global.allow_non_guid_client_id);
+    }
+
+    #[test]
+    fn test_scopes_are_split_and_combined() {
+        let cli = parse(&[
+            "tool",
+            "--resume",
+            "--scope",
+            "offline_access",
+            "--scopes",
+            "  https://outlook.office.com/SMTP.Send   offline_access\thttps://outlook.office.com/User.Read ",
+            "--scope",
+            " ",
+        ]);
+        let scopes = cli.global.scopes().unwrap();
+        assert_eq!(
+            scopes
+                .iter()
+                .map(|scope| scope.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "offline_access",
+                "https://outlook.office.com/SMTP.Send",
+                "https://outlook.office.com/User.Read"
+            ]
+        );
+
+        assert_eq!(parse(&["tool", "--resume"]).global.scopes(), None);
+        assert_eq!(
+            parse(&["tool", "--resume", "--scopes", "  "])
+                .global
+                .scopes(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_authorize_params() {
+        let cli = parse(&[
+            "tool",
+            "--resume",
+            "--authorize-param",
+            "domain_hint=contoso.com",
+            "--authorize-param",
+            "claims={\"a\":\"b=c\"}",
+        ]);
+        assert_eq!(
+            cli.global.authorize_param,
+            [
+                ("domain_hint".to_string(), "contoso.com".to_string()),
+                ("claims".to_string(), "{\"a\":\"b=c\"}".to_string()),
+            ]
+        );
+        assert!(parse_authorize_param("prompt").is_err());
+        assert!(parse_authorize_param("=consent").is_err());
+        assert!(parse_authorize_param("Client_ID=other")
+            .unwrap_err()
+            .contains("Client_ID"));
+        assert!(parse_authorize_param("scope=User.Read").is_err());
+    }
+
+    #[test]
+    fn test_run_id() {
+        let generated = parse(&["tool", "--resume"]).global.run_id;
+        assert!(parse_run_id(&generated).is_ok());
+        // clap computes the default once per process, the process id makes it unique.
+        assert!(generated.ends_with(&format!("-{:x}", std::process::id())));
+
+        let cli = parse(&["tool", "--resume", "--run-id", "nightly-2026.10.15_1"]);
+        assert_eq!(cli.global.run_id, "nightly-2026.10.15_1");
+        assert!(parse_run_id("").is_err());
+        assert!(parse_run_id("two words").is_err());
+        assert!(parse_run_id(&"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_tls_arguments() {
+        assert!(parse(&["tool", "--resume"]).global.tls().is_default());
+
+        let cli = parse(&[
+            "tool",
+            "--resume",
+            "--min-tls-version",
+            "1.3",
+            "--tls-ciphers",
+            "TLS_AES_256_GCM_SHA384,TLS_CHACHA20_POLY1305_SHA256",
+        ]);
+        assert_eq!(
+            cli.global.tls(),
+            TlsPolicy {
+                min_version: TlsVersion::V1_3,
+                cipher_suites: vec![
+                    TlsCipherSuite::Aes256GcmSha384,
+                    TlsCipherSuite::Chacha20Poly1305Sha256
+                ],
+            }
+        );
+        assert!(Cli::try_parse_from(["tool", "--resume", "--min-tls-version", "1.1"]).is_err());
+        assert!(Cli::try_parse_from(["tool", "--resume", "--tls-ciphers", "RC4-MD5"]).is_err());
+    }
+
+    #[test]
+    fn test_socks5_argument() {
+        assert_eq!(
+            parse(&["tool", "--resume"]).global.smtp_route().socks5,
+            None
+        );
+
+        let cli = parse(&[
+            "tool",
+            "--resume",
+            "--socks5",
+            "user:secret@proxy.contoso.com:1080",
+        ]);
+        let proxy = cli.global.smtp_route().socks5.unwrap();
+        assert_eq!(proxy.addr, "proxy.contoso.com:1080");
+        assert_eq!(
+            proxy.credentials,
+            Some(("user".to_string(), "secret".to_string()))
+        );
+        assert!(
+            Cli::try_parse_from(["tool", "--resume", "--socks5", "proxy.contoso.com"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_max_requests_per_minute_argument() {
+        let cli = parse(&[
+            "tool",
+            "refresh",
+            "--resume",
+            "--max-requests-per-minute",
+            "6",
+        ]);
+        assert_eq!(cli.global.max_requests_per_minute, Some(6));
+        assert_eq!(
+            parse(&["tool", "--resume"]).global.max_requests_per_minute,
+            None
+        );
+        let args = ["tool", "--resume", "--max-requests-per-minute", "0"];
+        assert!(Cli::try_parse_from(args).is_err());
+    }
+
+    #[test]
+    fn test_oauth_version_arguments() {
+        let cli = parse(&["tool", "--resume"]);
+        assert_eq!(cli.global.oauth_version, OAuthVersion::V2);
+        assert_eq!(cli.global.resource, None);
+
+        let cli = parse(&[
+            "tool",
+            "--resume",
+            "--oauth-version",
+            "v1",
+            "--resource",
+            "https://outlook.office365.com",
+        ]);
+        assert_eq!(cli.global.oauth_version, OAuthVersion::V1);
+        assert_eq!(
+            cli.global.resource.as_deref(),
+            Some("https://outlook.office365.com")
+        );
+        assert!(parse_resource("outlook.office365.com").is_err());
+        assert!(parse_authorize_param("resource=https://outlook.office.com").is_err());
+        assert!(Cli::try_parse_from(["tool", "--resume", "--oauth-version", "v3"]).is_err());
+    }
+
+    #[test]
+    fn test_validate_token_arguments() {
+        let cli = parse(&["tool", "validate-token", "--token-file", "token.json"]);
+        let Some(Command::ValidateToken(args)) = cli.command else {
+            panic!("not the validate-token command");
+        };
+        assert!(args.grant_type.is_none());
+
+        let args = ["tool", "validate-token", "--grant-type", "DeviceCodeFlow"];
+        assert!(Cli::try_parse_from(args).is_err());
+        assert!(Cli::try_parse_from(args.iter().chain(&["--client-id", "client"])).is_ok());
+        assert!(Cli::try_parse_from(["tool", "validate-token"]).is_err());
+    }
+
+    #[test]
+    fn test_benchmark_connect_arguments() {
+        let cli = parse(&["tool", "benchmark-connect", "--count", "5", "--tcp-nodelay"]);
+        let Some(Command::BenchmarkConnect(args)) = cli.command else {
+            panic!("not the benchmark-connect command");
+        };
+        assert_eq!(args.count, 5);
+        assert!(args.smtp.tcp_nodelay);
+
+        assert!(Cli::try_parse_from(["tool", "benchmark-connect"]).is_err());
+        assert!(Cli::try_parse_from(["tool", "benchmark-connect", "--count", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_status_arguments() {
+        let cli = parse(&[
+            "tool",
+            "status",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+            "--refresh-if-needed",
+        ]);
+        let Some(Command::Status(args)) = cli.command else {
+            panic!("not the status command");
+        };
+        assert!(args.refresh_if_needed);
+        assert!(!args.with_profile);
+        let client = args.client.resolve(None).unwrap();
+        assert_eq!(client.grant_type, OAuth2TokenGrantFlow::DeviceCodeFlow);
+    }
+
+    #[test]
+    fn test_poll_steps() {
+        let cli = parse(&[
+            "tool",
+            "poll",
+            "--client-id",
+            "client",
+            "--request-only",
+            "--qr",
+        ]);
+        let Some(Command::Poll(args)) = cli.command else {
+            panic!("not the poll command");
+        };
+        assert!(args.request_only);
+        assert_eq!(args.tenant, "common");
+
+        let cli = parse(&["tool", "poll", "--client-id", "client"]);
+        let Some(Command::Poll(args)) = cli.command else {
+            panic!("not the poll command");
+        };
+        assert!(!args.request_only);
+        assert!(Cli::try_parse_from(["tool", "poll", "--client-id", "client", "--qr"]).is_err());
+    }
+
+    #[test]
+    fn test_sender_needs_both_name_and_email() {
+        let cli = parse(&[
+            "tool",
+            "--resume",
+            "--sender-email",
+            "shared@example.com",
+            "--sender-name",
+            "Shared",
+        ]);
+        assert_eq!(
+            cli.run.sender(),
+            Some(("Shared".to_string(), "shared@example.com".to_string()))
+        );
+        assert_eq!(parse(&["tool", "--resume"]).run.sender(), None);
+
+        assert!(
+            Cli::try_parse_from(["tool", "--resume", "--sender-email", "a@example.com"]).is_err()
+        );
+        assert!(Cli::try_parse_from(["tool", "--resume", "--sender-name", "A"]).is_err());
+    }
+
+    #[test]
+    fn test_basic_auth_replaces_the_login() {
+        let basic = [
+            "tool",
+            "--to-self",
+            "--auth-mode",
+            "basic",
+            "--smtp-username",
+            "me@example.com",
+            "--smtp-password",
+            "secret",
+        ];
+        let cli = parse(&basic);
+        assert_eq!(cli.run.smtp.auth_mode, SmtpAuthMode::Basic);
+        assert_eq!(
+            cli.run.smtp.basic_credentials().unwrap(),
+            Some(("me@example.com", "secret"))
+        );
+        // The username is the sender, without a display name.
+        assert_eq!(
+            cli.run.sender(),
+            Some((String::new(), "me@example.com".to_string()))
+        );
+        assert!(Cli::try_parse_from(&basic[..6]).is_err());
+        assert!(Cli::try_parse_from(basic.iter().chain(&["--access-token", "token"])).is_err());
+
+        let cli = parse(&["tool", "--resume"]);
+        assert_eq!(cli.run.smtp.auth_mode, SmtpAuthMode::Xoauth2);
+        assert_eq!(cli.run.smtp.basic_credentials().unwrap(), None);
+        let cli = parse(&[
+            "tool",
+            "--resume",
+            "--smtp-username",
+            "u",
+            "--smtp-password",
+            "p",
+        ]);
+        assert!(cli.run.smtp.basic_credentials().is_err());
+        assert!(Cli::try_parse_from(["tool", "--to-self", "--auth-mode", "ntlm"]).is_err());
+    }
+
+    #[test]
+    fn test_batch_size_argument() {
+        assert_eq!(parse(&["tool", "--resume"]).run.batch_size, 500);
+        let cli = parse(&["tool", "--resume", "--batch-size", "50"]);
+        assert_eq!(cli.run.batch_size, 50);
+        assert!(Cli::try_parse_from(["tool", "--resume", "--batch-size", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_exec_argument() {
+        // No recipient is needed, the email is not sent.
+        let cli = parse(&[
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+            "--exec",
+            "./deploy.sh",
+        ]);
+        assert_eq!(cli.run.exec.as_deref(), Some("./deploy.sh"));
+        assert_eq!(parse(&["tool", "--resume"]).run.exec, None);
+
+        let exec = ["tool", "--resume", "--exec", "./deploy.sh"];
+        assert!(Cli::try_parse_from(exec.iter().chain(&["--no-send"])).is_err());
+        assert!(Cli::try_parse_from(exec.iter().chain(&["--dry-run"])).is_err());
+    }
+
+    #[test]
+    fn test_scenario_argument() {
+        let cli = parse(&["tool", "--resume", "--scenario", "html-with-inline"]);
+        assert_eq!(cli.run.message.scenario, Some(TestScenario::HtmlWithInline));
+        assert_eq!(parse(&["tool", "--resume"]).run.message.scenario, None);
+
+        let scenario = ["tool", "--resume", "--scenario", "calendar"];
+        assert!(Cli::try_parse_from(scenario.iter().chain(&["--importance", "low"])).is_err());
+        assert!(Cli::try_parse_from(scenario.iter().chain(&["--ics-file", "a.ics"])).is_err());
+        assert!(Cli::try_parse_from(scenario.iter().chain(&["--footer", "Footer"])).is_ok());
+        assert!(Cli::try_parse_from(["tool", "--resume", "--scenario", "unknown"]).is_err());
+    }
+
+    #[test]
+    fn test_access_token_replaces_the_login() {
+        let directory = tempfile::tempdir().unwrap();
+        let token_file = directory.path().join("token.txt");
+        std::fs::write(&token_file, "Bearer eyJ0eXAi.token\n").unwrap();
+
+        let cli = parse(&[
+            "tool",
+            "--access-token-file",
+            token_file.to_str().unwrap(),
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ]);
+        let client = cli.run.client.resolve(None).unwrap();
+        assert_eq!(client.grant_type, OAuth2TokenGrantFlow::PreAcquired);
+        assert_eq!(client.access_token.unwrap().secret(), "eyJ0eXAi.token");
+
+        let cli = parse(&[
+            "tool",
+            "--access-token",
+            "token",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ]);
+        assert!(cli.run.client.resolve(None).is_err());
+        let cli = parse(&[
+            "tool",
+            "--access-token",
+            "Bearer ",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ]);
+        assert!(cli.run.client.resolve(None).is_err());
+        let cli = parse(&[
+            "tool",
+            "--grant-type",
+            "PreAcquired",
+            "--client-id",
+            "client",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ]);
+        assert!(cli.run.client.resolve(None).is_err());
+    }
+
+    #[test]
+    fn test_manifest_gives_the_client_id() {
+        let directory = tempfile::tempdir().unwrap();
+        let manifest = directory.path().join("manifest.json");
+        std::fs::write(
+            &manifest,
+            r#"{"appId": "11111111-1111-1111-1111-111111111111", "displayName": "App"}"#,
+        )
+        .unwrap();
+        let manifest = manifest.to_str().unwrap();
+
+        let cli = parse(&[
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--manifest",
+            manifest,
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ]);
+        let client = cli.run.client.resolve(None).unwrap();
+        assert_eq!(client.client_id, "11111111-1111-1111-1111-111111111111");
+
+        // The given client id takes precedence.
+        let cli = parse(&[
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--manifest",
+            manifest,
+            "--client-id",
+            "22222222-2222-2222-2222-222222222222",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ]);
+        let client = cli.run.client.resolve(Some(&last_run())).unwrap();
+        assert_eq!(client.client_id, "22222222-2222-2222-2222-222222222222");
+
+        let cli = parse(&[
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--manifest",
+            "/nonexistent/manifest.json",
+            "--to-self",
+        ]);
+        assert!(cli.run.client.resolve(None).is_err());
+    }
+}
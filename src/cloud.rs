@@ -0,0 +1,325 @@
+// 3rd party crates
+use oauth2::Scope;
+use serde::{Deserialize, Serialize};
+use strum_macros::EnumString;
+
+// My crates
+use crate::discovery::Endpoints;
+
+/// The Microsoft 365 national clouds, each with its own login, Outlook and SMTP hosts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum Cloud {
+    #[default]
+    Commercial,
+    GccHigh,
+    Dod,
+    China,
+}
+
+impl Cloud {
+    /// The Microsoft identity platform host.
+    pub fn login_host(&self) -> &'static str {
+        match self {
+            Cloud::Commercial => "login.microsoftonline.com",
+            Cloud::GccHigh | Cloud::Dod => "login.microsoftonline.us",
+            Cloud::China => "login.partner.microsoftonline.cn",
+        }
+    }
+
+    /// The Exchange Online host, which is both the resource of the scopes and the
+    /// host of the sender profile.
+    pub fn outlook_host(&self) -> &'static str {
+        match self {
+            Cloud::Commercial => "outlook.office.com",
+            Cloud::GccHigh => "outlook.office365.us",
+            Cloud::Dod => "outlook-dod.office365.us",
+            Cloud::China => "partner.outlook.cn",
+        }
+    }
+
+    pub fn smtp_host(&self) -> &'static str {
+        match self {
+            Cloud::Commercial => "smtp.office365.com",
+            Cloud::GccHigh | Cloud::Dod => "smtp.office365.us",
+            Cloud::China => "smtp.partner.outlook.cn",
+        }
+    }
+
+    /// The Outlook REST API profile of the signed-in user.
+    pub fn profile_endpoint(&self) -> String {
+        format!("https://{}/api/v2.0/me/", self.outlook_host())
+    }
+
+    /// The scopes the interactive logins ask for by default.
+    pub fn scopes(&self) -> Vec<Scope> {
+        let outlook_host = self.outlook_host();
+        vec![
+            Scope::new("offline_access".to_string()),
+            Scope::new(format!("https://{outlook_host}/SMTP.Send")),
+            Scope::new(format!("https://{outlook_host}/User.Read")),
+        ]
+    }
+}
+
+/// The Microsoft identity platform endpoints to login with, with `--oauth-version`: the
+/// v1 (Azure AD) ones ask for a `resource`, the v2 ones for scopes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum OAuthVersion {
+    V1,
+    #[default]
+    V2,
+}
+
+impl OAuthVersion {
+    /// The version in the URLs of the endpoints and of the discovery document.
+    pub fn path(&self) -> &'static str {
+        match self {
+            OAuthVersion::V1 => "",
+            OAuthVersion::V2 => "v2.0/",
+        }
+    }
+}
+
+/// Where to login: a tenant in one of the clouds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Authority {
+    pub cloud: Cloud,
+    pub tenant: String,
+    /// The scopes given with `--scope` or `--scopes`, asked for instead of the ones
+    /// of the cloud.
+    pub scopes: Option<Vec<Scope>>,
+    /// The endpoints read from the discovery document with `--discover-endpoints`.
+    pub endpoints: Option<Endpoints>,
+    /// The query parameters given with `--authorize-param`, added to the authorize URL.
+    pub authorize_params: Vec<(String, String)>,
+    pub oauth_version: OAuthVersion,
+    /// The resource given with `--resource`, asked for with the v1 endpoints.
+    pub resource: Option<String>,
+}
+
+impl Authority {
+    pub fn new(cloud: Cloud, tenant: &str) -> Self {
+        Self {
+            cloud,
+            tenant: tenant.to_string(),
+            scopes: None,
+            endpoints: None,
+            authorize_params: Vec::new(),
+            oauth_version: OAuthVersion::default(),
+            resource: None,
+        }
+    }
+
+    pub fn with_scopes(mut self, scopes: Option<Vec<Scope>>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    pub fn with_authorize_params(mut self, authorize_params: Vec<(String, String)>) -> Self {
+        self.authorize_params = authorize_params;
+        self
+    }
+
+    pub fn with_oauth_version(mut self, oauth_version: OAuthVersion) -> Self {
+        self.oauth_version = oauth_version;
+        self
+    }
+
+    pub fn with_resource(mut self, resource: Option<String>) -> Self {
+        self.resource = resource;
+        self
+    }
+
+    pub fn with_endpoints(mut self, endpoints: Endpoints) -> Self {
+        self.endpoints = Some(endpoints);
+        self
+    }
+
+    /// The scopes the interactive logins ask for, only the given ones with the v1
+    /// endpoints, which ask for the resource instead.
+    pub fn scopes(&self) -> Vec<Scope> {
+        match self.oauth_version {
+            OAuthVersion::V1 => self.scopes.clone().unwrap_or_default(),
+            OAuthVersion::V2 => self.scopes.clone().unwrap_or_else(|| self.cloud.scopes()),
+        }
+    }
+
+    /// The resource the v1 endpoints ask for, Exchange Online of the cloud by default.
+    pub fn resource(&self) -> Option<String> {
+        match self.oauth_version {
+            OAuthVersion::V1 => Some(
+                self.resource
+                    .clone()
+                    .unwrap_or_else(|| format!("https://{}", self.cloud.outlook_host())),
+            ),
+            OAuthVersion::V2 => None,
+        }
+    }
+
+    /// The parameters added to the token requests: the resource with the v1 endpoints.
+    pub fn token_params(&self) -> Vec<(String, String)> {
+        self.resource()
+            .map(|resource| ("resource".to_string(), resource))
+            .into_iter()
+            .collect()
+    }
+
+    /// The query parameters of the authorize URL: the ones of the token requests and the
+    /// ones of `--authorize-param`.
+    pub fn authorize_url_params(&self) -> Vec<(String, String)> {
+        let mut params = self.token_params();
+        params.extend(self.authorize_params.iter().cloned());
+        params
+    }
+
+    /// The URL of an OAuth2 endpoint of the tenant, e.g. `token`, the discovered one if
+    /// there is one.
+    pub fn endpoint(&self, endpoint: &str) -> String {
+        let discovered = self
+            .endpoints
+            .as_ref()
+            .and_then(|endpoints| match endpoint {
+                "authorize" => Some(&endpoints.authorization_endpoint),
+                "token" => Some(&endpoints.token_endpoint),
+                "devicecode" => endpoints.device_authorization_endpoint.as_ref(),
+                _ => None,
+            });
+        if let Some(discovered) = discovered {
+            return discovered.clone();
+        }
+        format!(
+            "https://{}/{}/oauth2/{}{endpoint}",
+            self.cloud.login_host(),
+            self.tenant,
+            self.oauth_version.path()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use oauth2::Scope;
+
+    use super::{Authority, Cloud, OAuthVersion};
+
+    #[test]
+    fn test_cloud_hosts() {
+        let hosts = |cloud: Cloud| (cloud.login_host(), cloud.outlook_host(), cloud.smtp_host());
+        assert_eq!(
+            hosts(Cloud::Commercial),
+            (
+                "login.microsoftonline.com",
+                "outlook.office.com",
+                "smtp.office365.com"
+            )
+        );
+        assert_eq!(
+            hosts(Cloud::GccHigh),
+            (
+                "login.microsoftonline.us",
+                "outlook.office365.us",
+                "smtp.office365.us"
+            )
+        );
+        assert_eq!(
+            hosts(Cloud::Dod),
+            (
+                "login.microsoftonline.us",
+                "outlook-dod.office365.us",
+                "smtp.office365.us"
+            )
+        );
+        assert_eq!(
+            hosts(Cloud::China),
+            (
+                "login.partner.microsoftonline.cn",
+                "partner.outlook.cn",
+                "smtp.partner.outlook.cn"
+            )
+        );
+    }
+
+    #[test]
+    fn test_cloud_names() {
+        assert_eq!(Cloud::from_str("commercial").unwrap(), Cloud::Commercial);
+        assert_eq!(Cloud::from_str("gcc-high").unwrap(), Cloud::GccHigh);
+        assert_eq!(Cloud::from_str("dod").unwrap(), Cloud::Dod);
+        assert_eq!(Cloud::from_str("china").unwrap(), Cloud::China);
+        assert!(Cloud::from_str("gcc_high").is_err());
+    }
+
+    #[test]
+    fn test_cloud_endpoints() {
+        let authority = Authority::new(Cloud::GccHigh, "contoso.onmicrosoft.us");
+        assert_eq!(
+            authority.endpoint("token"),
+            "https://login.microsoftonline.us/contoso.onmicrosoft.us/oauth2/v2.0/token"
+        );
+        assert_eq!(
+            Cloud::China.profile_endpoint(),
+            "https://partner.outlook.cn/api/v2.0/me/"
+        );
+        let scopes = Cloud::Dod.scopes();
+        assert_eq!(
+            scopes
+                .iter()
+                .map(|scope| scope.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "offline_access",
+                "https://outlook-dod.office365.us/SMTP.Send",
+                "https://outlook-dod.office365.us/User.Read"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_given_scopes_replace_the_cloud_ones() {
+        let authority = Authority::new(Cloud::Commercial, "common");
+        assert_eq!(authority.scopes(), Cloud::Commercial.scopes());
+
+        let scopes = vec![Scope::new(
+            "https://outlook.office.com/SMTP.Send".to_string(),
+        )];
+        let authority = authority.with_scopes(Some(scopes.clone()));
+        assert_eq!(authority.scopes(), scopes);
+    }
+
+    #[test]
+    fn test_v1_endpoints_ask_for_the_resource() {
+        assert_eq!(OAuthVersion::from_str("v1").unwrap(), OAuthVersion::V1);
+        assert_eq!(OAuthVersion::default(), OAuthVersion::V2);
+        assert!(Authority::new(Cloud::Commercial, "common")
+            .token_params()
+            .is_empty());
+
+        let authority = Authority::new(Cloud::GccHigh, "contoso")
+            .with_oauth_version(OAuthVersion::V1)
+            .with_authorize_params(vec![("prompt".to_string(), "login".to_string())]);
+        assert_eq!(
+            authority.endpoint("authorize"),
+            "https://login.microsoftonline.us/contoso/oauth2/authorize"
+        );
+        assert!(authority.scopes().is_empty());
+        assert_eq!(
+            authority.token_params(),
+            [(
+                "resource".to_string(),
+                "https://outlook.office365.us".to_string()
+            )]
+        );
+        assert_eq!(authority.authorize_url_params().len(), 2);
+
+        let authority = authority.with_resource(Some("https://outlook.office365.com".to_string()));
+        assert_eq!(
+            authority.resource().as_deref(),
+            Some("https://outlook.office365.com")
+        );
+    }
+}
@@ -0,0 +1,118 @@
+// Standard libraries
+use std::fmt::Arguments;
+
+// 3rd party crates
+use log::Level;
+use strum_macros::EnumString;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// With `Auto`, the output is colored only on a terminal and when `NO_COLOR` is not set.
+    pub fn enabled(&self, is_terminal: bool, no_color: bool) -> bool {
+        match self {
+            ColorChoice::Auto => is_terminal && !no_color,
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+/// Whether the `NO_COLOR` environment variable asks for no colors.
+pub fn no_color_env() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// The `[LEVEL]` tag of a log line, in red, yellow or green for errors, warnings and
+/// infos when `color` is set.
+pub fn level_tag(level: Level, color: bool) -> String {
+    let code = match level {
+        Level::Error => "31",
+        Level::Warn => "33",
+        Level::Info => "32",
+        Level::Debug | Level::Trace => "",
+    };
+    if color && !code.is_empty() {
+        format!("[\x1b[{code}m{level}\x1b[0m]")
+    } else {
+        format!("[{level}]")
+    }
+}
+
+/// A log line: the time, the run id, the level tag and the last part of the module path
+/// before the message.
+pub fn log_line(
+    time: &str,
+    run_id: &str,
+    level_tag: &str,
+    module_path: Option<&str>,
+    message: &Arguments<'_>,
+) -> String {
+    let module = module_path
+        .and_then(|path| path.split("::").last())
+        .unwrap_or_default();
+    format!("{time}[{run_id}]{level_tag}:{module}: {message}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use log::Level;
+
+    use super::{level_tag, log_line, ColorChoice};
+
+    #[test]
+    fn test_never_has_no_ansi_escapes() {
+        let color = ColorChoice::from_str("never").unwrap().enabled(true, false);
+        for level in [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ] {
+            assert!(!level_tag(level, color).contains('\x1b'));
+        }
+        assert_eq!(level_tag(Level::Error, color), "[ERROR]");
+    }
+
+    #[test]
+    fn test_colored_level_tags() {
+        assert_eq!(level_tag(Level::Error, true), "[\x1b[31mERROR\x1b[0m]");
+        assert_eq!(level_tag(Level::Warn, true), "[\x1b[33mWARN\x1b[0m]");
+        assert_eq!(level_tag(Level::Info, true), "[\x1b[32mINFO\x1b[0m]");
+        assert_eq!(level_tag(Level::Debug, true), "[DEBUG]");
+    }
+
+    #[test]
+    fn test_auto_follows_terminal_and_no_color() {
+        assert!(ColorChoice::Auto.enabled(true, false));
+        assert!(!ColorChoice::Auto.enabled(false, false));
+        assert!(!ColorChoice::Auto.enabled(true, true));
+        assert!(ColorChoice::Always.enabled(false, true));
+    }
+
+    #[test]
+    fn test_log_line_has_the_run_id() {
+        let line = log_line(
+            "[15-10-2026 10:00:00]",
+            "run-42",
+            &level_tag(Level::Info, false),
+            Some("microsoft_smtp_xoauth2_test_tool::smtp"),
+            &format_args!("Sending Email success!!"),
+        );
+        assert_eq!(
+            line,
+            "[15-10-2026 10:00:00][run-42][INFO]:smtp: Sending Email success!!"
+        );
+        assert!(log_line("", "run-42", "[WARN]", None, &format_args!("x")).starts_with("[run-42]"));
+    }
+}
@@ -0,0 +1,280 @@
+// Standard libraries
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// 3rd party crates
+use serde::Deserialize;
+use toml::{Table, Value};
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The config file of `--config`. Its keys are the flags of the command without their
+/// dashes, e.g. `client-id = "..."`: those of `[defaults]`, each overridden by the one of
+/// the `[profiles.<name>]` picked with `--config-profile`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    defaults: Table,
+    #[serde(default)]
+    profiles: BTreeMap<String, Table>,
+}
+
+fn invalid_config(path: &Path, problem: String) -> OAuth2Error {
+    OAuth2Error::new(
+        ErrorCodes::ConfigurationError,
+        format!("{} is not a valid config file: {problem}", path.display()),
+    )
+}
+
+/// The flag of a key, the same in kebab-case or snake_case.
+fn flag(key: &str) -> String {
+    format!("--{}", key.replace('_', "-"))
+}
+
+/// The settings of `[defaults]` with those of the profile over them, by flag. A profile
+/// replaces a setting of the defaults as a whole, arrays included.
+fn merge(
+    config: ConfigFile,
+    profile: Option<&str>,
+    path: &Path,
+) -> OAuth2Result<BTreeMap<String, Value>> {
+    let mut settings: BTreeMap<String, Value> = config
+        .defaults
+        .into_iter()
+        .map(|(key, value)| (flag(&key), value))
+        .collect();
+    if let Some(name) = profile {
+        let names: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+        let Some(profile) = config.profiles.get(name) else {
+            return Err(invalid_config(
+                path,
+                if names.is_empty() {
+                    format!("there is no profile {name}, it has no [profiles.<name>] table")
+                } else {
+                    format!("there is no profile {name}, only {}", names.join(", "))
+                },
+            ));
+        };
+        settings.extend(
+            profile
+                .iter()
+                .map(|(key, value)| (flag(key), value.clone())),
+        );
+    }
+    Ok(settings)
+}
+
+/// A single value as the argument of its flag.
+fn argument(flag: &str, value: &Value, path: &Path) -> OAuth2Result<OsString> {
+    match value {
+        Value::String(value) => Ok(value.into()),
+        Value::Integer(value) => Ok(value.to_string().into()),
+        Value::Float(value) => Ok(value.to_string().into()),
+        _ => Err(invalid_config(
+            path,
+            format!("{flag} is neither a string, a number nor a boolean"),
+        )),
+    }
+}
+
+/// The settings as command line arguments: `true` is the flag alone, `false` leaves it
+/// out, and an array repeats the flag for each of its values.
+fn arguments(settings: &BTreeMap<String, Value>, path: &Path) -> OAuth2Result<Vec<OsString>> {
+    let mut arguments = Vec::new();
+    for (flag, value) in settings {
+        match value {
+            Value::Boolean(true) => arguments.push(flag.into()),
+            Value::Boolean(false) => {}
+            Value::Array(values) => {
+                for value in values {
+                    arguments.push(flag.into());
+                    arguments.push(argument(flag, value, path)?);
+                }
+            }
+            value => {
+                arguments.push(flag.into());
+                arguments.push(argument(flag, value, path)?);
+            }
+        }
+    }
+    Ok(arguments)
+}
+
+/// The value of `flag` on the command line, as `--flag value` or `--flag=value`.
+fn flag_value(args: &[OsString], flag: &str) -> Option<OsString> {
+    let prefix = format!("{flag}=");
+    args.iter().enumerate().find_map(|(index, arg)| {
+        let text = arg.to_string_lossy();
+        if text == flag {
+            args.get(index + 1).cloned()
+        } else {
+            text.strip_prefix(&prefix).map(OsString::from)
+        }
+    })
+}
+
+/// Whether `flag` is on the command line.
+fn is_given(args: &[OsString], flag: &str) -> bool {
+    let prefix = format!("{flag}=");
+    args.iter().any(|arg| {
+        let arg = arg.to_string_lossy();
+        arg == flag || arg.starts_with(&prefix)
+    })
+}
+
+/// Reads the config file of `--config` and adds its settings for `--config-profile` to
+/// the command line, after the subcommand if any. A flag given on the command line takes
+/// precedence over its setting. Returns `None` without `--config`.
+pub fn config_args(args: &[OsString]) -> OAuth2Result<Option<Vec<OsString>>> {
+    let Some(path) = flag_value(args, "--config").map(PathBuf::from) else {
+        return Ok(None);
+    };
+    let profile =
+        flag_value(args, "--config-profile").map(|profile| profile.to_string_lossy().into_owned());
+    let text = fs::read_to_string(&path).map_err(|e| {
+        OAuth2Error::new(
+            ErrorCodes::ConfigurationError,
+            format!("Unable to read the config file {}: {e}", path.display()),
+        )
+    })?;
+    let config: ConfigFile =
+        toml::from_str(&text).map_err(|e| invalid_config(&path, e.to_string()))?;
+    let mut settings = merge(config, profile.as_deref(), &path)?;
+    settings.retain(|flag, _| !is_given(args, flag));
+
+    let Some((program, rest)) = args.split_first() else {
+        return Ok(None);
+    };
+    let subcommand = rest
+        .first()
+        .filter(|arg| !arg.to_string_lossy().starts_with('-'));
+    let mut with_config = vec![program.clone()];
+    with_config.extend(subcommand.cloned());
+    with_config.extend(arguments(&settings, &path)?);
+    with_config.extend(rest.iter().skip(subcommand.iter().count()).cloned());
+    Ok(Some(with_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    use super::{config_args, merge, ConfigFile};
+    use crate::error::ErrorCodes;
+
+    const CONFIG: &str = r#"
+[defaults]
+grant-type = "DeviceCodeFlow"
+tenant = "contoso.onmicrosoft.com"
+scope = ["https://outlook.office.com/SMTP.Send", "offline_access"]
+auto_relogin = true
+
+[profiles.dev]
+client-id = "11111111-1111-1111-1111-111111111111"
+auto_relogin = false
+
+[profiles.prod]
+client-id = "22222222-2222-2222-2222-222222222222"
+tenant = "fabrikam.onmicrosoft.com"
+scope = ["https://outlook.office.com/SMTP.Send"]
+smtp-connect-timeout = 30
+"#;
+
+    fn config() -> ConfigFile {
+        toml::from_str(CONFIG).unwrap()
+    }
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_profile_overrides_the_defaults() {
+        let settings = merge(config(), Some("prod"), Path::new("config.toml")).unwrap();
+        assert_eq!(settings["--grant-type"].as_str(), Some("DeviceCodeFlow"));
+        assert_eq!(
+            settings["--tenant"].as_str(),
+            Some("fabrikam.onmicrosoft.com")
+        );
+        // The array of the profile replaces the one of the defaults.
+        assert_eq!(settings["--scope"].as_array().unwrap().len(), 1);
+        assert_eq!(settings["--smtp-connect-timeout"].as_integer(), Some(30));
+
+        let dev = merge(config(), Some("dev"), Path::new("config.toml")).unwrap();
+        assert_eq!(dev["--tenant"].as_str(), Some("contoso.onmicrosoft.com"));
+        assert_eq!(dev["--auto-relogin"].as_bool(), Some(false));
+
+        let defaults = merge(config(), None, Path::new("config.toml")).unwrap();
+        assert!(!defaults.contains_key("--client-id"));
+    }
+
+    #[test]
+    fn test_missing_profile() {
+        let error = merge(config(), Some("staging"), Path::new("config.toml")).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        assert!(
+            error
+                .error_code_desc
+                .contains("there is no profile staging, only dev, prod"),
+            "{}",
+            error.error_code_desc
+        );
+        let error = merge(ConfigFile::default(), Some("dev"), Path::new("config.toml"));
+        assert!(error.is_err());
+    }
+
+    #[test]
+    fn test_config_args() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        std::fs::write(&path, CONFIG).unwrap();
+        let path = path.to_str().unwrap();
+
+        let args = config_args(&os_args(&[
+            "tool",
+            "refresh",
+            "--config",
+            path,
+            "--config-profile=prod",
+            "--tenant",
+            "override.onmicrosoft.com",
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            args,
+            os_args(&[
+                "tool",
+                "refresh",
+                "--auto-relogin",
+                "--client-id",
+                "22222222-2222-2222-2222-222222222222",
+                "--grant-type",
+                "DeviceCodeFlow",
+                "--scope",
+                "https://outlook.office.com/SMTP.Send",
+                "--smtp-connect-timeout",
+                "30",
+                "--config",
+                path,
+                "--config-profile=prod",
+                "--tenant",
+                "override.onmicrosoft.com",
+            ])
+        );
+
+        assert_eq!(config_args(&os_args(&["tool", "--resume"])).unwrap(), None);
+        std::fs::write(
+            directory.path().join("bad.toml"),
+            "[defaults]\nscope = [[1]]\n",
+        )
+        .unwrap();
+        let bad = directory.path().join("bad.toml");
+        assert!(config_args(&os_args(&["tool", "--config", bad.to_str().unwrap()])).is_err());
+    }
+}
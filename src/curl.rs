@@ -1,73 +1,363 @@
+use std::ffi::CString;
+use std::os::raw::{c_int, c_long};
+
 use async_curl::actor::CurlActor;
+use async_trait::async_trait;
+use curl::easy::{Easy2, HttpVersion, List};
 use curl_http_client::{
-    collector::Collector, error::Error, http_client::HttpClient, request::HttpRequest,
-    response::HttpResponse,
+    collector::{Collector, ExtendedHandler},
+    error::Error,
+};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE, LOCATION},
+    Method, StatusCode,
 };
 
+use crate::error::OAuth2Result;
+use crate::net::IpVersion;
+use crate::rate_limit::RateLimiter;
+use crate::redact;
+use crate::socks5::Socks5Proxy;
+use crate::tls::{TlsPolicy, TlsVersion};
+
+/// CURLOPT_TLS13_CIPHERS, which the curl crate has no setter for.
+const CURLOPT_TLS13_CIPHERS: curl_sys::CURLoption = curl_sys::CURLOPTTYPE_OBJECTPOINT + 276;
+
+/// Sends the HTTP requests of the tool, so that the tests can answer them without a
+/// server. [`Curl`] is the real one.
+#[async_trait]
+pub trait HttpClient: Send + Sync {
+    async fn send(&self, request: oauth2::HttpRequest) -> OAuth2Result<oauth2::HttpResponse>;
+}
+
 #[derive(Clone)]
 pub struct Curl {
     pub actor_handle: CurlActor<Collector>,
+    ip_version: IpVersion,
+    http1_only: bool,
+    max_redirects: u32,
+    tls: TlsPolicy,
+    socks5: Option<Socks5Proxy>,
+    rate_limiter: Option<RateLimiter>,
+}
+
+/// CURLINFO_HTTP_VERSION of curl.h, which curl-sys does not export.
+const CURLINFO_HTTP_VERSION: curl_sys::CURLINFO = curl_sys::CURLINFO_LONG + 46;
+
+/// The HTTP version the response came over, for the debug log. The curl crate has no
+/// getter for it.
+fn negotiated_version(easy: &Easy2<Collector>) -> Option<&'static str> {
+    let mut version: c_long = 0;
+    // SAFETY: the handle is valid for the lifetime of `easy` and CURLINFO_HTTP_VERSION
+    // writes a long.
+    let code = unsafe {
+        curl_sys::curl_easy_getinfo(
+            easy.raw(),
+            CURLINFO_HTTP_VERSION,
+            &mut version as *mut c_long,
+        )
+    };
+    if code != curl_sys::CURLE_OK {
+        return None;
+    }
+    match version as c_int {
+        curl_sys::CURL_HTTP_VERSION_1_0 => Some("HTTP/1.0"),
+        curl_sys::CURL_HTTP_VERSION_1_1 => Some("HTTP/1.1"),
+        curl_sys::CURL_HTTP_VERSION_2_0 => Some("HTTP/2"),
+        curl_sys::CURL_HTTP_VERSION_3 => Some("HTTP/3"),
+        _ => None,
+    }
+}
+
+/// The request as dumped at trace level, with the secrets redacted.
+fn trace_request(request: &oauth2::HttpRequest) -> Vec<String> {
+    let mut lines = vec![format!("> {} {}", request.method, request.url)];
+    for (name, value) in request.headers.iter() {
+        lines.push(format!("> {}", redact::header(name, value)));
+    }
+    if !request.body.is_empty() {
+        lines.push(format!("> {}", redact::body(&request.body)));
+    }
+    lines
+}
+
+/// The response as dumped at trace level, with the secrets redacted.
+fn trace_response(response: &oauth2::HttpResponse) -> Vec<String> {
+    let mut lines = vec![format!("< {}", response.status_code)];
+    for (name, value) in response.headers.iter() {
+        lines.push(format!("< {}", redact::header(name, value)));
+    }
+    if !response.body.is_empty() {
+        lines.push(format!("< {}", redact::body(&response.body)));
+    }
+    lines
+}
+
+/// The request that follows a redirect response, none if the response is not one. The
+/// Authorization header is only sent again to the same origin, and a POST answered with
+/// 301, 302 or 303 becomes a GET without a body, as in the browsers. A redirect from
+/// https to http, or one that would send the body to another origin, is refused: the
+/// body holds the client secret, the refresh token or the codes.
+fn redirect(
+    request: &oauth2::HttpRequest,
+    response: &oauth2::HttpResponse,
+) -> Result<Option<oauth2::HttpRequest>, Error<Collector>> {
+    let status_code = response.status_code;
+    if !matches!(status_code.as_u16(), 301 | 302 | 303 | 307 | 308) {
+        return Ok(None);
+    }
+    let Some(location) = response.headers.get(LOCATION) else {
+        return Ok(None);
+    };
+    let url = location
+        .to_str()
+        .ok()
+        .and_then(|location| request.url.join(location).ok())
+        .ok_or_else(|| Error::Http(format!("invalid Location header {location:?}")))?;
+    if request.url.scheme() == "https" && url.scheme() != "https" {
+        return Err(Error::Http(format!(
+            "refusing the redirect from https to {}",
+            url.origin().ascii_serialization()
+        )));
+    }
+
+    let mut headers = request.headers.clone();
+    if url.origin() != request.url.origin() && headers.remove(AUTHORIZATION).is_some() {
+        log::debug!(
+            "Not sending the Authorization header to {}",
+            url.origin().ascii_serialization()
+        );
+    }
+    let keeps_method = matches!(
+        status_code,
+        StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT
+    );
+    let (method, body) = if request.method == Method::POST && !keeps_method {
+        headers.remove(CONTENT_TYPE);
+        (Method::GET, Vec::new())
+    } else {
+        (request.method.clone(), request.body.clone())
+    };
+    if !body.is_empty() && url.origin() != request.url.origin() {
+        return Err(Error::Http(format!(
+            "refusing to send the request body again to {}",
+            url.origin().ascii_serialization()
+        )));
+    }
+    Ok(Some(oauth2::HttpRequest {
+        url,
+        method,
+        headers,
+        body,
+    }))
 }
 
 impl Curl {
     pub fn new() -> Self {
         Self {
             actor_handle: CurlActor::new(),
+            ip_version: IpVersion::Auto,
+            http1_only: false,
+            max_redirects: 5,
+            tls: TlsPolicy::default(),
+            socks5: None,
+            rate_limiter: None,
         }
     }
 
-    fn to_curl_request(request: oauth2::HttpRequest) -> HttpRequest {
-        let body = if request.body.is_empty() {
-            None
+    pub fn ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.ip_version = ip_version;
+        self
+    }
+
+    /// Stays on HTTP/1.1 instead of negotiating HTTP/2 over TLS.
+    pub fn http1_only(mut self, http1_only: bool) -> Self {
+        self.http1_only = http1_only;
+        self
+    }
+
+    /// Follows at most this many redirects, a request that is redirected more often fails.
+    pub fn max_redirects(mut self, max_redirects: u32) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Limits the TLS versions and cipher suites as `--min-tls-version` and
+    /// `--tls-ciphers` say.
+    pub fn tls(mut self, tls: TlsPolicy) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Sends the requests through the SOCKS5 proxy of `--socks5`.
+    pub fn socks5(mut self, socks5: Option<Socks5Proxy>) -> Self {
+        self.socks5 = socks5;
+        self
+    }
+
+    /// Delays the requests to keep within the rate of `--max-requests-per-minute`.
+    pub fn rate_limiter(mut self, rate_limiter: Option<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// HTTP/2 is offered with ALPN on https, plain http stays on HTTP/1.1 without an
+    /// upgrade.
+    fn http_version(&self) -> HttpVersion {
+        if self.http1_only {
+            HttpVersion::V11
         } else {
-            Some(request.body)
-        };
-        HttpRequest {
-            url: request.url,
-            method: request.method,
-            headers: request.headers,
-            body,
+            HttpVersion::V2TLS
         }
     }
 
-    fn to_oauth_response(response: HttpResponse) -> oauth2::HttpResponse {
-        let body = if let Some(body) = response.body {
-            body
-        } else {
-            Vec::new()
-        };
-        oauth2::HttpResponse {
-            status_code: response.status_code,
-            headers: response.headers,
-            body,
+    fn to_curl_request(
+        &self,
+        request: &oauth2::HttpRequest,
+    ) -> Result<Easy2<Collector>, Error<Collector>> {
+        let mut easy = Easy2::new(Collector::RamAndHeaders(Vec::new(), Vec::new()));
+        easy.url(request.url.as_str()).map_err(Error::Curl)?;
+        easy.ip_resolve(self.ip_version.ip_resolve())
+            .map_err(Error::Curl)?;
+        if let Err(e) = easy.http_version(self.http_version()) {
+            // libcurl was built without HTTP/2, it then speaks HTTP/1.1 anyway.
+            log::debug!("Unable to set {:?}: {e}", self.http_version());
+        }
+
+        self.set_tls_options(&mut easy)?;
+        if let Some(proxy) = &self.socks5 {
+            easy.proxy(&proxy.curl_url()).map_err(Error::Curl)?;
+            if let Some((username, password)) = &proxy.credentials {
+                easy.proxy_username(username).map_err(Error::Curl)?;
+                easy.proxy_password(password).map_err(Error::Curl)?;
+            }
+        }
+
+        let mut headers = List::new();
+        for (name, value) in request.headers.iter() {
+            let value = value
+                .to_str()
+                .map_err(|_| Error::Other(format!("invalid {name} header value {value:?}")))?;
+            headers
+                .append(&format!("{name}: {value}"))
+                .map_err(Error::Curl)?;
+        }
+        easy.http_headers(headers).map_err(Error::Curl)?;
+
+        match &request.method {
+            &Method::POST => {
+                easy.post(true).map_err(Error::Curl)?;
+                if !request.body.is_empty() {
+                    easy.post_field_size(request.body.len() as u64)
+                        .map_err(Error::Curl)?;
+                    easy.post_fields_copy(request.body.as_slice())
+                        .map_err(Error::Curl)?;
+                }
+            }
+            &Method::GET => easy.get(true).map_err(Error::Curl)?,
+            method => return Err(Error::Other(format!("unsupported method {method}"))),
+        }
+        Ok(easy)
+    }
+
+    fn set_tls_options(&self, easy: &mut Easy2<Collector>) -> Result<(), Error<Collector>> {
+        let (oldest, newest) = self
+            .tls
+            .curl_versions()
+            .map_err(|e| Error::Other(e.error_code_desc))?;
+        easy.ssl_min_max_version(oldest, newest)
+            .map_err(Error::Curl)?;
+        if let Some(ciphers) = self.tls.curl_cipher_list(TlsVersion::V1_2) {
+            easy.ssl_cipher_list(&ciphers).map_err(Error::Curl)?;
+        }
+        if let Some(ciphers) = self.tls.curl_cipher_list(TlsVersion::V1_3) {
+            let ciphers = CString::new(ciphers).map_err(|e| Error::Other(e.to_string()))?;
+            // SAFETY: the handle is valid for the lifetime of `easy`, and libcurl copies
+            // the string.
+            let code = unsafe {
+                curl_sys::curl_easy_setopt(easy.raw(), CURLOPT_TLS13_CIPHERS, ciphers.as_ptr())
+            };
+            if code != curl_sys::CURLE_OK {
+                return Err(Error::Other(format!(
+                    "libcurl is unable to limit the TLS 1.3 cipher suites (error {code})"
+                )));
+            }
         }
+        Ok(())
     }
 
+    fn to_oauth_response(easy: Easy2<Collector>) -> Result<oauth2::HttpResponse, Error<Collector>> {
+        if let Some(version) = negotiated_version(&easy) {
+            log::debug!("Response Protocol: {version}");
+        }
+        let status_code = easy.response_code().map_err(Error::Curl)? as u16;
+        let (body, headers) = easy.get_ref().get_response_body_and_headers();
+        Ok(oauth2::HttpResponse {
+            status_code: StatusCode::from_u16(status_code)
+                .map_err(|err| Error::Http(err.to_string()))?,
+            headers: headers.unwrap_or_default(),
+            body: body.unwrap_or_default(),
+        })
+    }
+
+    /// Sends the request, following the redirects itself so that the Authorization
+    /// header never leaves the origin it was meant for.
     pub async fn send(
         &self,
-        request: oauth2::HttpRequest,
+        mut request: oauth2::HttpRequest,
     ) -> Result<oauth2::HttpResponse, Error<Collector>> {
+        let mut redirects = 0;
+        loop {
+            let response = self.send_once(&request).await?;
+            let Some(next) = redirect(&request, &response)? else {
+                return Ok(response);
+            };
+            if redirects == self.max_redirects {
+                return Err(Error::Http(format!(
+                    "too many redirects, at most {} are followed",
+                    self.max_redirects
+                )));
+            }
+            redirects += 1;
+            log::debug!(
+                "Following the {} redirect to {}",
+                response.status_code,
+                next.url
+            );
+            request = next;
+        }
+    }
+
+    async fn send_once(
+        &self,
+        request: &oauth2::HttpRequest,
+    ) -> Result<oauth2::HttpResponse, Error<Collector>> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter
+                .acquire(request.url.host_str().unwrap_or_default())
+                .await;
+        }
         log::debug!("Request Url: {}", request.url);
-        log::debug!("Request Header: {:?}", request.headers);
         log::debug!("Request Method: {}", request.method);
-        log::debug!(
-            "Request Body: {}",
-            std::str::from_utf8(request.body.as_slice()).unwrap_or_default()
-        );
+        if log::log_enabled!(log::Level::Trace) {
+            for line in trace_request(request) {
+                log::trace!("{line}");
+            }
+        }
 
-        let response = HttpClient::new(Collector::RamAndHeaders(Vec::new(), Vec::new()))
-            .request(Curl::to_curl_request(request))?
-            .nonblocking(self.actor_handle.clone())
-            .perform()
+        let easy = self
+            .actor_handle
+            .send_request(self.to_curl_request(request)?)
             .await
-            .map(Curl::to_oauth_response)?;
+            .map_err(Error::Perform)?;
+        let response = Curl::to_oauth_response(easy)?;
 
-        log::debug!("Response Header: {:?}", response.headers);
-        log::debug!(
-            "Response Body: {}",
-            std::str::from_utf8(response.body.as_slice()).unwrap_or_default()
-        );
         log::debug!("Response Status: {}", response.status_code);
+        if log::log_enabled!(log::Level::Trace) {
+            for line in trace_response(&response) {
+                log::trace!("{line}");
+            }
+        }
         Ok(response)
     }
 }
@@ -77,3 +367,293 @@ impl Default for Curl {
         Self::new()
     }
 }
+
+#[async_trait]
+impl HttpClient for Curl {
+    async fn send(&self, request: oauth2::HttpRequest) -> OAuth2Result<oauth2::HttpResponse> {
+        Ok(Curl::send(self, request).await?)
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use http::{HeaderMap, StatusCode};
+
+    use super::HttpClient;
+    use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+    /// An [`HttpClient`] that answers the requests with the given responses in turn and
+    /// keeps the requests. A request after the last response fails.
+    #[derive(Default)]
+    pub struct MockHttpClient {
+        responses: Mutex<VecDeque<oauth2::HttpResponse>>,
+        requests: Mutex<Vec<oauth2::HttpRequest>>,
+    }
+
+    impl MockHttpClient {
+        pub fn new(responses: Vec<oauth2::HttpResponse>) -> Self {
+            Self {
+                responses: Mutex::new(responses.into()),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        /// A response with this status and body.
+        pub fn response(status_code: StatusCode, body: &str) -> oauth2::HttpResponse {
+            oauth2::HttpResponse {
+                status_code,
+                headers: HeaderMap::new(),
+                body: body.as_bytes().to_vec(),
+            }
+        }
+
+        /// The requests sent so far.
+        pub fn requests(&self) -> Vec<oauth2::HttpRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for MockHttpClient {
+        async fn send(&self, request: oauth2::HttpRequest) -> OAuth2Result<oauth2::HttpResponse> {
+            let url = request.url.clone();
+            self.requests.lock().unwrap().push(request);
+            self.responses.lock().unwrap().pop_front().ok_or_else(|| {
+                OAuth2Error::new(
+                    ErrorCodes::HttpError,
+                    format!("No response for the request to {url}"),
+                )
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{
+        header::{AUTHORIZATION, LOCATION},
+        HeaderMap, HeaderValue, Method, StatusCode,
+    };
+    use oauth2::url::Url;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{negotiated_version, redirect, trace_request, trace_response, Curl};
+    use crate::net::IpVersion;
+
+    /// Answers one request and returns its head.
+    async fn serve_once(listener: TcpListener) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    /// Redirects the first `redirects` requests to the next path, then answers. Returns
+    /// the request heads.
+    async fn serve_redirects(listener: TcpListener, redirects: usize) -> Vec<String> {
+        let mut heads = Vec::new();
+        for count in 0..=redirects {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            heads.push(String::from_utf8(head).unwrap());
+            let response = if count < redirects {
+                format!(
+                    "HTTP/1.1 302 Found\r\nLocation: /{}\r\nContent-Length: 0\r\n\
+                    Connection: close\r\n\r\n",
+                    count + 1
+                )
+            } else {
+                "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".to_string()
+            };
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        heads
+    }
+
+    fn request(port: u16) -> oauth2::HttpRequest {
+        oauth2::HttpRequest {
+            url: Url::parse(&format!("http://localhost:{port}/")).unwrap(),
+            method: Method::GET,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ip_version_is_used_by_curl() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_once(listener));
+
+        let curl = Curl::new().ip_version(IpVersion::V6);
+        assert!(curl.send(request(port)).await.is_err());
+
+        let curl = Curl::new().ip_version(IpVersion::V4);
+        let response = curl.send(request(port)).await.unwrap();
+        assert_eq!(response.body, b"ok");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_is_not_upgraded_to_http2() {
+        for http1_only in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = tokio::spawn(serve_once(listener));
+
+            let curl = Curl::new().ip_version(IpVersion::V4).http1_only(http1_only);
+            let easy = curl
+                .actor_handle
+                .send_request(curl.to_curl_request(&request(port)).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(negotiated_version(&easy), Some("HTTP/1.1"));
+
+            let head = server.await.unwrap();
+            assert!(head.starts_with("GET / HTTP/1.1\r\n"));
+            assert!(!head.to_ascii_lowercase().contains("upgrade: h2c"));
+        }
+    }
+
+    #[test]
+    fn test_trace_redacts_the_secrets() {
+        let mut request = request(443);
+        request.method = Method::POST;
+        request.headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer secret-access-token"),
+        );
+        request.body = b"grant_type=refresh_token&refresh_token=secret-refresh-token".to_vec();
+        let lines = trace_request(&request);
+        assert_eq!(lines[0], "> POST http://localhost:443/");
+        assert!(lines.contains(&"> authorization: Bearer <redacted>".to_string()));
+        assert!(lines.iter().all(|line| !line.contains("secret")));
+
+        let response = oauth2::HttpResponse {
+            status_code: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: br#"{"access_token":"secret-access-token","expires_in":3600}"#.to_vec(),
+        };
+        let lines = trace_response(&response);
+        assert_eq!(lines[0], "< 200 OK");
+        assert!(lines[1].contains(r#""access_token":"<redacted>""#));
+        assert!(lines.iter().all(|line| !line.contains("secret")));
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_followed_up_to_the_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_redirects(listener, 2));
+
+        let curl = Curl::new().ip_version(IpVersion::V4).max_redirects(2);
+        let response = curl.send(request(port)).await.unwrap();
+        assert_eq!(response.body, b"ok");
+        let heads = server.await.unwrap();
+        assert!(heads[1].starts_with("GET /1 HTTP/1.1\r\n"));
+        assert!(heads[2].starts_with("GET /2 HTTP/1.1\r\n"));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(serve_redirects(listener, 2));
+
+        let curl = Curl::new().ip_version(IpVersion::V4).max_redirects(1);
+        let error = curl.send(request(port)).await.unwrap_err();
+        assert!(format!("{error:?}").contains("too many redirects"));
+        server.abort();
+    }
+
+    #[test]
+    fn test_authorization_is_not_sent_to_another_host() {
+        let mut request = oauth2::HttpRequest {
+            url: Url::parse("https://outlook.office.com/api/v2.0/me/").unwrap(),
+            method: Method::GET,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        };
+        request
+            .headers
+            .insert(AUTHORIZATION, HeaderValue::from_static("Bearer token"));
+        let response = |location: &'static str| oauth2::HttpResponse {
+            status_code: StatusCode::FOUND,
+            headers: HeaderMap::from_iter([(LOCATION, HeaderValue::from_static(location))]),
+            body: Vec::new(),
+        };
+
+        let next = redirect(&request, &response("/api/v2.0/me"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.url.as_str(), "https://outlook.office.com/api/v2.0/me");
+        assert_eq!(next.headers[AUTHORIZATION], "Bearer token");
+
+        for location in [
+            "https://attacker.example.com/me",
+            "https://outlook.office.com:8443/api/v2.0/me/",
+        ] {
+            let next = redirect(&request, &response(location)).unwrap().unwrap();
+            assert!(next.headers.get(AUTHORIZATION).is_none(), "{location}");
+        }
+        assert!(redirect(
+            &request,
+            &response("http://outlook.office.com/api/v2.0/me/")
+        )
+        .is_err());
+
+        let ok = oauth2::HttpResponse {
+            status_code: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        };
+        assert!(redirect(&request, &ok).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_post_redirects() {
+        let mut request = request(443);
+        request.method = Method::POST;
+        request.body = b"grant_type=refresh_token".to_vec();
+        let response = |status_code| oauth2::HttpResponse {
+            status_code,
+            headers: HeaderMap::from_iter([(LOCATION, HeaderValue::from_static("/token"))]),
+            body: Vec::new(),
+        };
+
+        let next = redirect(&request, &response(StatusCode::TEMPORARY_REDIRECT))
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.method, Method::POST);
+        assert_eq!(next.body, request.body);
+
+        let next = redirect(&request, &response(StatusCode::SEE_OTHER))
+            .unwrap()
+            .unwrap();
+        assert_eq!(next.method, Method::GET);
+        assert!(next.body.is_empty());
+
+        let elsewhere = oauth2::HttpResponse {
+            status_code: StatusCode::TEMPORARY_REDIRECT,
+            headers: HeaderMap::from_iter([(
+                LOCATION,
+                HeaderValue::from_static("https://attacker.example.com/token"),
+            )]),
+            body: Vec::new(),
+        };
+        assert!(redirect(&request, &elsewhere).is_err());
+    }
+}
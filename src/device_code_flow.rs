@@ -1,22 +1,32 @@
 // Standard libraries
 use std::{
+    fs,
     future::Future,
+    io::ErrorKind,
     path::{Path, PathBuf},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 // 3rd party crates
 use async_trait::async_trait;
-use directories::UserDirs;
+use chrono::{DateTime, SecondsFormat, Utc};
 use oauth2::{
     basic::{BasicClient, BasicTokenType},
     devicecode::StandardDeviceAuthorizationResponse,
     AccessToken, AuthUrl, ClientId, ClientSecret, DeviceAuthorizationUrl, EmptyExtraTokenFields,
-    HttpRequest, HttpResponse, Scope, StandardTokenResponse, TokenUrl,
+    HttpRequest, HttpResponse, RequestTokenError, Scope, StandardTokenResponse, TokenUrl,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 // My crates
+use crate::cli::{LoginArgs, PollLimitArgs};
+use crate::cloud::{Authority, Cloud, OAuthVersion};
 use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
-use crate::{curl::Curl, TokenKeeper};
+use crate::report::OutputFormat;
+use crate::token_keeper::{no_token_to_refresh, numeric_durations, TokenKeeper};
+use crate::{curl::Curl, qr};
 
 #[async_trait]
 pub trait DeviceCodeFlowTrait {
@@ -36,6 +46,8 @@ pub trait DeviceCodeFlowTrait {
     >(
         &self,
         device_auth_response: StandardDeviceAuthorizationResponse,
+        requested_at: Duration,
+        poll_limits: PollLimitArgs,
         async_http_callback: T,
     ) -> OAuth2Result<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>>;
     async fn get_access_token<
@@ -46,6 +58,19 @@ pub trait DeviceCodeFlowTrait {
         &self,
         file_directory: &Path,
         file_name: &Path,
+        force_refresh: bool,
+        async_http_callback: T,
+    ) -> OAuth2Result<TokenKeeper>;
+
+    async fn refresh_access_token<
+        F: Future<Output = Result<HttpResponse, RE>> + Send,
+        RE: std::error::Error + 'static + Send,
+        T: Fn(HttpRequest) -> F + Send + Sync,
+    >(
+        &self,
+        file_directory: &Path,
+        file_name: &Path,
+        token_keeper: TokenKeeper,
         async_http_callback: T,
     ) -> OAuth2Result<TokenKeeper>;
 }
@@ -55,6 +80,8 @@ pub struct DeviceCodeFlow {
     client_secret: Option<ClientSecret>,
     device_auth_endpoint: DeviceAuthorizationUrl,
     token_endpoint: TokenUrl,
+    /// Added to the device code and token requests, e.g. the resource of the v1 endpoints.
+    token_params: Vec<(String, String)>,
 }
 
 #[async_trait]
@@ -73,10 +100,15 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
             .create_client()?
             .set_device_authorization_url(self.device_auth_endpoint.to_owned());
 
-        let device_auth_response = client
-            .exchange_device_code()?
-            .add_scopes(scopes)
-            .request_async(async_http_callback)
+        let mut request = client.exchange_device_code()?.add_scopes(scopes);
+        for (key, value) in &self.token_params {
+            request = request.add_extra_param(key, value);
+        }
+        let device_auth_response = request
+            .request_async(|request| {
+                let response = async_http_callback(request);
+                async move { response.await.map(numeric_durations) }
+            })
             .await?;
 
         Ok(device_auth_response)
@@ -88,13 +120,79 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
     >(
         &self,
         device_auth_response: StandardDeviceAuthorizationResponse,
+        requested_at: Duration,
+        poll_limits: PollLimitArgs,
         async_http_callback: T,
     ) -> OAuth2Result<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> {
         let client = self.create_client()?;
-        let token_result = client
-            .exchange_device_access_token(&device_auth_response)
-            .request_async(async_http_callback, tokio::time::sleep, None)
-            .await?;
+        let expires_at = requested_at + device_auth_response.expires_in();
+        let last_warning = AtomicU64::new(u64::MAX);
+        let last_reminder = AtomicU64::new(requested_at.as_secs());
+        let verification_uri = device_auth_response.verification_uri().as_str();
+        let user_code = device_auth_response.user_code().secret();
+        // oauth2 adds 5 seconds to the interval on each slow_down, without a limit.
+        let slow_downs = AtomicU32::new(0);
+        let mut request = client.exchange_device_access_token(&device_auth_response);
+        for (key, value) in &self.token_params {
+            request = request.add_extra_param(key, value);
+        }
+        let token_result = request
+            .request_async(
+                |request| {
+                    let response = async_http_callback(request);
+                    let slow_downs = &slow_downs;
+                    async move {
+                        let response = response.await?;
+                        if !is_slow_down(&response) {
+                            slow_downs.store(0, Ordering::Relaxed);
+                        } else if slow_downs.fetch_add(1, Ordering::Relaxed) + 1
+                            >= poll_limits.max_slow_down
+                        {
+                            return Ok::<_, RE>(too_many_slow_downs(response));
+                        }
+                        Ok(numeric_durations(response))
+                    }
+                },
+                |interval| {
+                    let now = since_epoch();
+                    let remaining = expires_at.saturating_sub(now);
+                    if let Some(remaining) = reminder(
+                        now,
+                        expires_at,
+                        poll_limits.reminder_interval,
+                        &last_reminder,
+                    ) {
+                        log::info!(
+                            "Still waiting for the login: open {verification_uri} and input the \
+                            code {user_code}, which expires in {} s.",
+                            remaining.as_secs()
+                        );
+                    }
+                    if let Some(threshold) = expiry_warning(remaining, &last_warning) {
+                        log::warn!(
+                            "The device code expires in less than {threshold} s, complete the login."
+                        );
+                    }
+                    tokio::time::sleep(interval.min(poll_limits.max_poll_interval))
+                },
+                None,
+            )
+            .await
+            .map_err(|e| match e {
+                RequestTokenError::ServerResponse(response)
+                    if response.error().as_ref() == TOO_MANY_SLOW_DOWNS =>
+                {
+                    OAuth2Error::new(
+                        ErrorCodes::SlowDown,
+                        format!(
+                            "The server answered slow_down {} times in a row, giving up. \
+                        Wait a few minutes, then login again.",
+                            poll_limits.max_slow_down
+                        ),
+                    )
+                }
+                e => OAuth2Error::from(e),
+            })?;
         log::info!("Access token successfuly retrieved from the endpoint.");
         Ok(token_result)
     }
@@ -107,53 +205,81 @@ impl DeviceCodeFlowTrait for DeviceCodeFlow {
         &self,
         file_directory: &Path,
         file_name: &Path,
+        force_refresh: bool,
         async_http_callback: T,
     ) -> OAuth2Result<TokenKeeper> {
         let mut token_keeper = TokenKeeper::new(file_directory.to_path_buf());
         token_keeper.read(file_name)?;
 
+        if force_refresh {
+            log::info!("Refreshing the access token even if it has not expired, --force-refresh was given.");
+            return self
+                .refresh_access_token(file_directory, file_name, token_keeper, async_http_callback)
+                .await;
+        }
         if token_keeper.has_access_token_expired() {
-            match token_keeper.refresh_token {
-                Some(ref_token) => {
-                    log::info!(
-                        "Access token has expired, contacting endpoint to get a new access token."
-                    );
-                    let response = self
-                        .create_client()?
-                        .exchange_refresh_token(&ref_token)
-                        .request_async(async_http_callback)
-                        .await;
-
-                    match response {
-                        Ok(res) => {
-                            token_keeper = TokenKeeper::from(res);
-                            token_keeper.set_directory(file_directory.to_path_buf());
-                            token_keeper.save(file_name)?;
-                            Ok(token_keeper)
-                        }
-                        Err(e) => {
-                            let error = OAuth2Error::from(e);
-                            if error.error_code == ErrorCodes::InvalidGrant {
-                                let file = TokenKeeper::new(file_directory.to_path_buf());
-                                file.delete(file_name).unwrap()
-                            }
-                            Err(error)
-                        }
-                    }
-                }
-                None => {
-                    log::info!("Access token has expired but there is no refresh token, please login again.");
-                    token_keeper.delete(file_name)?;
-                    Err(OAuth2Error::new(
-                        ErrorCodes::NoToken,
-                        "There is no refresh token.".into(),
-                    ))
-                }
+            if token_keeper.refresh_token.is_none() {
+                log::info!(
+                    "Access token has expired but there is no refresh token, please login again."
+                );
+                token_keeper.delete(file_name)?;
+                return Err(OAuth2Error::new(
+                    ErrorCodes::NoToken,
+                    "There is no refresh token.".into(),
+                ));
             }
+            log::info!("Access token has expired, contacting endpoint to get a new access token.");
+            self.refresh_access_token(file_directory, file_name, token_keeper, async_http_callback)
+                .await
         } else {
             Ok(token_keeper)
         }
     }
+
+    async fn refresh_access_token<
+        F: Future<Output = Result<HttpResponse, RE>> + Send,
+        RE: std::error::Error + 'static + Send,
+        T: Fn(HttpRequest) -> F + Send + Sync,
+    >(
+        &self,
+        file_directory: &Path,
+        file_name: &Path,
+        token_keeper: TokenKeeper,
+        async_http_callback: T,
+    ) -> OAuth2Result<TokenKeeper> {
+        let ref_token = token_keeper.refresh_token.ok_or(OAuth2Error::new(
+            ErrorCodes::NoToken,
+            "There is no refresh token.".into(),
+        ))?;
+        let client = self.create_client()?;
+        let mut request = client.exchange_refresh_token(&ref_token);
+        for (key, value) in &self.token_params {
+            request = request.add_extra_param(key, value);
+        }
+        let response = request
+            .request_async(|request| {
+                let response = async_http_callback(request);
+                async move { response.await.map(numeric_durations) }
+            })
+            .await;
+
+        match response {
+            Ok(res) => {
+                let mut token_keeper = TokenKeeper::from(res);
+                token_keeper.set_directory(file_directory.to_path_buf());
+                token_keeper.save(file_name)?;
+                Ok(token_keeper)
+            }
+            Err(e) => {
+                let error = OAuth2Error::from(e);
+                if error.error_code == ErrorCodes::InvalidGrant {
+                    let file = TokenKeeper::new(file_directory.to_path_buf());
+                    file.delete(file_name).unwrap()
+                }
+                Err(error)
+            }
+        }
+    }
 }
 
 impl DeviceCodeFlow {
@@ -168,9 +294,15 @@ impl DeviceCodeFlow {
             client_secret,
             device_auth_endpoint,
             token_endpoint,
+            token_params: Vec::new(),
         }
     }
 
+    pub fn with_token_params(mut self, token_params: Vec<(String, String)>) -> Self {
+        self.token_params = token_params;
+        self
+    }
+
     fn create_client(&self) -> OAuth2Result<BasicClient> {
         Ok(BasicClient::new(
             self.client_id.to_owned(),
@@ -182,50 +314,267 @@ impl DeviceCodeFlow {
     }
 }
 
-pub async fn device_code_flow(
+/// The error a slow_down answer becomes once the server has answered slow_down
+/// `--max-slow-down` times in a row. oauth2 keeps polling after an error of the HTTP
+/// callback, only an error answer other than authorization_pending and slow_down ends it.
+const TOO_MANY_SLOW_DOWNS: &str = "too_many_slow_downs";
+
+/// The slow_down answer turned into the one that ends the polling.
+fn too_many_slow_downs(mut response: HttpResponse) -> HttpResponse {
+    response.body = serde_json::json!({ "error": TOO_MANY_SLOW_DOWNS })
+        .to_string()
+        .into_bytes();
+    response
+}
+
+/// Whether the token endpoint asks to poll less often.
+fn is_slow_down(response: &HttpResponse) -> bool {
+    serde_json::from_slice::<Value>(&response.body)
+        .is_ok_and(|json| json["error"].as_str() == Some("slow_down"))
+}
+
+/// The name of the cached token file for this flow.
+pub fn token_file(client_id: &str) -> PathBuf {
+    PathBuf::from(format!("{}_device_code_flow.json", client_id))
+}
+
+/// The name of the file keeping a requested device code until `poll` completes it.
+fn pending_code_file(client_id: &str) -> PathBuf {
+    PathBuf::from(format!("{}_device_code_state.json", client_id))
+}
+
+/// A device code requested with `poll --request-only`, saved for the `poll` step.
+#[derive(Debug, Deserialize, Serialize)]
+struct PendingCode {
+    #[serde(default)]
+    cloud: Cloud,
+    tenant: String,
+    /// When the code was requested, since the UNIX epoch.
+    requested_at: Duration,
+    device_auth_response: StandardDeviceAuthorizationResponse,
+    #[serde(default)]
+    oauth_version: OAuthVersion,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resource: Option<String>,
+}
+
+fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+impl PendingCode {
+    fn has_expired(&self, now: Duration) -> bool {
+        now >= self.requested_at + self.device_auth_response.expires_in()
+    }
+
+    fn save(&self, directory: &Path, client_id: &str) -> OAuth2Result<()> {
+        Ok(fs::write(
+            directory.join(pending_code_file(client_id)),
+            serde_json::to_string(self)?,
+        )?)
+    }
+
+    /// Loads the requested device code, deleting it once it has expired.
+    fn load(directory: &Path, client_id: &str, now: Duration) -> OAuth2Result<Self> {
+        let path = directory.join(pending_code_file(client_id));
+        let pending_code: Self = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str(&text)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(OAuth2Error::new(
+                    ErrorCodes::ConfigurationError,
+                    "There is no device code to poll, run poll with --request-only first.".into(),
+                ))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if pending_code.has_expired(now) {
+            fs::remove_file(&path)?;
+            return Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                "The device code has expired, run poll with --request-only again.".into(),
+            ));
+        }
+        Ok(pending_code)
+    }
+}
+
+/// The seconds before the device code expires at which polling warns that it is about to.
+const EXPIRY_WARNINGS: [u64; 4] = [300, 60, 30, 10];
+
+/// The warning threshold `remaining` has just gone below, each one only once: the last
+/// one warned about is kept in `last_warning`.
+fn expiry_warning(remaining: Duration, last_warning: &AtomicU64) -> Option<u64> {
+    let threshold = EXPIRY_WARNINGS
+        .into_iter()
+        .filter(|&threshold| remaining.as_secs() < threshold)
+        .min()?;
+    (last_warning.swap(threshold, Ordering::Relaxed) > threshold).then_some(threshold)
+}
+
+/// The time left before the device code expires when the login is to be recalled at
+/// `now`, every `reminder_interval` seconds since it was shown: the time of the last
+/// reminder is kept in `last_reminder`. None once the code has expired.
+fn reminder(
+    now: Duration,
+    expires_at: Duration,
+    reminder_interval: u64,
+    last_reminder: &AtomicU64,
+) -> Option<Duration> {
+    let remaining = expires_at
+        .checked_sub(now)
+        .filter(|remaining| !remaining.is_zero())?;
+    if reminder_interval == 0
+        || now.as_secs() < last_reminder.load(Ordering::Relaxed) + reminder_interval
+    {
+        return None;
+    }
+    last_reminder.store(now.as_secs(), Ordering::Relaxed);
+    Some(remaining)
+}
+
+/// What the device authorization response tells the user: where to login with which
+/// code, for how long and how often the token endpoint is polled meanwhile.
+#[derive(Debug, PartialEq, Serialize)]
+struct DeviceLogin {
+    verification_uri: String,
+    user_code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    /// When the device code expires, in RFC 3339.
+    expires_at: String,
+    interval: u64,
+}
+
+impl DeviceLogin {
+    fn of(
+        device_auth_response: &StandardDeviceAuthorizationResponse,
+        requested_at: Duration,
+    ) -> Self {
+        let expires_in = device_auth_response.expires_in();
+        Self {
+            verification_uri: device_auth_response.verification_uri().to_string(),
+            user_code: device_auth_response.user_code().secret().clone(),
+            verification_uri_complete: device_auth_response
+                .verification_uri_complete()
+                .map(|uri| uri.secret().clone()),
+            expires_in: expires_in.as_secs(),
+            expires_at: DateTime::<Utc>::from(UNIX_EPOCH + requested_at + expires_in)
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            interval: device_auth_response.interval().as_secs(),
+        }
+    }
+}
+
+/// Logs the link and the code to login with, also as a QR code with `--qr`, and how
+/// long the code is valid. With `--output json`, they are also printed as JSON.
+fn show_login(
+    device_auth_response: &StandardDeviceAuthorizationResponse,
+    requested_at: Duration,
+    show_qr: bool,
+    output: OutputFormat,
+) -> OAuth2Result<()> {
+    let login = DeviceLogin::of(device_auth_response, requested_at);
+    log::info!("Open this link: {}", login.verification_uri);
+    log::info!("Input this code: {}", login.user_code);
+    log::info!(
+        "The code expires in {} s (at {}), the login is polled for every {} s.",
+        login.expires_in,
+        login.expires_at,
+        login.interval
+    );
+    if output == OutputFormat::Json {
+        println!("{}", serde_json::to_string(&login)?);
+    }
+    if show_qr {
+        // Microsoft may not send the complete URI, then the code still has to be typed.
+        let uri = device_auth_response
+            .verification_uri_complete()
+            .map(|uri| uri.secret().as_str())
+            .unwrap_or(device_auth_response.verification_uri().as_str());
+        match qr::render(uri) {
+            Some(code) => eprintln!("{code}"),
+            None => log::info!("Open this link: {uri}"),
+        }
+    }
+    Ok(())
+}
+
+fn microsoft_device_code_flow(
     client_id: &str,
     client_secret: Option<ClientSecret>,
-    curl: Curl,
-) -> OAuth2Result<AccessToken> {
-    let oauth2_cloud = DeviceCodeFlow::new(
+    authority: &Authority,
+) -> OAuth2Result<DeviceCodeFlow> {
+    Ok(DeviceCodeFlow::new(
         ClientId::new(client_id.to_string()),
         client_secret,
-        DeviceAuthorizationUrl::new(
-            "https://login.microsoftonline.com/common/oauth2/v2.0/devicecode".to_string(),
-        )?,
-        TokenUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string())?,
-    );
-    let scopes = vec![
-        Scope::new("offline_access".to_string()),
-        Scope::new("https://outlook.office.com/SMTP.Send".to_string()),
-        Scope::new("https://outlook.office.com/User.Read".to_string()),
-    ];
-    let directory = UserDirs::new().unwrap();
-    let mut directory = directory.home_dir().to_owned();
+        DeviceAuthorizationUrl::new(authority.endpoint("devicecode"))?,
+        TokenUrl::new(authority.endpoint("token"))?,
+    )
+    .with_token_params(authority.token_params()))
+}
 
-    directory = directory.join("token");
+/// Exchanges the cached refresh token for a new access token, even if the cached
+/// access token has not expired yet.
+pub async fn refresh_device_code_flow(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    authority: &Authority,
+    directory: &Path,
+    curl: Curl,
+) -> OAuth2Result<TokenKeeper> {
+    let oauth2_cloud = microsoft_device_code_flow(client_id, client_secret, authority)?;
+    let token_file = token_file(client_id);
+    let mut token_keeper = TokenKeeper::new(directory.to_path_buf());
+    token_keeper.read(&token_file)?;
+
+    oauth2_cloud
+        .refresh_access_token(directory, &token_file, token_keeper, |request| async {
+            curl.send(request).await
+        })
+        .await
+}
 
-    let token_file = PathBuf::from(format!("{}_device_code_flow.json", client_id));
+pub async fn device_code_flow(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    authority: &Authority,
+    directory: &Path,
+    curl: Curl,
+    login: LoginArgs,
+) -> OAuth2Result<AccessToken> {
+    let oauth2_cloud = microsoft_device_code_flow(client_id, client_secret, authority)?;
+    let scopes = authority.scopes();
+    let token_file = token_file(client_id);
     let mut token_keeper = TokenKeeper::new(directory.to_path_buf());
 
-    // If there is no exsting token, get it from the cloud
-    if let Err(_err) = token_keeper.read(&token_file) {
+    // If there is no exsting token, or one with fewer scopes, get it from the cloud
+    if token_keeper.read(&token_file).is_err()
+        || (login.fail_fast_on_scope_downgrade && token_keeper.lacks_scopes(&scopes))
+    {
+        if login.force_refresh {
+            return Err(no_token_to_refresh());
+        }
         let device_auth_response = oauth2_cloud
             .request_device_code(scopes, |request| async { curl.send(request).await })
             .await?;
 
-        log::info!(
-            "Open this link: {}",
-            &device_auth_response.verification_uri().as_str(),
-        );
-        log::info!(
-            "Input this code: {}",
-            &device_auth_response.user_code().secret()
-        );
+        let requested_at = since_epoch();
+        show_login(
+            &device_auth_response,
+            requested_at,
+            login.qr,
+            OutputFormat::Text,
+        )?;
         let token = oauth2_cloud
-            .poll_access_token(device_auth_response, |request| async {
-                curl.send(request).await
-            })
+            .poll_access_token(
+                device_auth_response,
+                requested_at,
+                login.poll_limits,
+                |request| async { curl.send(request).await },
+            )
             .await?;
         token_keeper = TokenKeeper::from(token);
         token_keeper.set_directory(directory.to_path_buf());
@@ -233,10 +582,320 @@ pub async fn device_code_flow(
         token_keeper.save(&token_file)?;
     } else {
         token_keeper = oauth2_cloud
-            .get_access_token(&directory, &token_file, |request| async {
-                curl.send(request).await
-            })
+            .get_access_token(
+                directory,
+                &token_file,
+                login.force_refresh,
+                |request| async { curl.send(request).await },
+            )
             .await?;
     }
     Ok(token_keeper.access_token)
 }
+
+/// The first step of the scripted device code flow: requests a device code, shows the
+/// link and the code to login with and saves it for `poll_login`.
+pub async fn request_login(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    authority: &Authority,
+    directory: &Path,
+    curl: Curl,
+    show_qr: bool,
+    output: OutputFormat,
+) -> OAuth2Result<()> {
+    let oauth2_cloud = microsoft_device_code_flow(client_id, client_secret, authority)?;
+    let device_auth_response = oauth2_cloud
+        .request_device_code(authority.scopes(), |request| async {
+            curl.send(request).await
+        })
+        .await?;
+
+    let requested_at = since_epoch();
+    show_login(&device_auth_response, requested_at, show_qr, output)?;
+    let pending_code = PendingCode {
+        cloud: authority.cloud,
+        tenant: authority.tenant.clone(),
+        requested_at,
+        device_auth_response,
+        oauth_version: authority.oauth_version,
+        resource: authority.resource.clone(),
+    };
+    pending_code.save(directory, client_id)
+}
+
+/// The second step of the scripted device code flow: polls for the access token of the
+/// saved device code and caches it like the interactive login does.
+pub async fn poll_login(
+    client_id: &str,
+    client_secret: Option<ClientSecret>,
+    directory: &Path,
+    curl: Curl,
+    poll_limits: PollLimitArgs,
+) -> OAuth2Result<AccessToken> {
+    let pending_code = PendingCode::load(directory, client_id, since_epoch())?;
+    let authority = Authority::new(pending_code.cloud, &pending_code.tenant)
+        .with_oauth_version(pending_code.oauth_version)
+        .with_resource(pending_code.resource);
+    let oauth2_cloud = microsoft_device_code_flow(client_id, client_secret, &authority)?;
+    let token = oauth2_cloud
+        .poll_access_token(
+            pending_code.device_auth_response,
+            pending_code.requested_at,
+            poll_limits,
+            |request| async { curl.send(request).await },
+        )
+        .await?;
+
+    let mut token_keeper = TokenKeeper::from(token);
+    token_keeper.set_directory(directory.to_path_buf());
+    token_keeper.save(&token_file(client_id))?;
+    fs::remove_file(directory.join(pending_code_file(client_id)))?;
+    Ok(token_keeper.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+    use http::{HeaderMap, StatusCode};
+    use oauth2::{
+        devicecode::StandardDeviceAuthorizationResponse, ClientId, DeviceAuthorizationUrl,
+        HttpRequest, HttpResponse, TokenUrl,
+    };
+
+    use super::{
+        expiry_warning, microsoft_device_code_flow, pending_code_file, poll_login, reminder,
+        since_epoch, DeviceCodeFlow, DeviceCodeFlowTrait, DeviceLogin, PendingCode,
+    };
+    use crate::cli::PollLimitArgs;
+    use crate::cloud::{Authority, Cloud, OAuthVersion};
+    use crate::curl::Curl;
+    use crate::error::ErrorCodes;
+
+    const REQUESTED_AT: Duration = Duration::from_secs(1_700_000_000);
+
+    fn pending_code() -> PendingCode {
+        let device_auth_response: StandardDeviceAuthorizationResponse = serde_json::from_str(
+            r#"{
+                    "device_code": "device-code",
+                    "user_code": "ABCD1234",
+                    "verification_uri": "https://microsoft.com/devicelogin",
+                    "expires_in": 900,
+                    "interval": 5,
+                    "message": "To sign in, use a web browser."
+                }"#,
+        )
+        .unwrap();
+        PendingCode {
+            cloud: Cloud::GccHigh,
+            tenant: "contoso.onmicrosoft.us".to_string(),
+            requested_at: REQUESTED_AT,
+            device_auth_response,
+            oauth_version: OAuthVersion::V2,
+            resource: None,
+        }
+    }
+
+    #[test]
+    fn test_pending_code_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        pending_code().save(directory.path(), "client").unwrap();
+
+        let now = REQUESTED_AT + Duration::from_secs(899);
+        let loaded = PendingCode::load(directory.path(), "client", now).unwrap();
+        assert_eq!(loaded.cloud, Cloud::GccHigh);
+        assert_eq!(loaded.tenant, "contoso.onmicrosoft.us");
+        assert_eq!(loaded.requested_at, REQUESTED_AT);
+        let response = loaded.device_auth_response;
+        assert_eq!(response.device_code().secret(), "device-code");
+        assert_eq!(response.user_code().secret(), "ABCD1234");
+        assert_eq!(
+            response.verification_uri().as_str(),
+            "https://microsoft.com/devicelogin"
+        );
+        assert_eq!(response.expires_in(), Duration::from_secs(900));
+        assert_eq!(response.interval(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_expired_pending_code_is_deleted() {
+        let directory = tempfile::tempdir().unwrap();
+        pending_code().save(directory.path(), "client").unwrap();
+
+        let now = REQUESTED_AT + Duration::from_secs(900);
+        let error = PendingCode::load(directory.path(), "client", now).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        assert!(error.error_code_desc.contains("expired"));
+        assert!(!directory.path().join(pending_code_file("client")).exists());
+    }
+
+    #[tokio::test]
+    async fn test_poll_without_requested_code() {
+        let directory = tempfile::tempdir().unwrap();
+        let error = poll_login(
+            "client",
+            None,
+            directory.path(),
+            Curl::new(),
+            PollLimitArgs::default(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        assert!(!fs::read_dir(directory.path()).unwrap().any(|_| true));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_slow_down_aborts() {
+        let flow = DeviceCodeFlow::new(
+            ClientId::new("client".to_string()),
+            None,
+            DeviceAuthorizationUrl::new("https://localhost/devicecode".to_string()).unwrap(),
+            TokenUrl::new("https://localhost/token".to_string()).unwrap(),
+        );
+        let poll_limits = PollLimitArgs {
+            max_poll_interval: Duration::from_millis(1),
+            max_slow_down: 3,
+            ..Default::default()
+        };
+        let polls = AtomicU32::new(0);
+
+        let error = flow
+            .poll_access_token(
+                pending_code().device_auth_response,
+                since_epoch(),
+                poll_limits,
+                |_request| async {
+                    polls.fetch_add(1, Ordering::Relaxed);
+                    Ok::<_, std::io::Error>(HttpResponse {
+                        status_code: StatusCode::BAD_REQUEST,
+                        headers: HeaderMap::new(),
+                        body: br#"{"error":"slow_down","error_description":"Slow down."}"#.to_vec(),
+                    })
+                },
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::SlowDown);
+        assert!(error.error_code_desc.contains("login again"));
+        assert_eq!(polls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_v1_device_code_sends_the_resource() {
+        let authority = Authority::new(Cloud::Commercial, "contoso.onmicrosoft.com")
+            .with_oauth_version(OAuthVersion::V1);
+        let flow = microsoft_device_code_flow("client", None, &authority).unwrap();
+        let device_auth_response = flow
+            .request_device_code(authority.scopes(), |request: HttpRequest| async move {
+                assert_eq!(
+                    request.url.as_str(),
+                    "https://login.microsoftonline.com/contoso.onmicrosoft.com/oauth2/devicecode"
+                );
+                let body = String::from_utf8(request.body).unwrap();
+                assert!(body.contains("resource=https%3A%2F%2Foutlook.office.com"));
+                assert!(!body.contains("scope="));
+                Ok::<_, std::io::Error>(HttpResponse {
+                    status_code: StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: br#"{"device_code":"device-code","user_code":"ABCD1234","verification_url":"https://microsoft.com/devicelogin","expires_in":"900","interval":"5","message":"To sign in, use a web browser."}"#.to_vec(),
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(device_auth_response.expires_in(), Duration::from_secs(900));
+        assert_eq!(device_auth_response.interval(), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_device_login_shows_the_interval_and_expiry() {
+        let authority = Authority::new(Cloud::Commercial, "common");
+        let flow = microsoft_device_code_flow("client", None, &authority).unwrap();
+        let device_auth_response = flow
+            .request_device_code(authority.scopes(), |_request| async {
+                Ok::<_, std::io::Error>(HttpResponse {
+                    status_code: StatusCode::OK,
+                    headers: HeaderMap::new(),
+                    body: br#"{"device_code":"device-code","user_code":"ABCD1234","verification_uri":"https://microsoft.com/devicelogin","expires_in":900,"interval":5,"message":"To sign in, use a web browser."}"#.to_vec(),
+                })
+            })
+            .await
+            .unwrap();
+
+        let login = DeviceLogin::of(&device_auth_response, REQUESTED_AT);
+        assert_eq!(login.expires_in, 900);
+        assert_eq!(login.interval, 5);
+        assert_eq!(login.expires_at, "2023-11-14T22:28:20Z");
+        assert_eq!(login.user_code, "ABCD1234");
+
+        let json = serde_json::to_value(&login).unwrap();
+        assert_eq!(json["expires_in"], 900);
+        assert_eq!(json["interval"], 5);
+        assert_eq!(json["expires_at"], "2023-11-14T22:28:20Z");
+        assert_eq!(
+            json["verification_uri"],
+            "https://microsoft.com/devicelogin"
+        );
+        assert!(json.get("verification_uri_complete").is_none());
+    }
+
+    #[test]
+    fn test_expiry_warnings_count_down() {
+        let last_warning = AtomicU64::new(u64::MAX);
+        let warning = |secs| expiry_warning(Duration::from_secs(secs), &last_warning);
+        assert_eq!(warning(600), None);
+        assert_eq!(warning(299), Some(300));
+        assert_eq!(warning(290), None);
+        assert_eq!(warning(59), Some(60));
+        // A long interval may skip a threshold.
+        assert_eq!(warning(9), Some(10));
+        assert_eq!(warning(5), None);
+        assert_eq!(warning(0), None);
+    }
+
+    #[test]
+    fn test_reminders_follow_the_interval() {
+        let expires_at = REQUESTED_AT + Duration::from_secs(150);
+        let last_reminder = AtomicU64::new(REQUESTED_AT.as_secs());
+        // Polled every 5 s until the code expires and some more.
+        let reminders: Vec<(u64, u64)> = (1..=40)
+            .map(|poll| Duration::from_secs(poll * 5))
+            .filter_map(|elapsed| {
+                reminder(REQUESTED_AT + elapsed, expires_at, 60, &last_reminder)
+                    .map(|remaining| (elapsed.as_secs(), remaining.as_secs()))
+            })
+            .collect();
+        // None is left once the code has expired at 150 s.
+        assert_eq!(reminders, [(60, 90), (120, 30)]);
+
+        // A slower poll is reminded at the first one after the interval.
+        let last_reminder = AtomicU64::new(REQUESTED_AT.as_secs());
+        let at = |secs| {
+            reminder(
+                REQUESTED_AT + Duration::from_secs(secs),
+                expires_at,
+                60,
+                &last_reminder,
+            )
+        };
+        assert_eq!(at(45), None);
+        assert_eq!(at(90), Some(Duration::from_secs(60)));
+        assert_eq!(at(135), None);
+        assert_eq!(at(150), None);
+
+        let never = AtomicU64::new(REQUESTED_AT.as_secs());
+        assert!((1..=30).all(|secs| {
+            reminder(
+                REQUESTED_AT + Duration::from_secs(secs * 5),
+                expires_at,
+                0,
+                &never,
+            )
+            .is_none()
+        }));
+    }
+}
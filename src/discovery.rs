@@ -0,0 +1,123 @@
+// 3rd party crates
+use http::{HeaderMap, Method};
+use oauth2::{url::Url, HttpRequest};
+use serde::Deserialize;
+
+// My crates
+use crate::cloud::Authority;
+use crate::curl::HttpClient;
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The login endpoints of a tenant, as listed in its OpenID discovery document.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Endpoints {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    /// Not listed by every provider, the default one is used then.
+    pub device_authorization_endpoint: Option<String>,
+}
+
+/// The URL of the OpenID discovery document of the tenant, the v1 one with
+/// `--oauth-version v1`.
+pub fn discovery_url(authority: &Authority) -> String {
+    format!(
+        "https://{}/{}/{}.well-known/openid-configuration",
+        authority.cloud.login_host(),
+        authority.tenant,
+        authority.oauth_version.path()
+    )
+}
+
+/// Reads the login endpoints of the tenant from its OpenID discovery document, once per
+/// run with `--discover-endpoints`.
+pub async fn discover(
+    authority: &Authority,
+    http_client: &dyn HttpClient,
+) -> OAuth2Result<Endpoints> {
+    let request = HttpRequest {
+        url: Url::parse(&discovery_url(authority))?,
+        method: Method::GET,
+        headers: HeaderMap::new(),
+        body: Vec::new(),
+    };
+    log::debug!("Discovery document: {}", request.url);
+    let response = http_client.send(request).await?;
+    if !response.status_code.is_success() {
+        return Err(OAuth2Error::new(
+            ErrorCodes::HttpError,
+            format!(
+                "The discovery document request failed with {}, check the tenant.",
+                response.status_code
+            ),
+        ));
+    }
+    let endpoints: Endpoints = serde_json::from_slice(&response.body)?;
+    log::debug!("Discovered endpoints: {endpoints:?}");
+    Ok(endpoints)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use super::{discover, discovery_url, Endpoints};
+    use crate::cloud::{Authority, Cloud};
+    use crate::curl::mock::MockHttpClient;
+    use crate::error::ErrorCodes;
+
+    const DISCOVERY_DOCUMENT: &str = r#"{
+        "token_endpoint": "https://login.microsoftonline.us/contoso/oauth2/v2.0/token",
+        "token_endpoint_auth_methods_supported": ["client_secret_post", "private_key_jwt"],
+        "issuer": "https://login.microsoftonline.us/contoso/v2.0",
+        "authorization_endpoint": "https://login.microsoftonline.us/contoso/oauth2/v2.0/authorize",
+        "device_authorization_endpoint": "https://login.microsoftonline.us/contoso/oauth2/v2.0/devicecode",
+        "scopes_supported": ["openid", "profile", "email", "offline_access"]
+    }"#;
+
+    #[tokio::test]
+    async fn test_discover_endpoints() {
+        let authority = Authority::new(Cloud::GccHigh, "contoso");
+        let http_client = MockHttpClient::new(vec![MockHttpClient::response(
+            StatusCode::OK,
+            DISCOVERY_DOCUMENT,
+        )]);
+
+        let endpoints = discover(&authority, &http_client).await.unwrap();
+        assert_eq!(
+            endpoints,
+            Endpoints {
+                authorization_endpoint:
+                    "https://login.microsoftonline.us/contoso/oauth2/v2.0/authorize".to_string(),
+                token_endpoint: "https://login.microsoftonline.us/contoso/oauth2/v2.0/token"
+                    .to_string(),
+                device_authorization_endpoint: Some(
+                    "https://login.microsoftonline.us/contoso/oauth2/v2.0/devicecode".to_string()
+                ),
+            }
+        );
+        let requests = http_client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url.as_str(), discovery_url(&authority));
+        assert_eq!(
+            discovery_url(&authority),
+            "https://login.microsoftonline.us/contoso/v2.0/.well-known/openid-configuration"
+        );
+
+        let authority = authority.with_endpoints(endpoints);
+        assert_eq!(
+            authority.endpoint("devicecode"),
+            "https://login.microsoftonline.us/contoso/oauth2/v2.0/devicecode"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discovery_of_unknown_tenant() {
+        let authority = Authority::new(Cloud::Commercial, "unknown.example");
+        let http_client = MockHttpClient::new(vec![MockHttpClient::response(
+            StatusCode::BAD_REQUEST,
+            r#"{"error":"invalid_tenant"}"#,
+        )]);
+        let error = discover(&authority, &http_client).await.unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::HttpError);
+    }
+}
@@ -0,0 +1,219 @@
+// 3rd party crates
+use oauth2::AccessToken;
+
+// My crates
+use crate::error::OAuth2Error;
+use crate::token_keeper::TokenKeeper;
+use crate::validate_token;
+
+/// The result of one check of `--first-use-doctor`.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub ok: bool,
+    pub message: String,
+}
+
+impl Finding {
+    fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: message.into(),
+        }
+    }
+
+    fn problem(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: message.into(),
+        }
+    }
+}
+
+/// The hints for the AADSTS errors of a misconfigured app registration.
+const HINTS: &[(&str, &str)] = &[
+    (
+        "AADSTS50011",
+        "The redirect URI http://localhost:8080 is not registered, add it to the app \
+        registration as a Mobile and desktop applications platform.",
+    ),
+    (
+        "AADSTS7000218",
+        "The app registration is confidential, give --client-secret or enable Allow public \
+        client flows in its Authentication settings.",
+    ),
+    (
+        "AADSTS700025",
+        "The app registration is a public client, do not give --client-secret.",
+    ),
+    (
+        "AADSTS700016",
+        "There is no app registration with this client id in the tenant, check --client-id \
+        and --tenant.",
+    ),
+    (
+        "AADSTS65001",
+        "The app has no consent for its scopes, grant admin consent in API permissions.",
+    ),
+    (
+        "AADSTS70011",
+        "A scope is not valid for the app registration, check --scope and --scopes.",
+    ),
+];
+
+/// What the token says about the app registration: a refresh token for
+/// `offline_access`, the `SMTP.Send` scope and the version of the access token. `token`
+/// is the cached token, none for a pre-acquired access token.
+pub fn check_token(access_token: &AccessToken, token: Option<&TokenKeeper>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    match token {
+        Some(token) if token.refresh_token.is_some() => findings.push(Finding::ok(
+            "There is a refresh token, offline_access is granted.",
+        )),
+        Some(_) => findings.push(Finding::problem(
+            "There is no refresh token, add offline_access to the scopes.",
+        )),
+        None => {}
+    }
+
+    let claims = validate_token::claims(access_token.secret()).and_then(Result::ok);
+    let scopes = match token.and_then(TokenKeeper::scopes) {
+        Some(scopes) => Some(scopes.to_vec()),
+        None => claims
+            .as_ref()
+            .and_then(|claims| claims.scp.as_ref())
+            .map(|scp| scp.split_whitespace().map(str::to_string).collect()),
+    };
+    match scopes {
+        Some(scopes)
+            if scopes
+                .iter()
+                .any(|scope| scope.to_ascii_lowercase().ends_with("smtp.send")) =>
+        {
+            findings.push(Finding::ok("The SMTP.Send scope is granted."))
+        }
+        Some(scopes) => findings.push(Finding::problem(format!(
+            "The SMTP.Send scope is not granted, only {}. Add the Office 365 Exchange Online \
+            SMTP.Send permission.",
+            scopes.join(" ")
+        ))),
+        None => findings.push(Finding::problem(
+            "The granted scopes are not known, SMTP.Send cannot be checked.",
+        )),
+    }
+
+    match claims.as_ref().and_then(|claims| claims.ver.as_deref()) {
+        Some("1.0") => findings.push(Finding::ok("The access token is a v1.0 token.")),
+        Some("2.0") => findings.push(Finding::ok("The access token is a v2.0 token.")),
+        Some(ver) => findings.push(Finding::problem(format!(
+            "The access token has the unknown version {ver}."
+        ))),
+        None => findings.push(Finding::problem(
+            "The access token is not a JWT, e.g. of a personal account, its version is not known.",
+        )),
+    }
+    findings
+}
+
+/// The hint for a failed login, when its error is a known misconfiguration of the app
+/// registration.
+pub fn diagnose(error: &OAuth2Error) -> Option<&'static str> {
+    HINTS
+        .iter()
+        .find(|(code, _)| error.error_code_desc.contains(code))
+        .map(|(_, hint)| *hint)
+}
+
+/// Logs the findings, the problems as warnings.
+pub fn log_findings(findings: &[Finding]) {
+    for finding in findings {
+        if finding.ok {
+            log::info!("Doctor: {}", finding.message);
+        } else {
+            log::warn!("Doctor: {}", finding.message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use oauth2::{basic::BasicTokenResponse, AccessToken};
+
+    use super::{check_token, diagnose, Finding};
+    use crate::error::{ErrorCodes, OAuth2Error};
+    use crate::token_keeper::TokenKeeper;
+
+    fn jwt(claims: &str) -> AccessToken {
+        AccessToken::new(format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"RS256"}"#),
+            URL_SAFE_NO_PAD.encode(claims)
+        ))
+    }
+
+    fn token(json: &str) -> TokenKeeper {
+        let token_response: BasicTokenResponse = serde_json::from_str(json).unwrap();
+        TokenKeeper::from(token_response)
+    }
+
+    fn problems(findings: &[Finding]) -> Vec<&str> {
+        findings
+            .iter()
+            .filter(|finding| !finding.ok)
+            .map(|finding| finding.message.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_well_configured_app() {
+        let access_token = jwt(r#"{"ver":"1.0","scp":"SMTP.Send User.Read"}"#);
+        let token = token(
+            r#"{"access_token":"token","token_type":"Bearer","refresh_token":"refresh",
+            "scope":"https://outlook.office.com/SMTP.Send https://outlook.office.com/User.Read"}"#,
+        );
+        let findings = check_token(&access_token, Some(&token));
+        assert_eq!(problems(&findings), Vec::<&str>::new());
+        assert!(findings
+            .iter()
+            .any(|finding| finding.message.contains("v1.0")));
+
+        // A pre-acquired token has no cached token, its scopes are read from the claims.
+        let findings = check_token(&jwt(r#"{"ver":"2.0","scp":"SMTP.Send"}"#), None);
+        assert_eq!(findings.len(), 2);
+        assert!(findings[1].message.contains("v2.0"));
+    }
+
+    #[test]
+    fn test_misconfigured_app() {
+        let access_token = jwt(r#"{"ver":"2.0","scp":"User.Read"}"#);
+        let token = token(r#"{"access_token":"token","token_type":"Bearer"}"#);
+        let findings = check_token(&access_token, Some(&token));
+        let found = problems(&findings);
+        assert_eq!(found.len(), 2, "{found:?}");
+        assert!(found[0].contains("offline_access"));
+        assert!(found[1].contains("only User.Read"));
+
+        let findings = check_token(&AccessToken::new("opaque".to_string()), None);
+        assert_eq!(problems(&findings).len(), 2);
+    }
+
+    #[test]
+    fn test_diagnose_login_errors() {
+        let error =
+            |description: &str| OAuth2Error::new(ErrorCodes::InvalidClient, description.into());
+        assert!(diagnose(&error(
+            "AADSTS7000218: The request body must contain the following parameter: 'client_assertion' or 'client_secret'."
+        ))
+        .unwrap()
+        .contains("--client-secret"));
+        assert!(
+            diagnose(&error("AADSTS50011: The redirect URI does not match."))
+                .unwrap()
+                .contains("redirect URI")
+        );
+        assert_eq!(
+            diagnose(&error("AADSTS50126: Invalid username or password.")),
+            None
+        );
+    }
+}
@@ -39,11 +39,23 @@ pub enum ErrorCodes {
     UrlParseError,
     SerdeJsonParseError,
     IoError,
+    /// A local file, e.g. the token file, could not be read or written.
+    FileError,
     HttpError,
     NoToken,
     RequestError,
     ParseError,
     CurlError,
+    SmtpError,
+    SmtpAuthenticationFailed,
+    SmtpTimeout,
+    /// The connection failed or timed out once the message was handed to the SMTP
+    /// server, which may have sent it.
+    SmtpSendInterrupted,
+    ConsentRevoked,
+    InvalidClientId,
+    TlsRequired,
+    InvalidTokenFile,
     OtherError,
 }
 
@@ -116,9 +128,11 @@ impl From<serde_json::Error> for OAuth2Error {
     }
 }
 
+/// The I/O errors that reach `?` are those of the local files. The SMTP connection and
+/// the probes of the hosts report theirs as [`ErrorCodes::IoError`].
 impl From<std::io::Error> for OAuth2Error {
     fn from(e: std::io::Error) -> Self {
-        OAuth2Error::new(ErrorCodes::IoError, e.to_string())
+        OAuth2Error::new(ErrorCodes::FileError, e.to_string())
     }
 }
 
@@ -137,11 +151,143 @@ impl From<InvalidHeaderValue> for OAuth2Error {
     }
 }
 
+impl From<mail_send::Error> for OAuth2Error {
+    fn from(e: mail_send::Error) -> Self {
+        match e {
+            mail_send::Error::AuthenticationFailed(_) => {
+                OAuth2Error::new(ErrorCodes::SmtpAuthenticationFailed, e.to_string())
+            }
+            mail_send::Error::Io(_) => OAuth2Error::new(ErrorCodes::IoError, e.to_string()),
+            mail_send::Error::MissingStartTls => OAuth2Error::new(
+                ErrorCodes::TlsRequired,
+                "The SMTP server does not offer STARTTLS, the credentials were not sent."
+                    .to_string(),
+            ),
+            _ => OAuth2Error::new(ErrorCodes::SmtpError, e.to_string()),
+        }
+    }
+}
+
 pub type OAuth2Result<T> = Result<T, OAuth2Error>;
 
+/// The exit codes of the process, stable for scripts to tell the failures apart.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(i32)]
+pub enum ExitCode {
+    Success = 0,
+    /// An error that is none of the others, e.g. an unreadable response.
+    OtherError = 1,
+    /// The login, the token or the SMTP authentication was rejected.
+    AuthFailed = 2,
+    /// The SMTP server did not accept the email.
+    SendFailed = 3,
+    /// The arguments, the settings or the token file are not valid.
+    ConfigError = 4,
+    /// The SMTP server did not answer in time.
+    Timeout = 5,
+    /// A connection or an HTTP request failed.
+    NetworkError = 6,
+    /// A local file, e.g. the token file, could not be read or written.
+    FileError = 7,
+}
+
+impl ExitCode {
+    /// The exit code of the result of a run.
+    pub fn of<T>(result: &OAuth2Result<T>) -> Self {
+        let Err(error) = result else {
+            return ExitCode::Success;
+        };
+        match error.error_code {
+            ErrorCodes::BadRequest
+            | ErrorCodes::Unauthorized
+            | ErrorCodes::Forbidden
+            | ErrorCodes::InvalidRequest
+            | ErrorCodes::UnauthorizedClient
+            | ErrorCodes::AccessDenied
+            | ErrorCodes::InvalidScope
+            | ErrorCodes::InvalidClient
+            | ErrorCodes::InvalidGrant
+            | ErrorCodes::UnsupportedTokenType
+            | ErrorCodes::AuthorizationPending
+            | ErrorCodes::AuthorizationDeclined
+            | ErrorCodes::SlowDown
+            | ErrorCodes::ExpiredToken
+            | ErrorCodes::InteractionRequired
+            | ErrorCodes::LoginRequired
+            | ErrorCodes::NoToken
+            | ErrorCodes::SmtpAuthenticationFailed
+            | ErrorCodes::ConsentRevoked => ExitCode::AuthFailed,
+            ErrorCodes::SmtpError | ErrorCodes::SmtpSendInterrupted | ErrorCodes::TlsRequired => {
+                ExitCode::SendFailed
+            }
+            ErrorCodes::UnsupportedResponseType
+            | ErrorCodes::UnsupportedGrantType
+            | ErrorCodes::ConfigurationError
+            | ErrorCodes::UrlParseError
+            | ErrorCodes::InvalidClientId
+            | ErrorCodes::InvalidTokenFile => ExitCode::ConfigError,
+            ErrorCodes::SmtpTimeout => ExitCode::Timeout,
+            ErrorCodes::ServerError
+            | ErrorCodes::TemporarilyUnavailable
+            | ErrorCodes::IoError
+            | ErrorCodes::HttpError
+            | ErrorCodes::RequestError
+            | ErrorCodes::CurlError => ExitCode::NetworkError,
+            ErrorCodes::FileError => ExitCode::FileError,
+            ErrorCodes::SerdeJsonParseError | ErrorCodes::ParseError | ErrorCodes::OtherError => {
+                ExitCode::OtherError
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::ErrorCodes;
+    use super::{ErrorCodes, ExitCode, OAuth2Error, OAuth2Result};
+
+    #[test]
+    fn test_exit_codes() {
+        let exit_code =
+            |error_code| ExitCode::of::<()>(&Err(OAuth2Error::new(error_code, String::new())));
+        assert_eq!(ExitCode::of(&OAuth2Result::Ok(())), ExitCode::Success);
+        assert_eq!(exit_code(ErrorCodes::OtherError), ExitCode::OtherError);
+        assert_eq!(exit_code(ErrorCodes::InvalidGrant), ExitCode::AuthFailed);
+        assert_eq!(
+            exit_code(ErrorCodes::SmtpAuthenticationFailed),
+            ExitCode::AuthFailed
+        );
+        assert_eq!(exit_code(ErrorCodes::SmtpError), ExitCode::SendFailed);
+        assert_eq!(
+            exit_code(ErrorCodes::SmtpSendInterrupted),
+            ExitCode::SendFailed
+        );
+        assert_eq!(
+            exit_code(ErrorCodes::ConfigurationError),
+            ExitCode::ConfigError
+        );
+        assert_eq!(
+            exit_code(ErrorCodes::InvalidTokenFile),
+            ExitCode::ConfigError
+        );
+        assert_eq!(exit_code(ErrorCodes::SmtpTimeout), ExitCode::Timeout);
+        assert_eq!(exit_code(ErrorCodes::IoError), ExitCode::NetworkError);
+        assert_eq!(exit_code(ErrorCodes::CurlError), ExitCode::NetworkError);
+        assert_eq!(exit_code(ErrorCodes::FileError), ExitCode::FileError);
+        assert_eq!(
+            [
+                ExitCode::Success,
+                ExitCode::OtherError,
+                ExitCode::AuthFailed,
+                ExitCode::SendFailed,
+                ExitCode::ConfigError,
+                ExitCode::Timeout,
+                ExitCode::NetworkError,
+                ExitCode::FileError
+            ]
+            .map(|exit_code| exit_code as i32),
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+    }
 
     #[test]
     fn test_error_codes_to_json_snake_case() {
@@ -0,0 +1,90 @@
+// 3rd party crates
+use oauth2::AccessToken;
+use tokio::process::Command;
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The environment variable the access token is handed to the command of `--exec` in.
+pub const ACCESS_TOKEN_VAR: &str = "XOAUTH2_ACCESS_TOKEN";
+
+/// The command line of `--exec`, run by the shell with the access token in
+/// [`ACCESS_TOKEN_VAR`]. The token is not in the arguments, where other users could see
+/// it in the process list.
+fn command(command_line: &str, access_token: &AccessToken) -> Command {
+    #[cfg(windows)]
+    let mut command = {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(command_line);
+        command
+    };
+    #[cfg(not(windows))]
+    let mut command = {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(command_line);
+        command
+    };
+    command.env(ACCESS_TOKEN_VAR, access_token.secret());
+    command
+}
+
+/// Runs the command line of `--exec` with the access token and returns its exit code.
+pub async fn run(command_line: &str, access_token: &AccessToken) -> OAuth2Result<i32> {
+    log::info!("Running {command_line:?} with the access token in ${ACCESS_TOKEN_VAR}.");
+    let status = command(command_line, access_token)
+        .status()
+        .await
+        .map_err(|e| {
+            OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!("Unable to run {command_line:?} of --exec: {e}"),
+            )
+        })?;
+    log::debug!("{command_line:?} exited with {status}");
+    Ok(status.code().unwrap_or_else(|| {
+        log::warn!("{command_line:?} was terminated by a signal.");
+        1
+    }))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use oauth2::AccessToken;
+
+    use super::{command, run};
+
+    #[tokio::test]
+    async fn test_command_gets_the_token_in_its_environment() {
+        let access_token = AccessToken::new("secret-token".to_string());
+        let command_line = "echo \"token=$XOAUTH2_ACCESS_TOKEN\"";
+        let mut command = command(command_line, &access_token);
+        // The token is only in the environment, not in the arguments.
+        assert!(!command
+            .as_std()
+            .get_args()
+            .any(|arg| arg.to_string_lossy().contains("secret-token")));
+
+        let output = command.output().await.unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "token=secret-token\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_is_forwarded() {
+        let access_token = AccessToken::new("secret-token".to_string());
+        assert_eq!(run("true", &access_token).await.unwrap(), 0);
+        assert_eq!(run("exit 3", &access_token).await.unwrap(), 3);
+        assert_eq!(
+            run(
+                "test \"$XOAUTH2_ACCESS_TOKEN\" = secret-token",
+                &access_token
+            )
+            .await
+            .unwrap(),
+            0
+        );
+    }
+}
@@ -1,12 +1,99 @@
-use http::{HeaderMap, HeaderValue};
+use http::{header::ACCEPT_LANGUAGE, HeaderMap, HeaderValue, StatusCode};
 use oauth2::{url::Url, AccessToken, HttpRequest};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use crate::{
-    curl::Curl,
-    error::{OAuth2Error, OAuth2Result},
+    cloud::Cloud,
+    curl::HttpClient,
+    error::{ErrorCodes, OAuth2Error, OAuth2Result},
+    smtp, validate_token,
 };
 
+/// How much of a malformed response body is kept in the error.
+const BODY_SNIPPET_LEN: usize = 200;
+
+/// The errors of a 403 that mean the app may no longer access the mailbox although
+/// the access token is still valid, e.g. because the admin consent was revoked.
+const CONSENT_ERRORS: [&str; 3] = [
+    "ErrorAccessDenied",
+    "Authorization_RequestDenied",
+    "consent_required",
+];
+
+/// The start of a response body for an error, which is not trusted to be valid UTF-8.
+fn snippet(body: &[u8]) -> String {
+    String::from_utf8_lossy(body)
+        .chars()
+        .take(BODY_SNIPPET_LEN)
+        .collect()
+}
+
+/// Whether the error body is one of [`CONSENT_ERRORS`], either as the `error.code` of
+/// the Outlook and Graph APIs or as an OAuth2 `error`, or names AADSTS65001 (no consent).
+fn is_consent_error(body: &[u8]) -> bool {
+    let error = serde_json::from_slice::<Value>(body).ok().and_then(|json| {
+        json["error"]["code"]
+            .as_str()
+            .or(json["error"].as_str())
+            .map(str::to_string)
+    });
+    error.is_some_and(|error| CONSENT_ERRORS.contains(&error.as_str()))
+        || String::from_utf8_lossy(body).contains("AADSTS65001")
+}
+
+/// Turns an unsuccessful response into an error, telling a revoked consent apart from
+/// any other failure.
+fn check_status(status_code: StatusCode, body: &[u8]) -> OAuth2Result<()> {
+    if status_code.is_success() {
+        return Ok(());
+    }
+    let snippet = snippet(body);
+    if status_code == StatusCode::FORBIDDEN && is_consent_error(body) {
+        return Err(OAuth2Error::new(
+            ErrorCodes::ConsentRevoked,
+            format!(
+                "The access token is valid but the access to the sender profile was denied, \
+                the consent to the app registration was probably revoked. Ask an administrator \
+                to grant the consent again, then login again. Response body: {snippet:?}"
+            ),
+        ));
+    }
+    Err(OAuth2Error::new(
+        ErrorCodes::HttpError,
+        format!("The sender profile request failed with {status_code}, response body: {snippet:?}"),
+    ))
+}
+
+/// The display name of the sender, or when it is empty or only whitespace, the
+/// `--default-display-name` or else the local part of the e-mail address.
+fn display_name_or_fallback(
+    display_name: String,
+    email_address: &str,
+    default_display_name: Option<&str>,
+) -> String {
+    if !display_name.trim().is_empty() {
+        return display_name;
+    }
+    let fallback = default_display_name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| {
+            email_address
+                .split_once('@')
+                .map_or(email_address, |(local_part, _)| local_part)
+        });
+    log::warn!("The sender has no display name, using {fallback:?}.");
+    fallback.to_string()
+}
+
+/// The claims of an id_token that name the signed-in user.
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    name: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct SenderProfile {
@@ -22,7 +109,14 @@ pub struct SenderProfile {
 }
 
 impl SenderProfile {
-    pub async fn get_sender_profile(access_token: &AccessToken, curl: Curl) -> OAuth2Result<Self> {
+    /// The GET request for the profile at `endpoint`, or at the profile endpoint of the
+    /// cloud when `--profile-endpoint` is not given, in the `--accept-language`.
+    fn request(
+        access_token: &AccessToken,
+        endpoint: Option<&Url>,
+        cloud: Cloud,
+        accept_language: Option<&str>,
+    ) -> OAuth2Result<HttpRequest> {
         let mut headers = HeaderMap::new();
 
         let header_val = format!("Bearer {}", access_token.secret().as_str());
@@ -30,21 +124,397 @@ impl SenderProfile {
             "Authorization",
             HeaderValue::from_str(&header_val).map_err(OAuth2Error::from)?,
         );
+        if let Some(accept_language) = accept_language {
+            headers.insert(
+                ACCEPT_LANGUAGE,
+                HeaderValue::from_str(accept_language).map_err(OAuth2Error::from)?,
+            );
+        }
 
-        let request = HttpRequest {
-            url: Url::parse("https://outlook.office.com/api/v2.0/me/")?,
+        let url = match endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => Url::parse(&cloud.profile_endpoint())?,
+        };
+        Ok(HttpRequest {
+            url,
             method: http::method::Method::GET,
             headers,
             body: Vec::new(),
-        };
+        })
+    }
 
-        let response = curl.send(request).await?;
+    pub async fn get_sender_profile(
+        access_token: &AccessToken,
+        endpoint: Option<&Url>,
+        cloud: Cloud,
+        accept_language: Option<&str>,
+        http_client: &dyn HttpClient,
+    ) -> OAuth2Result<Self> {
+        let request = Self::request(access_token, endpoint, cloud, accept_language)?;
+        log::debug!("Profile endpoint: {}", request.url);
 
-        let body = String::from_utf8(response.body).unwrap_or_default();
+        let response = http_client.send(request).await?;
+        check_status(response.status_code, &response.body)?;
 
-        let sender_profile: SenderProfile = serde_json::from_str(&body)?;
+        let sender_profile = Self::parse(&response.body)?;
         log::info!("Sender Name: {}", sender_profile.display_name.as_str());
         log::info!("Sender E-mail: {}", sender_profile.email_address.as_str());
         Ok(sender_profile)
     }
+
+    /// The display name and e-mail address of the sender: the `given` ones, when
+    /// `--sender-email` and `--sender-name` are used, without reading the profile.
+    /// An empty display name is replaced as [`display_name_or_fallback`] says.
+    pub async fn get_sender(
+        given: Option<(String, String)>,
+        default_display_name: Option<&str>,
+        access_token: &AccessToken,
+        endpoint: Option<&Url>,
+        cloud: Cloud,
+        accept_language: Option<&str>,
+        http_client: &dyn HttpClient,
+    ) -> OAuth2Result<(String, String)> {
+        let (display_name, email_address) = match given {
+            Some((display_name, email_address)) => {
+                log::info!(
+                    "Skipping the sender profile, sending as {display_name} <{email_address}>"
+                );
+                (display_name, email_address)
+            }
+            None => {
+                let sender_profile = Self::get_sender_profile(
+                    access_token,
+                    endpoint,
+                    cloud,
+                    accept_language,
+                    http_client,
+                )
+                .await?;
+                (sender_profile.display_name, sender_profile.email_address)
+            }
+        };
+        let display_name =
+            display_name_or_fallback(display_name, &email_address, default_display_name);
+        Ok((display_name, email_address))
+    }
+
+    /// The display name and e-mail address of the sender from the claims of the id_token
+    /// of the login: `email`, or else `preferred_username` when it is an e-mail address,
+    /// and `name`. None when there is no e-mail address, the profile is then read instead.
+    pub fn from_id_token(id_token: &str) -> Option<(String, String)> {
+        let claims: IdTokenClaims = match validate_token::decode_jwt(id_token)? {
+            Ok(claims) => claims,
+            Err(e) => {
+                log::warn!("The claims of the id_token are not readable: {e}");
+                return None;
+            }
+        };
+        let Some(email_address) = claims
+            .email
+            .into_iter()
+            .chain(claims.preferred_username)
+            .find_map(|email_address| smtp::parse_email(&email_address).ok())
+        else {
+            log::info!("The id_token has no e-mail address, reading the sender profile.");
+            return None;
+        };
+        log::info!("Using the sender in the id_token claims instead of reading the profile.");
+        Some((claims.name.unwrap_or_default(), email_address))
+    }
+
+    /// Parses the raw response body, which is not trusted to be valid UTF-8.
+    fn parse(body: &[u8]) -> OAuth2Result<Self> {
+        log::debug!("Profile response: {}", String::from_utf8_lossy(body));
+        serde_json::from_slice(body).map_err(|e| {
+            OAuth2Error::new(
+                ErrorCodes::SerdeJsonParseError,
+                format!(
+                    "Unable to parse the sender profile: {e}, response body: {:?}",
+                    snippet(body)
+                ),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use http::StatusCode;
+    use oauth2::{url::Url, AccessToken};
+
+    use super::{check_status, display_name_or_fallback, SenderProfile};
+    use crate::cloud::Cloud;
+    use crate::curl::mock::MockHttpClient;
+    use crate::error::ErrorCodes;
+
+    #[tokio::test]
+    async fn test_given_sender_skips_the_profile() {
+        let access_token = AccessToken::new("token".to_string());
+        // No response is given, the profile request would fail.
+        let http_client = MockHttpClient::default();
+
+        let sender = SenderProfile::get_sender(
+            Some(("Shared".to_string(), "shared@example.com".to_string())),
+            None,
+            &access_token,
+            None,
+            Cloud::Commercial,
+            None,
+            &http_client,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            sender,
+            ("Shared".to_string(), "shared@example.com".to_string())
+        );
+        assert!(http_client.requests().is_empty());
+
+        assert!(SenderProfile::get_sender(
+            None,
+            None,
+            &access_token,
+            None,
+            Cloud::Commercial,
+            None,
+            &http_client
+        )
+        .await
+        .is_err());
+        assert_eq!(http_client.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_sender_profile() {
+        let http_client = MockHttpClient::new(vec![MockHttpClient::response(
+            StatusCode::OK,
+            r#"{
+                "@odata.context": "context",
+                "@odata.id": "id",
+                "Id": "id",
+                "EmailAddress": "sender@example.com",
+                "DisplayName": "",
+                "Alias": "sender",
+                "MailboxGuid": "guid"
+            }"#,
+        )]);
+        let access_token = AccessToken::new("token".to_string());
+        let endpoint = Url::parse("https://graph.microsoft.com/v1.0/me").unwrap();
+
+        let sender = SenderProfile::get_sender(
+            None,
+            None,
+            &access_token,
+            Some(&endpoint),
+            Cloud::Commercial,
+            None,
+            &http_client,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            sender,
+            ("sender".to_string(), "sender@example.com".to_string())
+        );
+
+        let requests = http_client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url, endpoint);
+        assert_eq!(requests[0].headers["Authorization"], "Bearer token");
+        assert!(!requests[0].headers.contains_key("Accept-Language"));
+    }
+
+    #[tokio::test]
+    async fn test_accept_language() {
+        let access_token = AccessToken::new("token".to_string());
+        let http_client = MockHttpClient::default();
+        assert!(SenderProfile::get_sender(
+            None,
+            None,
+            &access_token,
+            None,
+            Cloud::Commercial,
+            Some("de-DE"),
+            &http_client,
+        )
+        .await
+        .is_err());
+
+        let requests = http_client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].headers["Accept-Language"], "de-DE");
+    }
+
+    #[tokio::test]
+    async fn test_get_sender_profile_revoked_consent() {
+        let http_client = MockHttpClient::new(vec![MockHttpClient::response(
+            StatusCode::FORBIDDEN,
+            r#"{"error":{"code":"ErrorAccessDenied","message":"Access is denied."}}"#,
+        )]);
+        let access_token = AccessToken::new("token".to_string());
+        let error = SenderProfile::get_sender_profile(
+            &access_token,
+            None,
+            Cloud::Commercial,
+            None,
+            &http_client,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConsentRevoked);
+    }
+
+    #[tokio::test]
+    async fn test_given_sender_without_a_name() {
+        let access_token = AccessToken::new("token".to_string());
+        let sender = SenderProfile::get_sender(
+            Some((" ".to_string(), "shared@example.com".to_string())),
+            None,
+            &access_token,
+            None,
+            Cloud::Commercial,
+            None,
+            &MockHttpClient::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(sender.0, "shared");
+    }
+
+    fn id_token(claims: &str) -> String {
+        format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"RS256"}"#),
+            URL_SAFE_NO_PAD.encode(claims)
+        )
+    }
+
+    #[test]
+    fn test_sender_from_id_token() {
+        let sender = SenderProfile::from_id_token(&id_token(
+            r#"{"name":"Jane Doe","preferred_username":"jane@contoso.com","email":"jane.doe@contoso.com"}"#,
+        ));
+        assert_eq!(
+            sender,
+            Some(("Jane Doe".to_string(), "jane.doe@contoso.com".to_string()))
+        );
+
+        // Without a name, the fallback of get_sender applies.
+        let sender =
+            SenderProfile::from_id_token(&id_token(r#"{"preferred_username":"jane@contoso.com"}"#));
+        assert_eq!(
+            sender,
+            Some((String::new(), "jane@contoso.com".to_string()))
+        );
+
+        // A user name that is not an e-mail address falls back to the profile request.
+        assert_eq!(
+            SenderProfile::from_id_token(&id_token(
+                r#"{"name":"Jane Doe","preferred_username":"CONTOSO\\jane"}"#
+            )),
+            None
+        );
+        assert_eq!(SenderProfile::from_id_token("opaque"), None);
+    }
+
+    #[test]
+    fn test_display_name_fallback() {
+        let email = "jane.doe@example.com";
+        assert_eq!(
+            display_name_or_fallback("Jane Doe".to_string(), email, Some("Test Sender")),
+            "Jane Doe"
+        );
+        assert_eq!(
+            display_name_or_fallback(String::new(), email, None),
+            "jane.doe"
+        );
+        assert_eq!(
+            display_name_or_fallback(" \t".to_string(), email, None),
+            "jane.doe"
+        );
+        assert_eq!(
+            display_name_or_fallback(" ".to_string(), email, Some("Test Sender")),
+            "Test Sender"
+        );
+        assert_eq!(
+            display_name_or_fallback(String::new(), email, Some("  ")),
+            "jane.doe"
+        );
+    }
+
+    #[test]
+    fn test_profile_endpoint_override() {
+        let access_token = AccessToken::new("token".to_string());
+        let endpoint = Url::parse("https://graph.microsoft.com/v1.0/me").unwrap();
+
+        let request =
+            SenderProfile::request(&access_token, Some(&endpoint), Cloud::Commercial, None)
+                .unwrap();
+        assert_eq!(request.url, endpoint);
+        assert_eq!(request.method, http::method::Method::GET);
+        assert_eq!(request.headers["Authorization"], "Bearer token");
+
+        let request = SenderProfile::request(&access_token, None, Cloud::Commercial, None).unwrap();
+        assert_eq!(
+            request.url.as_str(),
+            "https://outlook.office.com/api/v2.0/me/"
+        );
+        let request = SenderProfile::request(&access_token, None, Cloud::GccHigh, None).unwrap();
+        assert_eq!(
+            request.url.as_str(),
+            "https://outlook.office365.us/api/v2.0/me/"
+        );
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        let body = br#"{
+            "@odata.context": "context",
+            "@odata.id": "id",
+            "Id": "id",
+            "EmailAddress": "sender@example.com",
+            "DisplayName": "Sender",
+            "Alias": "sender",
+            "MailboxGuid": "guid"
+        }"#;
+        let sender_profile = SenderProfile::parse(body).unwrap();
+        assert_eq!(sender_profile.email_address, "sender@example.com");
+        assert_eq!(sender_profile.display_name, "Sender");
+    }
+
+    #[test]
+    fn test_parse_invalid_utf8() {
+        let error = SenderProfile::parse(b"<html>\xff\xfe Bad Gateway</html>").unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::SerdeJsonParseError);
+        assert!(error
+            .error_code_desc
+            .contains("<html>\u{fffd}\u{fffd} Bad Gateway</html>"));
+    }
+
+    #[test]
+    fn test_revoked_consent_on_profile() {
+        let body = br#"{"error":{"code":"ErrorAccessDenied","message":"Access is denied. Check credentials and try again."}}"#;
+        let error = check_status(StatusCode::FORBIDDEN, body).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConsentRevoked);
+        assert!(error.error_code_desc.contains("consent"));
+        assert!(error.error_code_desc.contains("ErrorAccessDenied"));
+
+        let body = br#"{"error":"invalid_grant","error_description":"AADSTS65001: The user or administrator has not consented to use the application."}"#;
+        let error = check_status(StatusCode::FORBIDDEN, body).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConsentRevoked);
+    }
+
+    #[test]
+    fn test_other_profile_failures() {
+        let body = br#"{"error":{"code":"ErrorAccessDenied","message":"Access is denied."}}"#;
+        let error = check_status(StatusCode::UNAUTHORIZED, body).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::HttpError);
+        assert!(error.error_code_desc.contains("401 Unauthorized"));
+
+        let error = check_status(StatusCode::FORBIDDEN, b"Forbidden").unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::HttpError);
+
+        assert!(check_status(StatusCode::OK, b"{}").is_ok());
+    }
 }
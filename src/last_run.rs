@@ -0,0 +1,69 @@
+// Standard libraries
+use std::{fs, io::ErrorKind, path::Path};
+
+// 3rd party crates
+use serde::{Deserialize, Serialize};
+
+// My crates
+use crate::error::OAuth2Result;
+use crate::OAuth2TokenGrantFlow;
+
+const LAST_RUN_FILE: &str = "last_run.json";
+
+/// The settings of the last successful run, reused with `--resume`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LastRun {
+    pub grant_type: OAuth2TokenGrantFlow,
+    pub client_id: String,
+    pub tenant: String,
+    pub recipient_email: String,
+    pub recipient_name: String,
+}
+
+impl LastRun {
+    pub fn load(directory: &Path) -> OAuth2Result<Option<Self>> {
+        match fs::read_to_string(directory.join(LAST_RUN_FILE)) {
+            Ok(text) => Ok(Some(serde_json::from_str(&text)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, directory: &Path) -> OAuth2Result<()> {
+        let json = serde_json::to_string(self)?;
+        Ok(fs::write(directory.join(LAST_RUN_FILE), json)?)
+    }
+
+    pub fn forget(directory: &Path) -> OAuth2Result<()> {
+        match fs::remove_file(directory.join(LAST_RUN_FILE)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LastRun;
+    use crate::OAuth2TokenGrantFlow;
+
+    #[test]
+    fn test_save_load_forget() {
+        let directory = tempfile::tempdir().unwrap();
+        assert_eq!(LastRun::load(directory.path()).unwrap(), None);
+
+        let last_run = LastRun {
+            grant_type: OAuth2TokenGrantFlow::DeviceCodeFlow,
+            client_id: "client".to_string(),
+            tenant: "contoso.onmicrosoft.com".to_string(),
+            recipient_email: "recipient@example.com".to_string(),
+            recipient_name: "Recipient".to_string(),
+        };
+        last_run.save(directory.path()).unwrap();
+        assert_eq!(LastRun::load(directory.path()).unwrap(), Some(last_run));
+
+        LastRun::forget(directory.path()).unwrap();
+        assert_eq!(LastRun::load(directory.path()).unwrap(), None);
+        LastRun::forget(directory.path()).unwrap();
+    }
+}
@@ -1,146 +1,1036 @@
+mod accounts;
 mod auth_code_grant;
+mod backoff;
+mod benchmark;
+mod cleanup;
+mod cli;
+mod cloud;
+mod color;
+mod config;
 mod curl;
 mod device_code_flow;
+mod discovery;
+mod doctor;
 mod error;
+mod exec;
 mod get_profile;
+mod last_run;
+mod manifest;
+mod message;
+mod metrics;
+mod mime;
+mod net;
+mod phrase;
+mod plan;
+mod preflight;
+mod qr;
+mod rate_limit;
+mod redact;
+mod report;
+mod smtp;
+mod socks5;
+mod status;
+mod telemetry;
+mod tls;
+mod token_encryption;
+mod token_export;
 mod token_keeper;
+mod transcript;
+mod validate_token;
 
 // Standard libraries
-use std::env;
-use std::io::Write;
-use std::str::FromStr;
+use std::cell::Cell;
+use std::ffi::OsString;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
 
 // 3rd party crates
 use chrono::Local;
+use clap::Parser;
 use log::LevelFilter;
-use mail_send::{mail_builder::MessageBuilder, Credentials, SmtpClientBuilder};
-use oauth2::ClientSecret;
+use oauth2::{AccessToken, ClientSecret};
+use serde::{Deserialize, Serialize};
 use strum_macros::EnumString;
 
 // My crates
-use crate::auth_code_grant::auth_code_grant;
+use crate::accounts::Account;
+use crate::auth_code_grant::{auth_code_grant, refresh_auth_code_grant};
+use crate::backoff::Backoff;
+use crate::benchmark::HandshakeStats;
+use crate::cli::{
+    BenchmarkConnectArgs, Cli, Client, Command, ExchangeArgs, GlobalArgs, LoginArgs, PollArgs,
+    RefreshArgs, RunArgs, StatusArgs, ValidateTokenArgs,
+};
+use crate::cloud::{Authority, OAuthVersion};
+use crate::color::ColorChoice;
 use crate::curl::Curl;
-use crate::device_code_flow::device_code_flow;
+use crate::device_code_flow::{device_code_flow, refresh_device_code_flow};
 use crate::get_profile::SenderProfile;
-use error::OAuth2Result;
+use crate::last_run::LastRun;
+use crate::plan::Plan;
+use crate::preflight::Target;
+use crate::rate_limit::{RateLimiter, RATE_LIMIT_FILE};
+use crate::report::{ByteCounter, LogEvents, Outcome, SendReport, Stage, TransferStats};
+use crate::smtp::SmtpAuthMode;
+use crate::token_export::ExportedToken;
+use error::{ErrorCodes, ExitCode, OAuth2Error, OAuth2Result};
 use token_keeper::TokenKeeper;
 
-enum ParamIndex {
-    TokenGrantType = 1,
-    ClientId,
-    ClientSecret,
-    RecipientEmail,
-    RecipientName,
-    DebugLevel,
-}
-
-#[derive(EnumString)]
+#[derive(Clone, Debug, PartialEq, EnumString, Serialize, Deserialize)]
 enum OAuth2TokenGrantFlow {
     AuthorizationCodeGrant,
     DeviceCodeFlow,
+    /// An access token given with `--access-token`, which is neither cached nor refreshed.
+    PreAcquired,
 }
 
-impl From<String> for OAuth2TokenGrantFlow {
-    fn from(str: String) -> Self {
-        OAuth2TokenGrantFlow::from_str(str.as_str()).unwrap()
-    }
+/// The error for a pre-acquired access token that had to be renewed.
+fn pre_acquired_error() -> OAuth2Error {
+    OAuth2Error::new(
+        ErrorCodes::ConfigurationError,
+        "A pre-acquired access token cannot be renewed or refreshed, get a new one.".into(),
+    )
 }
 
-fn init_logger(level: &str) {
-    //env_logger::Builder::from_env(Env::default().default_filter_or(level)).init();
-    let mut log_builder = env_logger::Builder::new();
-    log_builder.format(|buf, record| {
-        let mut module = "";
-        if let Some(path) = record.module_path() {
-            if let Some(split) = path.split("::").last() {
-                module = split;
+impl OAuth2TokenGrantFlow {
+    /// The cached token file, none for a pre-acquired access token.
+    fn token_file(&self, client_id: &str) -> Option<PathBuf> {
+        match self {
+            OAuth2TokenGrantFlow::AuthorizationCodeGrant => {
+                Some(auth_code_grant::token_file(client_id))
+            }
+            OAuth2TokenGrantFlow::DeviceCodeFlow => Some(device_code_flow::token_file(client_id)),
+            OAuth2TokenGrantFlow::PreAcquired => None,
+        }
+    }
+
+    async fn get_access_token(
+        &self,
+        client_id: &str,
+        client_secret: Option<ClientSecret>,
+        authority: &Authority,
+        directory: &Path,
+        curl: Curl,
+        login: LoginArgs,
+    ) -> OAuth2Result<AccessToken> {
+        // With --force-refresh, the cached token is refreshed by the login even if it has
+        // not expired yet.
+        let cached_file = self.token_file(client_id);
+        let (token_file, token_json) = if login.force_refresh {
+            (None, None)
+        } else {
+            (
+                cached_file.clone(),
+                std::env::var(token_keeper::TOKEN_JSON_ENV).ok(),
+            )
+        };
+        let required_scopes = login
+            .fail_fast_on_scope_downgrade
+            .then(|| authority.scopes());
+        token_keeper::cached_or_login(
+            directory,
+            token_file.as_deref(),
+            token_json.as_deref(),
+            required_scopes.as_deref(),
+            || {
+                token_keeper::login_or_relogin(
+                    directory,
+                    cached_file.as_deref(),
+                    login.on_refresh_failure,
+                    |relogin| {
+                        // The token file is gone for the second login, there is nothing
+                        // to refresh then.
+                        let login = LoginArgs {
+                            force_refresh: login.force_refresh && !relogin,
+                            ..login
+                        };
+                        let (client_secret, curl) = (client_secret.clone(), curl.clone());
+                        async move {
+                            match self {
+                                OAuth2TokenGrantFlow::AuthorizationCodeGrant => {
+                                    auth_code_grant(
+                                        client_id,
+                                        client_secret,
+                                        authority,
+                                        directory,
+                                        curl,
+                                        login,
+                                    )
+                                    .await
+                                }
+                                OAuth2TokenGrantFlow::DeviceCodeFlow => {
+                                    device_code_flow(
+                                        client_id,
+                                        client_secret,
+                                        authority,
+                                        directory,
+                                        curl,
+                                        login,
+                                    )
+                                    .await
+                                }
+                                OAuth2TokenGrantFlow::PreAcquired => Err(pre_acquired_error()),
+                            }
+                        }
+                    },
+                )
+            },
+        )
+        .await
+    }
+
+    async fn refresh_access_token(
+        &self,
+        client_id: &str,
+        client_secret: Option<ClientSecret>,
+        authority: &Authority,
+        directory: &Path,
+        curl: Curl,
+    ) -> OAuth2Result<TokenKeeper> {
+        match self {
+            OAuth2TokenGrantFlow::AuthorizationCodeGrant => {
+                refresh_auth_code_grant(client_id, client_secret, authority, directory, curl).await
             }
+            OAuth2TokenGrantFlow::DeviceCodeFlow => {
+                refresh_device_code_flow(client_id, client_secret, authority, directory, curl).await
+            }
+            OAuth2TokenGrantFlow::PreAcquired => Err(pre_acquired_error()),
         }
+    }
+}
 
+fn init_logger(level: LevelFilter, color: ColorChoice, run_id: &str) {
+    //env_logger::Builder::from_env(Env::default().default_filter_or(level)).init();
+    let color = color.enabled(std::io::stderr().is_terminal(), color::no_color_env());
+    let run_id = run_id.to_string();
+    let mut log_builder = env_logger::Builder::new();
+    log_builder.format(move |buf, record| {
         writeln!(
             buf,
-            "{}[{}]:{}: {}",
-            Local::now().format("[%d-%m-%Y %H:%M:%S]"),
-            record.level(),
-            module,
-            record.args()
+            "{}",
+            color::log_line(
+                &Local::now().format("[%d-%m-%Y %H:%M:%S]").to_string(),
+                &run_id,
+                &color::level_tag(record.level(), color),
+                record.module_path(),
+                record.args(),
+            )
         )
     });
 
-    log_builder.filter_level(LevelFilter::from_str(level).unwrap_or(LevelFilter::Info));
+    log_builder.filter_level(level);
     if let Err(e) = log_builder.try_init() {
         log::error!("{:?}", e);
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> OAuth2Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let client_secret = match args[ParamIndex::ClientSecret as usize].as_str() {
-        "None" => None,
-        _ => Some(ClientSecret::new(
-            args[ParamIndex::ClientSecret as usize].to_string(),
-        )),
-    };
-    let client_id = &args[ParamIndex::ClientId as usize];
-    let receiver_email = &args[ParamIndex::RecipientEmail as usize];
-    let receiver_name = &args[ParamIndex::RecipientName as usize];
-    if args.len() <= (ParamIndex::DebugLevel as usize) {
-        init_logger("info");
+/// The rate limit of `--max-requests-per-minute`, shared by all the requests of the run
+/// and kept in the token directory for the next runs.
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// The HTTP client for the token and profile requests.
+fn curl(global: &GlobalArgs) -> Curl {
+    let rate_limiter = global.max_requests_per_minute.map(|per_minute| {
+        RATE_LIMITER
+            .get_or_init(|| {
+                let state_file = token_keeper::token_directory(global.token_dir.as_deref())
+                    .ok()
+                    .map(|directory| directory.join(RATE_LIMIT_FILE));
+                RateLimiter::new(per_minute).state_file(state_file)
+            })
+            .clone()
+    });
+    Curl::new()
+        .ip_version(global.ip_version)
+        .http1_only(global.http1_only)
+        .max_redirects(global.max_redirects)
+        .tls(global.tls())
+        .socks5(global.socks5.clone())
+        .rate_limiter(rate_limiter)
+}
+
+/// Where to login, asking for the scopes given with `--scope` or `--scopes` and with
+/// the authorize URL parameters of `--authorize-param`, at the endpoints of
+/// `--oauth-version`.
+fn authority(global: &GlobalArgs, tenant: &str) -> Authority {
+    if global.resource.is_some() && global.oauth_version == OAuthVersion::V2 {
+        log::warn!("--resource is only asked for with --oauth-version v1, it is ignored.");
+    }
+    Authority::new(global.cloud, tenant)
+        .with_scopes(global.scopes())
+        .with_authorize_params(global.authorize_param.clone())
+        .with_oauth_version(global.oauth_version)
+        .with_resource(global.resource.clone())
+}
+
+/// The authority with the endpoints of its discovery document, with `--discover-endpoints`.
+async fn discover_endpoints(authority: Authority, global: &GlobalArgs) -> OAuth2Result<Authority> {
+    if !global.discover_endpoints {
+        return Ok(authority);
+    }
+    let endpoints = discovery::discover(&authority, &curl(global)).await?;
+    Ok(authority.with_endpoints(endpoints))
+}
+
+/// Clears the saved settings with `--forget`, otherwise loads them for `--resume`.
+fn last_run(resume: bool, global: &GlobalArgs, directory: &Path) -> OAuth2Result<Option<LastRun>> {
+    if global.forget {
+        LastRun::forget(directory)?;
+        log::info!("The saved settings were cleared.");
+    }
+    if resume {
+        LastRun::load(directory)
     } else {
-        init_logger(args[ParamIndex::DebugLevel as usize].as_str());
+        Ok(None)
     }
+}
 
-    let curl = Curl::new();
-    let access_token =
-        match OAuth2TokenGrantFlow::from(args[ParamIndex::TokenGrantType as usize].to_string()) {
-            OAuth2TokenGrantFlow::AuthorizationCodeGrant => {
-                auth_code_grant(client_id, client_secret, curl.clone()).await?
+async fn refresh_client(
+    client: Client,
+    args: &RefreshArgs,
+    global: &GlobalArgs,
+    directory: &Path,
+) -> OAuth2Result<()> {
+    cli::check_client_id(&client.client_id, global.allow_non_guid_client_id)?;
+    let token_keeper = client
+        .grant_type
+        .refresh_access_token(
+            &client.client_id,
+            client.client_secret,
+            &discover_endpoints(authority(global, &client.tenant), global).await?,
+            directory,
+            curl(global),
+        )
+        .await?;
+    log::info!("Access token successfuly refreshed and saved.");
+
+    if args.print_token {
+        println!("{}", token_keeper.access_token.secret());
+    }
+    Ok(())
+}
+
+async fn refresh(args: RefreshArgs, global: &GlobalArgs) -> OAuth2Result<()> {
+    let directory = token_keeper::token_directory(global.token_dir.as_deref())?;
+    if let Some(accounts_file) = args.client.accounts_file.as_deref() {
+        let accounts = Account::load(accounts_file)?;
+        let results = accounts::run_accounts(&accounts, |account| {
+            let directory = account.token_directory(&directory);
+            let args = &args;
+            async move { refresh_client(account.client(), args, global, &directory).await }
+        })
+        .await;
+        return accounts::report(&results);
+    }
+
+    let last_run = last_run(args.client.resume, global, &directory)?;
+    let client = args.client.resolve(last_run.as_ref())?;
+    refresh_client(client, &args, global, &directory).await
+}
+
+async fn status_client(
+    client: Client,
+    args: &StatusArgs,
+    global: &GlobalArgs,
+    directory: &Path,
+) -> OAuth2Result<()> {
+    cli::check_client_id(&client.client_id, global.allow_non_guid_client_id)?;
+    let token_file = client
+        .grant_type
+        .token_file(&client.client_id)
+        .ok_or_else(|| {
+            OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                "Only the AuthorizationCodeGrant and DeviceCodeFlow tokens are cached.".into(),
+            )
+        })?;
+    let client = &client;
+    let (mut status, token) = status::check(
+        directory,
+        &token_file,
+        args.refresh_if_needed,
+        || async move {
+            client
+                .grant_type
+                .refresh_access_token(
+                    &client.client_id,
+                    client.client_secret.clone(),
+                    &discover_endpoints(authority(global, &client.tenant), global).await?,
+                    directory,
+                    curl(global),
+                )
+                .await
+        },
+    )
+    .await?;
+    if args.with_profile && status.valid {
+        let profile = SenderProfile::get_sender_profile(
+            &token.access_token,
+            None,
+            global.cloud,
+            None,
+            &curl(global),
+        )
+        .await?;
+        status.sender = Some(profile.email_address);
+    }
+    status.print(global.output)?;
+
+    if !status.valid {
+        return Err(OAuth2Error::new(
+            ErrorCodes::ExpiredToken,
+            "The cached access token has expired.".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Reports on the cached token without sending, contacting the network only to refresh
+/// it with `--refresh-if-needed` or to get the profile with `--with-profile`.
+async fn status(args: StatusArgs, global: &GlobalArgs) -> OAuth2Result<()> {
+    let directory = token_keeper::token_directory(global.token_dir.as_deref())?;
+    if let Some(accounts_file) = args.client.accounts_file.as_deref() {
+        let accounts = Account::load(accounts_file)?;
+        let results = accounts::run_accounts(&accounts, |account| {
+            let directory = account.token_directory(&directory);
+            let args = &args;
+            async move { status_client(account.client(), args, global, &directory).await }
+        })
+        .await;
+        return accounts::report(&results);
+    }
+
+    let last_run = last_run(args.client.resume, global, &directory)?;
+    let client = args.client.resolve(last_run.as_ref())?;
+    status_client(client, &args, global, &directory).await
+}
+
+/// The scripted login of the authorization code grant, where printing the authorize URL
+/// and exchanging the code are two separate runs.
+async fn exchange(args: ExchangeArgs, global: &GlobalArgs) -> OAuth2Result<()> {
+    cli::check_client_id(&args.client_id, global.allow_non_guid_client_id)?;
+    let directory = token_keeper::token_directory(global.token_dir.as_deref())?;
+    let client_secret = cli::client_secret(args.client_secret.as_deref());
+    match args.code.as_deref() {
+        None => {
+            auth_code_grant::print_authorize_url(
+                &args.client_id,
+                client_secret,
+                &discover_endpoints(authority(global, &args.tenant), global).await?,
+                &directory,
+                global.output,
+            )
+            .await
+        }
+        Some(code) => {
+            auth_code_grant::exchange_code(
+                &args.client_id,
+                client_secret,
+                &directory,
+                code,
+                curl(global),
+            )
+            .await?;
+            log::info!("Access token successfuly saved, the next run with this client id uses it.");
+            Ok(())
+        }
+    }
+}
+
+/// The scripted login of the device code flow, where requesting the code and polling
+/// for the access token are two separate runs.
+async fn poll(args: PollArgs, global: &GlobalArgs) -> OAuth2Result<()> {
+    cli::check_client_id(&args.client_id, global.allow_non_guid_client_id)?;
+    let directory = token_keeper::token_directory(global.token_dir.as_deref())?;
+    let client_secret = cli::client_secret(args.client_secret.as_deref());
+    if args.request_only {
+        return device_code_flow::request_login(
+            &args.client_id,
+            client_secret,
+            &discover_endpoints(authority(global, &args.tenant), global).await?,
+            &directory,
+            curl(global),
+            args.qr,
+            global.output,
+        )
+        .await;
+    }
+    device_code_flow::poll_login(
+        &args.client_id,
+        client_secret,
+        &directory,
+        curl(global),
+        args.poll_limits,
+    )
+    .await?;
+    log::info!("Access token successfuly saved, the next run with this client id uses it.");
+    Ok(())
+}
+
+/// Times the handshakes with the SMTP server of the cloud, without a login, and prints
+/// their latency statistics.
+async fn benchmark_connect(args: BenchmarkConnectArgs, global: &GlobalArgs) -> OAuth2Result<()> {
+    let latencies = smtp::benchmark_connect(args.count, &args.smtp, &global.smtp_route()).await?;
+    if let Some(stats) = HandshakeStats::of(&latencies) {
+        stats.print(global.output)?;
+    }
+    Ok(())
+}
+
+/// Checks the integrity of a token file, without contacting the network.
+fn validate_token(args: ValidateTokenArgs, global: &GlobalArgs) -> OAuth2Result<()> {
+    let path = match (args.token_file, args.grant_type) {
+        (Some(token_file), _) => token_file,
+        (None, grant_type) => {
+            let client_id = args.client_id.as_deref().unwrap_or_default();
+            let token_file = grant_type
+                .as_ref()
+                .and_then(|grant_type| grant_type.token_file(client_id))
+                .ok_or_else(|| {
+                    OAuth2Error::new(
+                        ErrorCodes::ConfigurationError,
+                        "Only the AuthorizationCodeGrant and DeviceCodeFlow tokens are cached."
+                            .into(),
+                    )
+                })?;
+            token_keeper::token_directory(global.token_dir.as_deref())?.join(token_file)
+        }
+    };
+    validate_token::validate_token_file(
+        &path,
+        &validate_token::Expected {
+            client_id: args.client_id.as_deref(),
+            tenant_id: args.tenant_id.as_deref(),
+            cloud: global.cloud,
+        },
+    )
+}
+
+/// Logs in to get the access token, unless it was given with `--access-token` or
+/// `--access-token-file`.
+async fn login(
+    client: &Client,
+    authority: Authority,
+    login: LoginArgs,
+    global: &GlobalArgs,
+    directory: &Path,
+    curl: Curl,
+) -> OAuth2Result<(Authority, AccessToken)> {
+    if let Some(access_token) = &client.access_token {
+        return Ok((authority, access_token.clone()));
+    }
+    cli::check_client_id(&client.client_id, global.allow_non_guid_client_id)?;
+    let authority = discover_endpoints(authority, global).await?;
+    let access_token = client
+        .grant_type
+        .get_access_token(
+            &client.client_id,
+            client.client_secret.clone(),
+            &authority,
+            directory,
+            curl,
+            login,
+        )
+        .await?;
+    Ok((authority, access_token))
+}
+
+/// Logs in, looks up the sender and sends the test email to the recipients, or to the
+/// sender without any, reporting how far it got.
+async fn send_test_email(
+    client: &Client,
+    recipients: &[(&str, &str)],
+    args: &RunArgs,
+    global: &GlobalArgs,
+    directory: &Path,
+) -> SendReport {
+    let client_secret = client.client_secret.clone();
+    let client_id = &client.client_id;
+    let grant_type = &client.grant_type;
+    let authority = authority(global, &client.tenant);
+    let mut report = SendReport::new(
+        global.run_id.clone(),
+        grant_type.clone(),
+        recipients
+            .iter()
+            .map(|(receiver_email, _)| receiver_email.to_string())
+            .collect(),
+        smtp::transport(&args.smtp, global.cloud),
+    )
+    .with_events(Box::new(LogEvents))
+    .with_tenant(&client.tenant);
+
+    // Not to spend a login on a message the server would reject. A message that cannot be
+    // built fails at its stage.
+    if args.message.max_message_size.is_some() {
+        let sender = args.sender();
+        let sender = sender
+            .as_ref()
+            .map(|(name, email)| (name.as_str(), email.as_str()));
+        if let Ok(size) =
+            message::estimate_size(sender, recipients, &args.message, args.batch_size as usize)
+        {
+            message::check_size(size, args.message.max_message_size);
+        }
+    }
+
+    let curl = curl(global);
+    let Some((authority, access_token, token)) = report
+        .stage(Stage::Login, async {
+            let (authority, access_token) = login(
+                client,
+                authority,
+                args.login,
+                global,
+                directory,
+                curl.clone(),
+            )
+            .await?;
+            let token = grant_type.token_file(client_id).and_then(|token_file| {
+                let mut token = TokenKeeper::new(directory.to_path_buf());
+                token.read(&token_file).ok().map(|_| token)
+            });
+            if let Some(path) = &args.export_token {
+                ExportedToken::new(&access_token, token.as_ref(), client_id, &client.tenant)
+                    .write(path)?;
             }
-            OAuth2TokenGrantFlow::DeviceCodeFlow => {
-                device_code_flow(client_id, client_secret, curl.clone()).await?
+            Ok((authority, access_token, token))
+        })
+        .await
+    else {
+        if args.first_use_doctor {
+            if let Outcome::Failed { error, .. } = &report.outcome {
+                if let Some(hint) = doctor::diagnose(error) {
+                    log::warn!("Doctor: {hint}");
+                }
             }
-        };
+        }
+        return report;
+    };
+    let basic_auth = args.smtp.auth_mode == SmtpAuthMode::Basic;
+    if args.first_use_doctor && !basic_auth {
+        doctor::log_findings(&doctor::check_token(&access_token, token.as_ref()));
+    }
+
+    // The claims of the id_token, when the login asked for openid, save the profile request.
+    let sender = args.sender().or_else(|| {
+        let id_token = token.as_ref()?.id_token.as_deref()?;
+        SenderProfile::from_id_token(id_token)
+    });
+    let Some((sender_name, sender_email)) = report
+        .stage(
+            Stage::Profile,
+            SenderProfile::get_sender(
+                sender,
+                args.default_display_name.as_deref(),
+                &access_token,
+                args.profile_endpoint.as_ref(),
+                global.cloud,
+                args.accept_language.as_deref(),
+                &curl,
+            ),
+        )
+        .await
+    else {
+        return report;
+    };
+    report.sender = Some(sender_email.clone());
+    let recipients =
+        cli::recipients_or_sender(recipients, (sender_name.as_str(), sender_email.as_str()));
+    report.recipients = recipients
+        .iter()
+        .map(|(receiver_email, _)| receiver_email.to_string())
+        .collect();
 
-    let sender_profile = SenderProfile::get_sender_profile(&access_token, curl).await?;
     // Start of sending Email
-    let message = MessageBuilder::new()
-        .from((
-            sender_profile.display_name.as_ref(),
-            sender_profile.email_address.as_ref(),
-        ))
-        .to(vec![(receiver_name.as_ref(), receiver_email.as_ref())])
-        .subject("Microsoft - Test XOAUTH2 SMTP!")
-        .html_body("<h1>Hello, world!</h1>")
-        .text_body("Hello world!");
-
-    let credentials = Credentials::new_xoauth2(
-        sender_profile.email_address.as_ref(),
-        access_token.secret().as_str(),
-    );
-    log::info!("Authenticating SMTP XOAUTH2 Credentials....");
-    let email_connect = SmtpClientBuilder::new("smtp.office365.com", 587)
-        .implicit_tls(false)
-        .credentials(credentials)
-        .connect()
+    let Some((messages, login)) = report
+        .stage(Stage::Message, async {
+            let messages = message::build_messages(
+                (sender_name.as_ref(), sender_email.as_ref()),
+                recipients
+                    .iter()
+                    .map(|&(receiver_email, receiver_name)| (receiver_name, receiver_email))
+                    .collect(),
+                &args.message,
+                args.batch_size as usize,
+            )?;
+            let messages: Vec<_> = messages
+                .into_iter()
+                .map(|message| {
+                    smtp::with_envelope_from(message, args.smtp.envelope_from.as_deref())
+                })
+                .collect();
+            if let (Some(path), Some(message)) = (&args.message.dump_message, messages.first()) {
+                message::dump_message(message, path)?;
+            }
+            let login = smtp::smtp_login(args.smtp.smtp_login.as_deref(), sender_email.as_str())?;
+            smtp::check_send_as(&sender_email, login, args.smtp.allow_send_as)?;
+            Ok::<_, OAuth2Error>((messages, login))
+        })
+        .await
+    else {
+        return report;
+    };
+    report.message_id = messages.first().and_then(message::message_id);
+    report.messages = Some(messages.len());
+    report.bytes_sent = Some(messages.iter().map(|message| message.body.len()).sum());
+    if args.no_send {
+        log::info!("Not sending the email, --no-send was given.");
+        return report;
+    }
+
+    let send_attempts = Cell::new(0);
+    let written = ByteCounter::default();
+    let mut recipient_results = Vec::new();
+    // The messages of --batch-size are sent one after the other, each with the token the
+    // one before ended with.
+    report
+        .stage(Stage::Send, async {
+            let mut access_token = access_token;
+            for message in &messages {
+                let (client_secret, curl, authority) =
+                    (client_secret.clone(), curl.clone(), &authority);
+                let (results, used_token) = smtp::send_with_relogin(
+                    access_token,
+                    args.auto_relogin && !basic_auth,
+                    |access_token| {
+                        let (send_attempts, written) = (&send_attempts, &written);
+                        async move {
+                            let token = &access_token;
+                            let results = smtp::send_with_retries(
+                                args.smtp.send_retries,
+                                Backoff::new(smtp::SEND_RETRY_BACKOFF, args.smtp.retry_jitter),
+                                move || {
+                                    send_attempts.set(send_attempts.get() + 1);
+                                    let size = message.body.len();
+                                    let message = message::borrow_message(message);
+                                    let access_token = token.clone();
+                                    written.count(size, async move {
+                                        smtp::send_email(
+                                            message,
+                                            login,
+                                            &access_token,
+                                            &args.smtp,
+                                            &global.smtp_route(),
+                                        )
+                                        .await
+                                    })
+                                },
+                            )
+                            .await?;
+                            Ok((results, access_token))
+                        }
+                    },
+                    || async {
+                        if let Some(token_file) = grant_type.token_file(client_id) {
+                            TokenKeeper::new(directory.to_path_buf()).delete(&token_file)?;
+                        }
+                        // The cached token was just deleted, there is nothing to refresh.
+                        let login = LoginArgs {
+                            force_refresh: false,
+                            ..args.login
+                        };
+                        grant_type
+                            .get_access_token(
+                                client_id,
+                                client_secret,
+                                authority,
+                                directory,
+                                curl,
+                                login,
+                            )
+                            .await
+                    },
+                )
+                .await?;
+                recipient_results.extend(results);
+                access_token = used_token;
+            }
+            Ok(())
+        })
         .await;
+    report.send_attempts = Some(send_attempts.get());
+    if let Some(send_ms) = report.timings.send_ms.filter(|_| written.total() > 0) {
+        let transfer = TransferStats::new(written.total(), Duration::from_millis(send_ms));
+        log::info!("{}", transfer.summary());
+        report.transfer = Some(transfer);
+    }
+    // Those of the messages sent before a failed one are kept.
+    report.recipient_results = recipient_results;
+    report
+}
 
-    match email_connect {
-        Ok(mut result) => {
-            log::info!("Sending SMTP XOAUTH2 Email....");
-            let send = result.send(message).await;
-            match send {
-                Ok(_result) => {
-                    log::info!("Sending Email success!!");
-                }
-                Err(err) => {
-                    log::error!("SMTP Sending Error: {err:?}");
-                }
+/// Checks that the login and SMTP hosts are reachable, unless `--skip-preflight` is
+/// given. Only the hosts the run talks to are probed: no login host without a login, no
+/// SMTP host with `--no-send`.
+async fn preflight(login: bool, args: &RunArgs, global: &GlobalArgs) -> OAuth2Result<()> {
+    if args.skip_preflight {
+        return Ok(());
+    }
+    let mut targets = Vec::new();
+    if login {
+        targets.push(Target::https("login", global.cloud.login_host()));
+    }
+    if !args.no_send && args.exec.is_none() {
+        let (smtp_host, smtp_port) = smtp::server(&args.smtp, global.cloud);
+        targets.push(Target::tcp("SMTP", smtp_host, smtp_port));
+    }
+    preflight::check(&targets, &global.smtp_route())
+        .await
+        .map(|_| ())
+}
+
+/// Runs the login and the send, or the command of `--exec`, whose exit code is returned
+/// for the process to exit with.
+async fn run(args: RunArgs, global: &GlobalArgs) -> OAuth2Result<Option<i32>> {
+    let directory = token_keeper::token_directory(global.token_dir.as_deref())?;
+    if let Some(accounts_file) = args.client.accounts_file.as_deref() {
+        let accounts = Account::load(accounts_file)?;
+        preflight(true, &args, global).await?;
+        let results = accounts::run_accounts(&accounts, |account| {
+            let directory = account.token_directory(&directory);
+            let args = &args;
+            async move {
+                let recipient = (
+                    account.recipient_email.as_str(),
+                    account.recipient_name.as_str(),
+                );
+                let report =
+                    send_test_email(&account.client(), &[recipient], args, global, &directory)
+                        .await;
+                report.print(global.output)?;
+                report.into_result()
             }
+        })
+        .await;
+        return accounts::report(&results).map(|()| None);
+    }
+
+    let last_run = last_run(args.client.resume, global, &directory)?;
+    let client = if args.smtp.basic_credentials()?.is_some() {
+        log::info!("Skipping the OAuth2 login, --auth-mode basic sends the SMTP password.");
+        Client::basic_auth()
+    } else {
+        args.client.resolve(last_run.as_ref())?
+    };
+    // A mistyped client id is reported as such, not as an unreachable host.
+    if client.access_token.is_none() {
+        cli::check_client_id(&client.client_id, global.allow_non_guid_client_id)?;
+    }
+    if let Some(command_line) = &args.exec {
+        preflight(client.access_token.is_none(), &args, global).await?;
+        let authority = authority(global, &client.tenant);
+        let (_, access_token) = login(
+            &client,
+            authority,
+            args.login,
+            global,
+            &directory,
+            curl(global),
+        )
+        .await?;
+        let exit_code = exec::run(command_line, &access_token).await?;
+        log::debug!("Exiting with {exit_code}, the exit code of --exec.");
+        return Ok(Some(exit_code));
+    }
+    let recipients = args.recipients(last_run.as_ref())?;
+    let recipients: Vec<(&str, &str)> = recipients
+        .iter()
+        .map(|(email, name)| (email.as_str(), name.as_str()))
+        .collect();
+    if args.dry_run {
+        let plan = Plan::new(&client, &recipients, &args, global, &directory)?;
+        return plan.print(global.output).map(|()| None);
+    }
+    preflight(client.access_token.is_none(), &args, global).await?;
+    let report = send_test_email(&client, &recipients, &args, global, &directory).await;
+    report.print(global.output)?;
+    if let Some(metrics_file) = args.metrics_file.as_deref() {
+        metrics::write_metrics(metrics_file, &report)?;
+    }
+    report.into_result()?;
+    if client.grant_type == OAuth2TokenGrantFlow::PreAcquired {
+        return Ok(None);
+    }
+    // Without a single recipient, the settings are kept for the next --resume as they are.
+    let [(receiver_email, receiver_name)] = recipients.as_slice() else {
+        return Ok(None);
+    };
+
+    let last_run = LastRun {
+        grant_type: client.grant_type,
+        client_id: client.client_id,
+        tenant: client.tenant,
+        recipient_email: receiver_email.to_string(),
+        recipient_name: receiver_name.to_string(),
+    };
+    if let Err(e) = last_run.save(&directory) {
+        log::warn!("Unable to save the settings for --resume: {:?}", e);
+    }
+    Ok(None)
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let args = match config::config_args(&args) {
+        Ok(config_args) => config_args.unwrap_or(args),
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            std::process::exit(ExitCode::ConfigError as i32);
         }
-        Err(err) => {
-            log::error!("SMTP Connecting Error: {err:?}");
+    };
+    let legacy_args = cli::legacy_args(&args);
+    let cli = match Cli::try_parse_from(legacy_args.as_deref().unwrap_or(&args)) {
+        Ok(cli) => cli,
+        // clap exits with 2 on a usage error, the exit code of a rejected login.
+        Err(e) if e.use_stderr() => {
+            let _ = e.print();
+            std::process::exit(ExitCode::ConfigError as i32);
         }
+        Err(e) => e.exit(),
+    };
+
+    init_logger(cli.global.debug_level, cli.global.color, &cli.global.run_id);
+    token_encryption::set_mode(cli.global.token_encryption);
+    if let Err(e) = telemetry::init(cli.global.otel_endpoint.as_deref()) {
+        eprintln!("Error: {e:?}");
+        std::process::exit(ExitCode::ConfigError as i32);
+    }
+    if legacy_args.is_some() {
+        log::warn!(
+            "The positional arguments are deprecated, please use --grant-type, --client-id, \
+            --client-secret, --recipient-email, --recipient-name and --debug-level instead."
+        );
+    }
+
+    // On Ctrl-C, the guards of the temporary files are not dropped.
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            log::warn!("Interrupted, removing the temporary files.");
+            cleanup::remove_all();
+            std::process::exit(130);
+        }
+    });
+
+    // The exit code of the command of --exec, exited with once the telemetry is flushed.
+    let mut exec_exit_code = None;
+    let result = match cli.command {
+        Some(Command::Refresh(args)) => refresh(args, &cli.global).await,
+        Some(Command::Exchange(args)) => exchange(args, &cli.global).await,
+        Some(Command::Poll(args)) => poll(args, &cli.global).await,
+        Some(Command::ValidateToken(args)) => validate_token(args, &cli.global),
+        Some(Command::BenchmarkConnect(args)) => benchmark_connect(args, &cli.global).await,
+        Some(Command::Status(args)) => status(args, &cli.global).await,
+        // Only --forget is allowed without the arguments.
+        None if !cli.run.client.is_given() && cli.run.smtp.auth_mode == SmtpAuthMode::Xoauth2 => {
+            token_keeper::token_directory(cli.global.token_dir.as_deref())
+                .and_then(|directory| last_run(false, &cli.global, &directory).map(|_| ()))
+        }
+        None => run(cli.run, &cli.global)
+            .await
+            .map(|exit_code| exec_exit_code = exit_code),
+    };
+    let exit_code = ExitCode::of(&result);
+    if let Err(e) = result {
+        eprintln!("Error: {e:?}");
+        log::debug!("Exiting with {exit_code:?} ({})", exit_code as i32);
+    }
+    telemetry::shutdown();
+    std::process::exit(exec_exit_code.unwrap_or(exit_code as i32));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use oauth2::basic::BasicTokenResponse;
+
+    use clap::Parser;
+
+    use super::{run, OAuth2TokenGrantFlow};
+    use crate::cli::{Cli, LoginArgs};
+    use crate::cloud::{Authority, Cloud};
+    use crate::curl::Curl;
+    use crate::device_code_flow;
+    use crate::discovery::Endpoints;
+    use crate::error::ErrorCodes;
+    use crate::token_keeper::TokenKeeper;
+
+    /// A localhost port nothing listens on.
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn test_valid_cached_token_lacking_a_scope_logs_in_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let client_id = "00000000-0000-0000-0000-000000000000";
+        let token_response: BasicTokenResponse = serde_json::from_str(
+            r#"{"access_token":"cached","token_type":"Bearer","expires_in":3600,
+                "scope":"offline_access https://outlook.office.com/SMTP.Send"}"#,
+        )
+        .unwrap();
+        let mut token_keeper = TokenKeeper::from(token_response);
+        token_keeper.set_directory(directory.path().to_path_buf());
+        token_keeper
+            .save(&device_code_flow::token_file(client_id))
+            .unwrap();
+
+        // The login fails to connect instead of asking for a device code.
+        let endpoint = format!("http://127.0.0.1:{}/oauth2", closed_port());
+        let authority = Authority::new(Cloud::Commercial, "common").with_endpoints(Endpoints {
+            authorization_endpoint: format!("{endpoint}/authorize"),
+            token_endpoint: format!("{endpoint}/token"),
+            device_authorization_endpoint: Some(format!("{endpoint}/devicecode")),
+        });
+        let get_access_token = |fail_fast_on_scope_downgrade| {
+            OAuth2TokenGrantFlow::DeviceCodeFlow.get_access_token(
+                client_id,
+                None,
+                &authority,
+                directory.path(),
+                Curl::new(),
+                LoginArgs {
+                    fail_fast_on_scope_downgrade,
+                    ..LoginArgs::default()
+                },
+            )
+        };
+
+        let access_token = get_access_token(false).await.unwrap();
+        assert_eq!(access_token.secret(), "cached");
+        assert!(get_access_token(true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_client_id_is_reported_before_the_preflight() {
+        let directory = tempfile::tempdir().unwrap();
+        let smtp_server = format!("127.0.0.1:{}", closed_port());
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--token-dir",
+            directory.path().to_str().unwrap(),
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "not-a-guid",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+            "--smtp-server",
+            &smtp_server,
+        ])
+        .unwrap();
+
+        let error = run(cli.run, &cli.global).await.unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::InvalidClientId);
     }
-    Ok(())
 }
@@ -0,0 +1,200 @@
+// Standard libraries
+use std::fs;
+use std::path::Path;
+
+// 3rd party crates
+use oauth2::url::Url;
+use serde::Deserialize;
+
+// My crates
+use crate::cli::check_client_id;
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The redirect URI of the authorization code grant, which the app registration has to list.
+const LOCALHOST_REDIRECT_URI: &str = "http://localhost:8080";
+
+/// The redirect URIs of one platform of the Microsoft Graph manifest format.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Platform {
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+}
+
+/// A redirect URI of the older Azure AD Graph manifest format.
+#[derive(Debug, Deserialize)]
+struct ReplyUrl {
+    url: String,
+}
+
+/// The fields of an exported app registration manifest that are used, in either the
+/// Microsoft Graph format or the older Azure AD Graph one.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawManifest {
+    app_id: Option<String>,
+    display_name: Option<String>,
+    name: Option<String>,
+    web: Option<Platform>,
+    public_client: Option<Platform>,
+    spa: Option<Platform>,
+    #[serde(default)]
+    reply_urls_with_type: Vec<ReplyUrl>,
+}
+
+/// What `--manifest` reads from the app registration manifest.
+#[derive(Debug, PartialEq)]
+pub struct AppManifest {
+    pub client_id: String,
+    pub name: Option<String>,
+    pub redirect_uris: Vec<String>,
+}
+
+fn invalid_manifest(path: &Path, problem: String) -> OAuth2Error {
+    OAuth2Error::new(
+        ErrorCodes::ConfigurationError,
+        format!("{} is not a valid app manifest: {problem}", path.display()),
+    )
+}
+
+impl AppManifest {
+    /// Reads the manifest, which needs the `appId` of the app registration.
+    pub fn load(path: &Path) -> OAuth2Result<Self> {
+        Self::parse(&fs::read_to_string(path)?, path)
+    }
+
+    fn parse(json: &str, path: &Path) -> OAuth2Result<Self> {
+        let raw: RawManifest =
+            serde_json::from_str(json).map_err(|e| invalid_manifest(path, e.to_string()))?;
+        let client_id = raw
+            .app_id
+            .filter(|app_id| !app_id.trim().is_empty())
+            .ok_or_else(|| invalid_manifest(path, "there is no appId.".to_string()))?;
+        check_client_id(&client_id, false)?;
+
+        let mut redirect_uris: Vec<String> = Vec::new();
+        let platforms = [raw.web, raw.public_client, raw.spa];
+        let given = platforms
+            .into_iter()
+            .flatten()
+            .flat_map(|platform| platform.redirect_uris)
+            .chain(raw.reply_urls_with_type.into_iter().map(|reply| reply.url));
+        for redirect_uri in given {
+            if let Err(e) = Url::parse(&redirect_uri) {
+                return Err(invalid_manifest(
+                    path,
+                    format!("the redirect URI {redirect_uri:?} is not a URL: {e}."),
+                ));
+            }
+            if !redirect_uris.contains(&redirect_uri) {
+                redirect_uris.push(redirect_uri);
+            }
+        }
+        Ok(Self {
+            client_id,
+            name: raw.display_name.or(raw.name),
+            redirect_uris,
+        })
+    }
+
+    /// Whether the app registration lists the redirect URI of the authorization code grant.
+    pub fn has_localhost_redirect_uri(&self) -> bool {
+        self.redirect_uris
+            .iter()
+            .any(|uri| uri.trim_end_matches('/') == LOCALHOST_REDIRECT_URI)
+    }
+
+    /// Logs what was read from the manifest.
+    pub fn log(&self) {
+        if let Some(name) = &self.name {
+            log::info!("Manifest app: {name}");
+        }
+        log::info!("Manifest client id: {}", self.client_id);
+        log::info!("Manifest redirect URIs: {}", self.redirect_uris.join(", "));
+        if !self.has_localhost_redirect_uri() {
+            log::warn!(
+                "The manifest has no {LOCALHOST_REDIRECT_URI} redirect URI, the \
+                AuthorizationCodeGrant needs it."
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::AppManifest;
+    use crate::error::{ErrorCodes, OAuth2Result};
+
+    const MANIFEST: &str = r#"{
+        "id": "33333333-3333-3333-3333-333333333333",
+        "appId": "11111111-1111-1111-1111-111111111111",
+        "displayName": "SMTP test tool",
+        "signInAudience": "AzureADMyOrg",
+        "publicClient": {
+            "redirectUris": ["http://localhost:8080"]
+        },
+        "web": {
+            "homePageUrl": null,
+            "redirectUris": ["https://contoso.com/callback", "http://localhost:8080"],
+            "implicitGrantSettings": {"enableAccessTokenIssuance": false}
+        },
+        "spa": {"redirectUris": []},
+        "requiredResourceAccess": []
+    }"#;
+
+    const AAD_GRAPH_MANIFEST: &str = r#"{
+        "appId": "22222222-2222-2222-2222-222222222222",
+        "name": "Legacy app",
+        "replyUrlsWithType": [
+            {"url": "https://contoso.com/callback", "type": "Web"}
+        ]
+    }"#;
+
+    fn parse(json: &str) -> OAuth2Result<AppManifest> {
+        AppManifest::parse(json, Path::new("manifest.json"))
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = parse(MANIFEST).unwrap();
+        assert_eq!(
+            manifest,
+            AppManifest {
+                client_id: "11111111-1111-1111-1111-111111111111".to_string(),
+                name: Some("SMTP test tool".to_string()),
+                redirect_uris: vec![
+                    "https://contoso.com/callback".to_string(),
+                    "http://localhost:8080".to_string(),
+                ],
+            }
+        );
+        assert!(manifest.has_localhost_redirect_uri());
+
+        let manifest = parse(AAD_GRAPH_MANIFEST).unwrap();
+        assert_eq!(manifest.client_id, "22222222-2222-2222-2222-222222222222");
+        assert_eq!(manifest.name.as_deref(), Some("Legacy app"));
+        assert_eq!(manifest.redirect_uris, ["https://contoso.com/callback"]);
+        assert!(!manifest.has_localhost_redirect_uri());
+    }
+
+    #[test]
+    fn test_invalid_manifest() {
+        let error = parse("[]").unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        assert!(error.error_code_desc.contains("manifest.json"));
+
+        let error = parse(r#"{"displayName": "No app id"}"#).unwrap_err();
+        assert!(error.error_code_desc.contains("appId"));
+
+        let error = parse(r#"{"appId": "not-a-guid"}"#).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::InvalidClientId);
+
+        let error = parse(
+            r#"{"appId": "11111111-1111-1111-1111-111111111111", "web": {"redirectUris": ["localhost"]}}"#,
+        )
+        .unwrap_err();
+        assert!(error.error_code_desc.contains("\"localhost\""));
+    }
+}
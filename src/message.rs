@@ -0,0 +1,1362 @@
+// Standard libraries
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 3rd party crates
+use chrono::{DateTime, FixedOffset, SecondsFormat, Utc};
+use mail_send::{
+    mail_builder::{
+        headers::{
+            address::Address, content_type::ContentType, message_id::MessageId, raw::Raw,
+            HeaderType,
+        },
+        mime::MimePart,
+        MessageBuilder,
+    },
+    smtp::message::{Address as SmtpAddress, IntoMessage, Message, Parameters},
+};
+use strum_macros::EnumString;
+
+// My crates
+use crate::cli::{self, MessageArgs};
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::mime::{self, BodyEncoding, Charset, MimeLayout};
+use crate::phrase;
+
+#[derive(Clone, Copy, Debug, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum Importance {
+    High,
+    Normal,
+    Low,
+}
+
+impl Importance {
+    /// The values of the `Importance` and `X-Priority` headers.
+    fn header_values(&self) -> (&'static str, &'static str) {
+        match self {
+            Importance::High => ("high", "1"),
+            Importance::Normal => ("normal", "3"),
+            Importance::Low => ("low", "5"),
+        }
+    }
+}
+
+/// Which of the text and HTML bodies the message has, with `--content-type`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum BodyParts {
+    #[default]
+    Both,
+    Html,
+    Text,
+}
+
+/// A canned test message, with `--scenario`, instead of assembling it with the other
+/// message options.
+#[derive(Clone, Copy, Debug, PartialEq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum TestScenario {
+    /// Only a text/plain body.
+    SimpleText,
+    /// The text and HTML bodies, the HTML one showing an inline image.
+    HtmlWithInline,
+    /// The text and HTML bodies with a 10 MiB attachment.
+    LargeAttachment,
+    /// The text and HTML bodies with a generated meeting invite as a text/calendar
+    /// alternative.
+    Calendar,
+    /// The text and HTML bodies with the high Importance and X-Priority headers.
+    HighImportance,
+}
+
+impl TestScenario {
+    fn build<'x>(
+        self,
+        message: MessageBuilder<'x>,
+        (text_body, html_body): (String, String),
+        placeholders: &Placeholders,
+    ) -> MessageBuilder<'x> {
+        match self {
+            TestScenario::SimpleText => simple_text(message, text_body),
+            TestScenario::HtmlWithInline => html_with_inline(message, text_body, html_body),
+            TestScenario::LargeAttachment => large_attachment(message, text_body, html_body),
+            TestScenario::Calendar => {
+                let invite = calendar_invite(placeholders, Utc::now());
+                message.body(calendar_alternative(
+                    text_body,
+                    html_body,
+                    BodyParts::Both,
+                    invite,
+                ))
+            }
+            TestScenario::HighImportance => with_importance(
+                message.html_body(html_body).text_body(text_body),
+                Importance::High,
+            ),
+        }
+    }
+}
+
+/// The Date header of the message, with `--date`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DateHeader {
+    /// The time the message is built, which mail-builder adds.
+    #[default]
+    Now,
+    /// No Date header, to test the one the server inserts.
+    None,
+    /// This date, e.g. in the future or the past.
+    At(DateTime<FixedOffset>),
+}
+
+/// Parses `now`, `none`, or an RFC 2822 or RFC 3339 date for the Date header, keeping
+/// its offset. For use as a clap value parser.
+pub fn parse_date(date: &str) -> Result<DateHeader, String> {
+    match date {
+        "now" => Ok(DateHeader::Now),
+        "none" => Ok(DateHeader::None),
+        _ => DateTime::parse_from_rfc2822(date)
+            .or_else(|_| DateTime::parse_from_rfc3339(date))
+            .map(DateHeader::At)
+            .map_err(|_| {
+                format!(
+                    "{date:?} is not now, none or a date, e.g. \"Tue, 1 Jul 2003 10:52:37 +0200\" \
+                    or 2003-07-01T10:52:37+02:00"
+                )
+            }),
+    }
+}
+
+/// `body` without the `name` header of the top-level headers, continuation lines included.
+fn remove_header(body: &[u8], name: &str) -> Vec<u8> {
+    replace_header(body, name, None)
+}
+
+/// `body` with the `name` header of the top-level headers replaced by one with `value`,
+/// in its place, or removed without a value.
+fn replace_header(body: &[u8], name: &str, value: Option<&str>) -> Vec<u8> {
+    let text = String::from_utf8_lossy(body);
+    let Some((headers, _)) = text.split_once("\r\n\r\n") else {
+        return body.to_vec();
+    };
+    let mut output = Vec::with_capacity(body.len());
+    let mut removing = false;
+    let mut value = value;
+    for line in headers.split("\r\n") {
+        if !line.starts_with([' ', '\t']) {
+            removing = line
+                .split_once(':')
+                .is_some_and(|(header, _)| header.eq_ignore_ascii_case(name));
+            if let Some(value) = value.filter(|_| removing) {
+                output.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+            }
+            value = value.filter(|_| !removing);
+        }
+        if !removing {
+            output.extend_from_slice(line.as_bytes());
+            output.extend_from_slice(b"\r\n");
+        }
+    }
+    // The body is copied byte for byte, it may not be UTF-8. Each header line already
+    // ends with its line break, so only the empty line after them is left.
+    output.extend_from_slice(&body[headers.len() + 2..]);
+    output
+}
+
+const SUBJECT: &str = "Microsoft - Test XOAUTH2 SMTP!";
+const TEXT_BODY: &str = "Hello world!";
+const HTML_BODY: &str = "<h1>Hello, world!</h1>";
+
+/// The Content-ID of the inline image of `--scenario html-with-inline`.
+const INLINE_IMAGE_CID: &str = "test-image";
+/// A 1x1 PNG, the inline image of `--scenario html-with-inline`.
+const INLINE_IMAGE: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00, 0x00, 0x90, 0x77, 0x53,
+    0xde, 0x00, 0x00, 0x00, 0x0c, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x90, 0xcf, 0xdf, 0x02,
+    0x00, 0x01, 0xf3, 0x01, 0x43, 0x3f, 0x7a, 0x50, 0xcc, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e,
+    0x44, 0xae, 0x42, 0x60, 0x82,
+];
+/// The size of the attachment of `--scenario large-attachment`.
+const LARGE_ATTACHMENT_SIZE: usize = 10 * 1024 * 1024;
+
+/// The values of the `{{placeholder}}`s of the subject and the footer, the same for the
+/// whole message so that it can be traced back to the run.
+struct Placeholders {
+    sender: String,
+    recipient: String,
+    timestamp: String,
+    test_id: String,
+}
+
+impl Placeholders {
+    fn value(&self, name: &str) -> Option<&str> {
+        match name {
+            "sender" => Some(&self.sender),
+            "recipient" => Some(&self.recipient),
+            "timestamp" => Some(&self.timestamp),
+            "test_id" => Some(&self.test_id),
+            _ => None,
+        }
+    }
+
+    /// Replaces the known placeholders of `template`, the unknown ones are left as they
+    /// are with a warning.
+    fn substitute(&self, template: &str) -> String {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some((before, after)) = rest.split_once("{{") {
+            let Some((name, after)) = after.split_once("}}") else {
+                break;
+            };
+            output.push_str(before);
+            match self.value(name.trim()) {
+                Some(value) => output.push_str(value),
+                None => {
+                    log::warn!("Unknown placeholder {{{{{name}}}}}, it is left as it is.");
+                    output.push_str(&format!("{{{{{name}}}}}"));
+                }
+            }
+            rest = after;
+        }
+        output.push_str(rest);
+        output
+    }
+}
+
+/// Checks that `domain` is a syntactically valid host name, for use as a clap value parser.
+pub fn parse_domain(domain: &str) -> Result<String, String> {
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    if domain.len() <= 253 && domain.split('.').all(valid_label) {
+        Ok(domain.to_ascii_lowercase())
+    } else {
+        Err(format!("{domain:?} is not a valid domain name"))
+    }
+}
+
+/// Checks that `tag` looks like a BCP 47 language tag, e.g. `en` or `pt-BR`: a language
+/// of 2 or 3 letters, then subtags of 1 to 8 letters or digits, separated by `-`. For
+/// use as a clap value parser.
+pub fn parse_language_tag(tag: &str) -> Result<String, String> {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    let valid_subtag = |subtag: &str| {
+        (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+    };
+    if (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(valid_subtag)
+    {
+        Ok(tag.to_string())
+    } else {
+        Err(format!(
+            "{tag:?} is not a valid language tag, e.g. en or pt-BR"
+        ))
+    }
+}
+
+/// A unique id of the test message, the `{{test_id}}` and the left-hand side of its
+/// Message-ID.
+fn generate_test_id() -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    format!("{:x}.{}", timestamp.as_nanos(), std::process::id())
+}
+
+/// Reads the invite given with `--ics-file`, which has to be an iCalendar object.
+fn read_calendar(path: &Path) -> OAuth2Result<String> {
+    let calendar = fs::read_to_string(path)?;
+    let starts_with_vcalendar = calendar
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .get(.."BEGIN:VCALENDAR".len())
+        .is_some_and(|begin| begin.eq_ignore_ascii_case("BEGIN:VCALENDAR"));
+    if !starts_with_vcalendar {
+        return Err(OAuth2Error::new(
+            ErrorCodes::ConfigurationError,
+            format!("{} does not begin with BEGIN:VCALENDAR.", path.display()),
+        ));
+    }
+    Ok(calendar)
+}
+
+/// The footer given with `--footer` or `--footer-file`, without its trailing newlines.
+fn read_footer(args: &MessageArgs) -> OAuth2Result<Option<String>> {
+    let footer = match (&args.footer, &args.footer_file) {
+        (Some(footer), _) => footer.clone(),
+        (None, Some(footer_file)) => fs::read_to_string(footer_file)?,
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(footer.trim_end().to_string()))
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The text and HTML bodies, with the footer after a `-- ` signature separator in the
+/// text and after a horizontal rule in the HTML.
+fn bodies(footer: Option<&str>) -> (String, String) {
+    let Some(footer) = footer else {
+        return (TEXT_BODY.to_string(), HTML_BODY.to_string());
+    };
+    let html_footer = footer
+        .lines()
+        .map(escape_html)
+        .collect::<Vec<_>>()
+        .join("<br>\n");
+    (
+        format!("{TEXT_BODY}\n\n-- \n{footer}\n"),
+        format!("{HTML_BODY}\n<hr>\n<p>{html_footer}</p>\n"),
+    )
+}
+
+/// The body parts with the invite as one more alternative, the way calendar clients send it.
+fn calendar_alternative<'x>(
+    text_body: String,
+    html_body: String,
+    content_type: BodyParts,
+    calendar: String,
+) -> MimePart<'x> {
+    let mut parts = Vec::new();
+    if content_type != BodyParts::Html {
+        parts.push(MimePart::new_text(text_body));
+    }
+    if content_type != BodyParts::Text {
+        parts.push(MimePart::new_html(html_body));
+    }
+    parts.push(MimePart::new(
+        ContentType::new("text/calendar")
+            .attribute("method", "REQUEST")
+            .attribute("charset", "utf-8"),
+        calendar.into(),
+    ));
+    MimePart::new_multipart("multipart/alternative", parts)
+}
+
+fn with_importance(message: MessageBuilder<'_>, importance: Importance) -> MessageBuilder<'_> {
+    let (importance, priority) = importance.header_values();
+    message
+        .header("Importance", Raw::new(importance))
+        .header("X-Priority", Raw::new(priority))
+}
+
+fn simple_text<'x>(message: MessageBuilder<'x>, text_body: String) -> MessageBuilder<'x> {
+    message.text_body(text_body)
+}
+
+/// The HTML body and its image in a multipart/related, as the alternative to the text
+/// body. mail-builder would put an inline part next to the alternative in a
+/// multipart/mixed instead.
+fn html_with_inline<'x>(
+    message: MessageBuilder<'x>,
+    text_body: String,
+    html_body: String,
+) -> MessageBuilder<'x> {
+    let html_body =
+        format!("<p><img src=\"cid:{INLINE_IMAGE_CID}\" alt=\"Test image\"></p>\n{html_body}");
+    let image = MimePart::new_binary("image/png", INLINE_IMAGE)
+        .inline()
+        .cid(INLINE_IMAGE_CID);
+    message.body(MimePart::new_multipart(
+        "multipart/alternative",
+        vec![
+            MimePart::new_text(text_body),
+            MimePart::new_multipart(
+                "multipart/related",
+                vec![MimePart::new_html(html_body), image],
+            ),
+        ],
+    ))
+}
+
+/// The attachment is a repeating byte pattern rather than zeros, so that it does not
+/// compress away on the way.
+fn large_attachment<'x>(
+    message: MessageBuilder<'x>,
+    text_body: String,
+    html_body: String,
+) -> MessageBuilder<'x> {
+    let attachment: Vec<u8> = (0..LARGE_ATTACHMENT_SIZE)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    message
+        .html_body(html_body)
+        .text_body(text_body)
+        .binary_attachment(
+            "application/octet-stream",
+            "large-attachment.bin",
+            attachment,
+        )
+}
+
+/// A one hour meeting a day after `now`, organized by the sender with the recipients as
+/// attendees.
+fn calendar_invite(placeholders: &Placeholders, now: DateTime<Utc>) -> String {
+    let time = |time: DateTime<Utc>| time.format("%Y%m%dT%H%M%SZ").to_string();
+    let start = now + chrono::Duration::days(1);
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "PRODID:-//microsoft-smtp-xoauth2-test-tool//EN".to_string(),
+        "VERSION:2.0".to_string(),
+        "METHOD:REQUEST".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:{}", placeholders.test_id),
+        format!("DTSTAMP:{}", time(now)),
+        format!("DTSTART:{}", time(start)),
+        format!("DTEND:{}", time(start + chrono::Duration::hours(1))),
+        format!("SUMMARY:{}", placeholders.substitute(SUBJECT)),
+        format!("ORGANIZER:mailto:{}", placeholders.sender),
+    ];
+    for recipient in placeholders.recipient.split(", ") {
+        lines.push(format!(
+            "ATTENDEE;ROLE=REQ-PARTICIPANT;RSVP=TRUE:mailto:{recipient}"
+        ));
+    }
+    lines.extend(["END:VEVENT".to_string(), "END:VCALENDAR".to_string()]);
+    lines.join("\r\n") + "\r\n"
+}
+
+pub fn build_message<'x>(
+    from: (&'x str, &'x str),
+    to: Vec<(&'x str, &'x str)>,
+    args: &MessageArgs,
+) -> OAuth2Result<MessageBuilder<'x>> {
+    // Defaults to the domain of the sender, otherwise mail-builder falls back to the
+    // host name.
+    let message_id_domain = args.message_id_domain.clone().or_else(|| {
+        let (_, domain) = from.1.rsplit_once('@')?;
+        parse_domain(domain).ok()
+    });
+
+    let test_id = generate_test_id();
+    log::info!("Test id: {test_id}");
+    let placeholders = Placeholders {
+        sender: from.1.to_string(),
+        recipient: to
+            .iter()
+            .map(|(_, email)| *email)
+            .collect::<Vec<_>>()
+            .join(", "),
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        test_id: test_id.clone(),
+    };
+    let footer = read_footer(args)?.map(|footer| placeholders.substitute(&footer));
+    let (text_body, html_body) = bodies(footer.as_deref());
+
+    let mut message = MessageBuilder::new()
+        .from(from)
+        .to(to)
+        .subject(placeholders.substitute(SUBJECT));
+
+    message = match (args.scenario, args.ics_file.as_deref()) {
+        (Some(scenario), _) => scenario.build(message, (text_body, html_body), &placeholders),
+        // A single text/plain part of zero length, right after the blank line that ends
+        // the headers.
+        _ if args.empty_body => message.text_body(""),
+        (None, Some(ics_file)) => message.body(calendar_alternative(
+            text_body,
+            html_body,
+            args.content_type,
+            read_calendar(ics_file)?,
+        )),
+        (None, None) => match args.content_type {
+            BodyParts::Both => message.html_body(html_body).text_body(text_body),
+            BodyParts::Html => message.html_body(html_body),
+            BodyParts::Text => message.text_body(text_body),
+        },
+    };
+
+    if let Some(domain) = message_id_domain {
+        message = message.message_id(MessageId::new(format!("{test_id}@{domain}")));
+    }
+    if let Some(importance) = args.importance {
+        message = with_importance(message, importance);
+    }
+    if let Some(language) = &args.content_language {
+        message = message.header("Content-Language", Raw::new(language.clone()));
+    }
+    if let DateHeader::At(date) = args.date {
+        message = message.header("Date", Raw::new(date.to_rfc2822()));
+    }
+    Ok(message)
+}
+
+/// Builds a message for each `batch_size` recipients, as `--batch-size` says, so that a
+/// long list of recipients does not go over the limit of the server.
+pub fn build_messages<'x>(
+    from: (&'x str, &'x str),
+    to: Vec<(&'x str, &'x str)>,
+    args: &MessageArgs,
+    batch_size: usize,
+) -> OAuth2Result<Vec<Message<'x>>> {
+    let batches: Vec<&[(&str, &str)]> = to.chunks(batch_size).collect();
+    if batches.len() > 1 {
+        log::info!(
+            "Sending to {} recipients in {} messages of at most {batch_size} recipients.",
+            to.len(),
+            batches.len()
+        );
+    }
+    batches
+        .into_iter()
+        .map(|batch| into_message(build_message(from, batch.to_vec(), args)?, args))
+        .collect()
+}
+
+/// The sender the size of the message is estimated with when it is read from the profile
+/// after the login.
+const PLACEHOLDER_SENDER: (&str, &str) = ("", "sender@example.com");
+
+/// The size of the message as it is written in the SMTP DATA: the body, a dot more for
+/// each line starting with one and the final dot line.
+pub fn wire_size(message: &Message<'_>) -> usize {
+    let stuffed_dots = message
+        .body
+        .windows(3)
+        .filter(|window| window == b"\r\n.")
+        .count();
+    message.body.len() + stuffed_dots + b"\r\n.\r\n".len()
+}
+
+/// Estimates the size of the message before the login, built with the sender of the
+/// arguments or else a placeholder for the one of the profile, which only changes the
+/// From header. With `--batch-size`, the size of the largest message.
+pub fn estimate_size(
+    sender: Option<(&str, &str)>,
+    recipients: &[(&str, &str)],
+    args: &MessageArgs,
+    batch_size: usize,
+) -> OAuth2Result<usize> {
+    let sender = sender.unwrap_or(PLACEHOLDER_SENDER);
+    let recipients = cli::recipients_or_sender(recipients, sender)
+        .into_iter()
+        .map(|(email, name)| (name, email))
+        .collect();
+    let messages = build_messages(sender, recipients, args, batch_size)?;
+    Ok(messages.iter().map(wire_size).max().unwrap_or_default())
+}
+
+/// Logs the estimated size of the message and warns when it is over
+/// `--max-message-size`, as the server would likely reject it.
+pub fn check_size(size: usize, max_message_size: Option<u64>) {
+    log::info!("Estimated message size: {size} bytes");
+    if let Some(max_message_size) = max_message_size.filter(|&max| size as u64 > max) {
+        log::warn!(
+            "The message is about {size} bytes, over the --max-message-size of \
+            {max_message_size} bytes: the SMTP server may reject it."
+        );
+    }
+}
+
+/// The Message-ID header of the message as it is sent.
+pub fn message_id(message: &Message<'_>) -> Option<String> {
+    header(&message.body, "Message-ID")
+}
+
+/// The value of the `name` header of the top-level headers, unfolded.
+fn header(body: &[u8], name: &str) -> Option<String> {
+    let text = String::from_utf8_lossy(body);
+    let headers = text
+        .split("\r\n\r\n")
+        .next()?
+        .replace("\r\n ", " ")
+        .replace("\r\n\t", " ");
+    headers.split("\r\n").find_map(|line| {
+        let (header, value) = line.split_once(':')?;
+        header
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}
+
+/// The display name and the address of the From header of the message.
+fn from_address<'a>(message: &'a MessageBuilder<'_>) -> Option<(&'a str, &'a str)> {
+    message
+        .headers
+        .iter()
+        .find_map(|(name, value)| match value {
+            HeaderType::Address(Address::Address(address)) if name.eq_ignore_ascii_case("From") => {
+                Some((
+                    address.name.as_deref().unwrap_or_default(),
+                    address.email.as_ref(),
+                ))
+            }
+            _ => None,
+        })
+}
+
+/// Checks, for `--check-from-header`, that the From header of the sent message is a
+/// valid RFC 5322 mailbox whose display name decodes to the one of the sender.
+fn check_from_header(body: &[u8], name: &str) -> OAuth2Result<()> {
+    let invalid = |reason: String| {
+        OAuth2Error::new(
+            ErrorCodes::ParseError,
+            format!("The From header is not valid: {reason}"),
+        )
+    };
+    let value = header(body, "From").ok_or_else(|| invalid("there is none".into()))?;
+    let (decoded, _) = phrase::parse_mailbox(&value).map_err(invalid)?;
+    if decoded != name {
+        return Err(invalid(format!(
+            "{value} decodes to the display name {decoded:?} instead of {name:?}"
+        )));
+    }
+    log::info!("From header checked: {value}");
+    Ok(())
+}
+
+/// Writes the message to `path` exactly as it is sent in the SMTP DATA, for
+/// `--dump-message`.
+pub fn dump_message(message: &Message<'_>, path: &Path) -> OAuth2Result<()> {
+    fs::write(path, &message.body)?;
+    log::info!("Message written to {}", path.display());
+    Ok(())
+}
+
+/// A copy of the address that borrows its email. mail-send keeps its parameters private,
+/// so they are copied from their MAIL FROM or RCPT TO form.
+fn borrow_address<'x>(address: &'x SmtpAddress<'_>) -> SmtpAddress<'x> {
+    let mut parameters = Parameters::new();
+    for parameter in address.parameters.to_string().split_whitespace() {
+        match parameter.split_once('=') {
+            Some((key, value)) => parameters.add((key.to_string(), value.to_string())),
+            None => parameters.add(parameter.to_string()),
+        };
+    }
+    SmtpAddress {
+        email: Cow::Borrowed(address.email.as_ref()),
+        parameters,
+    }
+}
+
+/// A copy of the message that borrows its body. mail-send needs the whole message in
+/// memory, so the body is built once and each send attempt borrows it instead of
+/// cloning it.
+pub fn borrow_message<'x>(message: &'x Message<'_>) -> Message<'x> {
+    Message {
+        mail_from: borrow_address(&message.mail_from),
+        rcpt_to: message.rcpt_to.iter().map(borrow_address).collect(),
+        body: Cow::Borrowed(message.body.as_ref()),
+    }
+}
+
+/// Turns the message into the one sent over SMTP, with the body parts encoded as
+/// `--body-encoding` says and in the `--charset`, when they are given. Without
+/// `--body-encoding`, another charset than UTF-8 is sent as quoted-printable. The
+/// multiparts are laid out as `--multipart-structure` and `--mime-boundary` say. With
+/// `--date none`, the Date header mail-builder always adds is removed. The display name
+/// of the From header is quoted or encoded as an RFC 5322 phrase needs.
+pub fn into_message<'x>(
+    message: MessageBuilder<'x>,
+    args: &MessageArgs,
+) -> OAuth2Result<Message<'x>> {
+    let from = from_address(&message)
+        .map(|(name, email)| {
+            Ok::<_, OAuth2Error>((name.to_string(), phrase::mailbox(name, email)?))
+        })
+        .transpose()?;
+    let body_encoding = match (args.body_encoding, args.charset) {
+        (Some(body_encoding), _) => Some(body_encoding),
+        (None, Charset::Utf8) => None,
+        (None, _) => Some(BodyEncoding::QuotedPrintable),
+    };
+    let layout = MimeLayout {
+        structure: args.multipart_structure,
+        boundary: args.mime_boundary.clone(),
+    };
+    let mut sent = if body_encoding.is_some() || layout != MimeLayout::default() {
+        let body = mime::write_message(message.clone(), body_encoding, args.charset, &layout)?;
+        Message {
+            body: body.into(),
+            ..message.into_message()?
+        }
+    } else {
+        message.into_message()?
+    };
+    if args.date == DateHeader::None {
+        sent.body = remove_header(&sent.body, "Date").into();
+    }
+    if let Some((name, mailbox)) = from {
+        sent.body = replace_header(&sent.body, "From", Some(&mailbox)).into();
+        if args.check_from_header {
+            check_from_header(&sent.body, &name)?;
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{
+        bodies, borrow_message, build_message, build_messages, estimate_size, into_message,
+        message_id, parse_date, parse_domain, parse_language_tag, remove_header, replace_header,
+        wire_size, BodyParts, DateHeader, Importance, Placeholders, TestScenario,
+        LARGE_ATTACHMENT_SIZE,
+    };
+    use crate::cli::MessageArgs;
+    use crate::error::ErrorCodes;
+    use crate::mime::{BodyEncoding, MultipartStructure};
+
+    fn message_with(importance: Option<Importance>) -> String {
+        let args = MessageArgs {
+            importance,
+            ..Default::default()
+        };
+        write_message(&args)
+    }
+
+    fn write_message(args: &MessageArgs) -> String {
+        build_message(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            args,
+        )
+        .unwrap()
+        .write_to_string()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_importance_headers() {
+        let message = message_with(Some(Importance::High));
+        assert!(message.contains("Importance: high\r\n"));
+        assert!(message.contains("X-Priority: 1\r\n"));
+
+        let message = message_with(Some(Importance::Normal));
+        assert!(message.contains("Importance: normal\r\n"));
+        assert!(message.contains("X-Priority: 3\r\n"));
+
+        let message = message_with(Some(Importance::Low));
+        assert!(message.contains("Importance: low\r\n"));
+        assert!(message.contains("X-Priority: 5\r\n"));
+    }
+
+    #[test]
+    fn test_no_importance_headers_by_default() {
+        let message = message_with(None);
+        assert!(!message.contains("Importance:"));
+        assert!(!message.contains("X-Priority:"));
+    }
+
+    fn message_id_header(message: &str) -> String {
+        message
+            .lines()
+            .find_map(|line| line.strip_prefix("Message-ID: "))
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_message_id_domain() {
+        let args = MessageArgs {
+            message_id_domain: Some(parse_domain("Mail.Contoso.com").unwrap()),
+            ..Default::default()
+        };
+        let message_id = message_id_header(&write_message(&args));
+        assert!(message_id.starts_with('<'));
+        assert!(message_id.ends_with("@mail.contoso.com>"), "{message_id}");
+    }
+
+    #[test]
+    fn test_message_id_defaults_to_sender_domain() {
+        let message_id = message_id_header(&write_message(&MessageArgs::default()));
+        assert!(message_id.ends_with("@example.com>"), "{message_id}");
+    }
+
+    #[test]
+    fn test_empty_body() {
+        let args = MessageArgs {
+            empty_body: true,
+            ..Default::default()
+        };
+        let builder = build_message(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &args,
+        )
+        .unwrap();
+        let message = into_message(builder, &args).unwrap();
+        let message = String::from_utf8(message.body.into_owned()).unwrap();
+
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+        assert_eq!(body, "", "{message}");
+        assert!(headers.contains("Content-Type: text/plain"), "{headers}");
+        assert!(!headers.contains("multipart"), "{headers}");
+        assert!(headers.lines().all(|line| line.contains(':')), "{headers}");
+    }
+
+    #[test]
+    fn test_parse_domain() {
+        assert!(parse_domain("contoso.com").is_ok());
+        assert!(parse_domain("localhost").is_ok());
+        assert!(parse_domain("").is_err());
+        assert!(parse_domain("contoso..com").is_err());
+        assert!(parse_domain("-contoso.com").is_err());
+        assert!(parse_domain("contoso.com>").is_err());
+        assert!(parse_domain(&"a".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_content_language() {
+        assert!(!message_with(None).contains("Content-Language:"));
+
+        let args = MessageArgs {
+            content_language: Some(parse_language_tag("pt-BR").unwrap()),
+            ..Default::default()
+        };
+        assert!(write_message(&args).contains("Content-Language: pt-BR\r\n"));
+
+        assert!(parse_language_tag("en").is_ok());
+        assert!(parse_language_tag("zh-Hant-TW").is_ok());
+        assert!(parse_language_tag("").is_err());
+        assert!(parse_language_tag("en_US").is_err());
+        assert!(parse_language_tag("en-").is_err());
+        assert!(parse_language_tag("1en").is_err());
+        assert!(parse_language_tag("en-US\r\nBcc: x").is_err());
+        assert!(parse_language_tag("english-language").is_err());
+    }
+
+    fn date_headers(args: &MessageArgs) -> Vec<String> {
+        let builder = build_message(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            args,
+        )
+        .unwrap();
+        let message = into_message(builder, args).unwrap();
+        let message = String::from_utf8(message.body.into_owned()).unwrap();
+        let (headers, _) = message.split_once("\r\n\r\n").unwrap();
+        headers
+            .split("\r\n")
+            .filter_map(|line| line.strip_prefix("Date: "))
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_date_header() {
+        assert_eq!(date_headers(&MessageArgs::default()).len(), 1);
+
+        let args = MessageArgs {
+            date: parse_date("none").unwrap(),
+            ..Default::default()
+        };
+        assert!(date_headers(&args).is_empty());
+
+        let args = MessageArgs {
+            date: parse_date("Tue, 1 Jul 2003 10:52:37 +0200").unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(date_headers(&args), ["Tue, 1 Jul 2003 10:52:37 +0200"]);
+
+        let args = MessageArgs {
+            date: parse_date("2099-12-31T23:59:59Z").unwrap(),
+            body_encoding: Some(BodyEncoding::Base64),
+            ..Default::default()
+        };
+        assert_eq!(date_headers(&args), ["Thu, 31 Dec 2099 23:59:59 +0000"]);
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("now").unwrap(), DateHeader::Now);
+        assert_eq!(parse_date("none").unwrap(), DateHeader::None);
+        assert!(matches!(
+            parse_date("Mon, 1 Jan 1990 00:00:00 -0500").unwrap(),
+            DateHeader::At(_)
+        ));
+        assert!(parse_date("").is_err());
+        assert!(parse_date("yesterday").is_err());
+        assert!(parse_date("2003-07-01").is_err());
+        assert!(parse_date("Tue, 32 Jul 2003 10:52:37 +0200").is_err());
+        assert!(parse_date("2003-07-01T25:00:00Z").is_err());
+    }
+
+    #[test]
+    fn test_remove_header() {
+        let message =
+            b"From: a@b.c\r\nDate: Tue,\r\n 1 Jul 2003\r\nSubject: x\r\n\r\nDate: body\r\n";
+        assert_eq!(
+            remove_header(message, "date"),
+            b"From: a@b.c\r\nSubject: x\r\n\r\nDate: body\r\n"
+        );
+        assert_eq!(
+            replace_header(message, "From", Some("<d@e.f>")),
+            b"From: <d@e.f>\r\nDate: Tue,\r\n 1 Jul 2003\r\nSubject: x\r\n\r\nDate: body\r\n"
+        );
+    }
+
+    /// The From header of the message sent by `name`, checked with --check-from-header.
+    fn from_header(name: &str) -> String {
+        let args = MessageArgs {
+            check_from_header: true,
+            ..Default::default()
+        };
+        let message = build_message(
+            (name, "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &args,
+        )
+        .unwrap();
+        let message = into_message(message, &args).unwrap();
+        let body = String::from_utf8(message.body.to_vec()).unwrap();
+        assert_eq!(message.mail_from.email, "sender@example.com");
+        let from: Vec<&str> = body
+            .split("\r\n")
+            .filter(|line| line.starts_with("From: "))
+            .collect();
+        assert_eq!(from.len(), 1, "{body}");
+        from[0].to_string()
+    }
+
+    #[test]
+    fn test_from_header_phrase() {
+        assert_eq!(
+            from_header("Alex Smith"),
+            "From: Alex Smith <sender@example.com>"
+        );
+        assert_eq!(
+            from_header("Smith, Alex"),
+            "From: \"Smith, Alex\" <sender@example.com>"
+        );
+        assert_eq!(
+            from_header("Alex \"Al\" Smith"),
+            "From: \"Alex \\\"Al\\\" Smith\" <sender@example.com>"
+        );
+        assert_eq!(
+            from_header("Jürgen Müller"),
+            "From: =?utf-8?B?SsO8cmdlbiBNw7xsbGVy?= <sender@example.com>"
+        );
+        assert_eq!(from_header(""), "From: <sender@example.com>");
+
+        let error = build_message(
+            ("Alex\r\nBcc: victim@example.com", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &MessageArgs::default(),
+        )
+        .and_then(|message| into_message(message, &MessageArgs::default()))
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ParseError);
+    }
+
+    #[test]
+    fn test_body_encoding_forces_base64() {
+        let args = MessageArgs {
+            body_encoding: Some(BodyEncoding::Base64),
+            ..Default::default()
+        };
+        let message = build_message(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &args,
+        )
+        .unwrap();
+        let message = into_message(message, &args).unwrap();
+        assert!(message_id(&message).unwrap().ends_with("@example.com>"));
+        let body = String::from_utf8(message.body.into_owned()).unwrap();
+
+        assert_eq!(message.mail_from.email, "sender@example.com");
+        assert_eq!(message.rcpt_to[0].email, "recipient@example.com");
+        assert_eq!(
+            body.matches("Content-Transfer-Encoding: base64\r\n")
+                .count(),
+            2
+        );
+        assert!(body.contains("SGVsbG8gd29ybGQh"));
+    }
+
+    #[test]
+    fn test_multipart_structure_and_boundary() {
+        let args = MessageArgs {
+            scenario: Some(TestScenario::Calendar),
+            multipart_structure: MultipartStructure::Mixed,
+            mime_boundary: Some("interop".to_string()),
+            ..Default::default()
+        };
+        let builder = build_message(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &args,
+        )
+        .unwrap();
+        let message = into_message(builder, &args).unwrap();
+        let message = String::from_utf8(message.body.into_owned()).unwrap();
+
+        assert!(
+            message.contains("Content-Type: multipart/mixed"),
+            "{message}"
+        );
+        assert!(!message.contains("multipart/alternative"));
+        assert!(message.contains("boundary=\"interop\""));
+        // The text, the HTML and the invite.
+        assert_eq!(message.matches("\r\n--interop\r\n").count(), 3);
+        assert!(message.contains("Content-Type: text/calendar"));
+    }
+
+    #[test]
+    fn test_calendar_alternative() {
+        let directory = tempfile::tempdir().unwrap();
+        let ics_file = directory.path().join("invite.ics");
+        fs::write(
+            &ics_file,
+            "BEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nBEGIN:VEVENT\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        )
+        .unwrap();
+        let args = MessageArgs {
+            ics_file: Some(ics_file),
+            ..Default::default()
+        };
+        let message = write_message(&args);
+
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        let (_, calendar) = message.split_once("Content-Type: text/calendar").unwrap();
+        let (headers, contents) = calendar.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("method"));
+        assert!(headers.contains("REQUEST"));
+        assert!(contents.starts_with("BEGIN:VCALENDAR"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(message.contains("Content-Type: text/html"));
+    }
+
+    fn scenario_message(scenario: TestScenario) -> String {
+        write_message(&MessageArgs {
+            scenario: Some(scenario),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_simple_text_scenario() {
+        let message = scenario_message(TestScenario::SimpleText);
+        let (headers, body) = message.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("Content-Type: text/plain"), "{headers}");
+        assert!(!message.contains("multipart"), "{message}");
+        assert!(body.contains("Hello world!"));
+    }
+
+    #[test]
+    fn test_html_with_inline_scenario() {
+        let message = scenario_message(TestScenario::HtmlWithInline);
+        assert!(message.contains("multipart/related"), "{message}");
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(message.contains("Content-Type: text/html"));
+        assert!(message.contains("Content-Type: image/png"));
+        assert!(message.contains("<test-image>"));
+        assert!(message.contains("cid:test-image"));
+        assert!(!message.contains("Importance:"));
+    }
+
+    #[test]
+    fn test_large_attachment_scenario() {
+        let message = scenario_message(TestScenario::LargeAttachment);
+        assert!(message.contains("multipart/mixed"));
+        assert!(message.contains("large-attachment.bin"));
+        assert!(message.contains("Content-Type: application/octet-stream"));
+        // Base64 makes it a third larger.
+        assert!(message.len() > LARGE_ATTACHMENT_SIZE * 4 / 3);
+    }
+
+    #[test]
+    fn test_estimated_size_is_close_to_the_sent_one() {
+        let args = MessageArgs {
+            scenario: Some(TestScenario::LargeAttachment),
+            ..Default::default()
+        };
+        let recipients = [("recipient@example.com", "Recipient")];
+        // Estimated before the login, without the sender of the profile.
+        let estimate = estimate_size(None, &recipients, &args, 50).unwrap();
+
+        let sent = build_messages(
+            ("Alex Smith", "alex.smith@contoso.onmicrosoft.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &args,
+            50,
+        )
+        .unwrap();
+        let sent_size = wire_size(&sent[0]);
+        assert!(sent_size > LARGE_ATTACHMENT_SIZE * 4 / 3);
+        assert!(
+            estimate.abs_diff(sent_size) < sent_size / 100,
+            "{estimate} {sent_size}"
+        );
+    }
+
+    #[test]
+    fn test_wire_size_counts_the_stuffed_dots() {
+        let mut message = build_messages(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &MessageArgs::default(),
+            50,
+        )
+        .unwrap()
+        .remove(0);
+        message.body = b"Subject: dots\r\n\r\n.one\r\ntwo\r\n..three\r\n"
+            .to_vec()
+            .into();
+        // The body is sent with ".." for each line starting with a dot, then ".".
+        let sent = b"Subject: dots\r\n\r\n..one\r\ntwo\r\n...three\r\n\r\n.\r\n";
+        assert_eq!(wire_size(&message), sent.len());
+    }
+
+    #[test]
+    fn test_calendar_scenario() {
+        let message = scenario_message(TestScenario::Calendar);
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        let (_, calendar) = message.split_once("Content-Type: text/calendar").unwrap();
+        let (headers, contents) = calendar.split_once("\r\n\r\n").unwrap();
+        assert!(headers.contains("REQUEST"));
+        assert!(contents.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(contents.contains("ORGANIZER:mailto:sender@example.com\r\n"));
+        assert!(contents
+            .contains("ATTENDEE;ROLE=REQ-PARTICIPANT;RSVP=TRUE:mailto:recipient@example.com\r\n"));
+        assert!(contents.contains("END:VCALENDAR\r\n"));
+    }
+
+    #[test]
+    fn test_high_importance_scenario() {
+        let message = scenario_message(TestScenario::HighImportance);
+        assert!(message.contains("Importance: high\r\n"));
+        assert!(message.contains("X-Priority: 1\r\n"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(message.contains("Content-Type: text/html"));
+    }
+
+    #[test]
+    fn test_content_type_parts() {
+        let message_with = |content_type| {
+            write_message(&MessageArgs {
+                content_type,
+                ..Default::default()
+            })
+        };
+
+        let message = message_with(BodyParts::Both);
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(message.contains("Content-Type: text/html"));
+
+        let message = message_with(BodyParts::Html);
+        assert!(!message.contains("multipart/"));
+        assert!(message.contains("Content-Type: text/html"));
+        assert!(!message.contains("text/plain"));
+
+        let message = message_with(BodyParts::Text);
+        assert!(!message.contains("multipart/"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(!message.contains("text/html"));
+    }
+
+    #[test]
+    fn test_content_type_with_calendar() {
+        let directory = tempfile::tempdir().unwrap();
+        let ics_file = directory.path().join("invite.ics");
+        fs::write(&ics_file, "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n").unwrap();
+        let args = MessageArgs {
+            ics_file: Some(ics_file),
+            content_type: BodyParts::Text,
+            ..Default::default()
+        };
+        let message = write_message(&args);
+
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        assert!(message.contains("Content-Type: text/plain"));
+        assert!(message.contains("Content-Type: text/calendar"));
+        assert!(!message.contains("text/html"));
+    }
+
+    #[test]
+    fn test_calendar_must_begin_with_vcalendar() {
+        let directory = tempfile::tempdir().unwrap();
+        let ics_file = directory.path().join("invite.ics");
+        fs::write(&ics_file, "BEGIN:VEVENT\r\nEND:VEVENT\r\n").unwrap();
+        let args = MessageArgs {
+            ics_file: Some(ics_file),
+            ..Default::default()
+        };
+        let error = build_message(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &args,
+        )
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+    }
+
+    #[test]
+    fn test_borrowed_message_shares_the_body() {
+        let builder = build_message(
+            ("Sender", "sender@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &MessageArgs::default(),
+        )
+        .unwrap();
+        let message = into_message(builder, &MessageArgs::default()).unwrap();
+        let borrowed = borrow_message(&message);
+
+        assert_eq!(borrowed.body.as_ptr(), message.body.as_ptr());
+        assert_eq!(borrowed.mail_from.email, "sender@example.com");
+        assert_eq!(borrowed.rcpt_to.len(), 1);
+    }
+
+    #[test]
+    fn test_recipients_are_split_into_batches() {
+        let emails: Vec<String> = (1..=12)
+            .map(|n| format!("recipient{n}@example.com"))
+            .collect();
+        let recipients: Vec<(&str, &str)> = emails
+            .iter()
+            .map(|email| ("Recipient", email.as_str()))
+            .collect();
+        let messages = build_messages(
+            ("Sender", "sender@example.com"),
+            recipients.clone(),
+            &MessageArgs::default(),
+            5,
+        )
+        .unwrap();
+
+        let batches: Vec<usize> = messages
+            .iter()
+            .map(|message| message.rcpt_to.len())
+            .collect();
+        assert_eq!(batches, [5, 5, 2]);
+        // mail-send does not keep the order of the recipients.
+        let mut last_batch: Vec<&str> = messages[2]
+            .rcpt_to
+            .iter()
+            .map(|address| address.email.as_ref())
+            .collect();
+        last_batch.sort();
+        assert_eq!(
+            last_batch,
+            ["recipient11@example.com", "recipient12@example.com"]
+        );
+        // Each message only has the recipients of its batch, and its own Message-ID.
+        let body = String::from_utf8_lossy(&messages[2].body).to_string();
+        assert!(body.contains("recipient12@example.com"));
+        assert!(!body.contains("recipient1@example.com"));
+        assert_ne!(message_id(&messages[0]), message_id(&messages[1]));
+
+        let messages = build_messages(
+            ("Sender", "sender@example.com"),
+            recipients,
+            &MessageArgs::default(),
+            500,
+        )
+        .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].rcpt_to.len(), 12);
+    }
+
+    #[test]
+    fn test_footer_in_both_parts() {
+        let (text, html) = bodies(Some("Contoso <Legal> & \"Co\"\nConfidential"));
+        assert_eq!(
+            text,
+            "Hello world!\n\n-- \nContoso <Legal> & \"Co\"\nConfidential\n"
+        );
+        assert_eq!(
+            html,
+            "<h1>Hello, world!</h1>\n<hr>\n\
+            <p>Contoso &lt;Legal&gt; &amp; &quot;Co&quot;<br>\nConfidential</p>\n"
+        );
+        assert_eq!(
+            bodies(None),
+            (
+                "Hello world!".to_string(),
+                "<h1>Hello, world!</h1>".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_footer_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let footer_file = directory.path().join("footer.txt");
+        fs::write(&footer_file, "Sent by R&D\n\n").unwrap();
+        let args = MessageArgs {
+            footer_file: Some(footer_file),
+            ..Default::default()
+        };
+        let message = write_message(&args);
+        assert!(message.contains("Hello world!"));
+        assert!(message.contains("Sent by R&D"));
+        assert!(message.contains("<p>Sent by R&amp;D</p>"));
+    }
+
+    fn placeholders() -> Placeholders {
+        Placeholders {
+            sender: "sender@example.com".to_string(),
+            recipient: "recipient@example.com".to_string(),
+            timestamp: "2024-05-01T12:00:00Z".to_string(),
+            test_id: "18c2f.42".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_known_placeholders() {
+        assert_eq!(
+            placeholders().substitute(
+                "From {{sender}} to {{ recipient }} at {{timestamp}}, test {{test_id}}."
+            ),
+            "From sender@example.com to recipient@example.com at 2024-05-01T12:00:00Z, \
+            test 18c2f.42."
+        );
+        assert_eq!(
+            placeholders().substitute("No placeholder"),
+            "No placeholder"
+        );
+    }
+
+    #[test]
+    fn test_unknown_placeholders_are_left_intact() {
+        assert_eq!(
+            placeholders().substitute("{{run}} {{test_id}} {{ unclosed"),
+            "{{run}} 18c2f.42 {{ unclosed"
+        );
+        assert_eq!(placeholders().substitute("{{}}"), "{{}}");
+    }
+
+    #[test]
+    fn test_test_id_is_the_message_id() {
+        let args = MessageArgs {
+            footer: Some("Test {{test_id}} for {{recipient}}".to_string()),
+            ..Default::default()
+        };
+        let message = write_message(&args);
+        let message_id = message_id_header(&message);
+        let test_id = message_id
+            .trim_start_matches('<')
+            .split_once('@')
+            .unwrap()
+            .0;
+        assert!(
+            message.contains(&format!("Test {test_id} for recipient@example.com")),
+            "{message}"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+// Standard libraries
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// My crates
+use crate::cleanup::TempFile;
+use crate::error::OAuth2Result;
+use crate::report::{Outcome, SendReport};
+
+/// Renders the report in the Prometheus text exposition format. The durations of the
+/// stages that did not run are left out.
+fn render(report: &SendReport, timestamp: u64) -> String {
+    let success = matches!(report.outcome, Outcome::Sent) as u8;
+    let seconds = |ms: Option<u64>| ms.map(|ms| ms as f64 / 1000.0);
+
+    let mut metrics = String::new();
+    let mut gauge = |name: &str, help: &str, value: Option<String>| {
+        if let Some(value) = value {
+            let _ = write!(
+                metrics,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+            );
+        }
+    };
+    gauge(
+        "xoauth2_send_success",
+        "Whether the test email was sent (1) or not (0).",
+        Some(success.to_string()),
+    );
+    gauge(
+        "xoauth2_auth_duration_seconds",
+        "Time taken to get the access token.",
+        seconds(report.timings.login_ms).map(|value| value.to_string()),
+    );
+    gauge(
+        "xoauth2_send_duration_seconds",
+        "Time taken to send the test email over SMTP.",
+        seconds(report.timings.send_ms).map(|value| value.to_string()),
+    );
+    gauge(
+        "xoauth2_last_run_timestamp_seconds",
+        "When the test ran, in seconds since the Unix epoch.",
+        Some(timestamp.to_string()),
+    );
+    metrics
+}
+
+/// Writes the metrics for the node_exporter textfile collector. The file is replaced
+/// at once so that a scrape never sees half of it, the temporary file is removed if the
+/// run fails or is interrupted before that.
+pub fn write_metrics(path: &Path, report: &SendReport) -> OAuth2Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let temp_file = TempFile::new(temp_path.into());
+
+    fs::write(temp_file.path(), render(report, timestamp))?;
+    fs::rename(temp_file.path(), path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use super::{render, write_metrics};
+    use crate::error::{ErrorCodes, OAuth2Error};
+    use crate::report::{SendReport, Stage};
+    use crate::OAuth2TokenGrantFlow;
+
+    fn report() -> SendReport {
+        SendReport::new(
+            "run-42".to_string(),
+            OAuth2TokenGrantFlow::DeviceCodeFlow,
+            vec!["recipient@example.com".to_string()],
+            "smtp.office365.com:587 STARTTLS".to_string(),
+        )
+    }
+
+    /// Checks the exposition format line by line and returns the samples.
+    fn parse(text: &str) -> HashMap<String, f64> {
+        let valid_name = |name: &str| {
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':')
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+        };
+        let mut types = HashMap::new();
+        let mut samples = HashMap::new();
+        assert!(text.ends_with('\n'));
+        for line in text.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                let (name, _) = help.split_once(' ').unwrap();
+                assert!(valid_name(name), "{line}");
+            } else if let Some(type_line) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = type_line.split_once(' ').unwrap();
+                assert!(valid_name(name), "{line}");
+                assert_eq!(kind, "gauge");
+                assert!(types.insert(name.to_string(), kind).is_none());
+            } else {
+                let (name, value) = line.split_once(' ').unwrap();
+                assert!(valid_name(name), "{line}");
+                assert!(types.contains_key(name), "{name} has no TYPE");
+                samples.insert(name.to_string(), value.parse::<f64>().unwrap());
+            }
+        }
+        samples
+    }
+
+    #[tokio::test]
+    async fn test_metrics_file_is_valid_exposition_format() {
+        let mut report = report();
+        let success = || async { Ok::<_, OAuth2Error>(()) };
+        report.stage(Stage::Login, success()).await.unwrap();
+        report.stage(Stage::Send, success()).await.unwrap();
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("xoauth2.prom");
+        write_metrics(&path, &report).unwrap();
+
+        let samples = parse(&fs::read_to_string(&path).unwrap());
+        assert_eq!(samples["xoauth2_send_success"], 1.0);
+        assert!(samples["xoauth2_auth_duration_seconds"] >= 0.0);
+        assert!(samples["xoauth2_send_duration_seconds"] >= 0.0);
+        assert!(samples["xoauth2_last_run_timestamp_seconds"] > 0.0);
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_metrics_of_failed_login() {
+        let mut report = report();
+        report
+            .stage(Stage::Login, async {
+                Err::<(), _>(OAuth2Error::new(ErrorCodes::InvalidGrant, "failed".into()))
+            })
+            .await;
+
+        let samples = parse(&render(&report, 1_700_000_000));
+        assert_eq!(samples["xoauth2_send_success"], 0.0);
+        assert!(samples.contains_key("xoauth2_auth_duration_seconds"));
+        assert!(!samples.contains_key("xoauth2_send_duration_seconds"));
+        assert_eq!(samples["xoauth2_last_run_timestamp_seconds"], 1.7e9);
+    }
+}
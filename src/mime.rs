@@ -0,0 +1,567 @@
+// Standard libraries
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+// 3rd party crates
+use mail_send::mail_builder::{
+    encoders::{base64::base64_encode_mime, quoted_printable::quoted_printable_encode},
+    headers::{
+        content_type::ContentType, date::Date, message_id::generate_message_id_header, Header,
+        HeaderType,
+    },
+    mime::{make_boundary, BodyPart, MimePart},
+    MessageBuilder,
+};
+use strum_macros::{Display, EnumString};
+
+#[derive(Clone, Copy, Debug, PartialEq, Display, EnumString)]
+pub enum BodyEncoding {
+    #[strum(serialize = "7bit")]
+    SevenBit,
+    #[strum(serialize = "8bit")]
+    EightBit,
+    #[strum(serialize = "quoted-printable")]
+    QuotedPrintable,
+    #[strum(serialize = "base64")]
+    Base64,
+}
+
+/// How the alternatives of the body are put together, with `--multipart-structure`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum MultipartStructure {
+    /// A multipart/alternative, the plain text first.
+    #[default]
+    Alternative,
+    /// The alternatives as the parts of a multipart/mixed, which clients show one after the other.
+    Mixed,
+    /// A multipart/alternative with the parts in reverse order, the plain text last.
+    Reversed,
+}
+
+/// How the multipart parts are written, with `--multipart-structure` and `--mime-boundary`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MimeLayout {
+    pub structure: MultipartStructure,
+    /// The boundary of the outermost multipart, the nested ones get a `=_<n>_` prefix.
+    pub boundary: Option<String>,
+}
+
+/// Checks that `boundary` is a valid MIME boundary (RFC 2046) that leaves room for the
+/// prefix of the nested ones. For use as a clap value parser.
+pub fn parse_boundary(boundary: &str) -> Result<String, String> {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "'()+_,-./:=? ".contains(c);
+    if (1..=64).contains(&boundary.len())
+        && boundary.chars().all(valid_char)
+        && !boundary.ends_with(' ')
+    {
+        Ok(boundary.to_string())
+    } else {
+        Err(format!(
+            "{boundary:?} is not a valid boundary: 1 to 64 letters, digits or '()+_,-./:=? \
+            characters, not ending with a space"
+        ))
+    }
+}
+
+/// The charset of the text and HTML parts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Display, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum Charset {
+    #[default]
+    #[strum(to_string = "utf-8", serialize = "utf8")]
+    Utf8,
+    #[strum(to_string = "us-ascii", serialize = "ascii")]
+    UsAscii,
+    #[strum(to_string = "iso-8859-1", serialize = "latin1")]
+    Iso8859_1,
+}
+
+impl Charset {
+    /// The text in this charset, none if it has a character the charset cannot represent.
+    fn encode<'a>(&self, text: &'a str) -> Option<Cow<'a, [u8]>> {
+        let max = match self {
+            Charset::Utf8 => return Some(Cow::Borrowed(text.as_bytes())),
+            Charset::UsAscii => 0x7f,
+            Charset::Iso8859_1 => 0xff,
+        };
+        text.chars()
+            .map(|c| u8::try_from(u32::from(c)).ok().filter(|byte| *byte <= max))
+            .collect::<Option<Vec<u8>>>()
+            .map(Cow::Owned)
+    }
+}
+
+/// Writes the message like `MessageBuilder::write_to` does, except that the text
+/// parts are encoded with `encoding` instead of the one mail-builder picks, the text
+/// and HTML parts are in `charset` and the multiparts are laid out as `layout` says.
+/// Without `encoding`, a text part is sent as 7bit when it can be, otherwise as
+/// quoted-printable.
+pub fn write_message(
+    mut message: MessageBuilder<'_>,
+    encoding: Option<BodyEncoding>,
+    charset: Charset,
+    layout: &MimeLayout,
+) -> io::Result<Vec<u8>> {
+    let body = match message.body.take() {
+        Some(body) => body,
+        None => message_body(&mut message)?,
+    };
+    let body = restructure(body, layout.structure);
+    let mut output = Vec::new();
+
+    for (name, value) in &message.headers {
+        write_header(name, value, &mut output)?;
+    }
+    if !message.headers.iter().any(|(name, _)| name == "Message-ID") {
+        output.write_all(b"Message-ID: ")?;
+        generate_message_id_header(
+            &mut output,
+            gethostname::gethostname().to_str().unwrap_or("localhost"),
+        )?;
+        output.write_all(b"\r\n")?;
+    }
+    if !message.headers.iter().any(|(name, _)| name == "Date") {
+        write_header("Date", &HeaderType::Date(Date::now()), &mut output)?;
+    }
+
+    let mut writer = PartWriter {
+        encoding,
+        charset,
+        boundary: layout.boundary.as_deref(),
+        multiparts: 0,
+    };
+    writer.write_part(body, &mut output)?;
+    Ok(output)
+}
+
+/// Puts the text, HTML and attachment parts together the way mail-builder does.
+fn message_body<'x>(message: &mut MessageBuilder<'x>) -> io::Result<MimePart<'x>> {
+    let body = match (message.text_body.take(), message.html_body.take()) {
+        (Some(text), Some(html)) => Some(MimePart::new_multipart(
+            "multipart/alternative",
+            vec![text, html],
+        )),
+        (Some(part), None) | (None, Some(part)) => Some(part),
+        (None, None) => None,
+    };
+    match (body, message.attachments.take()) {
+        (Some(body), Some(mut attachments)) => {
+            attachments.insert(0, body);
+            Ok(MimePart::new_multipart("multipart/mixed", attachments))
+        }
+        (Some(body), None) => Ok(body),
+        (None, Some(attachments)) => Ok(MimePart::new_multipart("multipart/mixed", attachments)),
+        (None, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The message has no MIME body.",
+        )),
+    }
+}
+
+fn write_header(name: &str, value: &HeaderType<'_>, output: &mut Vec<u8>) -> io::Result<()> {
+    output.write_all(name.as_bytes())?;
+    output.write_all(b": ")?;
+    value.write_header(output, name.len() + 2)?;
+    Ok(())
+}
+
+/// Lays the multipart/alternative parts out as `structure` says.
+fn restructure(mut part: MimePart<'_>, structure: MultipartStructure) -> MimePart<'_> {
+    let BodyPart::Multipart(parts) = part.contents else {
+        return part;
+    };
+    let mut parts: Vec<_> = parts
+        .into_iter()
+        .map(|part| restructure(part, structure))
+        .collect();
+    for (name, value) in &mut part.headers {
+        match value {
+            HeaderType::ContentType(content_type)
+                if name == "Content-Type"
+                    && content_type
+                        .c_type
+                        .eq_ignore_ascii_case("multipart/alternative") =>
+            {
+                match structure {
+                    MultipartStructure::Alternative => (),
+                    MultipartStructure::Mixed => content_type.c_type = "multipart/mixed".into(),
+                    MultipartStructure::Reversed => parts.reverse(),
+                }
+            }
+            _ => (),
+        }
+    }
+    part.contents = BodyPart::Multipart(parts);
+    part
+}
+
+/// 7bit when the text is ASCII in lines of at most 998 bytes, otherwise quoted-printable.
+fn default_encoding(bytes: &[u8]) -> BodyEncoding {
+    let seven_bit = bytes.is_ascii()
+        && bytes
+            .split(|byte| *byte == b'\n')
+            .all(|line| line.len() <= 998);
+    if seven_bit {
+        BodyEncoding::SevenBit
+    } else {
+        BodyEncoding::QuotedPrintable
+    }
+}
+
+/// Whether `part` has a line starting with the delimiter of `boundary`.
+fn has_delimiter(part: &[u8], boundary: &str) -> bool {
+    let delimiter = format!("--{boundary}");
+    part.starts_with(delimiter.as_bytes())
+        || part
+            .windows(delimiter.len() + 2)
+            .any(|window| window.starts_with(b"\r\n") && &window[2..] == delimiter.as_bytes())
+}
+
+/// Whether the part is the text or the HTML body, which are written in the `--charset`.
+fn is_body(content_type: &ContentType<'_>) -> bool {
+    ["text/plain", "text/html"]
+        .iter()
+        .any(|body_type| content_type.c_type.eq_ignore_ascii_case(body_type))
+}
+
+/// Writes the parts of the message, counting the multiparts to give the nested ones their
+/// own boundary.
+struct PartWriter<'a> {
+    encoding: Option<BodyEncoding>,
+    charset: Charset,
+    boundary: Option<&'a str>,
+    multiparts: usize,
+}
+
+impl PartWriter<'_> {
+    fn boundary(&mut self) -> String {
+        self.multiparts += 1;
+        match (self.boundary, self.multiparts) {
+            (None, _) => make_boundary("_"),
+            (Some(boundary), 1) => boundary.to_string(),
+            (Some(boundary), n) => format!("=_{n}_{boundary}"),
+        }
+    }
+
+    fn write_part(&mut self, part: MimePart<'_>, output: &mut Vec<u8>) -> io::Result<()> {
+        match part.contents {
+            BodyPart::Multipart(parts) => {
+                let boundary = self.boundary();
+                for (name, value) in part.headers {
+                    match value {
+                        HeaderType::ContentType(content_type) if name == "Content-Type" => {
+                            let content_type =
+                                content_type.attribute("boundary", boundary.as_str());
+                            write_header(&name, &content_type.into(), output)?
+                        }
+                        value => write_header(&name, &value, output)?,
+                    }
+                }
+                output.write_all(b"\r\n")?;
+                for part in parts {
+                    let mut written = Vec::new();
+                    self.write_part(part, &mut written)?;
+                    if has_delimiter(&written, &boundary) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "The boundary {boundary:?} appears in the message, \
+                                choose another --mime-boundary."
+                            ),
+                        ));
+                    }
+                    write!(output, "\r\n--{boundary}\r\n")?;
+                    output.extend_from_slice(&written);
+                }
+                write!(output, "\r\n--{boundary}--\r\n")?;
+            }
+            BodyPart::Text(text) => {
+                let charset = self.charset;
+                let mut text_charset = Charset::Utf8;
+                for (name, value) in part.headers {
+                    match value {
+                        HeaderType::ContentType(mut content_type)
+                            if name == "Content-Type" && is_body(&content_type) =>
+                        {
+                            content_type
+                                .attributes
+                                .retain(|(name, _)| !name.eq_ignore_ascii_case("charset"));
+                            let content_type =
+                                content_type.attribute("charset", charset.to_string());
+                            text_charset = charset;
+                            write_header(&name, &content_type.into(), output)?
+                        }
+                        value => write_header(&name, &value, output)?,
+                    }
+                }
+                let bytes = text_charset.encode(&text).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("The message body has characters that {charset} cannot represent."),
+                    )
+                })?;
+                let encoding = self.encoding.unwrap_or_else(|| default_encoding(&bytes));
+                write!(output, "Content-Transfer-Encoding: {encoding}\r\n\r\n")?;
+                match encoding {
+                    BodyEncoding::SevenBit | BodyEncoding::EightBit => write_lines(&bytes, output)?,
+                    BodyEncoding::QuotedPrintable => {
+                        quoted_printable_encode(&bytes, &mut *output, false, true)?;
+                    }
+                    BodyEncoding::Base64 => {
+                        base64_encode_mime(&bytes, &mut *output, false)?;
+                    }
+                }
+            }
+            // Attachments are always sent as base64.
+            BodyPart::Binary(binary) => {
+                for (name, value) in &part.headers {
+                    write_header(name, value, output)?;
+                }
+                output.write_all(b"Content-Transfer-Encoding: base64\r\n\r\n")?;
+                base64_encode_mime(&binary, &mut *output, false)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the text as is, with bare line feeds turned into CRLF.
+fn write_lines(text: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+    for (index, line) in text.split(|byte| *byte == b'\n').enumerate() {
+        if index > 0 {
+            output.write_all(b"\r\n")?;
+        }
+        output.write_all(line.strip_suffix(b"\r").unwrap_or(line))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use mail_send::mail_builder::{mime::MimePart, MessageBuilder};
+
+    use super::{
+        parse_boundary, write_message, BodyEncoding, Charset, MimeLayout, MultipartStructure,
+    };
+
+    fn message(text: &'static str) -> MessageBuilder<'static> {
+        MessageBuilder::new()
+            .from(("Sender", "sender@example.com"))
+            .to(("Recipient", "recipient@example.com"))
+            .subject("Test")
+            .body(MimePart::new_multipart(
+                "multipart/alternative",
+                vec![
+                    MimePart::new_text(text),
+                    MimePart::new_html("<h1>Hello, world!</h1>"),
+                ],
+            ))
+    }
+
+    fn encoded(encoding: &str) -> String {
+        let message = message("Hello world!\nCaf\u{e9}");
+        let encoding = BodyEncoding::from_str(encoding).unwrap();
+        String::from_utf8(
+            write_message(
+                message,
+                Some(encoding),
+                Charset::Utf8,
+                &MimeLayout::default(),
+            )
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn count(message: &str, header: &str) -> usize {
+        message.matches(header).count()
+    }
+
+    #[test]
+    fn test_body_encoding_headers() {
+        for encoding in ["7bit", "8bit", "quoted-printable", "base64"] {
+            let message = encoded(encoding);
+            let header = format!("Content-Transfer-Encoding: {encoding}\r\n");
+            assert_eq!(count(&message, &header), 2, "{message}");
+            assert_eq!(count(&message, "Content-Transfer-Encoding:"), 2);
+            assert!(message.contains("Message-ID: <"));
+            assert!(message.contains("Date: "));
+        }
+    }
+
+    #[test]
+    fn test_body_encoding_contents() {
+        assert!(encoded("8bit").contains("Hello world!\r\nCaf\u{e9}"));
+        assert!(encoded("quoted-printable").contains("Caf=C3=A9"));
+        assert!(encoded("base64").contains("PGgxPkhlbGxvLCB3b3JsZCE8L2gxPg=="));
+    }
+
+    #[test]
+    fn test_multipart_boundary() {
+        let message = encoded("7bit");
+        let boundary = message
+            .split("boundary=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+        assert_eq!(count(&message, &format!("\r\n--{boundary}\r\n")), 2);
+        assert!(message.ends_with(&format!("\r\n--{boundary}--\r\n")));
+    }
+
+    #[test]
+    fn test_utf8_charset() {
+        let message = encoded("quoted-printable");
+        assert_eq!(count(&message, "charset=\"utf-8\""), 2, "{message}");
+    }
+
+    #[test]
+    fn test_latin1_charset() {
+        let charset = Charset::from_str("ISO-8859-1").unwrap();
+        assert_eq!(charset, Charset::Iso8859_1);
+        assert_eq!(Charset::from_str("latin1").unwrap(), Charset::Iso8859_1);
+
+        let message = message("Hello world!\nCaf\u{e9}");
+        let message = write_message(
+            message,
+            Some(BodyEncoding::EightBit),
+            charset,
+            &MimeLayout::default(),
+        )
+        .unwrap();
+        assert!(message
+            .windows(b"Caf\xe9".len())
+            .any(|window| window == b"Caf\xe9"));
+        let message = String::from_utf8_lossy(&message);
+        assert_eq!(count(&message, "charset=\"iso-8859-1\""), 2, "{message}");
+        assert!(!message.contains("utf-8"));
+
+        let message = message_with_encoding("Caf\u{e9}", BodyEncoding::QuotedPrintable);
+        assert!(message.contains("Caf=E9"), "{message}");
+    }
+
+    fn message_with_encoding(text: &'static str, encoding: BodyEncoding) -> String {
+        let message = write_message(
+            message(text),
+            Some(encoding),
+            Charset::Iso8859_1,
+            &MimeLayout::default(),
+        )
+        .unwrap();
+        String::from_utf8(message).unwrap()
+    }
+
+    #[test]
+    fn test_charset_that_cannot_represent_the_body() {
+        let error = write_message(
+            message("Hello \u{4e16}\u{754c}"),
+            Some(BodyEncoding::Base64),
+            Charset::Iso8859_1,
+            &MimeLayout::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("iso-8859-1"));
+
+        assert!(write_message(
+            message("Caf\u{e9}"),
+            Some(BodyEncoding::SevenBit),
+            Charset::UsAscii,
+            &MimeLayout::default(),
+        )
+        .is_err());
+    }
+
+    fn laid_out(layout: &MimeLayout) -> String {
+        let message = write_message(message("Hello world!"), None, Charset::Utf8, layout).unwrap();
+        String::from_utf8(message).unwrap()
+    }
+
+    #[test]
+    fn test_custom_boundary() {
+        let layout = MimeLayout {
+            boundary: Some(parse_boundary("interop-test.boundary").unwrap()),
+            ..Default::default()
+        };
+        let message = laid_out(&layout);
+        assert!(
+            message.contains("boundary=\"interop-test.boundary\""),
+            "{message}"
+        );
+        assert_eq!(count(&message, "\r\n--interop-test.boundary\r\n"), 2);
+        assert!(message.ends_with("\r\n--interop-test.boundary--\r\n"));
+        // Without --body-encoding, ASCII text is sent as 7bit.
+        assert_eq!(count(&message, "Content-Transfer-Encoding: 7bit\r\n"), 2);
+    }
+
+    #[test]
+    fn test_nested_multiparts_get_their_own_boundary() {
+        let message = MessageBuilder::new()
+            .from(("Sender", "sender@example.com"))
+            .to(("Recipient", "recipient@example.com"))
+            .subject("Test")
+            .text_body("Hello world!")
+            .html_body("<h1>Hello, world!</h1>")
+            .binary_attachment("application/octet-stream", "a.bin", vec![1, 2, 3]);
+        let layout = MimeLayout {
+            boundary: Some("outer".to_string()),
+            ..Default::default()
+        };
+        let message = write_message(message, None, Charset::Utf8, &layout).unwrap();
+        let message = String::from_utf8(message).unwrap();
+        assert!(message.contains("boundary=\"outer\""), "{message}");
+        assert!(message.contains("boundary=\"=_2_outer\""), "{message}");
+        assert_eq!(count(&message, "\r\n--outer\r\n"), 2);
+        assert_eq!(count(&message, "\r\n--=_2_outer\r\n"), 2);
+    }
+
+    #[test]
+    fn test_boundary_in_the_content_is_refused() {
+        let layout = MimeLayout {
+            boundary: Some("Hello".to_string()),
+            ..Default::default()
+        };
+        let message = write_message(message("--Hello world!"), None, Charset::Utf8, &layout);
+        assert!(message.unwrap_err().to_string().contains("--mime-boundary"));
+    }
+
+    #[test]
+    fn test_parse_boundary() {
+        assert!(parse_boundary("simple").is_ok());
+        assert!(parse_boundary("with spaces and (parens)?").is_ok());
+        assert!(parse_boundary("").is_err());
+        assert!(parse_boundary("trailing ").is_err());
+        assert!(parse_boundary("quote\"").is_err());
+        assert!(parse_boundary(&"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_multipart_structures() {
+        let structure = |structure| {
+            laid_out(&MimeLayout {
+                structure,
+                ..Default::default()
+            })
+        };
+        let message = structure(MultipartStructure::Alternative);
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        assert!(message.find("text/plain").unwrap() < message.find("text/html").unwrap());
+
+        let message = structure(MultipartStructure::Mixed);
+        assert!(
+            message.contains("Content-Type: multipart/mixed"),
+            "{message}"
+        );
+        assert!(!message.contains("multipart/alternative"));
+        assert!(message.find("text/plain").unwrap() < message.find("text/html").unwrap());
+
+        let message = structure(MultipartStructure::Reversed);
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        assert!(message.find("text/html").unwrap() < message.find("text/plain").unwrap());
+        assert_eq!(
+            MultipartStructure::from_str("reversed").unwrap(),
+            MultipartStructure::Reversed
+        );
+    }
+}
@@ -0,0 +1,90 @@
+// Standard libraries
+use std::net::SocketAddr;
+
+// 3rd party crates
+use curl::easy::IpResolve;
+use strum_macros::EnumString;
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum IpVersion {
+    #[default]
+    Auto,
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    pub fn ip_resolve(&self) -> IpResolve {
+        match self {
+            IpVersion::Auto => IpResolve::Any,
+            IpVersion::V4 => IpResolve::V4,
+            IpVersion::V6 => IpResolve::V6,
+        }
+    }
+
+    fn accepts(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpVersion::Auto => true,
+            IpVersion::V4 => addr.is_ipv4(),
+            IpVersion::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolves `host:port` to an address of the preferred IP version. With `Auto`, the
+/// address is left for the connection to resolve.
+pub async fn resolve(host: &str, port: u16, ip_version: IpVersion) -> OAuth2Result<String> {
+    if ip_version == IpVersion::Auto {
+        return Ok(format!("{host}:{port}"));
+    }
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .find(|addr| ip_version.accepts(addr))
+        .ok_or_else(|| {
+            OAuth2Error::new(
+                ErrorCodes::IoError,
+                format!("{host} has no {ip_version:?} address."),
+            )
+        })?;
+    log::debug!("Resolved {host} to {addr}");
+    Ok(addr.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, IpVersion};
+    use crate::error::ErrorCodes;
+
+    #[tokio::test]
+    async fn test_resolve_with_ip_version() {
+        assert_eq!(
+            resolve("127.0.0.1", 587, IpVersion::V4).await.unwrap(),
+            "127.0.0.1:587"
+        );
+        assert_eq!(
+            resolve("::1", 587, IpVersion::V6).await.unwrap(),
+            "[::1]:587"
+        );
+        assert_eq!(
+            resolve("127.0.0.1", 587, IpVersion::V6)
+                .await
+                .unwrap_err()
+                .error_code,
+            ErrorCodes::IoError
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_auto_keeps_hostname() {
+        assert_eq!(
+            resolve("smtp.office365.com", 587, IpVersion::Auto)
+                .await
+                .unwrap(),
+            "smtp.office365.com:587"
+        );
+    }
+}
@@ -0,0 +1,292 @@
+// 3rd party crates
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The longest RFC 2047 encoded-word.
+const MAX_ENCODED_WORD: usize = 75;
+/// The bytes of the display name in each encoded-word, whose base64 fits in it.
+const ENCODED_WORD_BYTES: usize = 45;
+
+/// Whether `c` can be in an RFC 5322 atom.
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c)
+}
+
+/// The display name as RFC 2047 encoded-words, split on character boundaries.
+fn encoded_words(name: &str) -> String {
+    let mut words = Vec::new();
+    let mut start = 0;
+    for (index, c) in name.char_indices() {
+        if index + c.len_utf8() - start > ENCODED_WORD_BYTES {
+            words.push(&name[start..index]);
+            start = index;
+        }
+    }
+    words.push(&name[start..]);
+    words
+        .into_iter()
+        .map(|word| format!("=?utf-8?B?{}?=", STANDARD.encode(word)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The display name as an RFC 5322 phrase: as it is when it is made of atoms, a quoted
+/// string when it has specials such as quotes, commas or parentheses, or encoded-words
+/// when it is not ASCII. A name with control characters cannot be in a header.
+pub fn encode(name: &str) -> OAuth2Result<String> {
+    if name.chars().any(char::is_control) {
+        return Err(OAuth2Error::new(
+            ErrorCodes::ParseError,
+            format!(
+                "The display name {name:?} has control characters, which cannot be in a header."
+            ),
+        ));
+    }
+    if !name.is_ascii() {
+        return Ok(encoded_words(name));
+    }
+    if name
+        .split(' ')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+    {
+        return Ok(name.to_string());
+    }
+    Ok(format!(
+        "\"{}\"",
+        name.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// The From header value of the display name and address.
+pub fn mailbox(name: &str, email: &str) -> OAuth2Result<String> {
+    if name.is_empty() {
+        return Ok(format!("<{email}>"));
+    }
+    Ok(format!("{} <{email}>", encode(name)?))
+}
+
+/// Decodes an RFC 2047 encoded-word, e.g. `=?utf-8?B?...?=`.
+fn decode_encoded_word(word: &str) -> Result<String, String> {
+    if word.len() > MAX_ENCODED_WORD {
+        return Err(format!(
+            "the encoded-word {word} is longer than 75 characters"
+        ));
+    }
+    let invalid = || format!("{word} is not a valid encoded-word");
+    let inner = word
+        .strip_prefix("=?")
+        .and_then(|word| word.strip_suffix("?="))
+        .ok_or_else(invalid)?;
+    let [charset, encoding, text] = inner.splitn(3, '?').collect::<Vec<_>>()[..] else {
+        return Err(invalid());
+    };
+    if !charset.eq_ignore_ascii_case("utf-8") && !charset.eq_ignore_ascii_case("us-ascii") {
+        return Err(format!("the charset {charset} of {word} is not UTF-8"));
+    }
+    let bytes = match encoding {
+        "B" | "b" => STANDARD.decode(text).map_err(|_| invalid())?,
+        "Q" | "q" => {
+            let mut bytes = Vec::new();
+            let mut input = text.bytes();
+            while let Some(byte) = input.next() {
+                match byte {
+                    b'_' => bytes.push(b' '),
+                    b'=' => {
+                        let hex = [input.next(), input.next()];
+                        let [Some(high), Some(low)] = hex else {
+                            return Err(invalid());
+                        };
+                        let hex = std::str::from_utf8(&[high, low])
+                            .ok()
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                            .ok_or_else(invalid)?;
+                        bytes.push(hex);
+                    }
+                    b'?' | b' ' => return Err(invalid()),
+                    byte => bytes.push(byte),
+                }
+            }
+            bytes
+        }
+        _ => return Err(invalid()),
+    };
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Parses a From header value as an RFC 5322 mailbox, a phrase of atoms, quoted strings
+/// and encoded-words then the address in angle brackets, or the bare address. Returns
+/// the decoded display name and the address, or why the value is not valid.
+pub fn parse_mailbox(value: &str) -> Result<(String, String), String> {
+    let value = value.trim();
+    let Some((phrase, address)) = value.rsplit_once('<') else {
+        if value.contains('@') && !value.contains(|c: char| c.is_whitespace() || "<>\"".contains(c))
+        {
+            return Ok((String::new(), value.to_string()));
+        }
+        return Err(format!("{value} has no address"));
+    };
+    let address = address
+        .strip_suffix('>')
+        .filter(|address| {
+            address.contains('@')
+                && !address.contains(|c: char| c.is_whitespace() || "<>".contains(c))
+        })
+        .ok_or_else(|| format!("{value} does not end with an address in angle brackets"))?;
+
+    // The words of the phrase, with whether each is an encoded-word: the whitespace
+    // between two of them is not part of the name.
+    let mut words: Vec<(String, bool)> = Vec::new();
+    let phrase = phrase.trim_end();
+    let mut rest = phrase;
+    while !rest.is_empty() {
+        let trimmed = rest.trim_start_matches(' ');
+        if trimmed.len() == rest.len() && !words.is_empty() {
+            let c = rest.chars().next().unwrap_or_default();
+            return Err(format!(
+                "{phrase} has {c:?}, which must be quoted or encoded"
+            ));
+        }
+        rest = trimmed;
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let mut word = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((index, '"')) => break index,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) if !c.is_control() => word.push(c),
+                        _ => return Err(format!("{phrase} has an invalid quoted pair")),
+                    },
+                    Some((_, c)) if c.is_control() => {
+                        return Err(format!("{phrase} has a control character"))
+                    }
+                    Some((_, c)) => word.push(c),
+                    None => return Err(format!("{phrase} has an unterminated quoted string")),
+                }
+            };
+            words.push((word, false));
+            rest = &quoted[end + 1..];
+        } else if rest.starts_with("=?") {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            words.push((decode_encoded_word(&rest[..end])?, true));
+            rest = &rest[end..];
+        } else {
+            let end = rest.find(|c: char| !is_atext(c)).unwrap_or(rest.len());
+            if end == 0 {
+                let c = rest.chars().next().unwrap_or_default();
+                return Err(format!(
+                    "{phrase} has {c:?}, which must be quoted or encoded"
+                ));
+            }
+            words.push((rest[..end].to_string(), false));
+            rest = &rest[end..];
+        }
+    }
+
+    let mut name = String::new();
+    for (index, (word, encoded)) in words.iter().enumerate() {
+        if index > 0 && !(*encoded && words[index - 1].1) {
+            name.push(' ');
+        }
+        name.push_str(word);
+    }
+    Ok((name, address.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, mailbox, parse_mailbox};
+    use crate::error::ErrorCodes;
+
+    /// The name encoded into a From header and parsed back.
+    fn round_trip(name: &str) -> String {
+        let value = mailbox(name, "me@example.com").unwrap();
+        let (decoded, email) = parse_mailbox(&value).unwrap();
+        assert_eq!(email, "me@example.com");
+        assert_eq!(decoded, name, "{value}");
+        value
+    }
+
+    #[test]
+    fn test_atoms_are_not_quoted() {
+        assert_eq!(round_trip("Alex Smith"), "Alex Smith <me@example.com>");
+        assert_eq!(round_trip(""), "<me@example.com>");
+    }
+
+    #[test]
+    fn test_specials_are_quoted() {
+        assert_eq!(
+            round_trip("Smith, Alex"),
+            "\"Smith, Alex\" <me@example.com>"
+        );
+        assert_eq!(
+            round_trip("Alex \"The Admin\" Smith"),
+            "\"Alex \\\"The Admin\\\" Smith\" <me@example.com>"
+        );
+        assert_eq!(
+            round_trip("Alex (IT) Smith"),
+            "\"Alex (IT) Smith\" <me@example.com>"
+        );
+        assert_eq!(
+            round_trip("Alex Q. Smith"),
+            "\"Alex Q. Smith\" <me@example.com>"
+        );
+        assert_eq!(
+            round_trip("back\\slash"),
+            "\"back\\\\slash\" <me@example.com>"
+        );
+        // Leading, trailing and repeated spaces are kept in the quotes.
+        assert_eq!(
+            round_trip(" Alex  Smith"),
+            "\" Alex  Smith\" <me@example.com>"
+        );
+    }
+
+    #[test]
+    fn test_non_ascii_is_encoded() {
+        assert_eq!(
+            round_trip("Jürgen Müller"),
+            "=?utf-8?B?SsO8cmdlbiBNw7xsbGVy?= <me@example.com>"
+        );
+        round_trip("Müller, \"Jürgen\" (IT)");
+        // A long name takes several encoded-words, none split inside a character.
+        let value = round_trip(&"東京".repeat(20));
+        assert!(value
+            .split(' ')
+            .filter(|word| word.starts_with("=?"))
+            .all(|word| word.len() <= 75));
+        assert!(value.matches("=?utf-8?B?").count() > 1);
+    }
+
+    #[test]
+    fn test_control_characters_are_rejected() {
+        let error = encode("Alex\r\nBcc: victim@example.com").unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ParseError);
+        assert!(encode("Alex\tSmith").is_err());
+    }
+
+    #[test]
+    fn test_parse_mailbox_rejects_malformed_phrases() {
+        assert_eq!(
+            parse_mailbox("Smith, Alex <me@example.com>").unwrap_err(),
+            "Smith, Alex has ',', which must be quoted or encoded"
+        );
+        assert!(parse_mailbox("\"Alex <me@example.com>").is_err());
+        assert!(parse_mailbox("Jürgen <me@example.com>").is_err());
+        assert!(parse_mailbox("=?utf-8?X?abc?= <me@example.com>").is_err());
+        assert!(parse_mailbox("Alex <not an address>").is_err());
+        assert!(parse_mailbox("Alex").is_err());
+        assert_eq!(
+            parse_mailbox("me@example.com").unwrap(),
+            (String::new(), "me@example.com".to_string())
+        );
+        assert_eq!(
+            parse_mailbox("=?UTF-8?Q?J=C3=BCrgen_M=C3=BCller?= <me@example.com>")
+                .unwrap()
+                .0,
+            "Jürgen Müller"
+        );
+    }
+}
@@ -0,0 +1,367 @@
+// Standard libraries
+use std::path::{Path, PathBuf};
+
+// 3rd party crates
+use serde::Serialize;
+
+// My crates
+use crate::cli::{self, Client, GlobalArgs, RunArgs};
+use crate::cloud::Authority;
+use crate::discovery;
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::message;
+use crate::report::OutputFormat;
+use crate::smtp;
+use crate::OAuth2TokenGrantFlow;
+
+/// What a run would do, printed by `--dry-run` instead of doing it.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    pub grant_type: OAuth2TokenGrantFlow,
+    pub client_id: Option<String>,
+    pub tenant: String,
+    /// The cached token file, used when it holds a valid token.
+    pub token_file: Option<PathBuf>,
+    /// The login endpoints, none with a pre-acquired access token or when they are read
+    /// from the discovery document.
+    pub login_endpoints: Vec<String>,
+    /// The discovery document read for the login endpoints, with `--discover-endpoints`.
+    pub discovery_document: Option<String>,
+    pub scopes: Vec<String>,
+    /// The resource asked for with `--oauth-version v1`.
+    pub resource: Option<String>,
+    /// The sender profile endpoint, none with `--sender-email`.
+    pub profile_endpoint: Option<String>,
+    pub sender: Option<String>,
+    pub smtp_login: Option<String>,
+    pub envelope_from: Option<String>,
+    pub transport: String,
+    pub recipients: Vec<String>,
+    /// The estimated size of the message in the SMTP DATA, in bytes, the largest one with
+    /// `--batch-size`.
+    pub message_size: usize,
+    /// Whether the email is sent, not with `--no-send`.
+    pub send: bool,
+}
+
+fn check_email(email: &str, name: &str) -> OAuth2Result<()> {
+    smtp::parse_email(email)
+        .map(|_| ())
+        .map_err(|e| OAuth2Error::new(ErrorCodes::ConfigurationError, format!("The {name}: {e}")))
+}
+
+impl Plan {
+    /// Checks the configuration and resolves the endpoints and scopes, without any
+    /// network request.
+    pub fn new(
+        client: &Client,
+        recipients: &[(&str, &str)],
+        args: &RunArgs,
+        global: &GlobalArgs,
+        directory: &Path,
+    ) -> OAuth2Result<Self> {
+        let pre_acquired = client.grant_type == OAuth2TokenGrantFlow::PreAcquired;
+        if !pre_acquired {
+            cli::check_client_id(&client.client_id, global.allow_non_guid_client_id)?;
+        }
+        for (recipient_email, _) in recipients {
+            check_email(recipient_email, "recipient email")?;
+        }
+        let sender = args.sender().map(|(_, sender_email)| sender_email);
+        if let Some(sender) = &sender {
+            check_email(sender, "sender email")?;
+        }
+        if let Some(smtp_login) = &args.smtp.smtp_login {
+            check_email(smtp_login, "SMTP login")?;
+            if let Some(sender) = &sender {
+                smtp::check_send_as(sender, smtp_login, args.smtp.allow_send_as)?;
+            }
+        }
+
+        let message_size = message::estimate_size(
+            args.sender()
+                .as_ref()
+                .map(|(name, email)| (name.as_str(), email.as_str())),
+            recipients,
+            &args.message,
+            args.batch_size as usize,
+        )?;
+        message::check_size(message_size, args.message.max_message_size);
+
+        let authority = Authority::new(global.cloud, &client.tenant)
+            .with_scopes(global.scopes())
+            .with_oauth_version(global.oauth_version)
+            .with_resource(global.resource.clone());
+        let discovery_document = (global.discover_endpoints && !pre_acquired)
+            .then(|| discovery::discovery_url(&authority));
+        let login_endpoints = match client.grant_type {
+            _ if discovery_document.is_some() => vec![],
+            OAuth2TokenGrantFlow::AuthorizationCodeGrant => vec!["authorize", "token"],
+            OAuth2TokenGrantFlow::DeviceCodeFlow => vec!["devicecode", "token"],
+            OAuth2TokenGrantFlow::PreAcquired => vec![],
+        };
+        let profile_endpoint = match (&sender, &args.profile_endpoint) {
+            (Some(_), _) => None,
+            (None, Some(endpoint)) => Some(endpoint.to_string()),
+            (None, None) => Some(global.cloud.profile_endpoint()),
+        };
+        Ok(Self {
+            grant_type: client.grant_type.clone(),
+            client_id: (!client.client_id.is_empty()).then(|| client.client_id.clone()),
+            tenant: client.tenant.clone(),
+            token_file: client
+                .grant_type
+                .token_file(&client.client_id)
+                .map(|token_file| directory.join(token_file)),
+            login_endpoints: login_endpoints
+                .into_iter()
+                .map(|endpoint| authority.endpoint(endpoint))
+                .collect(),
+            discovery_document,
+            scopes: if pre_acquired {
+                Vec::new()
+            } else {
+                authority
+                    .scopes()
+                    .iter()
+                    .map(|scope| scope.as_str().to_string())
+                    .collect()
+            },
+            resource: authority.resource().filter(|_| !pre_acquired),
+            profile_endpoint,
+            sender: sender.clone(),
+            smtp_login: args.smtp.smtp_login.clone(),
+            envelope_from: args.smtp.envelope_from.clone(),
+            transport: smtp::transport(&args.smtp, global.cloud),
+            // The sender with --to-self, unknown until the profile is read.
+            recipients: match recipients {
+                [] => sender.clone().into_iter().collect(),
+                recipients => recipients
+                    .iter()
+                    .map(|(recipient_email, _)| recipient_email.to_string())
+                    .collect(),
+            },
+            message_size,
+            send: !args.no_send,
+        })
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        let or_dash = |value: Option<&str>| value.unwrap_or("-").to_string();
+        let mut lines = vec![
+            format!("Grant type: {:?}", self.grant_type),
+            format!("Client id: {}", or_dash(self.client_id.as_deref())),
+            format!("Tenant: {}", self.tenant),
+        ];
+        if let Some(token_file) = &self.token_file {
+            lines.push(format!("Token file: {}", token_file.display()));
+        }
+        for endpoint in &self.login_endpoints {
+            lines.push(format!("Login endpoint: {endpoint}"));
+        }
+        if let Some(discovery_document) = &self.discovery_document {
+            lines.push(format!("Login endpoints from: {discovery_document}"));
+        }
+        if !self.scopes.is_empty() {
+            lines.push(format!("Scopes: {}", self.scopes.join(" ")));
+        }
+        if let Some(resource) = &self.resource {
+            lines.push(format!("Resource: {resource}"));
+        }
+        lines.extend([
+            format!(
+                "Profile endpoint: {}",
+                or_dash(self.profile_endpoint.as_deref())
+            ),
+            format!(
+                "Sender: {}",
+                self.sender.as_deref().unwrap_or("from the profile")
+            ),
+        ]);
+        if let Some(smtp_login) = &self.smtp_login {
+            lines.push(format!("SMTP login: {smtp_login}"));
+        }
+        if let Some(envelope_from) = &self.envelope_from {
+            lines.push(format!("Envelope from: {envelope_from}"));
+        }
+        lines.extend([
+            format!("Transport: {}", self.transport),
+            if self.recipients.is_empty() {
+                "Recipients: the sender".to_string()
+            } else {
+                format!("Recipients: {}", self.recipients.join(", "))
+            },
+            format!("Message size: about {} bytes", self.message_size),
+            format!("Send: {}", if self.send { "yes" } else { "no" }),
+        ]);
+        lines
+    }
+
+    pub fn print(&self, output: OutputFormat) -> OAuth2Result<()> {
+        match output {
+            OutputFormat::Text => {
+                log::info!("Dry run, nothing is sent:");
+                for line in self.summary() {
+                    log::info!("{line}");
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string(self)?),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use clap::Parser;
+
+    use super::Plan;
+    use crate::cli::Cli;
+    use crate::error::ErrorCodes;
+
+    const CLIENT_ID: &str = "00000000-0000-0000-0000-000000000000";
+
+    fn plan(args: &[&str]) -> Result<Plan, ErrorCodes> {
+        let cli = Cli::try_parse_from(
+            [
+                "xoauth2-test-tool",
+                "--dry-run",
+                "--recipient-name",
+                "Recipient",
+            ]
+            .iter()
+            .chain(args),
+        )
+        .unwrap();
+        let client = cli.run.client.resolve(None).unwrap();
+        let (recipient_email, recipient_name) = cli.run.recipient(None).unwrap();
+        Plan::new(
+            &client,
+            &[(recipient_email.as_str(), recipient_name.as_str())],
+            &cli.run,
+            &cli.global,
+            Path::new("/tokens"),
+        )
+        .map_err(|e| e.error_code)
+    }
+
+    #[test]
+    fn test_device_code_flow_plan() {
+        let plan = plan(&[
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            CLIENT_ID,
+            "--tenant",
+            "contoso.onmicrosoft.us",
+            "--cloud",
+            "gcc-high",
+            "--recipient-email",
+            "recipient@contoso.com",
+        ])
+        .unwrap();
+        let client_id = format!("Client id: {CLIENT_ID}");
+        let token_file = format!("Token file: /tokens/{CLIENT_ID}_device_code_flow.json");
+        // The size depends on the Date and Message-ID headers.
+        let mut summary = plan.summary();
+        let message_size = summary.remove(summary.len() - 2);
+        assert_eq!(
+            message_size,
+            format!("Message size: about {} bytes", plan.message_size)
+        );
+        assert!(plan.message_size > 0);
+        assert_eq!(
+            summary,
+            [
+                "Grant type: DeviceCodeFlow",
+                client_id.as_str(),
+                "Tenant: contoso.onmicrosoft.us",
+                token_file.as_str(),
+                "Login endpoint: https://login.microsoftonline.us/contoso.onmicrosoft.us/oauth2/v2.0/devicecode",
+                "Login endpoint: https://login.microsoftonline.us/contoso.onmicrosoft.us/oauth2/v2.0/token",
+                "Scopes: offline_access https://outlook.office365.us/SMTP.Send https://outlook.office365.us/User.Read",
+                "Profile endpoint: https://outlook.office365.us/api/v2.0/me/",
+                "Sender: from the profile",
+                "Transport: smtp.office365.us:587 STARTTLS",
+                "Recipients: recipient@contoso.com",
+                "Send: yes",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_v1_plan() {
+        let plan = plan(&[
+            "--grant-type",
+            "AuthorizationCodeGrant",
+            "--client-id",
+            CLIENT_ID,
+            "--oauth-version",
+            "v1",
+            "--recipient-email",
+            "recipient@contoso.com",
+        ])
+        .unwrap();
+        assert_eq!(
+            plan.login_endpoints,
+            [
+                "https://login.microsoftonline.com/common/oauth2/authorize",
+                "https://login.microsoftonline.com/common/oauth2/token"
+            ]
+        );
+        assert!(plan.scopes.is_empty());
+        assert!(plan
+            .summary()
+            .contains(&"Resource: https://outlook.office.com".to_string()));
+    }
+
+    #[test]
+    fn test_pre_acquired_plan() {
+        let plan = plan(&[
+            "--access-token",
+            "token",
+            "--sender-email",
+            "shared@contoso.com",
+            "--sender-name",
+            "Shared",
+            "--recipient-email",
+            "recipient@contoso.com",
+            "--no-send",
+        ])
+        .unwrap();
+        assert!(plan.client_id.is_none());
+        assert!(plan.token_file.is_none());
+        assert!(plan.login_endpoints.is_empty());
+        assert!(plan.scopes.is_empty());
+        assert!(plan.profile_endpoint.is_none());
+        assert_eq!(plan.sender.as_deref(), Some("shared@contoso.com"));
+        assert!(!plan.send);
+    }
+
+    #[test]
+    fn test_invalid_plan() {
+        let error = plan(&[
+            "--grant-type",
+            "AuthorizationCodeGrant",
+            "--client-id",
+            "my-client",
+            "--recipient-email",
+            "recipient@contoso.com",
+        ])
+        .unwrap_err();
+        assert_eq!(error, ErrorCodes::InvalidClientId);
+
+        let error = plan(&[
+            "--grant-type",
+            "AuthorizationCodeGrant",
+            "--client-id",
+            CLIENT_ID,
+            "--recipient-email",
+            "recipient",
+        ])
+        .unwrap_err();
+        assert_eq!(error, ErrorCodes::ConfigurationError);
+    }
+}
@@ -0,0 +1,315 @@
+// Standard libraries
+use std::io;
+use std::time::{Duration, Instant};
+
+// 3rd party crates
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::{self, ServerName};
+use tokio_rustls::TlsConnector;
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::net;
+use crate::smtp::SmtpRoute;
+
+/// How long a probe waits for its host before taking it as unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A host the run needs, probed before the login.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    /// What the host is for, e.g. `login` or `SMTP`.
+    pub role: &'static str,
+    pub host: String,
+    pub port: u16,
+    /// Whether the probe also does a TLS handshake, for the HTTPS hosts.
+    pub tls: bool,
+}
+
+impl Target {
+    pub fn https(role: &'static str, host: &str) -> Self {
+        Self {
+            role,
+            host: host.to_string(),
+            port: 443,
+            tls: true,
+        }
+    }
+
+    pub fn tcp(role: &'static str, host: &str, port: u16) -> Self {
+        Self {
+            role,
+            host: host.to_string(),
+            port,
+            tls: false,
+        }
+    }
+}
+
+/// The result of the probe of one host.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Probe {
+    pub role: &'static str,
+    pub host: String,
+    pub port: u16,
+    pub reachable: bool,
+    pub elapsed_ms: u128,
+    /// Why the host is unreachable, or what was odd about a reachable one.
+    pub error: Option<String>,
+}
+
+impl Probe {
+    pub fn summary(&self) -> String {
+        let status = if self.reachable {
+            "reachable"
+        } else {
+            "unreachable"
+        };
+        let mut summary = format!(
+            "Preflight: {} host {}:{} {status} in {} ms",
+            self.role, self.host, self.port, self.elapsed_ms
+        );
+        if let Some(error) = &self.error {
+            summary.push_str(&format!(" ({error})"));
+        }
+        summary
+    }
+}
+
+/// Whether the TLS handshake failed on the certificate, which still shows that the host
+/// is reachable: libcurl may trust other roots than the web PKI ones of the probe, e.g.
+/// those of a TLS inspecting proxy.
+fn is_certificate_error(error: &io::Error) -> bool {
+    matches!(
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(_))
+    )
+}
+
+/// Connects to the target over the IP version and the proxy of `route`, and does the
+/// TLS handshake of an HTTPS one.
+async fn connect(target: &Target, route: &SmtpRoute, connector: &TlsConnector) -> io::Result<()> {
+    let addr = net::resolve(&target.host, target.port, route.ip_version)
+        .await
+        .map_err(|error| io::Error::new(io::ErrorKind::NotFound, error.error_code_desc))?;
+    let stream = match &route.socks5 {
+        Some(proxy) => proxy.connect(&addr).await?,
+        None => TcpStream::connect(&addr).await?,
+    };
+    if target.tls {
+        let domain = ServerName::try_from(target.host.as_str())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        connector.connect(domain, stream).await?;
+    }
+    Ok(())
+}
+
+async fn probe(
+    target: &Target,
+    route: &SmtpRoute,
+    connector: &TlsConnector,
+    timeout: Duration,
+) -> Probe {
+    let start = Instant::now();
+    let result = tokio::time::timeout(timeout, connect(target, route, connector))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("no answer within {} s", timeout.as_secs()),
+            ))
+        });
+    let (reachable, error) = match result {
+        Ok(()) => (true, None),
+        Err(error) if is_certificate_error(&error) => (true, Some(error.to_string())),
+        Err(error) => (false, Some(error.to_string())),
+    };
+    Probe {
+        role: target.role,
+        host: target.host.clone(),
+        port: target.port,
+        reachable,
+        elapsed_ms: start.elapsed().as_millis(),
+        error,
+    }
+}
+
+/// Probes each target in turn and logs the results.
+async fn probe_all(
+    targets: &[Target],
+    route: &SmtpRoute,
+    timeout: Duration,
+) -> OAuth2Result<Vec<Probe>> {
+    let connector = route.tls.connector()?;
+    let mut probes = Vec::with_capacity(targets.len());
+    for target in targets {
+        let probe = probe(target, route, &connector, timeout).await;
+        if probe.reachable {
+            log::info!("{}", probe.summary());
+        } else {
+            log::error!("{}", probe.summary());
+        }
+        probes.push(probe);
+    }
+    Ok(probes)
+}
+
+/// Fails with a network error naming the unreachable hosts, if any.
+fn check_probes(probes: Vec<Probe>) -> OAuth2Result<Vec<Probe>> {
+    let unreachable: Vec<String> = probes
+        .iter()
+        .filter(|probe| !probe.reachable)
+        .map(|probe| {
+            format!(
+                "{}:{} ({})",
+                probe.host,
+                probe.port,
+                probe.error.as_deref().unwrap_or("unknown error")
+            )
+        })
+        .collect();
+    if unreachable.is_empty() {
+        return Ok(probes);
+    }
+    Err(OAuth2Error::new(
+        ErrorCodes::IoError,
+        format!(
+            "The preflight could not reach {}, check the DNS and the firewall or use \
+            --skip-preflight.",
+            unreachable.join(", ")
+        ),
+    ))
+}
+
+/// Probes the hosts the run needs before it starts, so that a wrong DNS or firewall fails
+/// the run at once instead of deep in the login.
+pub async fn check(targets: &[Target], route: &SmtpRoute) -> OAuth2Result<Vec<Probe>> {
+    check_probes(probe_all(targets, route, PROBE_TIMEOUT).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::{check, check_probes, probe_all, Target};
+    use crate::error::ErrorCodes;
+    use crate::net::IpVersion;
+    use crate::smtp::SmtpRoute;
+    use crate::socks5::parse_socks5;
+
+    fn route() -> SmtpRoute {
+        SmtpRoute {
+            ip_version: IpVersion::V4,
+            ..Default::default()
+        }
+    }
+
+    /// A port nothing listens on anymore, where the connection is refused.
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_reachable_hosts() {
+        let smtp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smtp_port = smtp.local_addr().unwrap().port();
+        tokio::spawn(async move { smtp.accept().await });
+
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(certificate.serialize_der().unwrap())],
+                PrivateKey(certificate.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let https = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let https_port = https.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = https.accept().await.unwrap();
+            let _ = acceptor.accept(stream).await;
+        });
+
+        let login = Target {
+            port: https_port,
+            ..Target::https("login", "127.0.0.1")
+        };
+        let probes = check(
+            &[login, Target::tcp("SMTP", "127.0.0.1", smtp_port)],
+            &route(),
+        )
+        .await
+        .unwrap();
+        assert!(probes.iter().all(|probe| probe.reachable), "{probes:?}");
+        // The self-signed certificate is not trusted, but the host answered.
+        assert!(probes[0].error.as_ref().unwrap().contains("certificate"));
+        assert_eq!(probes[1].error, None);
+        assert!(probes[1].summary().starts_with(&format!(
+            "Preflight: SMTP host 127.0.0.1:{smtp_port} reachable"
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_host_fails_fast() {
+        let smtp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let smtp_port = smtp.local_addr().unwrap().port();
+        let login_port = closed_port().await;
+
+        let login = Target {
+            port: login_port,
+            ..Target::https("login", "127.0.0.1")
+        };
+        let targets = [login, Target::tcp("SMTP", "127.0.0.1", smtp_port)];
+        let probes = probe_all(&targets, &route(), Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(!probes[0].reachable);
+        assert!(probes[1].reachable);
+
+        let error = check_probes(probes).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::IoError);
+        assert!(
+            error
+                .error_code_desc
+                .contains(&format!("127.0.0.1:{login_port}")),
+            "{}",
+            error.error_code_desc
+        );
+        drop(smtp);
+    }
+
+    #[tokio::test]
+    async fn test_silent_host_times_out() {
+        // A SOCKS5 proxy that never answers the greeting.
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let server = tokio::spawn(async move { proxy.accept().await });
+
+        // The proxy resolves the host, which is then never looked up.
+        let route = SmtpRoute {
+            socks5: Some(parse_socks5(&proxy_addr.to_string()).unwrap()),
+            ..Default::default()
+        };
+        let probes = probe_all(
+            &[Target::tcp("SMTP", "smtp.example.com", 587)],
+            &route,
+            Duration::from_millis(100),
+        )
+        .await
+        .unwrap();
+        assert!(!probes[0].reachable);
+        assert!(probes[0].error.as_ref().unwrap().contains("no answer"));
+        drop(server);
+    }
+}
@@ -0,0 +1,36 @@
+// 3rd party crates
+use qrcode::{render::unicode::Dense1x2, QrCode};
+
+/// Renders `data` as a QR code made of Unicode half blocks, two modules per character
+/// row, dark on a light background so it scans on dark terminals too.
+pub fn render(data: &str) -> Option<String> {
+    match QrCode::new(data.as_bytes()) {
+        Ok(code) => Some(
+            code.render::<Dense1x2>()
+                .dark_color(Dense1x2::Light)
+                .light_color(Dense1x2::Dark)
+                .build(),
+        ),
+        Err(e) => {
+            log::warn!("Unable to make a QR code of {data}: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn test_render_verification_uri() {
+        let code = render("https://microsoft.com/devicelogin?otc=ABCD1234").unwrap();
+        assert!(code.lines().count() > 10);
+        assert!(code.chars().any(|c| c != ' ' && c != '\n'));
+    }
+
+    #[test]
+    fn test_render_fails_for_too_much_data() {
+        assert!(render(&"a".repeat(8000)).is_none());
+    }
+}
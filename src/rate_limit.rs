@@ -0,0 +1,198 @@
+// Standard libraries
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// The file of the token directory keeping the rate limit across the runs.
+pub const RATE_LIMIT_FILE: &str = "rate_limit.json";
+
+/// A rate limit per host for the HTTP requests, with `--max-requests-per-minute`. Up to
+/// that many requests go out at once, the next ones are delayed to keep the rate rather
+/// than failing, like a token bucket refilled one request at a time. The clones share
+/// the limit, and with a state file so do the runs.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    /// Where the requests of each host are kept for the next runs.
+    state_file: Option<PathBuf>,
+    /// When each host has had all its requests so far at the rate.
+    next_free: Arc<Mutex<HashMap<String, SystemTime>>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            state_file: None,
+            next_free: Arc::default(),
+        }
+    }
+
+    /// Also keeps the requests of each host in `state_file`, so that the limit holds
+    /// across the runs, e.g. of a monitoring job started every minute.
+    pub fn state_file(mut self, state_file: Option<PathBuf>) -> Self {
+        self.state_file = state_file;
+        self
+    }
+
+    /// When each host of the state file has had all its requests at the rate, none
+    /// while there is no such file.
+    fn load(state_file: &Path) -> BTreeMap<String, SystemTime> {
+        fs::read_to_string(state_file)
+            .ok()
+            .and_then(|json| serde_json::from_str::<BTreeMap<String, u64>>(&json).ok())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(host, millis)| (host, UNIX_EPOCH + Duration::from_millis(millis)))
+            .collect()
+    }
+
+    /// Saves the hosts still limited at `now` to the state file.
+    fn save(state_file: &Path, hosts: &HashMap<String, SystemTime>, now: SystemTime) {
+        let millis: BTreeMap<&str, u64> = hosts
+            .iter()
+            .filter(|(_, next_free)| **next_free > now)
+            .map(|(host, next_free)| {
+                let since_epoch = next_free.duration_since(UNIX_EPOCH).unwrap_or_default();
+                (host.as_str(), since_epoch.as_millis() as u64)
+            })
+            .collect();
+        let saved = serde_json::to_string(&millis)
+            .map_err(std::io::Error::from)
+            .and_then(|json| fs::write(state_file, json));
+        if let Err(e) = saved {
+            log::warn!(
+                "Unable to save the rate limit to {}: {e}",
+                state_file.display()
+            );
+        }
+    }
+
+    /// Counts a request to `host` at `now`, and returns how long it has to wait to keep
+    /// within the rate.
+    fn reserve(&self, host: &str, now: SystemTime) -> Duration {
+        let interval = MINUTE / self.per_minute;
+        let mut hosts = self.next_free.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(state_file) = &self.state_file {
+            // The other runs may have sent requests since.
+            for (saved_host, saved) in Self::load(state_file) {
+                let next_free = hosts.entry(saved_host).or_insert(saved);
+                *next_free = (*next_free).max(saved);
+            }
+        }
+        let next_free = hosts.entry(host.to_string()).or_insert(now);
+        *next_free = (*next_free).max(now) + interval;
+        let delay = next_free.duration_since(now + MINUTE).unwrap_or_default();
+        if let Some(state_file) = &self.state_file {
+            Self::save(state_file, &hosts, now);
+        }
+        delay
+    }
+
+    /// Waits until a request to `host` keeps within the rate.
+    pub async fn acquire(&self, host: &str) {
+        let delay = self.reserve(host, SystemTime::now());
+        if !delay.is_zero() {
+            log::info!(
+                "Throttling the request to {host} for {} ms, at most {} requests per minute \
+                are sent to it (--max-requests-per-minute).",
+                delay.as_millis(),
+                self.per_minute
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use tokio::time::Instant;
+
+    use super::{RateLimiter, RATE_LIMIT_FILE};
+
+    #[test]
+    fn test_requests_are_spaced_past_the_limit() {
+        let limiter = RateLimiter::new(2);
+        let start = SystemTime::now();
+        assert_eq!(
+            limiter.reserve("login.microsoftonline.com", start),
+            Duration::ZERO
+        );
+        assert_eq!(
+            limiter.reserve("login.microsoftonline.com", start),
+            Duration::ZERO
+        );
+        // One request every 30 seconds past the limit.
+        assert_eq!(
+            limiter.reserve("login.microsoftonline.com", start),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            limiter.reserve("login.microsoftonline.com", start),
+            Duration::from_secs(60)
+        );
+        // Each host has its own bucket.
+        assert_eq!(
+            limiter.reserve("graph.microsoft.com", start),
+            Duration::ZERO
+        );
+
+        // The bucket refills with time.
+        let later = start + Duration::from_secs(90);
+        assert_eq!(
+            limiter.reserve("login.microsoftonline.com", later),
+            Duration::ZERO
+        );
+        assert_eq!(
+            limiter.reserve("login.microsoftonline.com", later),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_delays_instead_of_failing() {
+        let limiter = RateLimiter::new(60);
+        let start = Instant::now();
+        for _ in 0..60 {
+            limiter.acquire("login.microsoftonline.com").await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // The clones share the bucket.
+        let clone = limiter.clone();
+        clone.acquire("login.microsoftonline.com").await;
+        clone.acquire("login.microsoftonline.com").await;
+        assert!(
+            start.elapsed() >= Duration::from_secs(2),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_limit_holds_across_runs() {
+        let directory = tempfile::tempdir().unwrap();
+        let state_file = directory.path().join(RATE_LIMIT_FILE);
+        // On a whole second, the file keeps milliseconds.
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let first_run = RateLimiter::new(2).state_file(Some(state_file.clone()));
+        first_run.reserve("login.microsoftonline.com", start);
+        first_run.reserve("login.microsoftonline.com", start);
+
+        let second_run = RateLimiter::new(2).state_file(Some(state_file));
+        assert_eq!(
+            second_run.reserve("login.microsoftonline.com", start + Duration::from_secs(1)),
+            Duration::from_secs(29)
+        );
+        assert_eq!(
+            second_run.reserve("graph.microsoft.com", start),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,148 @@
+// 3rd party crates
+use http::{header::AUTHORIZATION, HeaderName, HeaderValue};
+use oauth2::url::form_urlencoded;
+use serde_json::Value;
+
+pub const REDACTED: &str = "<redacted>";
+
+/// The fields of the token requests and responses that must not end up in the logs.
+const SECRET_FIELDS: [&str; 10] = [
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "client_secret",
+    "client_assertion",
+    "assertion",
+    "code",
+    "code_verifier",
+    "device_code",
+    "password",
+];
+
+fn is_secret(field: &str) -> bool {
+    SECRET_FIELDS.contains(&field)
+}
+
+/// A header as logged, with the credentials of `Authorization` left out but its scheme
+/// kept, e.g. `Bearer <redacted>`.
+pub fn header(name: &HeaderName, value: &HeaderValue) -> String {
+    let value = value.to_str().unwrap_or("<binary>");
+    if name == AUTHORIZATION {
+        match value.split_once(' ') {
+            Some((scheme, _)) => format!("{name}: {scheme} {REDACTED}"),
+            None => format!("{name}: {REDACTED}"),
+        }
+    } else {
+        format!("{name}: {value}")
+    }
+}
+
+fn redact_json(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            for (field, value) in object.iter_mut() {
+                if is_secret(field) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// A request or response body as logged, with the secret fields of a JSON or form
+/// body replaced. Anything else is logged as it is.
+pub fn body(body: &[u8]) -> String {
+    if let Ok(mut json) = serde_json::from_slice::<Value>(body) {
+        redact_json(&mut json);
+        return json.to_string();
+    }
+
+    let text = String::from_utf8_lossy(body);
+    let is_form = !text.is_empty()
+        && !text.contains(char::is_whitespace)
+        && text.split('&').all(|pair| pair.contains('='));
+    if !is_form {
+        return text.into_owned();
+    }
+    let mut redacted = form_urlencoded::Serializer::new(String::new());
+    for (field, value) in form_urlencoded::parse(body) {
+        if is_secret(&field) {
+            redacted.append_pair(&field, REDACTED);
+        } else {
+            redacted.append_pair(&field, &value);
+        }
+    }
+    redacted.finish()
+}
+
+/// An SMTP command as logged, with the initial response of AUTH left out but its
+/// mechanism kept, e.g. `AUTH XOAUTH2 <redacted>`.
+pub fn smtp_command(command: &str) -> String {
+    let mut words = command.splitn(3, ' ');
+    match (words.next(), words.next(), words.next()) {
+        (Some(auth), Some(mechanism), Some(_)) if auth.eq_ignore_ascii_case("AUTH") => {
+            format!("{auth} {mechanism} {REDACTED}")
+        }
+        _ => command.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::CONTENT_TYPE, HeaderValue};
+
+    use super::{body, header, smtp_command};
+
+    #[test]
+    fn test_authorization_header_is_redacted() {
+        let value = HeaderValue::from_static("Bearer eyJ0eXAiOiJKV1Qi.secret");
+        assert_eq!(
+            header(&http::header::AUTHORIZATION, &value),
+            "authorization: Bearer <redacted>"
+        );
+        let value = HeaderValue::from_static("application/json");
+        assert_eq!(
+            header(&CONTENT_TYPE, &value),
+            "content-type: application/json"
+        );
+    }
+
+    #[test]
+    fn test_secret_fields_are_redacted() {
+        let redacted = body(
+            br#"{"token_type":"Bearer","access_token":"at","refresh_token":"rt","expires_in":3600}"#,
+        );
+        assert!(redacted.contains(r#""token_type":"Bearer""#));
+        assert!(redacted.contains(r#""access_token":"<redacted>""#));
+        assert!(redacted.contains(r#""refresh_token":"<redacted>""#));
+        assert!(!redacted.contains(r#""at""#));
+
+        assert_eq!(
+            body(b"grant_type=refresh_token&refresh_token=rt&client_id=client&client_secret=s%3Ds"),
+            "grant_type=refresh_token&refresh_token=%3Credacted%3E&client_id=client&client_secret=%3Credacted%3E"
+        );
+        assert_eq!(body(b"Not Found"), "Not Found");
+        assert_eq!(body(b""), "");
+    }
+
+    #[test]
+    fn test_smtp_auth_is_redacted() {
+        assert_eq!(
+            smtp_command("AUTH XOAUTH2 dXNlcj1tZQFhdXRoPUJlYXJlciB0b2tlbgEB"),
+            "AUTH XOAUTH2 <redacted>"
+        );
+        assert_eq!(
+            smtp_command("auth plain AG1lAHNlY3JldA=="),
+            "auth plain <redacted>"
+        );
+        assert_eq!(smtp_command("AUTH XOAUTH2"), "AUTH XOAUTH2");
+        assert_eq!(
+            smtp_command("MAIL FROM:<me@example.com>"),
+            "MAIL FROM:<me@example.com>"
+        );
+    }
+}
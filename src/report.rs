@@ -0,0 +1,555 @@
+// Standard libraries
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+// 3rd party crates
+use serde::Serialize;
+use strum_macros::EnumString;
+
+// My crates
+use crate::error::{OAuth2Error, OAuth2Result};
+use crate::telemetry;
+use crate::OAuth2TokenGrantFlow;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// The steps of the send flow, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Login,
+    Profile,
+    Message,
+    Send,
+}
+
+impl Stage {
+    /// The name of the span of the stage with `--otel-endpoint`, none for building the
+    /// message, which does no I/O.
+    fn span_name(self) -> Option<&'static str> {
+        match self {
+            Stage::Login => Some("acquire_token"),
+            Stage::Profile => Some("fetch_profile"),
+            Stage::Message => None,
+            Stage::Send => Some("send"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    Sent,
+    Failed { stage: Stage, error: OAuth2Error },
+}
+
+/// The reply of the server to the RCPT TO of one recipient.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RecipientResult {
+    pub email: String,
+    pub accepted: bool,
+    /// The reply, e.g. `550 5.1.1 User unknown`.
+    pub reply: String,
+}
+
+/// How long each stage took, in milliseconds, for the stages that were run.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Timings {
+    pub login_ms: Option<u64>,
+    pub profile_ms: Option<u64>,
+    pub message_ms: Option<u64>,
+    pub send_ms: Option<u64>,
+}
+
+/// Counts the message bytes written to the SMTP connection, over the retries and the
+/// relogin.
+#[derive(Debug, Default)]
+pub struct ByteCounter(Cell<u64>);
+
+impl ByteCounter {
+    /// Runs the send of a message of `bytes` and counts them when it succeeded.
+    pub async fn count<T>(
+        &self,
+        bytes: usize,
+        send: impl Future<Output = OAuth2Result<T>>,
+    ) -> OAuth2Result<T> {
+        let result = send.await;
+        if result.is_ok() {
+            self.0.set(self.0.get() + bytes as u64);
+        }
+        result
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+/// The message bytes written to the SMTP connection and the throughput of the send, to
+/// characterize the network path with large messages.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TransferStats {
+    pub bytes_written: u64,
+    /// None when the send took no measurable time.
+    pub bytes_per_sec: Option<u64>,
+}
+
+impl TransferStats {
+    pub fn new(bytes_written: u64, elapsed: Duration) -> Self {
+        let bytes_per_sec = (!elapsed.is_zero())
+            .then(|| (bytes_written as f64 / elapsed.as_secs_f64()).round() as u64);
+        Self {
+            bytes_written,
+            bytes_per_sec,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        match self.bytes_per_sec {
+            Some(bytes_per_sec) => format!(
+                "Transfer: {} bytes at {bytes_per_sec} bytes/s",
+                self.bytes_written
+            ),
+            None => format!("Transfer: {} bytes", self.bytes_written),
+        }
+    }
+}
+
+/// Hooks into the send flow, e.g. to show its progress in another UI than the log. They
+/// are called as the login and the send start and end, and when a stage fails, and do
+/// nothing by default.
+pub trait EventSink: Debug {
+    fn on_auth_started(&self) {}
+    fn on_token_acquired(&self) {}
+    /// The send starts by connecting to the SMTP server.
+    fn on_connect(&self) {}
+    fn on_message_sent(&self) {}
+    fn on_error(&self, _stage: Stage, _error: &OAuth2Error) {}
+}
+
+/// The events are not used.
+#[derive(Debug)]
+pub struct NoEvents;
+
+impl EventSink for NoEvents {}
+
+/// Logs the events, at the debug level as the flow logs its own progress.
+#[derive(Debug)]
+pub struct LogEvents;
+
+impl EventSink for LogEvents {
+    fn on_auth_started(&self) {
+        log::debug!("Event: login started");
+    }
+
+    fn on_token_acquired(&self) {
+        log::debug!("Event: access token acquired");
+    }
+
+    fn on_connect(&self) {
+        log::debug!("Event: connecting to the SMTP server");
+    }
+
+    fn on_message_sent(&self) {
+        log::debug!("Event: message sent");
+    }
+
+    fn on_error(&self, stage: Stage, error: &OAuth2Error) {
+        log::debug!("Event: {stage:?} failed: {}", error.error_code_desc);
+    }
+}
+
+/// What the send flow did, as far as it got.
+#[derive(Debug, Serialize)]
+pub struct SendReport {
+    /// The id of the run, also in each log line, to find its logs.
+    pub run_id: String,
+    pub grant_type: OAuth2TokenGrantFlow,
+    pub sender: Option<String>,
+    pub recipients: Vec<String>,
+    pub transport: String,
+    pub message_id: Option<String>,
+    /// How many messages the recipients were sent in, more than one with `--batch-size`.
+    pub messages: Option<usize>,
+    pub bytes_sent: Option<usize>,
+    /// Whether each recipient was accepted, once the send got that far.
+    pub recipient_results: Vec<RecipientResult>,
+    /// How many times the email was sent, with `--send-retries` and `--auto-relogin`.
+    pub send_attempts: Option<u32>,
+    /// The bytes written by the sends that succeeded and their throughput.
+    pub transfer: Option<TransferStats>,
+    pub timings: Timings,
+    /// Starts as `Sent` and is replaced by the first stage that fails.
+    pub outcome: Outcome,
+    #[serde(skip)]
+    events: Box<dyn EventSink>,
+    /// The tenant in the attributes of the spans.
+    #[serde(skip)]
+    tenant: Option<String>,
+}
+
+impl SendReport {
+    pub fn new(
+        run_id: String,
+        grant_type: OAuth2TokenGrantFlow,
+        recipients: Vec<String>,
+        transport: String,
+    ) -> Self {
+        Self {
+            run_id,
+            grant_type,
+            sender: None,
+            recipients,
+            transport,
+            message_id: None,
+            messages: None,
+            bytes_sent: None,
+            recipient_results: Vec::new(),
+            send_attempts: None,
+            transfer: None,
+            timings: Timings::default(),
+            outcome: Outcome::Sent,
+            events: Box::new(NoEvents),
+            tenant: None,
+        }
+    }
+
+    /// Calls the hooks of `events` as the stages are run.
+    pub fn with_events(mut self, events: Box<dyn EventSink>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Adds the tenant to the attributes of the spans of the stages.
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// Runs one stage of the flow and times it. On error, the report is marked as failed
+    /// at this stage and `None` is returned.
+    pub async fn stage<T>(
+        &mut self,
+        stage: Stage,
+        future: impl Future<Output = OAuth2Result<T>>,
+    ) -> Option<T> {
+        match stage {
+            Stage::Login => self.events.on_auth_started(),
+            Stage::Send => self.events.on_connect(),
+            Stage::Profile | Stage::Message => {}
+        }
+        let start = Instant::now();
+        let result = match stage.span_name() {
+            Some(name) => {
+                let mut attributes = vec![("transport", self.transport.clone())];
+                attributes.extend(self.tenant.clone().map(|tenant| ("tenant", tenant)));
+                telemetry::in_span(name, attributes, future).await
+            }
+            None => future.await,
+        };
+        let elapsed = Some(start.elapsed().as_millis() as u64);
+        match stage {
+            Stage::Login => self.timings.login_ms = elapsed,
+            Stage::Profile => self.timings.profile_ms = elapsed,
+            Stage::Message => self.timings.message_ms = elapsed,
+            Stage::Send => self.timings.send_ms = elapsed,
+        }
+
+        match result {
+            Ok(value) => {
+                match stage {
+                    Stage::Login => self.events.on_token_acquired(),
+                    Stage::Send => self.events.on_message_sent(),
+                    Stage::Profile | Stage::Message => {}
+                }
+                Some(value)
+            }
+            Err(error) => {
+                self.events.on_error(stage, &error);
+                self.outcome = Outcome::Failed { stage, error };
+                None
+            }
+        }
+    }
+
+    /// The summary logged in text mode.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("Run id: {}", self.run_id),
+            format!("Grant type: {:?}", self.grant_type),
+            format!("Sender: {}", self.sender.as_deref().unwrap_or("-")),
+            format!("Recipients: {}", self.recipients.join(", ")),
+            format!("Transport: {}", self.transport),
+        ];
+        if let Some(message_id) = &self.message_id {
+            lines.push(format!("Message-ID: {message_id}"));
+        }
+        if let Some(messages) = self.messages.filter(|&messages| messages > 1) {
+            lines.push(format!("Messages: {messages}"));
+        }
+        if let Some(bytes_sent) = self.bytes_sent {
+            lines.push(format!("Message size: {bytes_sent} bytes"));
+        }
+        if let Some(send_attempts) = self.send_attempts {
+            lines.push(format!("Send attempts: {send_attempts}"));
+        }
+        if let Some(transfer) = &self.transfer {
+            lines.push(transfer.summary());
+        }
+        for result in &self.recipient_results {
+            let status = if result.accepted {
+                "accepted"
+            } else {
+                "rejected"
+            };
+            lines.push(format!(
+                "Recipient {}: {status} ({})",
+                result.email, result.reply
+            ));
+        }
+        lines.push(match &self.outcome {
+            Outcome::Sent => "Outcome: sent".to_string(),
+            Outcome::Failed { stage, error } => {
+                format!("Outcome: failed at {stage:?}: {}", error.error_code_desc)
+            }
+        });
+        lines
+    }
+
+    pub fn print(&self, output: OutputFormat) -> OAuth2Result<()> {
+        match output {
+            OutputFormat::Text => {
+                for line in self.summary() {
+                    log::info!("{line}");
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string(self)?),
+        }
+        Ok(())
+    }
+
+    pub fn into_result(self) -> OAuth2Result<()> {
+        match self.outcome {
+            Outcome::Sent => Ok(()),
+            Outcome::Failed { error, .. } => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::{ByteCounter, EventSink, Outcome, SendReport, Stage, TransferStats};
+    use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+    use crate::telemetry;
+    use crate::OAuth2TokenGrantFlow;
+
+    fn report() -> SendReport {
+        SendReport::new(
+            "run-42".to_string(),
+            OAuth2TokenGrantFlow::DeviceCodeFlow,
+            vec!["recipient@example.com".to_string()],
+            "smtp.office365.com:587 STARTTLS".to_string(),
+        )
+    }
+
+    fn failure(error_code: ErrorCodes) -> OAuth2Result<()> {
+        Err(OAuth2Error::new(error_code, "failed".into()))
+    }
+
+    /// Records the events as they are called.
+    #[derive(Debug)]
+    struct RecordedEvents(Rc<RefCell<Vec<String>>>);
+
+    impl EventSink for RecordedEvents {
+        fn on_auth_started(&self) {
+            self.0.borrow_mut().push("auth_started".to_string());
+        }
+
+        fn on_token_acquired(&self) {
+            self.0.borrow_mut().push("token_acquired".to_string());
+        }
+
+        fn on_connect(&self) {
+            self.0.borrow_mut().push("connect".to_string());
+        }
+
+        fn on_message_sent(&self) {
+            self.0.borrow_mut().push("message_sent".to_string());
+        }
+
+        fn on_error(&self, stage: Stage, error: &OAuth2Error) {
+            self.0
+                .borrow_mut()
+                .push(format!("error {stage:?} {:?}", error.error_code));
+        }
+    }
+
+    /// Runs the stages in order, failing at `failing`, like the send flow does.
+    async fn run_until(failing: Option<Stage>) -> SendReport {
+        run_with(report(), failing).await
+    }
+
+    async fn run_with(mut report: SendReport, failing: Option<Stage>) -> SendReport {
+        for stage in [Stage::Login, Stage::Profile, Stage::Message, Stage::Send] {
+            let result = if Some(stage) == failing {
+                failure(ErrorCodes::OtherError)
+            } else {
+                Ok(())
+            };
+            if report.stage(stage, async { result }).await.is_none() {
+                break;
+            }
+            match stage {
+                Stage::Profile => report.sender = Some("sender@example.com".to_string()),
+                Stage::Message => {
+                    report.message_id = Some("<id@example.com>".to_string());
+                    report.bytes_sent = Some(512);
+                }
+                _ => {}
+            }
+        }
+        report
+    }
+
+    #[tokio::test]
+    async fn test_successful_report() {
+        let report = run_until(None).await;
+
+        assert!(matches!(report.outcome, Outcome::Sent));
+        assert!(report.timings.send_ms.is_some());
+        assert_eq!(report.bytes_sent, Some(512));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["outcome"]["status"], "sent");
+        assert_eq!(json["grant_type"], "DeviceCodeFlow");
+        assert_eq!(json["message_id"], "<id@example.com>");
+        assert_eq!(json["run_id"], "run-42");
+        assert_eq!(report.summary()[0], "Run id: run-42");
+        assert!(report.summary().contains(&"Outcome: sent".to_string()));
+        assert!(report.into_result().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_spans_of_the_stages() {
+        telemetry::take_started();
+        assert!(run_until(None).await.into_result().is_ok());
+        assert_eq!(
+            telemetry::take_started(),
+            ["acquire_token", "fetch_profile", "send"]
+        );
+
+        run_until(Some(Stage::Profile)).await;
+        assert_eq!(
+            telemetry::take_started(),
+            ["acquire_token", "fetch_profile"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_of_the_stages() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorded = report().with_events(Box::new(RecordedEvents(events.clone())));
+        assert!(run_with(recorded, None).await.into_result().is_ok());
+        assert_eq!(
+            *events.borrow(),
+            ["auth_started", "token_acquired", "connect", "message_sent"]
+        );
+
+        events.borrow_mut().clear();
+        let recorded = report().with_events(Box::new(RecordedEvents(events.clone())));
+        run_with(recorded, Some(Stage::Send)).await;
+        assert_eq!(
+            *events.borrow(),
+            [
+                "auth_started",
+                "token_acquired",
+                "connect",
+                "error Send OtherError"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_of_each_failed_stage() {
+        let report = run_until(Some(Stage::Login)).await;
+        assert!(matches!(
+            report.outcome,
+            Outcome::Failed {
+                stage: Stage::Login,
+                ..
+            }
+        ));
+        assert!(report.timings.login_ms.is_some());
+        assert_eq!(report.timings.profile_ms, None);
+        assert_eq!(report.sender, None);
+
+        let report = run_until(Some(Stage::Profile)).await;
+        assert!(report.timings.profile_ms.is_some());
+        assert_eq!(report.sender, None);
+        assert_eq!(report.message_id, None);
+
+        let report = run_until(Some(Stage::Message)).await;
+        assert_eq!(report.sender.as_deref(), Some("sender@example.com"));
+        assert_eq!(report.bytes_sent, None);
+        assert_eq!(report.timings.send_ms, None);
+
+        // The login and message succeeded, only the send failed.
+        let report = run_until(Some(Stage::Send)).await;
+        assert_eq!(report.sender.as_deref(), Some("sender@example.com"));
+        assert_eq!(report.bytes_sent, Some(512));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["outcome"]["status"], "failed");
+        assert_eq!(json["outcome"]["stage"], "send");
+        assert_eq!(json["outcome"]["error"]["error_code"], "other_error");
+        assert!(report
+            .summary()
+            .contains(&"Outcome: failed at Send: failed".to_string()));
+        assert_eq!(
+            report.into_result().unwrap_err().error_code,
+            ErrorCodes::OtherError
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transfer_stats() {
+        let message = b"From: sender@example.com\r\nSubject: Test\r\n\r\nHello\r\n";
+        let written = ByteCounter::default();
+        for _ in 0..3 {
+            written
+                .count(message.len(), async { Ok(()) })
+                .await
+                .unwrap();
+        }
+        written
+            .count(message.len(), async { failure(ErrorCodes::SmtpError) })
+            .await
+            .unwrap_err();
+        assert_eq!(written.total(), 3 * message.len() as u64);
+
+        let transfer = TransferStats::new(written.total(), Duration::from_millis(500));
+        assert_eq!(transfer.bytes_written, 150);
+        assert_eq!(transfer.bytes_per_sec, Some(300));
+        assert_eq!(transfer.summary(), "Transfer: 150 bytes at 300 bytes/s");
+        assert_eq!(TransferStats::new(150, Duration::ZERO).bytes_per_sec, None);
+
+        let mut report = run_until(None).await;
+        report.transfer = Some(transfer);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["transfer"]["bytes_written"], 150);
+        assert!(report
+            .summary()
+            .contains(&"Transfer: 150 bytes at 300 bytes/s".to_string()));
+    }
+}
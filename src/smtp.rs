@@ -0,0 +1,2183 @@
+// Standard libraries
+use std::future::Future;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// 3rd party crates
+use mail_send::{smtp::message::Message, Credentials, SmtpClient, SmtpClientBuilder};
+use oauth2::AccessToken;
+use regex::Regex;
+use smtp_proto::{Response, AUTH_PLAIN, AUTH_XOAUTH2, EXT_START_TLS};
+use socket2::{SockRef, TcpKeepalive};
+use strum_macros::EnumString;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::ServerName;
+
+// My crates
+use crate::backoff::Backoff;
+use crate::cli::SmtpArgs;
+use crate::cloud::Cloud;
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::message::parse_domain;
+use crate::net::{self, IpVersion};
+use crate::report::RecipientResult;
+use crate::socks5::Socks5Proxy;
+use crate::telemetry;
+use crate::tls::TlsPolicy;
+use crate::transcript::Transcript;
+
+const SMTP_PORT: u16 = 587;
+/// The timeout of mail-send, which covers both the connection and the send.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(60);
+/// The wait before the first retry of `--send-retries`, doubled for each next one
+/// before the jitter of `--retry-jitter`.
+pub const SEND_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How the XOAUTH2 credentials are sent in AUTH.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum SmtpAuthStyle {
+    /// `AUTH XOAUTH2 <base64>` on one line, which Office 365 expects.
+    #[default]
+    InitialResponse,
+    /// `AUTH XOAUTH2`, then the credentials after the server's 334 challenge.
+    Challenge,
+}
+
+/// How the SMTP session logs in, with `--auth-mode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "lowercase")]
+pub enum SmtpAuthMode {
+    /// AUTH XOAUTH2 with the access token of the OAuth2 login.
+    #[default]
+    Xoauth2,
+    /// AUTH PLAIN with `--smtp-username` and `--smtp-password`, without an OAuth2 login,
+    /// for the servers that do not support OAuth2.
+    Basic,
+}
+
+/// The parts of the SMTP session, each timed and reported on its own: the TCP and
+/// STARTTLS connection, the AUTH, and the commands that send the message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SmtpPhase {
+    Connect,
+    Auth,
+    Command,
+}
+
+impl SmtpPhase {
+    fn description(&self) -> &'static str {
+        match self {
+            SmtpPhase::Connect => "connecting to the SMTP server",
+            SmtpPhase::Auth => "waiting for the SMTP server to answer AUTH",
+            SmtpPhase::Command => "waiting for the SMTP server to answer a command",
+        }
+    }
+
+    /// The phase as it is named in the errors, e.g. `SMTP connect failed: ...`.
+    fn name(&self) -> &'static str {
+        match self {
+            SmtpPhase::Connect => "connect",
+            SmtpPhase::Auth => "AUTH",
+            SmtpPhase::Command => "send",
+        }
+    }
+}
+
+/// How the SMTP server of the cloud is reached: over `--ip-version`, with the TLS limits
+/// and through the proxy of `--socks5`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SmtpRoute {
+    pub cloud: Cloud,
+    pub ip_version: IpVersion,
+    pub tls: TlsPolicy,
+    pub socks5: Option<Socks5Proxy>,
+}
+
+/// The SMTP server of `--smtp-server`, instead of the one of the cloud.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SmtpServer {
+    pub host: String,
+    pub port: u16,
+}
+
+/// Checks the `--smtp-server`, a host name or IP address and a port, e.g.
+/// `smtp.example.com:587` or `[2001:db8::1]:25`.
+pub fn parse_smtp_server(server: &str) -> Result<SmtpServer, String> {
+    let (host, port) = server
+        .rsplit_once(':')
+        .ok_or_else(|| format!("{server:?} is not a HOST:PORT"))?;
+    let port = match port.parse::<u16>() {
+        Ok(0) | Err(_) => return Err(format!("{port:?} is not a valid port")),
+        Ok(port) => port,
+    };
+    let host = match host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+    {
+        Some(address) => address
+            .parse::<IpAddr>()
+            .map(|_| address.to_string())
+            .map_err(|_| format!("{host:?} is not a valid IP address"))?,
+        None if host.parse::<IpAddr>().is_ok() => host.to_string(),
+        None => parse_domain(host)?,
+    };
+    Ok(SmtpServer { host, port })
+}
+
+/// The host and port the email is sent to, the SMTP server of the cloud unless
+/// `--smtp-server` overrides it.
+pub fn server(smtp: &SmtpArgs, cloud: Cloud) -> (&str, u16) {
+    match &smtp.smtp_server {
+        Some(server) => (server.host.as_str(), server.port),
+        None => (cloud.smtp_host(), SMTP_PORT),
+    }
+}
+
+/// Where the email is sent, for the send report.
+pub fn transport(smtp: &SmtpArgs, cloud: Cloud) -> String {
+    let (host, port) = server(smtp, cloud);
+    format!("{host}:{port} STARTTLS")
+}
+
+/// Checks the `--ehlo-name`, which is a host name or an address literal like
+/// `[192.0.2.1]` or `[IPv6:2001:db8::1]`.
+pub fn parse_ehlo_name(ehlo_name: &str) -> Result<String, String> {
+    match ehlo_name
+        .strip_prefix('[')
+        .and_then(|literal| literal.strip_suffix(']'))
+    {
+        Some(literal) => {
+            let address = literal.strip_prefix("IPv6:").unwrap_or(literal);
+            match address.parse::<IpAddr>() {
+                Ok(IpAddr::V6(_)) if !literal.starts_with("IPv6:") => {
+                    Err(format!("{ehlo_name:?} needs the IPv6: prefix"))
+                }
+                Ok(_) => Ok(ehlo_name.to_string()),
+                Err(_) => Err(format!("{ehlo_name:?} is not a valid address literal")),
+            }
+        }
+        None => parse_domain(ehlo_name),
+    }
+}
+
+/// Checks an e-mail address like the one of `--envelope-from`, a local part and a domain
+/// without the angle brackets of the MAIL FROM.
+pub fn parse_email(email: &str) -> Result<String, String> {
+    let invalid = || format!("{email:?} is not a valid e-mail address");
+    let (local_part, domain) = email.rsplit_once('@').ok_or_else(invalid)?;
+    let valid_local_part = !local_part.is_empty()
+        && local_part.len() <= 64
+        && !local_part
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '<' | '>'));
+    if !valid_local_part {
+        return Err(invalid());
+    }
+    let domain = parse_domain(domain).map_err(|_| invalid())?;
+    Ok(format!("{local_part}@{domain}"))
+}
+
+/// Uses the `--envelope-from` address as the MAIL FROM of the message, instead of the
+/// sender address. The From header stays the same.
+pub fn with_envelope_from<'x>(
+    mut message: Message<'x>,
+    envelope_from: Option<&str>,
+) -> Message<'x> {
+    if let Some(envelope_from) = envelope_from {
+        log::info!("Envelope sender (MAIL FROM): {envelope_from}");
+        message.mail_from.email = envelope_from.to_string().into();
+    }
+    message
+}
+
+/// Checks the regex of `--expect-banner`, where plain text matches as a substring.
+pub fn parse_banner_pattern(pattern: &str) -> Result<Regex, String> {
+    if pattern.trim().is_empty() {
+        return Err("the banner pattern must not be empty".to_string());
+    }
+    Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+/// Checks the greeting of the server against `--expect-banner`, before anything else is
+/// sent, e.g. to catch DNS or a proxy routing to another server.
+fn check_banner(greeting: &Response<String>, smtp: &SmtpArgs) -> mail_send::Result<()> {
+    let Some(expected) = &smtp.expect_banner else {
+        return Ok(());
+    };
+    let banner = reply_text(greeting);
+    log::info!("SMTP banner: {banner}");
+    if expected.is_match(greeting.message()) {
+        return Ok(());
+    }
+    log::error!("The SMTP banner does not match --expect-banner {expected}, quitting.");
+    Err(mail_send::Error::UnexpectedReply(greeting.clone()))
+}
+
+/// Checks a timeout in whole seconds, which must not be zero.
+pub fn parse_timeout(seconds: &str) -> Result<Duration, String> {
+    match seconds.parse::<u64>() {
+        Ok(0) => Err("the timeout must be at least 1 second".to_string()),
+        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Runs one phase of the SMTP session within its timeout, if it has one, and logs how
+/// long it took. A timeout, ours or the one of mail-send, and any other error are
+/// reported with the phase they happened in.
+async fn within_timeout<T>(
+    phase: SmtpPhase,
+    timeout: Option<Duration>,
+    future: impl Future<Output = mail_send::Result<T>>,
+) -> OAuth2Result<T> {
+    let start = Instant::now();
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or(Err(mail_send::Error::Timeout)),
+        None => future.await,
+    };
+    log::info!(
+        "SMTP {} took {} ms",
+        phase.name(),
+        start.elapsed().as_millis()
+    );
+    result.map_err(|err| match err {
+        mail_send::Error::Timeout => {
+            let error = OAuth2Error::new(
+                ErrorCodes::SmtpTimeout,
+                format!("Timed out {}.", phase.description()),
+            );
+            log::error!("SMTP {phase:?} Timeout: {}", error.error_code_desc);
+            error
+        }
+        err => {
+            log::error!("SMTP {phase:?} Error: {err:?}");
+            let mut error = OAuth2Error::from(err);
+            error.error_code_desc =
+                format!("SMTP {} failed: {}", phase.name(), error.error_code_desc);
+            error
+        }
+    })
+}
+
+/// The host name announced in EHLO, which is the name of this machine unless
+/// `--ehlo-name` overrides it.
+fn ehlo_name(smtp: &SmtpArgs) -> String {
+    smtp.ehlo_name.clone().unwrap_or_else(|| {
+        gethostname::gethostname()
+            .to_str()
+            .unwrap_or("[127.0.0.1]")
+            .to_string()
+    })
+}
+
+/// Picks the identity used for SMTP AUTH, which is the sender unless `--smtp-login`
+/// overrides it.
+pub fn smtp_login<'a>(smtp_login: Option<&'a str>, sender_email: &'a str) -> OAuth2Result<&'a str> {
+    let Some(smtp_login) = smtp_login else {
+        log::info!("SMTP AUTH identity: {sender_email} (the sender)");
+        return Ok(sender_email);
+    };
+    if smtp_login.trim().is_empty() || sender_email.trim().is_empty() {
+        return Err(OAuth2Error::new(
+            ErrorCodes::ConfigurationError,
+            "Both the SMTP login and the sender address are needed with --smtp-login.".into(),
+        ));
+    }
+    log::info!("SMTP AUTH identity: {smtp_login}");
+    log::info!("Sending as: {sender_email}");
+    Ok(smtp_login)
+}
+
+/// Checks that the From address is the authenticated mailbox, which Office 365 requires
+/// unless the mailbox has the SendAs permission on the From address, as `--allow-send-as`
+/// says. The addresses are compared case-insensitively.
+pub fn check_send_as(from: &str, mailbox: &str, allow_send_as: bool) -> OAuth2Result<()> {
+    if from.eq_ignore_ascii_case(mailbox) {
+        return Ok(());
+    }
+    if allow_send_as {
+        log::info!("Sending as {from} with the SendAs permission of {mailbox} (--allow-send-as).");
+        return Ok(());
+    }
+    Err(OAuth2Error::new(
+        ErrorCodes::ConfigurationError,
+        format!(
+            "The From address {from} is not the authenticated mailbox {mailbox}. Office 365 \
+            rejects the send unless {mailbox} has the SendAs permission on {from}; grant it \
+            and use --allow-send-as, or send as {mailbox}."
+        ),
+    ))
+}
+
+/// Checks that the SMTP password is not sent over a connection without TLS, unless
+/// `--allow-plaintext` allows it.
+fn check_plaintext(allow_plaintext: bool) -> OAuth2Result<()> {
+    if !allow_plaintext {
+        return Err(OAuth2Error::new(
+            ErrorCodes::TlsRequired,
+            "The SMTP server does not offer STARTTLS, the password of --auth-mode basic is \
+            only sent over a plaintext connection with --allow-plaintext."
+                .into(),
+        ));
+    }
+    log::warn!("Sending the SMTP password without TLS (--allow-plaintext).");
+    Ok(())
+}
+
+pub fn xoauth2_credentials<'a>(
+    login: &'a str,
+    access_token: &'a AccessToken,
+) -> Credentials<&'a str> {
+    Credentials::new_xoauth2(login, access_token.secret().as_str())
+}
+
+/// The STARTTLS client builder, connecting to `host` over the preferred IP version.
+/// It does not authenticate, `authenticate` does once the connection is up, so that a
+/// failed connection and a rejected login are told apart.
+async fn client_builder<'a>(
+    host: &'a str,
+    port: u16,
+    smtp: &SmtpArgs,
+    ip_version: IpVersion,
+) -> OAuth2Result<SmtpClientBuilder<&'a str>> {
+    let ehlo_name = ehlo_name(smtp);
+    log::debug!("EHLO name: {ehlo_name}");
+    // Leave room for our own timeouts, so that they fire first and name the phase.
+    let timeout = [smtp.smtp_connect_timeout, smtp.smtp_command_timeout]
+        .into_iter()
+        .flatten()
+        .fold(SMTP_TIMEOUT, Duration::max);
+    let mut builder = SmtpClientBuilder::new(host, port)
+        .implicit_tls(false)
+        .helo_host(ehlo_name)
+        .timeout(timeout);
+    builder.addr = net::resolve(host, port, ip_version).await?;
+    Ok(builder)
+}
+
+/// Starts TLS with the versions and cipher suites of `--min-tls-version` and
+/// `--tls-ciphers`, when they limit them.
+fn with_tls_policy<'a>(
+    mut builder: SmtpClientBuilder<&'a str>,
+    tls: &TlsPolicy,
+) -> OAuth2Result<SmtpClientBuilder<&'a str>> {
+    if !tls.is_default() {
+        log::info!("SMTP TLS: {}", tls.describe());
+        builder.tls_connector = tls.connector()?;
+    }
+    Ok(builder)
+}
+
+/// Names the TLS limits in a failed connection, as they may be why the handshake failed.
+fn explain_tls_failure(mut error: OAuth2Error, tls: &TlsPolicy) -> OAuth2Error {
+    let connect_failed = format!("SMTP {} failed", SmtpPhase::Connect.name());
+    if !tls.is_default() && error.error_code_desc.starts_with(&connect_failed) {
+        error.error_code_desc = format!(
+            "{} The TLS handshake was limited to {}.",
+            error.error_code_desc,
+            tls.describe()
+        );
+    }
+    error
+}
+
+/// Whether the connection needs [`connect_with_socket_options`] instead of the one of
+/// mail-send.
+fn needs_own_connect(smtp: &SmtpArgs, route: &SmtpRoute) -> bool {
+    smtp.tcp_keepalive.is_some()
+        || smtp.tcp_nodelay
+        || smtp.expect_banner.is_some()
+        || smtp.smtp_transcript
+        || route.socks5.is_some()
+}
+
+/// Logs the proxy of `--socks5` the SMTP connection goes through.
+fn log_proxy(route: &SmtpRoute) {
+    if let Some(proxy) = &route.socks5 {
+        log::info!("SMTP through the SOCKS5 proxy {}", proxy.addr);
+    }
+}
+
+/// Sets `--tcp-keepalive` and `--tcp-nodelay` on the SMTP socket.
+fn set_socket_options(stream: &TcpStream, smtp: &SmtpArgs) -> std::io::Result<()> {
+    if smtp.tcp_nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = smtp.tcp_keepalive {
+        SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+    }
+    Ok(())
+}
+
+/// Connects on a socket with the options of `--tcp-keepalive` and `--tcp-nodelay`,
+/// through the proxy of `--socks5`, checks the greeting against `--expect-banner` and
+/// says EHLO, logging the conversation with `--smtp-transcript`. Returns whether the
+/// server offers STARTTLS.
+async fn connect_and_ehlo(
+    builder: &SmtpClientBuilder<&str>,
+    smtp: &SmtpArgs,
+    socks5: Option<&Socks5Proxy>,
+) -> mail_send::Result<(SmtpClient<Transcript<TcpStream>>, bool)> {
+    let stream = match socks5 {
+        Some(proxy) => proxy.connect(&builder.addr).await?,
+        None => TcpStream::connect(&builder.addr).await?,
+    };
+    set_socket_options(&stream, smtp)?;
+    log::debug!(
+        "TCP keepalive: {:?}, TCP_NODELAY: {}",
+        smtp.tcp_keepalive,
+        smtp.tcp_nodelay
+    );
+    let mut client = SmtpClient {
+        stream: Transcript::new(stream, smtp.smtp_transcript),
+        timeout: builder.timeout,
+    };
+    let greeting = client.read().await?;
+    if greeting.code() != 220 {
+        return Err(mail_send::Error::UnexpectedReply(greeting));
+    }
+    check_banner(&greeting, smtp)?;
+    let starttls = client
+        .ehlo(&ehlo_name(smtp))
+        .await?
+        .has_capability(EXT_START_TLS);
+    Ok((client, starttls))
+}
+
+/// Starts TLS on the connection of [`connect_and_ehlo`] and says EHLO again. The
+/// transcript goes on under TLS.
+async fn start_tls(
+    mut client: SmtpClient<Transcript<TcpStream>>,
+    builder: &SmtpClientBuilder<&str>,
+    smtp: &SmtpArgs,
+) -> mail_send::Result<SmtpClient<Transcript<TlsStream<TcpStream>>>> {
+    let reply = client.cmd(b"STARTTLS\r\n").await?;
+    if reply.code() != 220 {
+        return Err(mail_send::Error::UnexpectedReply(reply));
+    }
+    let (stream, log) = client.stream.into_parts();
+    let domain = ServerName::try_from(builder.tls_hostname)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let stream = builder.tls_connector.connect(domain, stream).await?;
+    let mut client = SmtpClient {
+        stream: Transcript::with_log(stream, log),
+        timeout: client.timeout,
+    };
+    client.ehlo(&ehlo_name(smtp)).await?;
+    Ok(client)
+}
+
+/// Connects and starts TLS like `SmtpClientBuilder::connect`, which does not give access
+/// to its socket or the greeting, but as [`connect_and_ehlo`] does.
+async fn connect_with_socket_options(
+    builder: &SmtpClientBuilder<&str>,
+    smtp: &SmtpArgs,
+    socks5: Option<&Socks5Proxy>,
+) -> mail_send::Result<SmtpClient<Transcript<TlsStream<TcpStream>>>> {
+    let connect = async {
+        let (client, starttls) = connect_and_ehlo(builder, smtp, socks5).await?;
+        if !starttls {
+            return Err(mail_send::Error::MissingStartTls);
+        }
+        start_tls(client, builder, smtp).await
+    };
+    tokio::time::timeout(builder.timeout, connect)
+        .await
+        .unwrap_or(Err(mail_send::Error::Timeout))
+}
+
+/// A connection of [`connect_allowing_plaintext`].
+enum Connection {
+    Tls(Box<SmtpClient<Transcript<TlsStream<TcpStream>>>>),
+    Plaintext(SmtpClient<Transcript<TcpStream>>),
+}
+
+/// Connects like [`connect_with_socket_options`], but stays on the plaintext connection
+/// when the server does not offer STARTTLS, for `--auth-mode basic` to refuse or, with
+/// `--allow-plaintext`, to use.
+async fn connect_allowing_plaintext(
+    builder: &SmtpClientBuilder<&str>,
+    smtp: &SmtpArgs,
+    socks5: Option<&Socks5Proxy>,
+) -> mail_send::Result<Connection> {
+    let connect = async {
+        let (client, starttls) = connect_and_ehlo(builder, smtp, socks5).await?;
+        if !starttls {
+            return Ok(Connection::Plaintext(client));
+        }
+        Ok(Connection::Tls(Box::new(
+            start_tls(client, builder, smtp).await?,
+        )))
+    };
+    tokio::time::timeout(builder.timeout, connect)
+        .await
+        .unwrap_or(Err(mail_send::Error::Timeout))
+}
+
+/// The SASL mechanism of the credentials, and its name in the AUTH command.
+fn mechanism(credentials: &Credentials<&str>) -> (u64, &'static str) {
+    match credentials {
+        Credentials::Plain { .. } => (AUTH_PLAIN, "PLAIN"),
+        _ => (AUTH_XOAUTH2, "XOAUTH2"),
+    }
+}
+
+/// Authenticates with `AUTH XOAUTH2`, or `AUTH PLAIN` for the username and password of
+/// `--auth-mode basic`, sending the credentials on the same line, or once the server
+/// asks for them with a 334 challenge with `--smtp-auth-style challenge`.
+async fn authenticate<T: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut SmtpClient<T>,
+    credentials: &Credentials<&str>,
+    auth_style: SmtpAuthStyle,
+) -> mail_send::Result<()> {
+    let (mechanism, name) = mechanism(credentials);
+    let encoded = credentials.encode(mechanism, "")?;
+    let reply = match auth_style {
+        SmtpAuthStyle::InitialResponse => client.cmd(format!("AUTH {name} {encoded}\r\n")).await?,
+        SmtpAuthStyle::Challenge => {
+            let reply = client.cmd(format!("AUTH {name}\r\n")).await?;
+            if reply.code() != 334 {
+                return Err(mail_send::Error::UnexpectedReply(reply));
+            }
+            client.cmd(format!("{encoded}\r\n")).await?
+        }
+    };
+    match reply.code() {
+        235 => Ok(()),
+        // The server sends the error details as another challenge, answered with an
+        // empty line to get the final reply.
+        334 => Err(mail_send::Error::AuthenticationFailed(
+            client.cmd(b"\r\n").await?,
+        )),
+        _ => Err(mail_send::Error::AuthenticationFailed(reply)),
+    }
+}
+
+/// Connects, then authenticates, as two phases that are timed and reported on their
+/// own: a failed TCP or STARTTLS connection is not mistaken for a rejected login.
+async fn connect_and_authenticate<T: AsyncRead + AsyncWrite + Unpin>(
+    connect: impl Future<Output = mail_send::Result<SmtpClient<T>>>,
+    credentials: &Credentials<&str>,
+    smtp: &SmtpArgs,
+) -> OAuth2Result<SmtpClient<T>> {
+    let client = within_timeout(SmtpPhase::Connect, smtp.smtp_connect_timeout, connect).await?;
+    authenticate_connected(client, credentials, smtp).await
+}
+
+/// Authenticates on a connection, as its own phase.
+async fn authenticate_connected<T: AsyncRead + AsyncWrite + Unpin>(
+    mut client: SmtpClient<T>,
+    credentials: &Credentials<&str>,
+    smtp: &SmtpArgs,
+) -> OAuth2Result<SmtpClient<T>> {
+    log::info!(
+        "Authenticating SMTP {} Credentials....",
+        mechanism(credentials).1
+    );
+    within_timeout(
+        SmtpPhase::Auth,
+        smtp.smtp_command_timeout,
+        authenticate(&mut client, credentials, smtp.smtp_auth_style),
+    )
+    .await?;
+    Ok(client)
+}
+
+/// Ends the session with QUIT, which the server answers with 221.
+async fn quit<T: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut SmtpClient<T>,
+) -> mail_send::Result<()> {
+    let reply = client.cmd(b"QUIT\r\n").await?;
+    if reply.code() != 221 {
+        return Err(mail_send::Error::UnexpectedReply(reply));
+    }
+    Ok(())
+}
+
+/// Connects, which says EHLO and starts TLS as for a send, then quits without
+/// authenticating.
+async fn handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    connect: impl Future<Output = mail_send::Result<SmtpClient<T>>>,
+    smtp: &SmtpArgs,
+) -> OAuth2Result<()> {
+    let mut client = within_timeout(SmtpPhase::Connect, smtp.smtp_connect_timeout, connect).await?;
+    within_timeout(
+        SmtpPhase::Command,
+        smtp.smtp_command_timeout,
+        quit(&mut client),
+    )
+    .await
+}
+
+/// Runs `count` handshakes one after the other and returns how long each took, the
+/// first failed one is the error.
+async fn time_handshakes<F, Fut>(count: u32, mut handshake: F) -> OAuth2Result<Vec<Duration>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = OAuth2Result<()>>,
+{
+    let mut latencies = Vec::with_capacity(count as usize);
+    for cycle in 1..=count {
+        let start = Instant::now();
+        handshake().await?;
+        let latency = start.elapsed();
+        log::debug!("Handshake {cycle}/{count}: {} ms", latency.as_millis());
+        latencies.push(latency);
+    }
+    Ok(latencies)
+}
+
+/// Times `count` connect, EHLO, STARTTLS and QUIT cycles with the SMTP server of the
+/// cloud for `benchmark-connect`. The host is resolved once, so that DNS is not part
+/// of the latencies.
+pub async fn benchmark_connect(
+    count: u32,
+    smtp: &SmtpArgs,
+    route: &SmtpRoute,
+) -> OAuth2Result<Vec<Duration>> {
+    let (smtp_host, port) = server(smtp, route.cloud);
+    let builder = client_builder(smtp_host, port, smtp, route.ip_version).await?;
+    let builder = with_tls_policy(builder, &route.tls)?;
+    log::info!(
+        "Timing {count} handshake(s) with {smtp_host} ({})",
+        builder.addr
+    );
+    log_proxy(route);
+    let latencies = if needs_own_connect(smtp, route) {
+        time_handshakes(count, || {
+            handshake(
+                connect_with_socket_options(&builder, smtp, route.socks5.as_ref()),
+                smtp,
+            )
+        })
+        .await
+    } else {
+        time_handshakes(count, || handshake(builder.connect(), smtp)).await
+    };
+    latencies.map_err(|error| explain_tls_failure(error, &route.tls))
+}
+
+/// The reply as logged and reported, e.g. `550 5.1.1 User unknown`.
+fn reply_text(reply: &Response<String>) -> String {
+    match reply.esc {
+        [0, ..] => format!("{} {}", reply.code(), reply.message()),
+        [class, subject, detail] => format!(
+            "{} {class}.{subject}.{detail} {}",
+            reply.code(),
+            reply.message()
+        ),
+    }
+}
+
+/// Sends the message like `SmtpClient::send` does, except that a rejected recipient
+/// does not stop the others. The message is only sent when a recipient was accepted.
+async fn send_message<T: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut SmtpClient<T>,
+    message: Message<'_>,
+) -> mail_send::Result<Vec<RecipientResult>> {
+    let reply = client
+        .cmd(format!("MAIL FROM:<{}>\r\n", message.mail_from.email))
+        .await?;
+    if !reply.is_positive_completion() {
+        return Err(mail_send::Error::UnexpectedReply(reply));
+    }
+
+    let mut results = Vec::new();
+    for rcpt in &message.rcpt_to {
+        let reply = client.cmd(format!("RCPT TO:<{}>\r\n", rcpt.email)).await?;
+        let result = RecipientResult {
+            email: rcpt.email.to_string(),
+            accepted: reply.is_positive_completion(),
+            reply: reply_text(&reply),
+        };
+        if !result.accepted {
+            log::warn!("Recipient {} rejected: {}", result.email, result.reply);
+        }
+        results.push(result);
+    }
+    if results.iter().any(|result| result.accepted) {
+        client.data(message.body.as_ref()).await?;
+    }
+    Ok(results)
+}
+
+/// Fails the send when no recipient was accepted, listing the reasons.
+fn check_recipients(results: Vec<RecipientResult>) -> OAuth2Result<Vec<RecipientResult>> {
+    if results.iter().any(|result| result.accepted) {
+        return Ok(results);
+    }
+    let reasons: Vec<String> = results
+        .iter()
+        .map(|result| format!("{}: {}", result.email, result.reply))
+        .collect();
+    Err(OAuth2Error::new(
+        ErrorCodes::SmtpError,
+        format!("All the recipients were rejected, {}", reasons.join(", ")),
+    ))
+}
+
+/// Logs in and sends the message, returning whether each recipient was accepted. The
+/// access token is not used with `--auth-mode basic`.
+pub async fn send_email(
+    message: Message<'_>,
+    login: &str,
+    access_token: &AccessToken,
+    smtp: &SmtpArgs,
+    route: &SmtpRoute,
+) -> OAuth2Result<Vec<RecipientResult>> {
+    let (host, port) = server(smtp, route.cloud);
+    let builder = client_builder(host, port, smtp, route.ip_version).await?;
+    let builder = with_tls_policy(builder, &route.tls)?;
+    log_proxy(route);
+
+    if let Some((username, password)) = smtp.basic_credentials()? {
+        let credentials = Credentials::new(username, password);
+        let connect = telemetry::in_span(
+            "smtp_connect",
+            vec![("transport", transport(smtp, route.cloud))],
+            connect_allowing_plaintext(&builder, smtp, route.socks5.as_ref()),
+        );
+        let connection = within_timeout(SmtpPhase::Connect, smtp.smtp_connect_timeout, connect)
+            .await
+            .map_err(|error| explain_tls_failure(error, &route.tls))?;
+        return match connection {
+            Connection::Tls(client) => {
+                let client = authenticate_connected(*client, &credentials, smtp).await?;
+                send_authenticated(client, message, smtp).await
+            }
+            Connection::Plaintext(client) => {
+                check_plaintext(smtp.allow_plaintext)?;
+                let client = authenticate_connected(client, &credentials, smtp).await?;
+                send_authenticated(client, message, smtp).await
+            }
+        };
+    }
+
+    let credentials = xoauth2_credentials(login, access_token);
+    let connect = telemetry::in_span(
+        "smtp_connect",
+        vec![("transport", transport(smtp, route.cloud))],
+        connect_with_socket_options(&builder, smtp, route.socks5.as_ref()),
+    );
+    let client = connect_and_authenticate(connect, &credentials, smtp)
+        .await
+        .map_err(|error| explain_tls_failure(error, &route.tls))?;
+    send_authenticated(client, message, smtp).await
+}
+
+/// A network error or a timeout while the message is sent leaves it unknown whether the
+/// server got it, so it is not sent again by [`send_with_retries`].
+fn interrupted_send(mut error: OAuth2Error) -> OAuth2Error {
+    if is_transient(&error) {
+        error.error_code = ErrorCodes::SmtpSendInterrupted;
+        error.error_code_desc = format!(
+            "{} The message may have been sent, it is not sent again.",
+            error.error_code_desc
+        );
+    }
+    error
+}
+
+/// Sends the message on an authenticated connection.
+async fn send_authenticated<T: AsyncRead + AsyncWrite + Unpin>(
+    mut client: SmtpClient<T>,
+    message: Message<'_>,
+    smtp: &SmtpArgs,
+) -> OAuth2Result<Vec<RecipientResult>> {
+    log::info!("Sending SMTP Email....");
+    let results = within_timeout(
+        SmtpPhase::Command,
+        smtp.smtp_command_timeout,
+        send_message(&mut client, message),
+    )
+    .await
+    .map_err(interrupted_send)?;
+    let results = check_recipients(results)?;
+    log::info!("Sending Email success!!");
+    Ok(results)
+}
+
+/// Sends the email with the given access token. When `auto_relogin` is set and the
+/// server rejects the token, `relogin` is called once for a fresh token and the send
+/// is retried with it.
+pub async fn send_with_relogin<T, S, SF, R, RF>(
+    access_token: AccessToken,
+    auto_relogin: bool,
+    send: S,
+    relogin: R,
+) -> OAuth2Result<T>
+where
+    S: Fn(AccessToken) -> SF,
+    SF: Future<Output = OAuth2Result<T>>,
+    R: FnOnce() -> RF,
+    RF: Future<Output = OAuth2Result<AccessToken>>,
+{
+    match send(access_token).await {
+        Err(error) if auto_relogin && error.error_code == ErrorCodes::SmtpAuthenticationFailed => {
+            log::info!("The SMTP server rejected the access token, please login again.");
+            let access_token = relogin().await?;
+            send(access_token).await
+        }
+        result => result,
+    }
+}
+
+/// Whether a failed send may succeed when tried again: a network error or a timeout
+/// before the message was handed over, but not a rejected login or recipient.
+fn is_transient(error: &OAuth2Error) -> bool {
+    matches!(
+        error.error_code,
+        ErrorCodes::IoError | ErrorCodes::SmtpTimeout
+    )
+}
+
+/// Sends the email again after a transient failure, at most `retries` more times,
+/// waiting as long as `backoff` tells before each.
+pub async fn send_with_retries<T, S, SF>(retries: u32, backoff: Backoff, send: S) -> OAuth2Result<T>
+where
+    S: Fn() -> SF,
+    SF: Future<Output = OAuth2Result<T>>,
+{
+    for retry in 1..=retries {
+        match send().await {
+            Err(error) if is_transient(&error) => {
+                let wait = backoff.delay(retry);
+                log::warn!(
+                    "The send failed with {:?}, retry {retry} of {retries} in {wait:?}.",
+                    error.error_code
+                );
+                tokio::time::sleep(wait).await;
+            }
+            result => return result,
+        }
+    }
+    send().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::fs;
+    use std::time::Duration;
+
+    use clap::Parser;
+    use mail_send::mail_builder::encoders::base64::base64_encode;
+    use oauth2::AccessToken;
+    use smtp_proto::{Response, AUTH_XOAUTH2};
+    use socket2::SockRef;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{
+        authenticate, check_recipients, check_send_as, client_builder, connect_and_authenticate,
+        connect_and_ehlo, connect_with_socket_options, explain_tls_failure, handshake,
+        parse_banner_pattern, parse_ehlo_name, parse_email, parse_smtp_server, parse_timeout, quit,
+        send_email, send_message, send_with_relogin, send_with_retries, set_socket_options,
+        smtp_login, time_handshakes, transport, with_envelope_from, with_tls_policy,
+        within_timeout, xoauth2_credentials, SmtpAuthMode, SmtpAuthStyle, SmtpPhase, SmtpRoute,
+        SmtpServer, SMTP_TIMEOUT,
+    };
+    use crate::backoff::{Backoff, Jitter};
+    use crate::cli::{Cli, MessageArgs, SmtpArgs};
+    use crate::cloud::Cloud;
+    use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+    use crate::message;
+    use crate::net::IpVersion;
+    use crate::socks5::parse_socks5;
+    use crate::telemetry;
+    use crate::tls::{TlsCipherSuite, TlsPolicy, TlsVersion};
+
+    const EHLO_REPLY: &str = "250-mock\r\n250 AUTH XOAUTH2\r\n";
+
+    /// A plain text SMTP server for one session, answering each line it reads with the
+    /// next reply. The message after a DATA answered with 354 is read up to the final
+    /// dot as one line. Returns the lines it read.
+    async fn mock_smtp(listener: TcpListener, replies: Vec<&'static str>) -> Vec<String> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        mock_session(&mut stream, replies).await
+    }
+
+    /// The session of [`mock_smtp`] on an accepted connection.
+    async fn mock_session(stream: &mut TcpStream, replies: Vec<&'static str>) -> Vec<String> {
+        let (reader, mut writer) = stream.split();
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"220 mock ESMTP\r\n").await.unwrap();
+
+        let mut lines = Vec::new();
+        let mut in_data = false;
+        for reply in replies {
+            let mut line = String::new();
+            loop {
+                let mut next = String::new();
+                reader.read_line(&mut next).await.unwrap();
+                line.push_str(&next);
+                if !in_data || next == ".\r\n" {
+                    break;
+                }
+            }
+            in_data = line == "DATA\r\n" && reply.starts_with("354");
+            lines.push(line.trim_end().to_string());
+            writer.write_all(reply.as_bytes()).await.unwrap();
+        }
+        lines
+    }
+
+    /// A SOCKS5 proxy without a login whose tunnel ends in [`mock_session`]. Returns the
+    /// host it was asked to connect to and the lines of the session.
+    async fn mock_socks5_smtp(
+        listener: TcpListener,
+        replies: Vec<&'static str>,
+    ) -> (String, Vec<String>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        let mut request = [0; 5];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..4], [5, 1, 0, 3]);
+        let mut host = vec![0; request[4] as usize + 2];
+        stream.read_exact(&mut host).await.unwrap();
+        host.truncate(request[4] as usize);
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        let lines = mock_session(&mut stream, replies).await;
+        (String::from_utf8(host).unwrap(), lines)
+    }
+
+    fn encoded_credentials() -> String {
+        String::from_utf8(
+            base64_encode(b"user=me@example.com\x01auth=Bearer token\x01\x01").unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_auth_with_initial_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(listener, vec![EHLO_REPLY, "235 2.7.0 OK\r\n"]));
+
+        let access_token = AccessToken::new("token".to_string());
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        assert!(builder.credentials.is_none());
+        let mut client = builder.connect_plain().await.unwrap();
+        authenticate(&mut client, &credentials, smtp.smtp_auth_style)
+            .await
+            .unwrap();
+
+        let lines = server.await.unwrap();
+        assert!(lines[0].starts_with("EHLO "));
+        assert_eq!(lines[1], format!("AUTH XOAUTH2 {}", encoded_credentials()));
+    }
+
+    #[tokio::test]
+    async fn test_auth_with_pre_acquired_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(listener, vec![EHLO_REPLY, "235 2.7.0 OK\r\n"]));
+
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--access-token",
+            "Bearer token",
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ])
+        .unwrap();
+        let client = cli.run.client.resolve(None).unwrap();
+        let access_token = client.access_token.unwrap();
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        let builder = client_builder("localhost", port, &cli.run.smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let mut client = builder.connect_plain().await.unwrap();
+        authenticate(&mut client, &credentials, cli.run.smtp.smtp_auth_style)
+            .await
+            .unwrap();
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines[1], format!("AUTH XOAUTH2 {}", encoded_credentials()));
+    }
+
+    #[tokio::test]
+    async fn test_auth_after_challenge() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![EHLO_REPLY, "334 \r\n", "235 2.7.0 OK\r\n"],
+        ));
+
+        let access_token = AccessToken::new("token".to_string());
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        let smtp = SmtpArgs {
+            smtp_auth_style: SmtpAuthStyle::Challenge,
+            ..Default::default()
+        };
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let mut client = builder.connect_plain().await.unwrap();
+        authenticate(&mut client, &credentials, smtp.smtp_auth_style)
+            .await
+            .unwrap();
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines[1], "AUTH XOAUTH2");
+        assert_eq!(lines[2], encoded_credentials());
+    }
+
+    #[tokio::test]
+    async fn test_auth_after_challenge_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                EHLO_REPLY,
+                "334 \r\n",
+                "334 eyJzdGF0dXMiOiI0MDEifQ==\r\n",
+                "535 5.7.3 Authentication unsuccessful\r\n",
+            ],
+        ));
+
+        let access_token = AccessToken::new("token".to_string());
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        let smtp = SmtpArgs {
+            smtp_auth_style: SmtpAuthStyle::Challenge,
+            ..Default::default()
+        };
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let mut client = builder.connect_plain().await.unwrap();
+        let error = authenticate(&mut client, &credentials, smtp.smtp_auth_style)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            OAuth2Error::from(error).error_code,
+            ErrorCodes::SmtpAuthenticationFailed
+        );
+
+        // The error challenge is answered with an empty line.
+        assert_eq!(server.await.unwrap()[3], "");
+    }
+
+    #[tokio::test]
+    async fn test_connect_failure_is_reported_as_connect() {
+        // Nothing listens on the port anymore, the connection is refused.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = closed.local_addr().unwrap().port();
+        drop(closed);
+
+        let access_token = AccessToken::new("token".to_string());
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let error = connect_and_authenticate(builder.connect_plain(), &credentials, &smtp)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.error_code, ErrorCodes::IoError);
+        assert!(
+            error.error_code_desc.starts_with("SMTP connect failed"),
+            "{}",
+            error.error_code_desc
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_is_reported_as_auth() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![EHLO_REPLY, "535 5.7.3 Authentication unsuccessful\r\n"],
+        ));
+
+        let access_token = AccessToken::new("token".to_string());
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let error = connect_and_authenticate(builder.connect_plain(), &credentials, &smtp)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.error_code, ErrorCodes::SmtpAuthenticationFailed);
+        assert!(
+            error.error_code_desc.starts_with("SMTP AUTH failed"),
+            "{}",
+            error.error_code_desc
+        );
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines[1], format!("AUTH XOAUTH2 {}", encoded_credentials()));
+    }
+
+    /// The SMTP arguments of `--auth-mode basic` with the mock server at `port`.
+    fn basic_auth_args(port: u16, allow_plaintext: bool) -> SmtpArgs {
+        SmtpArgs {
+            smtp_server: Some(SmtpServer {
+                host: "127.0.0.1".to_string(),
+                port,
+            }),
+            auth_mode: SmtpAuthMode::Basic,
+            smtp_username: Some("me@example.com".to_string()),
+            smtp_password: Some("secret".to_string()),
+            allow_plaintext,
+            ..Default::default()
+        }
+    }
+
+    fn test_message() -> mail_send::smtp::message::Message<'static> {
+        let message = message::build_message(
+            ("Sender", "me@example.com"),
+            vec![("Recipient", "recipient@example.com")],
+            &MessageArgs::default(),
+        )
+        .unwrap();
+        message::into_message(message, &MessageArgs::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_over_plaintext() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                "250-mock\r\n250 AUTH PLAIN LOGIN\r\n",
+                "235 2.7.0 Authentication successful\r\n",
+                "250 2.1.0 Sender OK\r\n",
+                "250 2.1.5 Recipient OK\r\n",
+                "354 Start mail input\r\n",
+                "250 2.0.0 OK queued\r\n",
+            ],
+        ));
+
+        let route = SmtpRoute {
+            ip_version: IpVersion::V4,
+            ..Default::default()
+        };
+        // The access token is not sent with --auth-mode basic.
+        let access_token = AccessToken::new(String::new());
+        telemetry::take_started();
+        let results = send_email(
+            test_message(),
+            "me@example.com",
+            &access_token,
+            &basic_auth_args(port, true),
+            &route,
+        )
+        .await
+        .unwrap();
+        assert!(results[0].accepted);
+        assert_eq!(telemetry::take_started(), ["smtp_connect"]);
+
+        let lines = server.await.unwrap();
+        let encoded =
+            String::from_utf8(base64_encode(b"\0me@example.com\0secret").unwrap()).unwrap();
+        assert_eq!(lines[1], format!("AUTH PLAIN {encoded}"));
+        assert_eq!(lines[2], "MAIL FROM:<me@example.com>");
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_refused_over_plaintext() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(listener, vec![EHLO_REPLY]));
+
+        let route = SmtpRoute {
+            ip_version: IpVersion::V4,
+            ..Default::default()
+        };
+        let access_token = AccessToken::new(String::new());
+        let error = send_email(
+            test_message(),
+            "me@example.com",
+            &access_token,
+            &basic_auth_args(port, false),
+            &route,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::TlsRequired);
+        assert!(
+            error.error_code_desc.contains("--allow-plaintext"),
+            "{}",
+            error.error_code_desc
+        );
+
+        // Only EHLO was sent, not the password.
+        assert_eq!(server.await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_recipient_does_not_stop_the_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                EHLO_REPLY,
+                "250 2.1.0 Sender OK\r\n",
+                "250 2.1.5 Recipient OK\r\n",
+                "550 5.1.10 RESOLVER.ADR.RecipientNotFound; Recipient not found\r\n",
+                "250 2.1.5 Recipient OK\r\n",
+                "354 Start mail input\r\n",
+                "250 2.0.0 OK queued\r\n",
+            ],
+        ));
+
+        let message = message::build_message(
+            ("Sender", "me@example.com"),
+            vec![
+                ("A", "a@example.com"),
+                ("Unknown", "unknown@example.com"),
+                ("B", "b@example.com"),
+            ],
+            &MessageArgs::default(),
+        )
+        .unwrap();
+        let mut message = message::into_message(message, &MessageArgs::default()).unwrap();
+        // mail-send does not keep the order of the To header, the replies follow this one.
+        message.rcpt_to = vec![
+            "a@example.com".into(),
+            "unknown@example.com".into(),
+            "b@example.com".into(),
+        ];
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let mut client = builder.connect_plain().await.unwrap();
+        let results = send_message(&mut client, message).await.unwrap();
+
+        let accepted: Vec<(&str, bool)> = results
+            .iter()
+            .map(|result| (result.email.as_str(), result.accepted))
+            .collect();
+        assert_eq!(
+            accepted,
+            [
+                ("a@example.com", true),
+                ("unknown@example.com", false),
+                ("b@example.com", true)
+            ]
+        );
+        assert_eq!(
+            results[1].reply,
+            "550 5.1.10 RESOLVER.ADR.RecipientNotFound; Recipient not found"
+        );
+        let results = check_recipients(results).unwrap();
+        assert_eq!(results.len(), 3);
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines[2], "RCPT TO:<a@example.com>");
+        assert_eq!(lines[5], "DATA");
+        assert!(lines[6].ends_with("\r\n."));
+    }
+
+    #[tokio::test]
+    async fn test_dumped_message_is_the_sent_one() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                EHLO_REPLY,
+                "250 2.1.0 Sender OK\r\n",
+                "250 2.1.5 Recipient OK\r\n",
+                "354 Start mail input\r\n",
+                "250 2.0.0 OK queued\r\n",
+            ],
+        ));
+
+        let message = message::build_message(
+            ("Sender", "me@example.com"),
+            vec![("Recipient", "you@example.com")],
+            &MessageArgs::default(),
+        )
+        .unwrap();
+        let message = message::into_message(message, &MessageArgs::default()).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("message.eml");
+        message::dump_message(&message, &path).unwrap();
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let mut client = builder.connect_plain().await.unwrap();
+        send_message(&mut client, message).await.unwrap();
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines[3], "DATA");
+        // Undo the dot-stuffing and the final dot of the DATA.
+        let sent = lines[4]
+            .strip_suffix("\r\n.")
+            .unwrap()
+            .replace("\r\n..", "\r\n.");
+        let dumped = fs::read_to_string(&path).unwrap();
+        assert_eq!(dumped.trim_end(), sent.trim_end());
+    }
+
+    #[tokio::test]
+    async fn test_all_recipients_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                EHLO_REPLY,
+                "250 2.1.0 Sender OK\r\n",
+                "550 5.1.1 User unknown\r\n",
+            ],
+        ));
+
+        let message = message::build_message(
+            ("Sender", "me@example.com"),
+            vec![("Unknown", "unknown@example.com")],
+            &MessageArgs::default(),
+        )
+        .unwrap();
+        let message = message::into_message(message, &MessageArgs::default()).unwrap();
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let mut client = builder.connect_plain().await.unwrap();
+        let results = send_message(&mut client, message).await.unwrap();
+        // No DATA is sent.
+        assert_eq!(server.await.unwrap().len(), 3);
+
+        let error = check_recipients(results).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::SmtpError);
+        assert!(error
+            .error_code_desc
+            .contains("unknown@example.com: 550 5.1.1 User unknown"));
+    }
+
+    #[tokio::test]
+    async fn test_client_builder_uses_ip_version() {
+        let smtp = SmtpArgs::default();
+
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        assert_eq!(builder.addr, "127.0.0.1:587");
+        assert_eq!(builder.tls_hostname, "localhost");
+
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::Auto)
+            .await
+            .unwrap();
+        assert_eq!(builder.addr, "localhost:587");
+    }
+
+    #[tokio::test]
+    async fn test_tls_policy() {
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        assert!(with_tls_policy(builder, &TlsPolicy::default()).is_ok());
+
+        let tls = TlsPolicy {
+            min_version: TlsVersion::V1_3,
+            cipher_suites: vec![TlsCipherSuite::EcdheRsaAes128GcmSha256],
+        };
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let error = with_tls_policy(builder, &tls).err().unwrap();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+
+        let tls = TlsPolicy {
+            min_version: TlsVersion::V1_3,
+            cipher_suites: Vec::new(),
+        };
+        let error = explain_tls_failure(
+            OAuth2Error::new(
+                ErrorCodes::SmtpError,
+                "SMTP connect failed: received fatal alert: ProtocolVersion".to_string(),
+            ),
+            &tls,
+        );
+        assert!(error
+            .error_code_desc
+            .ends_with("The TLS handshake was limited to TLS 1.3 or later."));
+        let error = explain_tls_failure(
+            OAuth2Error::new(ErrorCodes::SmtpError, "SMTP send failed: 554".to_string()),
+            &tls,
+        );
+        assert_eq!(error.error_code_desc, "SMTP send failed: 554");
+    }
+
+    #[tokio::test]
+    async fn test_client_builder_uses_ehlo_name() {
+        let smtp = SmtpArgs {
+            ehlo_name: Some("mail.contoso.com".to_string()),
+            ..Default::default()
+        };
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::Auto)
+            .await
+            .unwrap();
+        assert_eq!(builder.local_host, "mail.contoso.com");
+
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::Auto)
+            .await
+            .unwrap();
+        assert_eq!(
+            builder.local_host,
+            gethostname::gethostname().to_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_ehlo_name() {
+        assert_eq!(
+            parse_ehlo_name("Mail.Contoso.com").unwrap(),
+            "mail.contoso.com"
+        );
+        assert!(parse_ehlo_name("[192.0.2.1]").is_ok());
+        assert!(parse_ehlo_name("[IPv6:2001:db8::1]").is_ok());
+        assert!(parse_ehlo_name("[2001:db8::1]").is_err());
+        assert!(parse_ehlo_name("[not an address]").is_err());
+        assert!(parse_ehlo_name("my host").is_err());
+        assert!(parse_ehlo_name("").is_err());
+    }
+
+    #[test]
+    fn test_parse_smtp_server() {
+        let server = parse_smtp_server("Mail.Example.com:25").unwrap();
+        assert_eq!(
+            (server.host.as_str(), server.port),
+            ("mail.example.com", 25)
+        );
+        assert_eq!(
+            parse_smtp_server("192.0.2.1:587").unwrap().host,
+            "192.0.2.1"
+        );
+        assert_eq!(
+            parse_smtp_server("[2001:db8::1]:587").unwrap().host,
+            "2001:db8::1"
+        );
+        assert!(parse_smtp_server("mail.example.com").is_err());
+        assert!(parse_smtp_server("mail.example.com:0").is_err());
+        assert!(parse_smtp_server("mail.example.com:smtp").is_err());
+        assert!(parse_smtp_server("[not an address]:25").is_err());
+
+        let smtp = SmtpArgs {
+            smtp_server: Some(server),
+            ..Default::default()
+        };
+        assert_eq!(
+            transport(&smtp, Cloud::default()),
+            "mail.example.com:25 STARTTLS"
+        );
+        assert_eq!(
+            transport(&SmtpArgs::default(), Cloud::default()),
+            "smtp.office365.com:587 STARTTLS"
+        );
+    }
+
+    #[test]
+    fn test_parse_email() {
+        assert_eq!(
+            parse_email("Bounces+Test@Mail.Contoso.com").unwrap(),
+            "Bounces+Test@mail.contoso.com"
+        );
+        assert!(parse_email("bounces").is_err());
+        assert!(parse_email("@contoso.com").is_err());
+        assert!(parse_email("bounces@").is_err());
+        assert!(parse_email("<bounces@contoso.com>").is_err());
+        assert!(parse_email("boun ces@contoso.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("30").unwrap(), Duration::from_secs(30));
+        assert!(parse_timeout("0").is_err());
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("1.5").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_builder_timeout_leaves_room_for_ours() {
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        assert_eq!(builder.timeout, SMTP_TIMEOUT);
+
+        let smtp = SmtpArgs {
+            smtp_connect_timeout: Some(Duration::from_secs(5)),
+            smtp_command_timeout: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let builder = client_builder("localhost", 587, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        assert_eq!(builder.timeout, Duration::from_secs(300));
+    }
+
+    #[tokio::test]
+    async fn test_connect_times_out_when_the_server_stalls_at_greeting() {
+        // The connection is accepted by the kernel, but no greeting is ever sent.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+
+        let error = within_timeout(
+            SmtpPhase::Connect,
+            Some(Duration::from_millis(200)),
+            builder.connect(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(error.error_code, ErrorCodes::SmtpTimeout);
+        assert!(error.error_code_desc.contains("connecting"));
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout_names_the_phase() {
+        let error = within_timeout(
+            SmtpPhase::Command,
+            Some(Duration::from_millis(50)),
+            std::future::pending::<mail_send::Result<()>>(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::SmtpTimeout);
+        assert!(error.error_code_desc.contains("answer a command"));
+
+        // The timeout of mail-send is reported the same way.
+        let error = within_timeout(SmtpPhase::Command, None, async {
+            Err::<(), _>(mail_send::Error::Timeout)
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::SmtpTimeout);
+
+        let error = within_timeout(SmtpPhase::Connect, None, async {
+            Err::<(), _>(mail_send::Error::MissingCredentials)
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::SmtpError);
+    }
+
+    #[test]
+    fn test_send_as_matching_mailbox() {
+        assert!(check_send_as("me@example.com", "me@example.com", false).is_ok());
+        assert!(check_send_as("Me@Example.com", "me@example.COM", false).is_ok());
+    }
+
+    #[test]
+    fn test_send_as_other_mailbox() {
+        let error = check_send_as("shared@example.com", "me@example.com", false).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        assert!(error.error_code_desc.contains("SendAs"));
+        assert!(error.error_code_desc.contains("--allow-send-as"));
+        assert!(error.error_code_desc.contains("shared@example.com"));
+    }
+
+    #[test]
+    fn test_allow_send_as() {
+        assert!(check_send_as("shared@example.com", "me@example.com", true).is_ok());
+
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--resume",
+            "--smtp-login",
+            "me@example.com",
+            "--allow-send-as",
+        ])
+        .unwrap();
+        assert!(cli.run.smtp.allow_send_as);
+    }
+
+    #[test]
+    fn test_xoauth2_uses_smtp_login_override() {
+        let access_token = AccessToken::new("token".to_string());
+        let login = smtp_login(Some("me@example.com"), "shared@example.com").unwrap();
+        let credentials = xoauth2_credentials(login, &access_token);
+
+        assert_eq!(
+            credentials.encode(AUTH_XOAUTH2, "").unwrap().into_bytes(),
+            base64_encode(b"user=me@example.com\x01auth=Bearer token\x01\x01").unwrap()
+        );
+
+        // --sasl-username is another name of --smtp-login.
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--grant-type",
+            "DeviceCodeFlow",
+            "--client-id",
+            "client",
+            "--to-self",
+            "--sasl-username",
+            "relay@example.com",
+        ])
+        .unwrap();
+        let login = smtp_login(cli.run.smtp.smtp_login.as_deref(), "sender@example.com").unwrap();
+        let credentials = xoauth2_credentials(login, &access_token);
+        assert_eq!(
+            credentials.encode(AUTH_XOAUTH2, "").unwrap().into_bytes(),
+            base64_encode(b"user=relay@example.com\x01auth=Bearer token\x01\x01").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_smtp_login_defaults_to_sender() {
+        assert_eq!(
+            smtp_login(None, "sender@example.com").unwrap(),
+            "sender@example.com"
+        );
+    }
+
+    #[test]
+    fn test_smtp_login_requires_both_identities() {
+        assert_eq!(
+            smtp_login(Some(""), "shared@example.com")
+                .unwrap_err()
+                .error_code,
+            ErrorCodes::ConfigurationError
+        );
+        assert_eq!(
+            smtp_login(Some("me@example.com"), " ")
+                .unwrap_err()
+                .error_code,
+            ErrorCodes::ConfigurationError
+        );
+    }
+
+    async fn mock_send(access_token: AccessToken, attempts: &Cell<u32>) -> OAuth2Result<()> {
+        attempts.set(attempts.get() + 1);
+        if access_token.secret() == "fresh" {
+            Ok(())
+        } else {
+            Err(OAuth2Error::from(mail_send::Error::AuthenticationFailed(
+                Response::new(535, 5, 7, 3, "Authentication unsuccessful".to_string()),
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_with_relogin_retries_with_fresh_token() {
+        let attempts = Cell::new(0);
+        let relogins = Cell::new(0);
+
+        let result = send_with_relogin(
+            AccessToken::new("revoked".to_string()),
+            true,
+            |token| mock_send(token, &attempts),
+            || async {
+                relogins.set(relogins.get() + 1);
+                Ok(AccessToken::new("fresh".to_string()))
+            },
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 2);
+        assert_eq!(relogins.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_with_relogin_retries_only_once() {
+        let attempts = Cell::new(0);
+
+        let result = send_with_relogin(
+            AccessToken::new("revoked".to_string()),
+            true,
+            |token| mock_send(token, &attempts),
+            || async { Ok(AccessToken::new("still revoked".to_string())) },
+        )
+        .await;
+
+        assert_eq!(
+            result.unwrap_err().error_code,
+            ErrorCodes::SmtpAuthenticationFailed
+        );
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_send_without_auto_relogin_does_not_retry() {
+        let attempts = Cell::new(0);
+
+        let result = send_with_relogin(
+            AccessToken::new("revoked".to_string()),
+            false,
+            |token| mock_send(token, &attempts),
+            || async { Ok(AccessToken::new("fresh".to_string())) },
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_transient_connect_failure_is_retried() {
+        // Nothing listens on the first port anymore, the connection is refused.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(listener, vec![EHLO_REPLY]));
+
+        let smtp = SmtpArgs::default();
+        let attempts = Cell::new(0);
+        let result = send_with_retries(
+            2,
+            Backoff::new(Duration::from_millis(10), Jitter::None),
+            || {
+                attempts.set(attempts.get() + 1);
+                let port = if attempts.get() == 1 {
+                    closed_port
+                } else {
+                    port
+                };
+                let smtp = &smtp;
+                async move {
+                    let builder = client_builder("localhost", port, smtp, IpVersion::V4).await?;
+                    builder.connect_plain().await?;
+                    Ok::<_, OAuth2Error>(())
+                }
+            },
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 2);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_permanent_failures_are_not_retried() {
+        let attempts = Cell::new(0);
+        let result = send_with_retries(3, Backoff::new(Duration::ZERO, Jitter::None), || {
+            mock_send(AccessToken::new("revoked".to_string()), &attempts)
+        })
+        .await;
+        assert_eq!(
+            result.unwrap_err().error_code,
+            ErrorCodes::SmtpAuthenticationFailed
+        );
+        assert_eq!(attempts.get(), 1);
+
+        let attempts = Cell::new(0);
+        let result = send_with_retries(2, Backoff::new(Duration::ZERO, Jitter::None), || async {
+            attempts.set(attempts.get() + 1);
+            Err::<(), _>(OAuth2Error::new(
+                ErrorCodes::SmtpTimeout,
+                "timed out".into(),
+            ))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_timed_out_after_data_is_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // Kept open without answering the end of the data.
+            let lines = mock_session(
+                &mut stream,
+                vec![
+                    "250-mock\r\n250 AUTH PLAIN LOGIN\r\n",
+                    "235 2.7.0 Authentication successful\r\n",
+                    "250 2.1.0 Sender OK\r\n",
+                    "250 2.1.5 Recipient OK\r\n",
+                    "354 Start mail input\r\n",
+                ],
+            )
+            .await;
+            // A second attempt would connect again.
+            let second = tokio::time::timeout(Duration::from_millis(500), listener.accept()).await;
+            drop(stream);
+            (lines, second.is_ok())
+        });
+
+        let smtp = SmtpArgs {
+            smtp_command_timeout: Some(Duration::from_millis(200)),
+            ..basic_auth_args(port, true)
+        };
+        let route = SmtpRoute {
+            ip_version: IpVersion::V4,
+            ..Default::default()
+        };
+        let access_token = AccessToken::new(String::new());
+        let attempts = Cell::new(0);
+        let error = send_with_retries(2, Backoff::new(Duration::ZERO, Jitter::None), || {
+            attempts.set(attempts.get() + 1);
+            send_email(
+                test_message(),
+                "me@example.com",
+                &access_token,
+                &smtp,
+                &route,
+            )
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::SmtpSendInterrupted);
+        assert!(error.error_code_desc.contains("answer a command"));
+        assert_eq!(attempts.get(), 1);
+
+        let (lines, reconnected) = server.await.unwrap();
+        assert_eq!(lines[4], "DATA");
+        assert!(!reconnected);
+    }
+
+    #[tokio::test]
+    async fn test_no_credentials_without_starttls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // No STARTTLS in the EHLO reply.
+        let server = tokio::spawn(mock_smtp(listener, vec![EHLO_REPLY]));
+
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let error = OAuth2Error::from(builder.connect().await.err().unwrap());
+        assert_eq!(error.error_code, ErrorCodes::TlsRequired);
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("EHLO "));
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        set_socket_options(&stream, &SmtpArgs::default()).unwrap();
+        assert!(!stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let smtp = SmtpArgs {
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            tcp_nodelay: true,
+            ..Default::default()
+        };
+        set_socket_options(&stream, &smtp).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+
+        // The connection with the socket options still requires STARTTLS.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(listener, vec![EHLO_REPLY]));
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let error = connect_with_socket_options(&builder, &smtp, None)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(OAuth2Error::from(error).error_code, ErrorCodes::TlsRequired);
+        assert!(server.await.unwrap()[0].starts_with("EHLO "));
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = parse_socks5(&listener.local_addr().unwrap().to_string()).unwrap();
+        let server = tokio::spawn(mock_socks5_smtp(listener, vec![EHLO_REPLY]));
+        let smtp = SmtpArgs::default();
+        // The proxy resolves the host.
+        let builder = client_builder("smtp.office365.com", 587, &smtp, IpVersion::Auto)
+            .await
+            .unwrap();
+        let error = connect_with_socket_options(&builder, &smtp, Some(&proxy))
+            .await
+            .err()
+            .unwrap();
+        // Through the tunnel, up to the missing STARTTLS of the mock server.
+        assert_eq!(OAuth2Error::from(error).error_code, ErrorCodes::TlsRequired);
+        let (host, lines) = server.await.unwrap();
+        assert_eq!(host, "smtp.office365.com");
+        assert!(lines[0].starts_with("EHLO "));
+    }
+
+    #[tokio::test]
+    async fn test_expect_banner() {
+        // The mock server greets with "220 mock ESMTP".
+        let smtp = SmtpArgs {
+            expect_banner: Some(parse_banner_pattern("Microsoft ESMTP").unwrap()),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(listener, vec![]));
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let error = connect_with_socket_options(&builder, &smtp, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, mail_send::Error::UnexpectedReply(_)));
+        // Nothing is sent to the wrong server.
+        assert!(server.await.unwrap().is_empty());
+
+        let smtp = SmtpArgs {
+            expect_banner: Some(parse_banner_pattern(r"^\w+ ESMTP$").unwrap()),
+            ..Default::default()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(listener, vec![EHLO_REPLY]));
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let error = connect_with_socket_options(&builder, &smtp, None)
+            .await
+            .err()
+            .unwrap();
+        // Past the banner, up to the missing STARTTLS.
+        assert_eq!(OAuth2Error::from(error).error_code, ErrorCodes::TlsRequired);
+        assert!(server.await.unwrap()[0].starts_with("EHLO "));
+
+        assert!(parse_banner_pattern("").is_err());
+        assert!(parse_banner_pattern("(unclosed").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_envelope_from() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                EHLO_REPLY,
+                "250 2.1.0 Sender OK\r\n",
+                "250 2.1.5 Recipient OK\r\n",
+                "354 Start mail input\r\n",
+                "250 2.0.0 OK queued\r\n",
+            ],
+        ));
+
+        let message = message::build_message(
+            ("Sender", "me@example.com"),
+            vec![("Recipient", "you@example.com")],
+            &MessageArgs::default(),
+        )
+        .unwrap();
+        let message = message::into_message(message, &MessageArgs::default()).unwrap();
+        let message = with_envelope_from(message, Some("bounces@example.com"));
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let mut client = builder.connect_plain().await.unwrap();
+        send_message(&mut client, message).await.unwrap();
+
+        let lines = server.await.unwrap();
+        assert_eq!(lines[1], "MAIL FROM:<bounces@example.com>");
+        // The From header is still the sender.
+        assert!(lines[4].contains("<me@example.com>"));
+        assert!(!lines[4].contains("bounces@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_smtp_transcript() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                EHLO_REPLY,
+                "235 2.7.0 OK\r\n",
+                "250 2.1.0 Sender OK\r\n",
+                "250 2.1.5 Recipient OK\r\n",
+                "354 Start mail input\r\n",
+                "250 2.0.0 OK queued\r\n",
+                "221 2.0.0 Bye\r\n",
+            ],
+        ));
+
+        let smtp = SmtpArgs {
+            smtp_transcript: true,
+            ehlo_name: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let (mut client, starttls) = connect_and_ehlo(&builder, &smtp, None).await.unwrap();
+        assert!(!starttls);
+        let access_token = AccessToken::new("token".to_string());
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        authenticate(&mut client, &credentials, SmtpAuthStyle::InitialResponse)
+            .await
+            .unwrap();
+        send_message(&mut client, test_message()).await.unwrap();
+        quit(&mut client).await.unwrap();
+        server.await.unwrap();
+
+        let lines = client.stream.log().unwrap().lines();
+        let commands: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line.strip_prefix("C: "))
+            .collect();
+        assert_eq!(commands[0], "EHLO localhost");
+        assert_eq!(
+            commands[1..4],
+            [
+                "AUTH XOAUTH2 <redacted>",
+                "MAIL FROM:<me@example.com>",
+                "RCPT TO:<recipient@example.com>"
+            ]
+        );
+        assert_eq!(commands[4], "DATA");
+        assert!(
+            commands[5].ends_with(" bytes of message>"),
+            "{}",
+            commands[5]
+        );
+        assert_eq!(commands[6..], [".", "QUIT"]);
+        assert_eq!(lines[0], "S: 220 mock ESMTP");
+        assert!(lines.contains(&"S: 250-mock".to_string()));
+        assert!(lines.contains(&"S: 221 2.0.0 Bye".to_string()));
+        // Neither the credentials nor the message are logged.
+        assert!(!lines
+            .iter()
+            .any(|line| line.contains(&encoded_credentials())));
+        assert!(!lines.iter().any(|line| line.contains("Subject")));
+    }
+
+    #[tokio::test]
+    async fn test_smtp_transcript_redacts_the_challenge_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![
+                EHLO_REPLY,
+                "334 \r\n",
+                "235 2.7.0 OK\r\n",
+                "221 2.0.0 Bye\r\n",
+            ],
+        ));
+
+        let smtp = SmtpArgs {
+            smtp_transcript: true,
+            ehlo_name: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let (mut client, _) = connect_and_ehlo(&builder, &smtp, None).await.unwrap();
+        let access_token = AccessToken::new("token".to_string());
+        let credentials = xoauth2_credentials("me@example.com", &access_token);
+        authenticate(&mut client, &credentials, SmtpAuthStyle::Challenge)
+            .await
+            .unwrap();
+        quit(&mut client).await.unwrap();
+        server.await.unwrap();
+
+        let commands: Vec<&str> = client
+            .stream
+            .log()
+            .unwrap()
+            .lines()
+            .iter()
+            .filter_map(|line| line.strip_prefix("C: "))
+            .collect();
+        assert_eq!(
+            commands,
+            ["EHLO localhost", "AUTH XOAUTH2", "<redacted>", "QUIT"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_benchmark_handshakes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut sessions = Vec::new();
+            while sessions.len() < 3 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                sessions
+                    .push(mock_session(&mut stream, vec![EHLO_REPLY, "221 2.0.0 Bye\r\n"]).await);
+            }
+            sessions
+        });
+
+        let smtp = SmtpArgs::default();
+        // Resolved once, each cycle connects to the same address.
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let latencies = time_handshakes(3, || handshake(builder.connect_plain(), &smtp))
+            .await
+            .unwrap();
+        assert_eq!(latencies.len(), 3);
+
+        let sessions = server.await.unwrap();
+        assert_eq!(sessions.len(), 3);
+        for lines in sessions {
+            assert!(lines[0].starts_with("EHLO "));
+            assert_eq!(lines[1], "QUIT");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_handshake_stops_the_benchmark() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(mock_smtp(
+            listener,
+            vec![EHLO_REPLY, "421 4.3.2 Service not available\r\n"],
+        ));
+
+        let smtp = SmtpArgs::default();
+        let builder = client_builder("localhost", port, &smtp, IpVersion::V4)
+            .await
+            .unwrap();
+        let attempts = Cell::new(0);
+        let result = time_handshakes(3, || {
+            attempts.set(attempts.get() + 1);
+            handshake(builder.connect_plain(), &smtp)
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,376 @@
+// Standard libraries
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+
+// 3rd party crates
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const USERNAME_PASSWORD_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// A SOCKS5 proxy given with `--socks5 [user:password@]host:port`, which the SMTP
+/// connection and the HTTP requests go through.
+#[derive(Clone, PartialEq)]
+pub struct Socks5Proxy {
+    /// The `host:port` of the proxy.
+    pub addr: String,
+    /// The username and the password, when the proxy asks for them.
+    pub credentials: Option<(String, String)>,
+}
+
+impl fmt::Debug for Socks5Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Socks5Proxy")
+            .field("addr", &self.addr)
+            .field(
+                "username",
+                &self.credentials.as_ref().map(|(username, _)| username),
+            )
+            .finish()
+    }
+}
+
+/// Splits `host:port`, the host of an IPv6 address may be in brackets.
+fn split_host_port(addr: &str) -> Option<(&str, u16)> {
+    let (host, port) = addr.rsplit_once(':')?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port.parse().ok()?;
+    (!host.is_empty()).then_some((host, port))
+}
+
+/// Parses `[user:password@]host:port` of `--socks5`.
+pub fn parse_socks5(proxy: &str) -> Result<Socks5Proxy, String> {
+    let (credentials, addr) = match proxy.rsplit_once('@') {
+        Some((userinfo, addr)) => {
+            let (username, password) = userinfo
+                .split_once(':')
+                .ok_or_else(|| format!("{userinfo:?} is not user:password"))?;
+            // The lengths are sent in a byte.
+            if !(1..=255).contains(&username.len()) || !(1..=255).contains(&password.len()) {
+                return Err("the username and the password have 1 to 255 bytes".to_string());
+            }
+            (Some((username.to_string(), password.to_string())), addr)
+        }
+        None => (None, proxy),
+    };
+    if split_host_port(addr).is_none() {
+        return Err(format!("{addr:?} is not host:port"));
+    }
+    Ok(Socks5Proxy {
+        addr: addr.to_string(),
+        credentials,
+    })
+}
+
+/// The error of a proxy that does not speak SOCKS5 as expected.
+fn protocol_error(problem: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("The SOCKS5 proxy {problem}."),
+    )
+}
+
+/// What a failed CONNECT reply says, as RFC 1928 names it.
+fn reply_error(reply: u8) -> io::Error {
+    let (kind, reason) = match reply {
+        2 => (ErrorKind::PermissionDenied, "the connection is not allowed"),
+        3 => (ErrorKind::Other, "the network is unreachable"),
+        4 => (ErrorKind::Other, "the host is unreachable"),
+        5 => (ErrorKind::ConnectionRefused, "the connection was refused"),
+        6 => (ErrorKind::TimedOut, "the TTL expired"),
+        7 => (ErrorKind::Unsupported, "CONNECT is not supported"),
+        8 => (ErrorKind::Unsupported, "the address type is not supported"),
+        _ => (ErrorKind::Other, "the connection failed"),
+    };
+    io::Error::new(kind, format!("SOCKS5 proxy: {reason} (reply {reply})."))
+}
+
+impl Socks5Proxy {
+    /// The proxy URL for libcurl, which leaves the name resolution to the proxy like the
+    /// SMTP connection does.
+    pub fn curl_url(&self) -> String {
+        format!("socks5h://{}", self.addr)
+    }
+
+    /// Connects to `target`, a `host:port`, through the proxy. The stream then carries
+    /// the connection to the target.
+    pub async fn connect(&self, target: &str) -> io::Result<TcpStream> {
+        let (host, port) = split_host_port(target).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{target:?} is not host:port"),
+            )
+        })?;
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        log::debug!(
+            "Connecting to {target} through the SOCKS5 proxy {}",
+            self.addr
+        );
+        self.handshake(&mut stream, host, port).await?;
+        Ok(stream)
+    }
+
+    /// Authenticates and sends CONNECT, as in RFC 1928 and RFC 1929.
+    async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        host: &str,
+        port: u16,
+    ) -> io::Result<()> {
+        let greeting: &[u8] = match self.credentials {
+            Some(_) => &[VERSION, 2, NO_AUTHENTICATION, USERNAME_PASSWORD],
+            None => &[VERSION, 1, NO_AUTHENTICATION],
+        };
+        stream.write_all(greeting).await?;
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != VERSION {
+            return Err(protocol_error("does not speak SOCKS5"));
+        }
+        match (choice[1], &self.credentials) {
+            (NO_AUTHENTICATION, _) => {}
+            (USERNAME_PASSWORD, Some((username, password))) => {
+                let mut request = vec![USERNAME_PASSWORD_VERSION, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+                let mut status = [0; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "The SOCKS5 proxy rejected the username and the password.",
+                    ));
+                }
+            }
+            (NO_ACCEPTABLE_METHOD, None) | (USERNAME_PASSWORD, None) => {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    "The SOCKS5 proxy asks for a username and a password, give them with \
+                    --socks5 user:password@host:port.",
+                ))
+            }
+            _ => return Err(protocol_error("accepts none of the offered logins")),
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(IPV4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(IPV6);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let length = u8::try_from(host.len())
+                    .map_err(|_| protocol_error("cannot be given a host name this long"))?;
+                request.extend_from_slice(&[DOMAIN_NAME, length]);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(protocol_error("does not speak SOCKS5"));
+        }
+        if reply[1] != 0 {
+            return Err(reply_error(reply[1]));
+        }
+        // The address the proxy bound, which is not needed.
+        let address_length = match reply[3] {
+            IPV4 => 4,
+            IPV6 => 16,
+            DOMAIN_NAME => stream.read_u8().await? as usize,
+            _ => return Err(protocol_error("replied with an unknown address type")),
+        };
+        let mut bound = vec![0; address_length + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{parse_socks5, Socks5Proxy};
+
+    /// A SOCKS5 proxy that accepts one connection, checks the login if `credentials`
+    /// are given, and answers the CONNECT with `reply`. It returns the requested target
+    /// and echoes what comes through the tunnel.
+    async fn mock_socks5(
+        listener: TcpListener,
+        credentials: Option<(&'static str, &'static str)>,
+        reply: u8,
+    ) -> Option<(String, u16)> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0; 2];
+        stream.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0; greeting[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+
+        if let Some((username, password)) = credentials {
+            if !methods.contains(&2) {
+                stream.write_all(&[5, 0xff]).await.unwrap();
+                return None;
+            }
+            stream.write_all(&[5, 2]).await.unwrap();
+            let mut header = [0; 2];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut given_username = vec![0; header[1] as usize];
+            stream.read_exact(&mut given_username).await.unwrap();
+            let mut given_password = vec![0; stream.read_u8().await.unwrap() as usize];
+            stream.read_exact(&mut given_password).await.unwrap();
+            if given_username != username.as_bytes() || given_password != password.as_bytes() {
+                stream.write_all(&[1, 1]).await.unwrap();
+                return None;
+            }
+            stream.write_all(&[1, 0]).await.unwrap();
+        } else {
+            stream.write_all(&[5, 0]).await.unwrap();
+        }
+
+        let mut request = [0; 4];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..3], [5, 1, 0]);
+        let host = match request[3] {
+            1 => {
+                let mut ip = [0; 4];
+                stream.read_exact(&mut ip).await.unwrap();
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            3 => {
+                let mut name = vec![0; stream.read_u8().await.unwrap() as usize];
+                stream.read_exact(&mut name).await.unwrap();
+                String::from_utf8(name).unwrap()
+            }
+            atyp => panic!("unexpected address type {atyp}"),
+        };
+        let port = stream.read_u16().await.unwrap();
+        stream
+            .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x1f, 0x90])
+            .await
+            .unwrap();
+
+        let mut buffer = [0; 64];
+        if let Ok(read @ 1..) = stream.read(&mut buffer).await {
+            stream.write_all(&buffer[..read]).await.unwrap();
+        }
+        Some((host, port))
+    }
+
+    async fn proxy(
+        credentials: Option<(&'static str, &'static str)>,
+        reply: u8,
+    ) -> (String, tokio::task::JoinHandle<Option<(String, u16)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (
+            addr,
+            tokio::spawn(mock_socks5(listener, credentials, reply)),
+        )
+    }
+
+    async fn echo(mut stream: TcpStream) -> Vec<u8> {
+        stream.write_all(b"EHLO").await.unwrap();
+        let mut echoed = vec![0; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        echoed
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_the_proxy() {
+        let (addr, server) = proxy(None, 0).await;
+        let socks5 = parse_socks5(&addr).unwrap();
+        let stream = socks5.connect("smtp.office365.com:587").await.unwrap();
+        assert_eq!(echo(stream).await, b"EHLO");
+        assert_eq!(
+            server.await.unwrap(),
+            Some(("smtp.office365.com".to_string(), 587))
+        );
+
+        let (addr, server) = proxy(None, 0).await;
+        let stream = parse_socks5(&addr)
+            .unwrap()
+            .connect("52.96.0.1:587")
+            .await
+            .unwrap();
+        drop(stream);
+        assert_eq!(server.await.unwrap(), Some(("52.96.0.1".to_string(), 587)));
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_username_and_password() {
+        let (addr, server) = proxy(Some(("user", "secret")), 0).await;
+        let socks5 = parse_socks5(&format!("user:secret@{addr}")).unwrap();
+        let stream = socks5.connect("smtp.office365.com:587").await.unwrap();
+        assert_eq!(echo(stream).await, b"EHLO");
+        assert!(server.await.unwrap().is_some());
+
+        let (addr, _server) = proxy(Some(("user", "secret")), 0).await;
+        let socks5 = parse_socks5(&format!("user:wrong@{addr}")).unwrap();
+        let error = socks5.connect("smtp.office365.com:587").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        let (addr, _server) = proxy(Some(("user", "secret")), 0).await;
+        let error = parse_socks5(&addr)
+            .unwrap()
+            .connect("smtp.office365.com:587")
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("user:password@host:port"));
+    }
+
+    #[tokio::test]
+    async fn test_refused_connect() {
+        let (addr, _server) = proxy(None, 5).await;
+        let error = parse_socks5(&addr)
+            .unwrap()
+            .connect("smtp.office365.com:587")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_parse_socks5() {
+        assert_eq!(
+            parse_socks5("proxy.contoso.com:1080").unwrap(),
+            Socks5Proxy {
+                addr: "proxy.contoso.com:1080".to_string(),
+                credentials: None,
+            }
+        );
+        let proxy = parse_socks5("user:p@ss@[::1]:1080").unwrap();
+        assert_eq!(proxy.addr, "[::1]:1080");
+        assert_eq!(
+            proxy.credentials,
+            Some(("user".to_string(), "p@ss".to_string()))
+        );
+        assert_eq!(proxy.curl_url(), "socks5h://[::1]:1080");
+        assert!(!format!("{proxy:?}").contains("p@ss"));
+
+        assert!(parse_socks5("proxy.contoso.com").is_err());
+        assert!(parse_socks5("proxy.contoso.com:socks").is_err());
+        assert!(parse_socks5("user@proxy.contoso.com:1080").is_err());
+        assert!(parse_socks5(":secret@proxy.contoso.com:1080").is_err());
+    }
+}
@@ -0,0 +1,255 @@
+// Standard libraries
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 3rd party crates
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+// My crates
+use crate::error::OAuth2Result;
+use crate::report::OutputFormat;
+use crate::token_keeper::TokenKeeper;
+
+/// The health of a cached token, as `status` reports it.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TokenStatus {
+    pub token_file: PathBuf,
+    /// Whether the access token has not expired, after the refresh of
+    /// `--refresh-if-needed`.
+    pub valid: bool,
+    /// When the access token expires, in RFC 3339.
+    pub expires_at: Option<String>,
+    /// The seconds until the access token expires, negative once it has.
+    pub expires_in_secs: Option<i64>,
+    /// Whether the expired access token was refreshed with `--refresh-if-needed`.
+    pub refreshed: bool,
+    pub has_refresh_token: bool,
+    pub scopes: Vec<String>,
+    /// The sender of the profile, with `--with-profile`.
+    pub sender: Option<String>,
+}
+
+impl TokenStatus {
+    fn of(token_file: &Path, token: &TokenKeeper, refreshed: bool, now: Duration) -> Self {
+        let expires_at = token.expires_at();
+        Self {
+            token_file: token_file.to_path_buf(),
+            valid: !token.has_access_token_expired(),
+            expires_at: expires_at.map(|expires_at| {
+                DateTime::<Utc>::from(UNIX_EPOCH + expires_at)
+                    .to_rfc3339_opts(SecondsFormat::Secs, true)
+            }),
+            expires_in_secs: expires_at
+                .map(|expires_at| expires_at.as_secs() as i64 - now.as_secs() as i64),
+            refreshed,
+            has_refresh_token: token.refresh_token.is_some(),
+            scopes: token.scopes().map(<[String]>::to_vec).unwrap_or_default(),
+            sender: None,
+        }
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("Token file: {}", self.token_file.display()),
+            format!(
+                "Access token: {}",
+                if self.valid { "valid" } else { "expired" }
+            ),
+        ];
+        match (&self.expires_at, self.expires_in_secs) {
+            (Some(expires_at), Some(expires_in)) if expires_in > 0 => {
+                lines.push(format!("Expires at: {expires_at} (in {expires_in} s)"))
+            }
+            (Some(expires_at), _) => lines.push(format!("Expired at: {expires_at}")),
+            _ => lines.push("Expires at: unknown".to_string()),
+        }
+        if self.refreshed {
+            lines.push("Refreshed: yes".to_string());
+        }
+        lines.push(format!(
+            "Refresh token: {}",
+            if self.has_refresh_token { "yes" } else { "no" }
+        ));
+        if !self.scopes.is_empty() {
+            lines.push(format!("Scopes: {}", self.scopes.join(" ")));
+        }
+        if let Some(sender) = &self.sender {
+            lines.push(format!("Profile: {sender}"));
+        }
+        lines
+    }
+
+    pub fn print(&self, output: OutputFormat) -> OAuth2Result<()> {
+        match output {
+            OutputFormat::Text => {
+                for line in self.summary() {
+                    log::info!("{line}");
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string(self)?),
+        }
+        Ok(())
+    }
+}
+
+fn since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Reads the cached token and, with `refresh_if_needed`, refreshes it with `refresh` when
+/// it has expired and there is a refresh token. Nothing else contacts the network.
+pub async fn check<F, Fut>(
+    directory: &Path,
+    token_file: &Path,
+    refresh_if_needed: bool,
+    refresh: F,
+) -> OAuth2Result<(TokenStatus, TokenKeeper)>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = OAuth2Result<TokenKeeper>>,
+{
+    let mut token = TokenKeeper::new(directory.to_path_buf());
+    token.read(token_file)?;
+    let mut refreshed = false;
+    if token.has_access_token_expired() && refresh_if_needed {
+        if token.refresh_token.is_some() {
+            log::info!("The access token has expired, refreshing it.");
+            token = refresh().await?;
+            refreshed = true;
+        } else {
+            log::warn!("The access token has expired and there is no refresh token to refresh it.");
+        }
+    }
+    let status = TokenStatus::of(
+        &directory.join(token_file),
+        &token,
+        refreshed,
+        since_epoch(),
+    );
+    Ok((status, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::path::Path;
+
+    use oauth2::basic::BasicTokenResponse;
+
+    use super::check;
+    use crate::error::{ErrorCodes, OAuth2Error};
+    use crate::token_keeper::TokenKeeper;
+
+    const VALID: &str = r#"{"access_token":"valid","token_type":"Bearer","expires_in":3600,"refresh_token":"refresh","scope":"https://outlook.office.com/SMTP.Send"}"#;
+    const EXPIRED: &str = r#"{"access_token":"expired","token_type":"Bearer","expires_in":0,"refresh_token":"refresh"}"#;
+    const EXPIRED_WITHOUT_REFRESH_TOKEN: &str =
+        r#"{"access_token":"expired","token_type":"Bearer","expires_in":0}"#;
+
+    fn token(directory: &Path, json: &str) -> TokenKeeper {
+        let token_response: BasicTokenResponse = serde_json::from_str(json).unwrap();
+        let mut token = TokenKeeper::from(token_response);
+        token.set_directory(directory.to_path_buf());
+        token
+    }
+
+    fn save_token(directory: &Path, json: &str) {
+        token(directory, json)
+            .save(Path::new("token.json"))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_valid_token() {
+        let directory = tempfile::tempdir().unwrap();
+        save_token(directory.path(), VALID);
+
+        let (status, token) = check(directory.path(), Path::new("token.json"), true, || async {
+            panic!("a valid token is not refreshed")
+        })
+        .await
+        .unwrap();
+        assert!(status.valid);
+        assert!(!status.refreshed);
+        assert!(status.has_refresh_token);
+        assert!((3590..=3600).contains(&status.expires_in_secs.unwrap()));
+        assert_eq!(status.scopes, ["https://outlook.office.com/SMTP.Send"]);
+        assert_eq!(token.access_token.secret(), "valid");
+        assert_eq!(status.summary()[1], "Access token: valid");
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_is_refreshed() {
+        let directory = tempfile::tempdir().unwrap();
+        save_token(directory.path(), EXPIRED);
+
+        let refreshes = &Cell::new(0);
+        let path = directory.path();
+        let (status, refreshed) = check(path, Path::new("token.json"), true, || async move {
+            refreshes.set(refreshes.get() + 1);
+            Ok(token(path, VALID))
+        })
+        .await
+        .unwrap();
+        assert_eq!(refreshes.get(), 1);
+        assert!(status.valid);
+        assert!(status.refreshed);
+        assert_eq!(refreshed.access_token.secret(), "valid");
+        assert!(status.summary().contains(&"Refreshed: yes".to_string()));
+
+        // A failed refresh is the error of the check.
+        save_token(directory.path(), EXPIRED);
+        let error = check(directory.path(), Path::new("token.json"), true, || async {
+            Err(OAuth2Error::new(ErrorCodes::InvalidGrant, "revoked".into()))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::InvalidGrant);
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_without_refresh() {
+        let directory = tempfile::tempdir().unwrap();
+        save_token(directory.path(), EXPIRED);
+
+        // Without --refresh-if-needed.
+        let (status, _) = check(directory.path(), Path::new("token.json"), false, || async {
+            panic!("the token is only refreshed with --refresh-if-needed")
+        })
+        .await
+        .unwrap();
+        assert!(!status.valid);
+        assert!(!status.refreshed);
+        assert!(status.expires_in_secs.unwrap() <= 0);
+        assert_eq!(status.summary()[1], "Access token: expired");
+        assert!(status.summary()[2].starts_with("Expired at: "));
+
+        // Without a refresh token.
+        save_token(directory.path(), EXPIRED_WITHOUT_REFRESH_TOKEN);
+        let (status, _) = check(directory.path(), Path::new("token.json"), true, || async {
+            panic!("there is no refresh token")
+        })
+        .await
+        .unwrap();
+        assert!(!status.valid);
+        assert!(!status.has_refresh_token);
+
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["valid"], false);
+        assert_eq!(json["refreshed"], false);
+    }
+
+    #[tokio::test]
+    async fn test_missing_token() {
+        let directory = tempfile::tempdir().unwrap();
+        let error = check(directory.path(), Path::new("token.json"), true, || async {
+            panic!("there is no token to refresh")
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::FileError);
+    }
+}
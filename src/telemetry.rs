@@ -0,0 +1,158 @@
+// Standard libraries
+#[cfg(test)]
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::future::Future;
+
+// 3rd party crates
+#[cfg(feature = "otel")]
+use opentelemetry::{
+    global,
+    trace::{FutureExt, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The name of the tracer and of the service in the exported spans.
+#[cfg(feature = "otel")]
+const SERVICE_NAME: &str = "microsoft-smtp-xoauth2-test-tool";
+
+#[cfg(test)]
+thread_local! {
+    /// The names of the spans started on this thread, to check which the flow creates.
+    static STARTED: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The names of the spans started on this thread so far, and forgets them.
+#[cfg(test)]
+pub fn take_started() -> Vec<&'static str> {
+    STARTED.with(|started| started.take())
+}
+
+/// Exports the spans of the flow over OTLP (gRPC) to `--otel-endpoint`, e.g.
+/// `http://localhost:4317`. Without it, the spans go nowhere.
+#[cfg(feature = "otel")]
+pub fn init(endpoint: Option<&str>) -> OAuth2Result<()> {
+    use opentelemetry_otlp::WithExportConfig;
+
+    let Some(endpoint) = endpoint else {
+        return Ok(());
+    };
+    let resource = opentelemetry_sdk::Resource::new([KeyValue::new("service.name", SERVICE_NAME)]);
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource))
+        .install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)
+        .map_err(|e| {
+            OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!("Unable to export the spans to {endpoint}: {e}"),
+            )
+        })?;
+    log::debug!("Exporting the spans to {endpoint}");
+    Ok(())
+}
+
+/// Without the `otel` feature, there is nothing to export the spans with.
+#[cfg(not(feature = "otel"))]
+pub fn init(endpoint: Option<&str>) -> OAuth2Result<()> {
+    match endpoint {
+        None => Ok(()),
+        Some(_) => Err(OAuth2Error::new(
+            ErrorCodes::ConfigurationError,
+            "--otel-endpoint needs a build with the otel feature, e.g. cargo run --features otel"
+                .to_string(),
+        )),
+    }
+}
+
+/// Exports the spans not exported yet, before exiting.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    global::shutdown_tracer_provider();
+}
+
+/// Runs `future` in a span named `name` with `attributes`, ended with the `outcome`
+/// attribute `ok` or `error`. The spans started meanwhile are its children.
+#[cfg(feature = "otel")]
+pub async fn in_span<T, E: Debug>(
+    name: &'static str,
+    attributes: Vec<(&'static str, String)>,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    #[cfg(test)]
+    STARTED.with(|started| started.borrow_mut().push(name));
+    let tracer = global::tracer(SERVICE_NAME);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(
+            attributes
+                .into_iter()
+                .map(|(key, value)| KeyValue::new(key, value))
+                .collect::<Vec<_>>(),
+        )
+        .start(&tracer);
+    let context = Context::current_with_span(span);
+    let result = future.with_context(context.clone()).await;
+    let span = context.span();
+    match &result {
+        Ok(_) => {
+            span.set_attribute(KeyValue::new("outcome", "ok"));
+            span.set_status(Status::Ok);
+        }
+        Err(e) => {
+            span.set_attribute(KeyValue::new("outcome", "error"));
+            span.set_status(Status::error(format!("{e:?}")));
+        }
+    }
+    span.end();
+    result
+}
+
+/// Without the `otel` feature, runs `future` as it is.
+#[cfg(not(feature = "otel"))]
+pub async fn in_span<T, E: Debug>(
+    name: &'static str,
+    attributes: Vec<(&'static str, String)>,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    #[cfg(test)]
+    STARTED.with(|started| started.borrow_mut().push(name));
+    let _ = (name, attributes);
+    future.await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{in_span, init, take_started};
+
+    #[tokio::test]
+    async fn test_spans_are_nested_in_order() {
+        take_started();
+        let result: Result<(), String> = in_span("send", vec![], async {
+            in_span(
+                "smtp_connect",
+                vec![("transport", "smtp:587".to_string())],
+                async { Err::<(), _>("refused".to_string()) },
+            )
+            .await
+        })
+        .await;
+        assert_eq!(result.unwrap_err(), "refused");
+        assert_eq!(take_started(), ["send", "smtp_connect"]);
+    }
+
+    #[test]
+    fn test_no_endpoint_is_a_no_op() {
+        assert!(init(None).is_ok());
+        #[cfg(not(feature = "otel"))]
+        assert!(init(Some("http://localhost:4317")).is_err());
+    }
+}
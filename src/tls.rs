@@ -0,0 +1,363 @@
+// Standard libraries
+use std::sync::Arc;
+
+// 3rd party crates
+use curl::easy::SslVersion;
+use strum_macros::{Display, EnumString};
+use tokio_rustls::rustls::{
+    self, cipher_suite, ClientConfig, OwnedTrustAnchor, RootCertStore, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+use tokio_rustls::TlsConnector;
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The oldest TLS version the connections accept, with `--min-tls-version`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd, EnumString)]
+pub enum TlsVersion {
+    #[default]
+    #[strum(serialize = "1.2")]
+    V1_2,
+    #[strum(serialize = "1.3")]
+    V1_3,
+}
+
+impl TlsVersion {
+    fn name(&self) -> &'static str {
+        match self {
+            TlsVersion::V1_2 => "1.2",
+            TlsVersion::V1_3 => "1.3",
+        }
+    }
+
+    fn rustls(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::V1_2 => &rustls::version::TLS12,
+            TlsVersion::V1_3 => &rustls::version::TLS13,
+        }
+    }
+
+    fn curl(&self) -> SslVersion {
+        match self {
+            TlsVersion::V1_2 => SslVersion::Tlsv12,
+            TlsVersion::V1_3 => SslVersion::Tlsv13,
+        }
+    }
+}
+
+/// The cipher suites of `--tls-ciphers`, by their IANA names: the ones rustls supports,
+/// which libcurl knows too.
+#[derive(Clone, Copy, Debug, Display, PartialEq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum TlsCipherSuite {
+    #[strum(serialize = "TLS_AES_128_GCM_SHA256")]
+    Aes128GcmSha256,
+    #[strum(serialize = "TLS_AES_256_GCM_SHA384")]
+    Aes256GcmSha384,
+    #[strum(serialize = "TLS_CHACHA20_POLY1305_SHA256")]
+    Chacha20Poly1305Sha256,
+    #[strum(serialize = "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256")]
+    EcdheEcdsaAes128GcmSha256,
+    #[strum(serialize = "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384")]
+    EcdheEcdsaAes256GcmSha384,
+    #[strum(serialize = "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256")]
+    EcdheEcdsaChacha20Poly1305Sha256,
+    #[strum(serialize = "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256")]
+    EcdheRsaAes128GcmSha256,
+    #[strum(serialize = "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384")]
+    EcdheRsaAes256GcmSha384,
+    #[strum(serialize = "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256")]
+    EcdheRsaChacha20Poly1305Sha256,
+}
+
+impl TlsCipherSuite {
+    /// The TLS version the cipher suite belongs to.
+    fn version(&self) -> TlsVersion {
+        match self {
+            TlsCipherSuite::Aes128GcmSha256
+            | TlsCipherSuite::Aes256GcmSha384
+            | TlsCipherSuite::Chacha20Poly1305Sha256 => TlsVersion::V1_3,
+            _ => TlsVersion::V1_2,
+        }
+    }
+
+    /// The OpenSSL name, which libcurl takes. The TLS 1.3 ones are the IANA names.
+    fn openssl_name(&self) -> &'static str {
+        match self {
+            TlsCipherSuite::Aes128GcmSha256 => "TLS_AES_128_GCM_SHA256",
+            TlsCipherSuite::Aes256GcmSha384 => "TLS_AES_256_GCM_SHA384",
+            TlsCipherSuite::Chacha20Poly1305Sha256 => "TLS_CHACHA20_POLY1305_SHA256",
+            TlsCipherSuite::EcdheEcdsaAes128GcmSha256 => "ECDHE-ECDSA-AES128-GCM-SHA256",
+            TlsCipherSuite::EcdheEcdsaAes256GcmSha384 => "ECDHE-ECDSA-AES256-GCM-SHA384",
+            TlsCipherSuite::EcdheEcdsaChacha20Poly1305Sha256 => "ECDHE-ECDSA-CHACHA20-POLY1305",
+            TlsCipherSuite::EcdheRsaAes128GcmSha256 => "ECDHE-RSA-AES128-GCM-SHA256",
+            TlsCipherSuite::EcdheRsaAes256GcmSha384 => "ECDHE-RSA-AES256-GCM-SHA384",
+            TlsCipherSuite::EcdheRsaChacha20Poly1305Sha256 => "ECDHE-RSA-CHACHA20-POLY1305",
+        }
+    }
+
+    fn rustls(&self) -> SupportedCipherSuite {
+        match self {
+            TlsCipherSuite::Aes128GcmSha256 => cipher_suite::TLS13_AES_128_GCM_SHA256,
+            TlsCipherSuite::Aes256GcmSha384 => cipher_suite::TLS13_AES_256_GCM_SHA384,
+            TlsCipherSuite::Chacha20Poly1305Sha256 => cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+            TlsCipherSuite::EcdheEcdsaAes128GcmSha256 => {
+                cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+            }
+            TlsCipherSuite::EcdheEcdsaAes256GcmSha384 => {
+                cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+            }
+            TlsCipherSuite::EcdheEcdsaChacha20Poly1305Sha256 => {
+                cipher_suite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+            }
+            TlsCipherSuite::EcdheRsaAes128GcmSha256 => {
+                cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+            }
+            TlsCipherSuite::EcdheRsaAes256GcmSha384 => {
+                cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+            }
+            TlsCipherSuite::EcdheRsaChacha20Poly1305Sha256 => {
+                cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
+            }
+        }
+    }
+}
+
+/// The TLS versions and cipher suites of `--min-tls-version` and `--tls-ciphers`, for
+/// both the SMTP connection and the token and profile requests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsPolicy {
+    pub min_version: TlsVersion,
+    /// The cipher suites to offer, all the default ones when empty.
+    pub cipher_suites: Vec<TlsCipherSuite>,
+}
+
+impl TlsPolicy {
+    /// Whether nothing is restricted beyond the TLS 1.2 that both rustls and Office 365
+    /// need anyway.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The versions that are both at least the minimum and have one of the cipher suites.
+    fn versions(&self) -> OAuth2Result<Vec<TlsVersion>> {
+        let versions: Vec<TlsVersion> = [TlsVersion::V1_2, TlsVersion::V1_3]
+            .into_iter()
+            .filter(|version| *version >= self.min_version)
+            .filter(|version| {
+                self.cipher_suites.is_empty()
+                    || self
+                        .cipher_suites
+                        .iter()
+                        .any(|suite| suite.version() == *version)
+            })
+            .collect();
+        if versions.is_empty() {
+            return Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!(
+                    "None of the --tls-ciphers is a TLS {} or later cipher suite.",
+                    self.min_version.name()
+                ),
+            ));
+        }
+        Ok(versions)
+    }
+
+    /// The constraints as logged and named in the errors, e.g. `TLS 1.3 or later with
+    /// TLS_AES_256_GCM_SHA384`.
+    pub fn describe(&self) -> String {
+        let mut description = format!("TLS {} or later", self.min_version.name());
+        if !self.cipher_suites.is_empty() {
+            let suites: Vec<String> = self
+                .cipher_suites
+                .iter()
+                .map(TlsCipherSuite::to_string)
+                .collect();
+            description.push_str(&format!(" with {}", suites.join(", ")));
+        }
+        description
+    }
+
+    /// The STARTTLS connector, trusting the same web PKI roots as the one of mail-send.
+    pub fn connector(&self) -> OAuth2Result<TlsConnector> {
+        let versions: Vec<&'static SupportedProtocolVersion> =
+            self.versions()?.iter().map(TlsVersion::rustls).collect();
+        let cipher_suites: Vec<SupportedCipherSuite> = if self.cipher_suites.is_empty() {
+            rustls::DEFAULT_CIPHER_SUITES.to_vec()
+        } else {
+            self.cipher_suites
+                .iter()
+                .map(TlsCipherSuite::rustls)
+                .collect()
+        };
+        let mut roots = RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        let config = ClientConfig::builder()
+            .with_cipher_suites(&cipher_suites)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&versions)
+            .map_err(|e| {
+                OAuth2Error::new(
+                    ErrorCodes::ConfigurationError,
+                    format!("Unable to use {}: {e}", self.describe()),
+                )
+            })?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(TlsConnector::from(Arc::new(config)))
+    }
+
+    /// The oldest and newest TLS versions for libcurl, where `Default` is the newest
+    /// one it supports.
+    pub fn curl_versions(&self) -> OAuth2Result<(SslVersion, SslVersion)> {
+        let versions = self.versions()?;
+        let newest = match versions.last() {
+            Some(TlsVersion::V1_2) => SslVersion::Tlsv12,
+            _ => SslVersion::Default,
+        };
+        Ok((versions[0].curl(), newest))
+    }
+
+    /// The OpenSSL names of the cipher suites of `version`, separated by colons as
+    /// libcurl takes them, none to leave the defaults.
+    pub fn curl_cipher_list(&self, version: TlsVersion) -> Option<String> {
+        let names: Vec<&str> = self
+            .cipher_suites
+            .iter()
+            .filter(|suite| suite.version() == version)
+            .map(TlsCipherSuite::openssl_name)
+            .collect();
+        (!names.is_empty()).then(|| names.join(":"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    use curl::easy::SslVersion;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::{self, Certificate, PrivateKey, ServerConfig, ServerName};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::{TlsCipherSuite, TlsPolicy, TlsVersion};
+    use crate::error::ErrorCodes;
+
+    #[test]
+    fn test_tls_names() {
+        assert_eq!(TlsVersion::from_str("1.3").unwrap(), TlsVersion::V1_3);
+        assert!(TlsVersion::from_str("1.1").is_err());
+        assert_eq!(
+            TlsCipherSuite::from_str("tls_aes_256_gcm_sha384").unwrap(),
+            TlsCipherSuite::Aes256GcmSha384
+        );
+        assert!(TlsCipherSuite::from_str("TLS_RSA_WITH_RC4_128_SHA").is_err());
+
+        let policy = TlsPolicy {
+            min_version: TlsVersion::V1_3,
+            cipher_suites: vec![TlsCipherSuite::Aes256GcmSha384],
+        };
+        assert_eq!(
+            policy.describe(),
+            "TLS 1.3 or later with TLS_AES_256_GCM_SHA384"
+        );
+    }
+
+    #[test]
+    fn test_tls_versions() {
+        let policy = TlsPolicy::default();
+        assert!(policy.is_default());
+        assert!(matches!(
+            policy.curl_versions().unwrap(),
+            (SslVersion::Tlsv12, SslVersion::Default)
+        ));
+        assert_eq!(policy.curl_cipher_list(TlsVersion::V1_2), None);
+
+        // Only TLS 1.2 cipher suites, so only TLS 1.2.
+        let policy = TlsPolicy {
+            min_version: TlsVersion::V1_2,
+            cipher_suites: vec![
+                TlsCipherSuite::EcdheRsaAes256GcmSha384,
+                TlsCipherSuite::EcdheRsaAes128GcmSha256,
+            ],
+        };
+        assert!(matches!(
+            policy.curl_versions().unwrap(),
+            (SslVersion::Tlsv12, SslVersion::Tlsv12)
+        ));
+        assert_eq!(
+            policy.curl_cipher_list(TlsVersion::V1_2).unwrap(),
+            "ECDHE-RSA-AES256-GCM-SHA384:ECDHE-RSA-AES128-GCM-SHA256"
+        );
+        assert_eq!(policy.curl_cipher_list(TlsVersion::V1_3), None);
+
+        let policy = TlsPolicy {
+            min_version: TlsVersion::V1_3,
+            cipher_suites: vec![TlsCipherSuite::EcdheRsaAes256GcmSha384],
+        };
+        let error = policy.connector().err().unwrap();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        assert!(policy.curl_versions().is_err());
+    }
+
+    /// A server that only speaks TLS 1.2, with a self-signed certificate.
+    async fn tls12_server() -> (TcpListener, TlsAcceptor) {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS12])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(certificate.serialize_der().unwrap())],
+                PrivateKey(certificate.serialize_private_key_der()),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        (listener, TlsAcceptor::from(Arc::new(config)))
+    }
+
+    async fn handshake(policy: &TlsPolicy) -> String {
+        let (listener, acceptor) = tls12_server().await;
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(stream).await;
+        });
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let domain = ServerName::try_from("localhost").unwrap();
+        let error = policy
+            .connector()
+            .unwrap()
+            .connect(domain, stream)
+            .await
+            .err()
+            .unwrap();
+        server.await.unwrap();
+        error.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_tls13_required_against_a_tls12_server() {
+        // TLS 1.2 gets as far as the certificate, which no root trusts.
+        let error = handshake(&TlsPolicy::default()).await;
+        assert!(error.contains("certificate"), "{error}");
+
+        let policy = TlsPolicy {
+            min_version: TlsVersion::V1_3,
+            cipher_suites: Vec::new(),
+        };
+        let error = handshake(&policy).await;
+        assert!(error.contains("ProtocolVersion"), "{error}");
+    }
+}
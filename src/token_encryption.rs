@@ -0,0 +1,370 @@
+// Standard libraries
+use std::num::NonZeroU32;
+use std::sync::OnceLock;
+
+// 3rd party crates
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use strum_macros::EnumString;
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// The environment variable that can hold the passphrase of `--token-encryption
+/// passphrase`, which is asked for otherwise.
+pub const PASSPHRASE_ENV: &str = "XOAUTH2_TOKEN_PASSPHRASE";
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const PBKDF2_ITERATIONS: u32 = 600_000;
+/// Where the key of `--token-encryption os` is kept in the keyring, outside Windows.
+#[cfg(not(windows))]
+const KEYRING_SERVICE: &str = "xoauth2-test-tool";
+#[cfg(not(windows))]
+const KEYRING_USER: &str = "token-encryption-key";
+/// The prefix of an encrypted field, before its base64.
+const SEALED_PREFIX: &str = "enc:";
+
+/// How the secrets of the token files are encrypted, with `--token-encryption`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString, Serialize, Deserialize)]
+#[strum(serialize_all = "kebab-case")]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenEncryption {
+    /// Not at all, the file is only readable by the user on unix.
+    #[default]
+    None,
+    /// With a key derived from a passphrase.
+    Passphrase,
+    /// With DPAPI on Windows, or a key kept in the keyring of the OS elsewhere.
+    Os,
+}
+
+/// How the fields of a token file were encrypted, saved along with them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Encryption {
+    pub mode: TokenEncryption,
+    /// The base64 salt of the passphrase key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<String>,
+}
+
+/// The `--token-encryption` of the run, the token files are saved with.
+static MODE: OnceLock<TokenEncryption> = OnceLock::new();
+/// The passphrase, asked for once per run.
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+pub fn set_mode(mode: TokenEncryption) {
+    let _ = MODE.set(mode);
+}
+
+fn mode() -> TokenEncryption {
+    MODE.get().copied().unwrap_or_default()
+}
+
+fn error(message: String) -> OAuth2Error {
+    OAuth2Error::new(ErrorCodes::ConfigurationError, message)
+}
+
+fn undecryptable() -> OAuth2Error {
+    error(
+        "The token file could not be decrypted, it was encrypted with another key or \
+        passphrase. Delete it to login again."
+            .into(),
+    )
+}
+
+fn random<const N: usize>() -> OAuth2Result<[u8; N]> {
+    let mut bytes = [0; N];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| error("Unable to generate the random bytes of the encryption.".into()))?;
+    Ok(bytes)
+}
+
+/// The passphrase from `XOAUTH2_TOKEN_PASSPHRASE`, or asked for without echo.
+fn passphrase() -> OAuth2Result<&'static str> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase);
+    }
+    let passphrase = match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => passphrase,
+        Err(_) => rpassword::prompt_password("Token file passphrase: ").map_err(|e| {
+            error(format!(
+                "Unable to read the token file passphrase, set {PASSPHRASE_ENV} instead: {e}"
+            ))
+        })?,
+    };
+    if passphrase.is_empty() {
+        return Err(error("The token file passphrase is empty.".into()));
+    }
+    Ok(PASSPHRASE.get_or_init(|| passphrase))
+}
+
+/// The key the secrets of a token file are encrypted with.
+pub enum FieldKey {
+    ChaCha20Poly1305(LessSafeKey),
+    /// DPAPI, keyed by the Windows user.
+    #[cfg(windows)]
+    Dpapi,
+}
+
+impl FieldKey {
+    fn from_bytes(bytes: &[u8]) -> OAuth2Result<Self> {
+        let key = UnboundKey::new(&CHACHA20_POLY1305, bytes)
+            .map_err(|_| error("The token encryption key is not valid.".into()))?;
+        Ok(FieldKey::ChaCha20Poly1305(LessSafeKey::new(key)))
+    }
+
+    /// The key derived from the passphrase and the salt with PBKDF2.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> OAuth2Result<Self> {
+        let mut key = [0; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).expect("the iterations are not zero"),
+            salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        Self::from_bytes(&key)
+    }
+
+    /// The key of the Windows user.
+    #[cfg(windows)]
+    pub fn os() -> OAuth2Result<Self> {
+        Ok(FieldKey::Dpapi)
+    }
+
+    /// The key kept in the keyring of the OS, created on first use.
+    #[cfg(not(windows))]
+    pub fn os() -> OAuth2Result<Self> {
+        let keyring_error =
+            |e: keyring::Error| error(format!("The keyring of the OS is not available: {e}"));
+        let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(keyring_error)?;
+        let key = match entry.get_password() {
+            Ok(key) => STANDARD.decode(key).map_err(|e| {
+                error(format!(
+                    "The token encryption key in the keyring is not valid: {e}"
+                ))
+            })?,
+            Err(keyring::Error::NoEntry) => {
+                let key = random::<KEY_LEN>()?;
+                entry
+                    .set_password(&STANDARD.encode(key))
+                    .map_err(keyring_error)?;
+                log::info!("Created the token encryption key in the keyring of the OS.");
+                key.to_vec()
+            }
+            Err(e) => return Err(keyring_error(e)),
+        };
+        Self::from_bytes(&key)
+    }
+
+    /// The field encrypted, as `enc:` and the base64 of the nonce and the ciphertext.
+    pub fn seal(&self, plaintext: &str) -> OAuth2Result<String> {
+        let sealed = match self {
+            FieldKey::ChaCha20Poly1305(key) => {
+                let nonce = random::<NONCE_LEN>()?;
+                let mut in_out = plaintext.as_bytes().to_vec();
+                key.seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::empty(),
+                    &mut in_out,
+                )
+                .map_err(|_| error("Unable to encrypt the token.".into()))?;
+                [nonce.as_slice(), &in_out].concat()
+            }
+            #[cfg(windows)]
+            FieldKey::Dpapi => dpapi::protect(plaintext.as_bytes())
+                .map_err(|e| error(format!("Unable to encrypt the token with DPAPI: {e}")))?,
+        };
+        Ok(format!("{SEALED_PREFIX}{}", STANDARD.encode(sealed)))
+    }
+
+    /// The field decrypted, failing when it was encrypted with another key.
+    pub fn open(&self, sealed: &str) -> OAuth2Result<String> {
+        let sealed = sealed
+            .strip_prefix(SEALED_PREFIX)
+            .and_then(|sealed| STANDARD.decode(sealed).ok())
+            .ok_or_else(undecryptable)?;
+        let plaintext = match self {
+            FieldKey::ChaCha20Poly1305(key) => {
+                if sealed.len() < NONCE_LEN {
+                    return Err(undecryptable());
+                }
+                let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+                let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| undecryptable())?;
+                let mut in_out = ciphertext.to_vec();
+                key.open_in_place(nonce, Aad::empty(), &mut in_out)
+                    .map_err(|_| undecryptable())?
+                    .to_vec()
+            }
+            #[cfg(windows)]
+            FieldKey::Dpapi => dpapi::unprotect(&sealed).map_err(|_| undecryptable())?,
+        };
+        String::from_utf8(plaintext).map_err(|_| undecryptable())
+    }
+}
+
+/// The key to save a token file with and how it was made, none without encryption.
+/// With `--token-encryption os`, a keyring of the OS that is not available is an error
+/// rather than a reason to save the file unencrypted.
+pub fn sealing_key() -> OAuth2Result<Option<(FieldKey, Encryption)>> {
+    match mode() {
+        TokenEncryption::None => Ok(None),
+        TokenEncryption::Passphrase => {
+            let salt = random::<SALT_LEN>()?;
+            let key = FieldKey::from_passphrase(passphrase()?, &salt)?;
+            let encryption = Encryption {
+                mode: TokenEncryption::Passphrase,
+                salt: Some(STANDARD.encode(salt)),
+            };
+            Ok(Some((key, encryption)))
+        }
+        TokenEncryption::Os => {
+            let encryption = Encryption {
+                mode: TokenEncryption::Os,
+                salt: None,
+            };
+            Ok(Some((FieldKey::os()?, encryption)))
+        }
+    }
+}
+
+/// The key to read a token file encrypted as `encryption` says, whatever the
+/// `--token-encryption` of the run.
+pub fn opening_key(encryption: &Encryption) -> OAuth2Result<FieldKey> {
+    match encryption.mode {
+        TokenEncryption::None => Err(undecryptable()),
+        TokenEncryption::Passphrase => {
+            let salt = encryption
+                .salt
+                .as_deref()
+                .and_then(|salt| STANDARD.decode(salt).ok())
+                .ok_or_else(undecryptable)?;
+            FieldKey::from_passphrase(passphrase()?, &salt)
+        }
+        TokenEncryption::Os => FieldKey::os(),
+    }
+}
+
+/// The data protection API of Windows, encrypting with a key of the user.
+#[cfg(windows)]
+mod dpapi {
+    use std::io;
+    use std::ptr;
+
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB,
+    };
+    use windows_sys::Win32::System::Memory::LocalFree;
+
+    fn blob(data: &[u8]) -> CRYPT_INTEGER_BLOB {
+        CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
+        }
+    }
+
+    /// Copies the output of DPAPI and frees it.
+    fn take(ok: i32, output: CRYPT_INTEGER_BLOB) -> io::Result<Vec<u8>> {
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: DPAPI succeeded, so the output holds cbData bytes it allocated with
+        // LocalAlloc, freed once copied.
+        let data =
+            unsafe { std::slice::from_raw_parts(output.pbData, output.cbData as usize) }.to_vec();
+        unsafe { LocalFree(output.pbData as isize) };
+        Ok(data)
+    }
+
+    pub fn protect(data: &[u8]) -> io::Result<Vec<u8>> {
+        let input = blob(data);
+        let mut output = blob(&[]);
+        // SAFETY: the input blob points into `data`, which outlives the call.
+        let ok = unsafe {
+            CryptProtectData(
+                &input,
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut output,
+            )
+        };
+        take(ok, output)
+    }
+
+    pub fn unprotect(data: &[u8]) -> io::Result<Vec<u8>> {
+        let input = blob(data);
+        let mut output = blob(&[]);
+        // SAFETY: the input blob points into `data`, which outlives the call.
+        let ok = unsafe {
+            CryptUnprotectData(
+                &input,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut output,
+            )
+        };
+        take(ok, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FieldKey, TokenEncryption};
+    use crate::error::ErrorCodes;
+
+    fn round_trip(key: &FieldKey) {
+        let sealed = key.seal("EwBwA8l6BAAU.access-token").unwrap();
+        assert!(sealed.starts_with("enc:"));
+        assert!(!sealed.contains("access-token"));
+        assert_eq!(key.open(&sealed).unwrap(), "EwBwA8l6BAAU.access-token");
+    }
+
+    #[test]
+    fn test_passphrase_round_trip() {
+        let key = FieldKey::from_passphrase("correct horse", b"0123456789abcdef").unwrap();
+        round_trip(&key);
+
+        // Each field gets its own nonce.
+        assert_ne!(key.seal("token").unwrap(), key.seal("token").unwrap());
+
+        let sealed = key.seal("token").unwrap();
+        let other = FieldKey::from_passphrase("battery staple", b"0123456789abcdef").unwrap();
+        let error = other.open(&sealed).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        let other_salt = FieldKey::from_passphrase("correct horse", b"fedcba9876543210").unwrap();
+        assert!(other_salt.open(&sealed).is_err());
+        assert!(key.open("token").is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_dpapi_round_trip() {
+        round_trip(&FieldKey::os().unwrap());
+    }
+
+    /// The keychain of macOS is there for each user, unlike a Secret Service on Linux.
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn test_keyring_round_trip() {
+        round_trip(&FieldKey::os().unwrap());
+    }
+
+    #[test]
+    fn test_token_encryption_argument() {
+        let parse = |mode: &str| mode.parse::<TokenEncryption>().unwrap();
+        assert_eq!(parse("none"), TokenEncryption::None);
+        assert_eq!(parse("passphrase"), TokenEncryption::Passphrase);
+        assert_eq!(parse("os"), TokenEncryption::Os);
+        assert!("aes".parse::<TokenEncryption>().is_err());
+    }
+}
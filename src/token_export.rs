@@ -0,0 +1,216 @@
+// Standard libraries
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+// 3rd party crates
+use chrono::{DateTime, SecondsFormat, Utc};
+use oauth2::AccessToken;
+use serde::{Deserialize, Serialize};
+
+// My crates
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::token_keeper::TokenKeeper;
+
+/// The `format` of the files of `--export-token`, changed when the fields change.
+pub const TOKEN_EXPORT_FORMAT: &str = "xoauth2-test-tool-token/1";
+
+/// The acquired token as `--export-token` writes it, for other tools and for
+/// `--access-token-file`. See the README for the fields.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExportedToken {
+    pub format: String,
+    pub token_type: String,
+    pub access_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// When the access token expires, in RFC 3339.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl ExportedToken {
+    /// The access token with what the cached token knows about it, none for a token
+    /// given with `--access-token`.
+    pub fn new(
+        access_token: &AccessToken,
+        token: Option<&TokenKeeper>,
+        client_id: &str,
+        tenant: &str,
+    ) -> Self {
+        Self {
+            format: TOKEN_EXPORT_FORMAT.to_string(),
+            token_type: "Bearer".to_string(),
+            access_token: access_token.secret().clone(),
+            refresh_token: token
+                .and_then(|token| token.refresh_token.as_ref())
+                .map(|refresh_token| refresh_token.secret().clone()),
+            expires_at: token.and_then(TokenKeeper::expires_at).map(|expires_at| {
+                DateTime::<Utc>::from(UNIX_EPOCH + expires_at)
+                    .to_rfc3339_opts(SecondsFormat::Secs, true)
+            }),
+            scopes: token
+                .and_then(TokenKeeper::scopes)
+                .map(<[String]>::to_vec)
+                .unwrap_or_default(),
+            client_id: Some(client_id.to_string()).filter(|client_id| !client_id.is_empty()),
+            tenant: Some(tenant.to_string()),
+        }
+    }
+
+    /// Writes the token to `path`, readable and writable only by the current user on unix.
+    pub fn write(&self, path: &Path) -> OAuth2Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to a new file.
+            if path.exists() {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        options.open(path)?.write_all(json.as_bytes())?;
+        log::info!("Token exported to {}", path.display());
+        Ok(())
+    }
+
+    /// Reads an exported token, e.g. given with `--access-token-file`.
+    pub fn parse(json: &str) -> OAuth2Result<Self> {
+        let token: Self = serde_json::from_str(json).map_err(|e| {
+            OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!("The token file is not an exported token: {e}"),
+            )
+        })?;
+        if token.format != TOKEN_EXPORT_FORMAT {
+            return Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!(
+                    "The exported token is in the {:?} format, only {TOKEN_EXPORT_FORMAT:?} is known.",
+                    token.format
+                ),
+            ));
+        }
+        Ok(token)
+    }
+
+    /// Whether the access token has expired, unknown without `expires_at`.
+    pub fn has_expired(&self) -> Option<bool> {
+        let expires_at = DateTime::parse_from_rfc3339(self.expires_at.as_deref()?).ok()?;
+        Some(expires_at <= Utc::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+    use oauth2::{basic::BasicTokenResponse, AccessToken};
+
+    use super::{ExportedToken, TOKEN_EXPORT_FORMAT};
+    use crate::cli::Cli;
+    use crate::error::ErrorCodes;
+    use crate::token_keeper::TokenKeeper;
+
+    fn token_keeper() -> TokenKeeper {
+        let token_response: BasicTokenResponse = serde_json::from_str(
+            r#"{"access_token": "access", "token_type": "bearer", "expires_in": 3600,
+                "refresh_token": "refresh", "scope": "https://outlook.office.com/SMTP.Send offline_access"}"#,
+        )
+        .unwrap();
+        TokenKeeper::from(token_response)
+    }
+
+    #[test]
+    fn test_export_then_import() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("exported.json");
+        let token = token_keeper();
+        let exported = ExportedToken::new(&token.access_token, Some(&token), "client", "common");
+        exported.write(&path).unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let imported = ExportedToken::parse(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(imported, exported);
+        assert_eq!(imported.format, TOKEN_EXPORT_FORMAT);
+        assert_eq!(imported.refresh_token.as_deref(), Some("refresh"));
+        assert_eq!(
+            imported.scopes,
+            ["https://outlook.office.com/SMTP.Send", "offline_access"]
+        );
+        assert_eq!(imported.has_expired(), Some(false));
+
+        // The exported file is an --access-token-file.
+        let cli = Cli::try_parse_from([
+            "tool",
+            "--access-token-file",
+            path.to_str().unwrap(),
+            "--recipient-email",
+            "recipient@example.com",
+            "--recipient-name",
+            "Recipient",
+        ])
+        .unwrap();
+        let client = cli.run.client.resolve(None).unwrap();
+        assert_eq!(client.access_token.unwrap().secret(), "access");
+    }
+
+    #[test]
+    fn test_export_a_given_token() {
+        let exported = ExportedToken::new(&AccessToken::new("given".into()), None, "", "common");
+        let json = serde_json::to_string(&exported).unwrap();
+        assert_eq!(
+            json,
+            r#"{"format":"xoauth2-test-tool-token/1","token_type":"Bearer","access_token":"given","tenant":"common"}"#
+        );
+        assert_eq!(ExportedToken::parse(&json).unwrap().has_expired(), None);
+    }
+
+    #[test]
+    fn test_export_overwrites_a_longer_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("exported.json");
+        fs::write(&path, "x".repeat(1000)).unwrap();
+        let exported = ExportedToken::new(&AccessToken::new("given".into()), None, "", "common");
+        exported.write(&path).unwrap();
+        assert_eq!(
+            ExportedToken::parse(&fs::read_to_string(&path).unwrap()).unwrap(),
+            exported
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_invalid_exported_token() {
+        let error = ExportedToken::parse("{}").unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        let error = ExportedToken::parse(
+            r#"{"format": "other/2", "token_type": "Bearer", "access_token": "a"}"#,
+        )
+        .unwrap_err();
+        assert!(error.error_code_desc.contains("other/2"));
+    }
+}
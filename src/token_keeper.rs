@@ -1,19 +1,131 @@
 // Standard libraries
-use std::fs::{self, File};
+use std::fs::{self, OpenOptions};
+use std::future::Future;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // 3rd party crates
+use directories::{ProjectDirs, UserDirs};
 use oauth2::basic::BasicTokenType;
 use oauth2::{
-    AccessToken, EmptyExtraTokenFields, RefreshToken, StandardTokenResponse, TokenResponse,
+    AccessToken, EmptyExtraTokenFields, ExtraTokenFields, HttpResponse, RefreshToken, Scope,
+    StandardTokenResponse, TokenResponse,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use strum_macros::EnumString;
 
 // My crates
-use crate::error::OAuth2Result;
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+use crate::token_encryption::{self, Encryption, FieldKey};
+use crate::validate_token;
+
+/// The scopes that are requested with the access token but never in its `scp` claim.
+const OPENID_SCOPES: [&str; 4] = ["openid", "profile", "email", "offline_access"];
+
+/// The name of a scope as the `scp` claim has it, e.g. `smtp.send` for
+/// `https://outlook.office.com/SMTP.Send`.
+fn scope_name(scope: &str) -> String {
+    scope
+        .rsplit('/')
+        .next()
+        .unwrap_or(scope)
+        .to_ascii_lowercase()
+}
+
+/// Picks the directory where the token files are kept.
+///
+/// The first available location wins:
+/// 1. the directory passed with `--token-dir`,
+/// 2. `$HOME/token`, the location used by earlier versions of this tool, if it already
+///    holds files, so that their tokens are still found,
+/// 3. the platform config directory, e.g. `$XDG_CONFIG_HOME/xoauth2-test-tool` on Linux,
+///    `~/Library/Application Support/xoauth2-test-tool` on macOS or
+///    `%APPDATA%\xoauth2-test-tool\config` on Windows,
+/// 4. `$HOME/token` otherwise.
+///
+/// On unix, [`token_directory`] restricts it to the current user, whether it creates
+/// it or it already exists.
+pub fn resolve_token_directory(
+    token_dir: Option<&Path>,
+    config_dir: Option<&Path>,
+    home_dir: Option<&Path>,
+) -> Option<PathBuf> {
+    let legacy_dir = home_dir.map(|home| home.join("token"));
+    let has_files = |directory: &PathBuf| {
+        fs::read_dir(directory).is_ok_and(|mut entries| entries.next().is_some())
+    };
+    token_dir
+        .map(Path::to_path_buf)
+        .or_else(|| legacy_dir.clone().filter(has_files))
+        .or_else(|| config_dir.map(Path::to_path_buf))
+        .or(legacy_dir)
+}
+
+/// Resolves the token directory for this platform and makes sure it exists.
+pub fn token_directory(token_dir: Option<&Path>) -> OAuth2Result<PathBuf> {
+    let project_dirs = ProjectDirs::from("", "", "xoauth2-test-tool");
+    let user_dirs = UserDirs::new();
+    let directory = resolve_token_directory(
+        token_dir,
+        project_dirs.as_ref().map(ProjectDirs::config_dir),
+        user_dirs.as_ref().map(UserDirs::home_dir),
+    )
+    .ok_or(OAuth2Error::new(
+        ErrorCodes::FileError,
+        "Unable to resolve a token directory, please use --token-dir.".into(),
+    ))?;
+
+    create_token_directory(&directory)?;
+    log::debug!("Token directory: {}", directory.display());
+    Ok(directory)
+}
+
+/// Creates the token directory, readable only by the current user on unix. An existing
+/// one that others can read, e.g. created by an earlier version of this tool, is
+/// tightened as well, or only warned about when it cannot be.
+fn create_token_directory(directory: &Path) -> OAuth2Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        builder.mode(0o700);
+        // The mode only applies to a new directory.
+        if let Ok(metadata) = fs::metadata(directory) {
+            let mode = metadata.permissions().mode();
+            if metadata.is_dir() && mode & 0o077 != 0 {
+                if let Err(e) = fs::set_permissions(directory, fs::Permissions::from_mode(0o700)) {
+                    log::warn!(
+                        "The token directory {} is accessible by other users ({:o}) and \
+                        could not be restricted to the current user: {e}",
+                        directory.display(),
+                        mode & 0o777
+                    );
+                }
+            }
+        }
+    }
+    Ok(builder.create(directory)?)
+}
+
+/// The environment variable that can hold the cached token as the JSON of a token
+/// file, e.g. in CI containers where no token file should be written.
+pub const TOKEN_JSON_ENV: &str = "XOAUTH2_TOKEN_JSON";
+
+/// The `id_token` of a token response, there when the `openid` scope was asked for.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct IdTokenFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+/// A token response that keeps its `id_token`.
+pub type IdTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TokenKeeper {
@@ -22,6 +134,12 @@ pub struct TokenKeeper {
     scopes: Option<Vec<String>>,
     expires_in: Option<Duration>,
     token_receive_time: Duration,
+    /// The id_token of the login, with the claims of the signed-in user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+    /// How the tokens are encrypted in the file, with `--token-encryption`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<Encryption>,
     #[serde(skip_serializing)]
     #[serde(skip_deserializing)]
     file_directory: PathBuf,
@@ -30,6 +148,22 @@ pub struct TokenKeeper {
 impl From<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> for TokenKeeper {
     fn from(
         token_response: StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>,
+    ) -> TokenKeeper {
+        TokenKeeper::from_response(&token_response, None)
+    }
+}
+
+impl From<IdTokenResponse> for TokenKeeper {
+    fn from(token_response: IdTokenResponse) -> TokenKeeper {
+        let id_token = token_response.extra_fields().id_token.clone();
+        TokenKeeper::from_response(&token_response, id_token)
+    }
+}
+
+impl TokenKeeper {
+    fn from_response<EF: ExtraTokenFields>(
+        token_response: &StandardTokenResponse<EF, BasicTokenType>,
+        id_token: Option<String>,
     ) -> TokenKeeper {
         let refresh_token = token_response
             .refresh_token()
@@ -47,12 +181,12 @@ impl From<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>> for Toke
             token_receive_time: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time went backwards"),
+            id_token,
+            encryption: None,
             file_directory: PathBuf::new(),
         }
     }
-}
 
-impl TokenKeeper {
     pub fn new(file_directory: PathBuf) -> Self {
         Self {
             access_token: AccessToken::new(String::new()),
@@ -60,10 +194,62 @@ impl TokenKeeper {
             scopes: None,
             expires_in: None,
             token_receive_time: Duration::new(0, 0),
+            id_token: None,
+            encryption: None,
             file_directory,
         }
     }
 
+    /// The scopes granted with the token, when the token endpoint listed them.
+    pub fn scopes(&self) -> Option<&[String]> {
+        self.scopes.as_deref()
+    }
+
+    /// The requested scopes the token was not granted, by the scopes the token endpoint
+    /// listed or else the `scp` claim of the access token. None are missing when neither
+    /// says, or for `.default`, which grants whatever the app was consented.
+    pub fn missing_scopes(&self, requested: &[Scope]) -> Vec<String> {
+        let granted: Vec<String> = match &self.scopes {
+            Some(scopes) => scopes.iter().map(|scope| scope_name(scope)).collect(),
+            None => match validate_token::claims(self.access_token.secret()) {
+                Some(Ok(validate_token::Claims { scp: Some(scp), .. })) => {
+                    scp.split_whitespace().map(scope_name).collect()
+                }
+                _ => return Vec::new(),
+            },
+        };
+        requested
+            .iter()
+            .filter(|scope| {
+                let name = scope_name(scope);
+                !OPENID_SCOPES.contains(&name.as_str())
+                    && name != ".default"
+                    && !granted.contains(&name)
+            })
+            .map(|scope| scope.to_string())
+            .collect()
+    }
+
+    /// Whether the cached token lacks some of the requested scopes, e.g. added to the app
+    /// since it was cached, for `--fail-fast-on-scope-downgrade` to login again.
+    pub fn lacks_scopes(&self, requested: &[Scope]) -> bool {
+        let missing = self.missing_scopes(requested);
+        if missing.is_empty() {
+            return false;
+        }
+        log::warn!(
+            "The cached token was not granted {}, logging in again to ask for it.",
+            missing.join(" ")
+        );
+        true
+    }
+
+    /// When the access token expires, since the Unix epoch, when the token endpoint said.
+    pub fn expires_at(&self) -> Option<Duration> {
+        self.expires_in
+            .map(|expires_in| self.token_receive_time + expires_in)
+    }
+
     pub fn set_directory(&mut self, file_directory: PathBuf) {
         self.file_directory = file_directory;
     }
@@ -79,23 +265,103 @@ impl TokenKeeper {
         }
     }
 
+    /// The token in the JSON of a token file, e.g. the one in `XOAUTH2_TOKEN_JSON`,
+    /// decrypted as [`TokenKeeper::read`] does when it was saved encrypted.
+    pub fn from_json(json: &str) -> OAuth2Result<Self> {
+        let token_keeper = serde_json::from_str::<TokenKeeper>(json).map_err(|e| {
+            OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!("{TOKEN_JSON_ENV} does not hold a token file: {e}"),
+            )
+        })?;
+        let token_keeper = token_keeper.opened_if_sealed()?;
+        if token_keeper.access_token.secret().trim().is_empty() {
+            return Err(OAuth2Error::new(
+                ErrorCodes::ConfigurationError,
+                format!("{TOKEN_JSON_ENV} has an empty access_token."),
+            ));
+        }
+        Ok(token_keeper)
+    }
+
     pub fn read(&mut self, file_name: &Path) -> OAuth2Result<()> {
         let temp_dir = self.file_directory.clone();
         let input_path = self.file_directory.join(file_name);
         let text = std::fs::read_to_string(input_path)?;
 
-        *self = serde_json::from_str::<TokenKeeper>(&text)?;
+        let token_keeper = serde_json::from_str::<TokenKeeper>(&text)?;
+        *self = token_keeper.opened_if_sealed()?;
         self.set_directory(temp_dir);
         Ok(())
     }
 
+    /// The token with its access, refresh and id tokens encrypted, as saved. The other
+    /// fields stay readable.
+    fn sealed(&self, key: &FieldKey, encryption: Encryption) -> OAuth2Result<TokenKeeper> {
+        let mut sealed = self.clone();
+        sealed.access_token = AccessToken::new(key.seal(self.access_token.secret())?);
+        sealed.refresh_token = self
+            .refresh_token
+            .as_ref()
+            .map(|refresh_token| key.seal(refresh_token.secret()).map(RefreshToken::new))
+            .transpose()?;
+        sealed.id_token = self
+            .id_token
+            .as_deref()
+            .map(|id_token| key.seal(id_token))
+            .transpose()?;
+        sealed.encryption = Some(encryption);
+        Ok(sealed)
+    }
+
+    /// The token decrypted with the key of its `encryption`, if it was saved encrypted.
+    fn opened_if_sealed(self) -> OAuth2Result<TokenKeeper> {
+        match &self.encryption {
+            Some(encryption) => {
+                let key = token_encryption::opening_key(encryption)?;
+                self.opened(&key)
+            }
+            None => Ok(self),
+        }
+    }
+
+    /// The token of [`TokenKeeper::sealed`] decrypted.
+    fn opened(mut self, key: &FieldKey) -> OAuth2Result<TokenKeeper> {
+        self.access_token = AccessToken::new(key.open(self.access_token.secret())?);
+        self.refresh_token = self
+            .refresh_token
+            .map(|refresh_token| key.open(refresh_token.secret()).map(RefreshToken::new))
+            .transpose()?;
+        self.id_token = self
+            .id_token
+            .map(|id_token| key.open(&id_token))
+            .transpose()?;
+        self.encryption = None;
+        Ok(self)
+    }
+
     pub fn save(&self, file_name: &Path) -> OAuth2Result<()> {
         let input_path = self.file_directory.join(file_name);
-        let json = serde_json::to_string(self)?;
+        let json = match token_encryption::sealing_key()? {
+            Some((key, encryption)) => serde_json::to_string(&self.sealed(&key, encryption)?)?,
+            None => serde_json::to_string(self)?,
+        };
 
-        fs::create_dir_all(self.file_directory.as_path())?;
+        create_token_directory(self.file_directory.as_path())?;
 
-        let mut file = File::create(input_path)?;
+        // Readable and writable only by the current user on unix.
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to a new file.
+            if input_path.exists() {
+                fs::set_permissions(&input_path, fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        let mut file = options.open(input_path)?;
 
         file.write_all(json.as_bytes())?;
 
@@ -107,3 +373,512 @@ impl TokenKeeper {
         Ok(fs::remove_file(input_path)?)
     }
 }
+
+/// The response with `expires_in` and `interval` as numbers: the v1 endpoints give them
+/// as strings, which oauth2 cannot read.
+pub fn numeric_durations(mut response: HttpResponse) -> HttpResponse {
+    let Ok(mut json) = serde_json::from_slice::<Value>(&response.body) else {
+        return response;
+    };
+    let mut changed = false;
+    for field in ["expires_in", "interval"] {
+        if let Some(seconds) = json[field].as_str().and_then(|s| s.parse::<u64>().ok()) {
+            json[field] = Value::from(seconds);
+            changed = true;
+        }
+    }
+    if changed {
+        response.body = json.to_string().into_bytes();
+    }
+    response
+}
+
+/// The error of `--force-refresh` when there is no cached token to refresh.
+pub fn no_token_to_refresh() -> OAuth2Error {
+    OAuth2Error::new(
+        ErrorCodes::NoToken,
+        "There is no cached token to refresh, login without --force-refresh first.".into(),
+    )
+}
+
+/// The cached access token when it has not expired yet, otherwise the one `login` gets.
+/// The cached token is used without creating the OAuth2 client or contacting the token
+/// endpoint. A token given in `XOAUTH2_TOKEN_JSON` is the cached token instead of the
+/// token file, which is then not read. With `required_scopes`, for
+/// `--fail-fast-on-scope-downgrade`, a cached token lacking some of them logs in again.
+pub async fn cached_or_login<F, Fut>(
+    directory: &Path,
+    file_name: Option<&Path>,
+    token_json: Option<&str>,
+    required_scopes: Option<&[Scope]>,
+    login: F,
+) -> OAuth2Result<AccessToken>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = OAuth2Result<AccessToken>>,
+{
+    let lacks_scopes = |token_keeper: &TokenKeeper| {
+        required_scopes.is_some_and(|scopes| token_keeper.lacks_scopes(scopes))
+    };
+    if let Some(token_json) = token_json {
+        let token_keeper = TokenKeeper::from_json(token_json)?;
+        if token_keeper.has_access_token_expired() {
+            log::warn!("The access token from {TOKEN_JSON_ENV} has expired, logging in.");
+        } else if !lacks_scopes(&token_keeper) {
+            log::info!("Using the access token from {TOKEN_JSON_ENV}.");
+            return Ok(token_keeper.access_token);
+        }
+    } else if let Some(file_name) = file_name {
+        let mut token_keeper = TokenKeeper::new(directory.to_path_buf());
+        if token_keeper.read(file_name).is_ok()
+            && !token_keeper.has_access_token_expired()
+            && !lacks_scopes(&token_keeper)
+        {
+            log::info!("Using the cached access token, it has not expired yet.");
+            return Ok(token_keeper.access_token);
+        }
+    }
+    login().await
+}
+
+/// What to do when the refresh of the cached token is rejected.
+#[derive(Clone, Copy, Debug, Default, PartialEq, EnumString)]
+#[strum(serialize_all = "kebab-case")]
+pub enum OnRefreshFailure {
+    /// Fail, the token file is deleted so that the next run logs in.
+    #[default]
+    Error,
+    /// Login again with the interactive flow.
+    Relogin,
+}
+
+/// Logs in with `login`, given whether it is the second login, which does it once more
+/// without the cached token when its refresh was rejected and `relogin` is given.
+pub async fn login_or_relogin<F, Fut>(
+    directory: &Path,
+    file_name: Option<&Path>,
+    on_refresh_failure: OnRefreshFailure,
+    login: F,
+) -> OAuth2Result<AccessToken>
+where
+    F: Fn(bool) -> Fut,
+    Fut: Future<Output = OAuth2Result<AccessToken>>,
+{
+    // Without a cached token, the error is the one of the interactive login.
+    let cached = file_name.filter(|file_name| directory.join(file_name).exists());
+    match login(false).await {
+        Err(e)
+            if on_refresh_failure == OnRefreshFailure::Relogin
+                && cached.is_some()
+                && matches!(
+                    e.error_code,
+                    ErrorCodes::InvalidGrant
+                        | ErrorCodes::InteractionRequired
+                        | ErrorCodes::LoginRequired
+                        | ErrorCodes::NoToken
+                ) =>
+        {
+            log::warn!(
+                "The cached token could not be refreshed ({:?}), logging in again.",
+                e.error_code
+            );
+            if let Some(file_name) = cached.filter(|file_name| directory.join(file_name).exists()) {
+                TokenKeeper::new(directory.to_path_buf()).delete(file_name)?;
+            }
+            login(true).await
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use http::{HeaderMap, StatusCode};
+    use oauth2::{basic::BasicTokenResponse, AccessToken, HttpResponse, Scope};
+
+    use super::{
+        cached_or_login, login_or_relogin, numeric_durations, resolve_token_directory,
+        token_directory, OnRefreshFailure, TokenKeeper,
+    };
+    use crate::error::{ErrorCodes, OAuth2Error};
+    use crate::token_encryption::{Encryption, FieldKey, TokenEncryption, PASSPHRASE_ENV};
+
+    fn save_token(directory: &Path, json: &str) {
+        let token_response: BasicTokenResponse = serde_json::from_str(json).unwrap();
+        let mut token_keeper = TokenKeeper::from(token_response);
+        token_keeper.set_directory(directory.to_path_buf());
+        token_keeper.save(Path::new("token.json")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_valid_cached_token_skips_the_login() {
+        let directory = tempfile::tempdir().unwrap();
+        save_token(
+            directory.path(),
+            r#"{"access_token":"cached","token_type":"Bearer","expires_in":3600}"#,
+        );
+
+        let access_token = cached_or_login(
+            directory.path(),
+            Some(Path::new("token.json")),
+            None,
+            None,
+            || async { panic!("the token endpoint must not be contacted") },
+        )
+        .await
+        .unwrap();
+        assert_eq!(access_token.secret(), "cached");
+    }
+
+    #[tokio::test]
+    async fn test_expired_or_missing_token_logs_in() {
+        let directory = tempfile::tempdir().unwrap();
+        save_token(
+            directory.path(),
+            r#"{"access_token":"cached","token_type":"Bearer","expires_in":0}"#,
+        );
+        let login = || async { Ok(AccessToken::new("new".to_string())) };
+
+        for file_name in [
+            Some(Path::new("token.json")),
+            Some(Path::new("none.json")),
+            None,
+        ] {
+            let access_token = cached_or_login(directory.path(), file_name, None, None, login)
+                .await
+                .unwrap();
+            assert_eq!(access_token.secret(), "new");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_json_is_the_cached_token() {
+        let directory = tempfile::tempdir().unwrap();
+        save_token(
+            directory.path(),
+            r#"{"access_token":"file","token_type":"Bearer","expires_in":3600}"#,
+        );
+        let token_response: BasicTokenResponse = serde_json::from_str(
+            r#"{"access_token":"injected","token_type":"Bearer","expires_in":3600}"#,
+        )
+        .unwrap();
+        let token_json = serde_json::to_string(&TokenKeeper::from(token_response)).unwrap();
+
+        let access_token = cached_or_login(
+            directory.path(),
+            Some(Path::new("token.json")),
+            Some(&token_json),
+            None,
+            || async { panic!("the token endpoint must not be contacted") },
+        )
+        .await
+        .unwrap();
+        assert_eq!(access_token.secret(), "injected");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token_json_is_rejected() {
+        let directory = tempfile::tempdir().unwrap();
+        for token_json in [
+            "not json",
+            r#"{"access_token":"token"}"#,
+            r#"{"access_token":"","token_receive_time":{"secs":0,"nanos":0}}"#,
+        ] {
+            let error = cached_or_login(directory.path(), None, Some(token_json), None, || async {
+                panic!("an invalid token must not log in")
+            })
+            .await
+            .unwrap_err();
+            assert_eq!(error.error_code, ErrorCodes::ConfigurationError);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejected_refresh_logs_in_again() {
+        let directory = tempfile::tempdir().unwrap();
+        let file_name = Some(Path::new("token.json"));
+        let refresh_or_login = |relogin: bool| async move {
+            if relogin {
+                Ok(AccessToken::new("fresh".to_string()))
+            } else {
+                Err(OAuth2Error::new(
+                    ErrorCodes::InvalidGrant,
+                    "AADSTS700082: The refresh token has expired.".into(),
+                ))
+            }
+        };
+
+        save_token(
+            directory.path(),
+            r#"{"access_token":"cached","token_type":"Bearer","expires_in":0}"#,
+        );
+        let access_token = login_or_relogin(
+            directory.path(),
+            file_name,
+            OnRefreshFailure::Relogin,
+            refresh_or_login,
+        )
+        .await
+        .unwrap();
+        assert_eq!(access_token.secret(), "fresh");
+        assert!(!directory.path().join("token.json").exists());
+
+        save_token(
+            directory.path(),
+            r#"{"access_token":"cached","token_type":"Bearer","expires_in":0}"#,
+        );
+        let error = login_or_relogin(
+            directory.path(),
+            file_name,
+            OnRefreshFailure::Error,
+            refresh_or_login,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::InvalidGrant);
+
+        // A failed interactive login is not tried again.
+        let error = login_or_relogin(
+            directory.path(),
+            Some(Path::new("none.json")),
+            OnRefreshFailure::Relogin,
+            |relogin| async move {
+                assert!(!relogin, "the login must not be tried twice");
+                Err(OAuth2Error::new(ErrorCodes::InvalidGrant, "denied".into()))
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::InvalidGrant);
+    }
+
+    #[test]
+    fn test_resolve_token_directory_prefers_token_dir() {
+        assert_eq!(
+            resolve_token_directory(
+                Some(Path::new("/tmp/tokens")),
+                Some(Path::new("/home/user/.config/xoauth2-test-tool")),
+                Some(Path::new("/home/user")),
+            ),
+            Some(PathBuf::from("/tmp/tokens"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_token_directory_uses_platform_config_dir() {
+        assert_eq!(
+            resolve_token_directory(
+                None,
+                Some(Path::new("/home/user/.config/xoauth2-test-tool")),
+                Some(Path::new("/home/user")),
+            ),
+            Some(PathBuf::from("/home/user/.config/xoauth2-test-tool"))
+        );
+    }
+
+    #[test]
+    fn test_resolve_token_directory_falls_back_to_home() {
+        assert_eq!(
+            resolve_token_directory(None, None, Some(Path::new("/home/user"))),
+            Some(PathBuf::from("/home/user/token"))
+        );
+        assert_eq!(resolve_token_directory(None, None, None), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_existing_token_directory_is_restricted_to_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let token_dir = directory.path().join("token");
+        fs::create_dir(&token_dir).unwrap();
+        fs::set_permissions(&token_dir, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert_eq!(token_directory(Some(&token_dir)).unwrap(), token_dir);
+        let mode = fs::metadata(&token_dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn test_resolve_token_directory_keeps_earlier_tokens() {
+        let home = std::env::temp_dir().join(format!("xoauth2-home-{}", std::process::id()));
+        let config_dir = Path::new("/home/user/.config/xoauth2-test-tool");
+        fs::create_dir_all(home.join("token")).unwrap();
+        // An empty $HOME/token is left for the platform config directory.
+        assert_eq!(
+            resolve_token_directory(None, Some(config_dir), Some(&home)),
+            Some(config_dir.to_path_buf())
+        );
+
+        fs::write(home.join("token").join("token.json"), "{}").unwrap();
+        assert_eq!(
+            resolve_token_directory(None, Some(config_dir), Some(&home)),
+            Some(home.join("token"))
+        );
+        assert_eq!(
+            resolve_token_directory(
+                Some(Path::new("/tmp/tokens")),
+                Some(config_dir),
+                Some(&home)
+            ),
+            Some(PathBuf::from("/tmp/tokens"))
+        );
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn test_numeric_durations() {
+        let response = |body: &str| HttpResponse {
+            status_code: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: body.as_bytes().to_vec(),
+        };
+        let v1 = numeric_durations(response(
+            r#"{"access_token":"a","token_type":"Bearer","expires_in":"3599","resource":"https://outlook.office.com"}"#,
+        ));
+        let token: BasicTokenResponse = serde_json::from_slice(&v1.body).unwrap();
+        assert_eq!(
+            TokenKeeper::from(token).expires_in,
+            Some(std::time::Duration::from_secs(3599))
+        );
+
+        let v2 = r#"{"access_token":"a","token_type":"Bearer","expires_in":3599}"#;
+        assert_eq!(numeric_durations(response(v2)).body, v2.as_bytes());
+        assert_eq!(numeric_durations(response("not json")).body, b"not json");
+    }
+
+    #[test]
+    fn test_sealed_token_fields() {
+        let token_response: BasicTokenResponse = serde_json::from_str(
+            r#"{"access_token":"access","token_type":"Bearer","expires_in":3600,"refresh_token":"refresh","scope":"SMTP.Send"}"#,
+        )
+        .unwrap();
+        let token_keeper = TokenKeeper::from(token_response);
+        let key = FieldKey::from_passphrase("passphrase", b"0123456789abcdef").unwrap();
+        let encryption = Encryption {
+            mode: TokenEncryption::Passphrase,
+            salt: Some("MDEyMzQ1Njc4OWFiY2RlZg==".to_string()),
+        };
+        let sealed = token_keeper.sealed(&key, encryption.clone()).unwrap();
+
+        // Only the secrets are encrypted.
+        let json = serde_json::to_value(&sealed).unwrap();
+        assert!(json["access_token"].as_str().unwrap().starts_with("enc:"));
+        assert!(json["refresh_token"].as_str().unwrap().starts_with("enc:"));
+        assert_eq!(json["scopes"][0], "SMTP.Send");
+        assert_eq!(json["encryption"]["mode"], "passphrase");
+        assert!(!json.to_string().contains("\"access\""));
+
+        let saved: TokenKeeper = serde_json::from_value(json).unwrap();
+        assert_eq!(saved.encryption, Some(encryption));
+        let opened = saved.opened(&key).unwrap();
+        assert_eq!(opened.access_token.secret(), "access");
+        assert_eq!(opened.refresh_token.as_ref().unwrap().secret(), "refresh");
+        assert_eq!(opened.encryption, None);
+        assert!(!opened.has_access_token_expired());
+
+        let other = FieldKey::from_passphrase("other", b"0123456789abcdef").unwrap();
+        assert!(sealed.opened(&other).is_err());
+    }
+
+    #[test]
+    fn test_sealed_token_json_is_opened() {
+        let token_response: BasicTokenResponse = serde_json::from_str(
+            r#"{"access_token":"access","token_type":"Bearer","expires_in":3600}"#,
+        )
+        .unwrap();
+        let key = FieldKey::from_passphrase("passphrase", b"0123456789abcdef").unwrap();
+        let encryption = Encryption {
+            mode: TokenEncryption::Passphrase,
+            salt: Some("MDEyMzQ1Njc4OWFiY2RlZg==".to_string()),
+        };
+        let sealed = TokenKeeper::from(token_response)
+            .sealed(&key, encryption)
+            .unwrap();
+        std::env::set_var(PASSPHRASE_ENV, "passphrase");
+
+        let token_keeper =
+            TokenKeeper::from_json(&serde_json::to_string(&sealed).unwrap()).unwrap();
+        assert_eq!(token_keeper.access_token.secret(), "access");
+        assert_eq!(token_keeper.encryption, None);
+    }
+
+    #[test]
+    fn test_saved_token_file_is_only_readable_by_the_user() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("token.json");
+        fs::write(&path, "x".repeat(1000)).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        save_token(
+            directory.path(),
+            r#"{"access_token":"saved","token_type":"Bearer","expires_in":3600}"#,
+        );
+
+        let mut token_keeper = TokenKeeper::new(directory.path().to_path_buf());
+        token_keeper.read(Path::new("token.json")).unwrap();
+        assert_eq!(token_keeper.access_token.secret(), "saved");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_cached_scopes_subset_of_requested() {
+        let requested = [
+            Scope::new("offline_access".to_string()),
+            Scope::new("https://outlook.office.com/SMTP.Send".to_string()),
+            Scope::new("https://outlook.office.com/Mail.Send".to_string()),
+        ];
+        let cached = |json: &str| -> TokenKeeper {
+            TokenKeeper::from(serde_json::from_str::<BasicTokenResponse>(json).unwrap())
+        };
+
+        // Mail.Send was added to the app after the token was cached.
+        let narrower = cached(
+            r#"{"access_token":"a","token_type":"Bearer","scope":"https://outlook.office.com/SMTP.Send"}"#,
+        );
+        assert_eq!(
+            narrower.missing_scopes(&requested),
+            ["https://outlook.office.com/Mail.Send"]
+        );
+        assert!(narrower.lacks_scopes(&requested));
+
+        let granted = cached(
+            r#"{"access_token":"a","token_type":"Bearer","scope":"https://outlook.office.com/SMTP.Send https://outlook.office.com/Mail.Send"}"#,
+        );
+        assert!(!granted.lacks_scopes(&requested));
+
+        // Without the listed scopes, the scp claim of the access token says.
+        let jwt = |claims: &str| {
+            format!(
+                r#"{{"access_token":"e30.{}.sig","token_type":"Bearer"}}"#,
+                URL_SAFE_NO_PAD.encode(claims)
+            )
+        };
+        assert_eq!(
+            cached(&jwt(r#"{"scp":"smtp.send"}"#)).missing_scopes(&requested),
+            ["https://outlook.office.com/Mail.Send"]
+        );
+        assert!(!cached(&jwt(r#"{"scp":"SMTP.Send Mail.Send"}"#)).lacks_scopes(&requested));
+
+        // When neither says, the token is used.
+        assert!(
+            !cached(r#"{"access_token":"opaque","token_type":"Bearer"}"#).lacks_scopes(&requested)
+        );
+        assert!(!narrower.lacks_scopes(&[Scope::new(
+            "https://outlook.office.com/.default".to_string()
+        )]));
+    }
+}
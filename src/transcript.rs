@@ -0,0 +1,181 @@
+// Standard libraries
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+// 3rd party crates
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// My crates
+use crate::redact;
+
+/// What the next lines of the client are.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum ClientState {
+    #[default]
+    Command,
+    /// The credentials answering the 334 challenge of an AUTH without an initial
+    /// response, until the server stops challenging.
+    AuthResponse,
+    /// DATA was sent, the message follows once the server answers with 354.
+    DataRequested,
+    /// The message, counted but not logged, up to the final dot.
+    Message { bytes: usize },
+}
+
+/// The SMTP conversation of `--smtp-transcript`, logged line by line at debug level with
+/// `C:` for the client and `S:` for the server. The credentials are redacted and the
+/// message is only counted.
+#[derive(Debug, Default)]
+pub struct TranscriptLog {
+    client: Vec<u8>,
+    server: Vec<u8>,
+    state: ClientState,
+    #[cfg(test)]
+    lines: Vec<String>,
+}
+
+/// Splits the complete lines off `pending`, without their line ending.
+fn take_lines(pending: &mut Vec<u8>, bytes: &[u8]) -> Vec<String> {
+    pending.extend_from_slice(bytes);
+    let mut lines = Vec::new();
+    while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+        let line: Vec<u8> = pending.drain(..=end).collect();
+        lines.push(
+            String::from_utf8_lossy(&line)
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        );
+    }
+    lines
+}
+
+impl TranscriptLog {
+    fn record(&mut self, line: String) {
+        log::debug!("SMTP {line}");
+        #[cfg(test)]
+        self.lines.push(line);
+    }
+
+    /// The lines logged so far.
+    #[cfg(test)]
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    fn client(&mut self, bytes: &[u8]) {
+        for line in take_lines(&mut self.client, bytes) {
+            match self.state {
+                ClientState::Message { bytes } if line == "." => {
+                    self.record(format!("C: <{bytes} bytes of message>"));
+                    self.record("C: .".to_string());
+                    self.state = ClientState::Command;
+                }
+                ClientState::Message { bytes } => {
+                    self.state = ClientState::Message {
+                        bytes: bytes + line.len() + 2,
+                    };
+                }
+                ClientState::AuthResponse => {
+                    self.record(format!("C: {}", redact::REDACTED));
+                }
+                ClientState::Command | ClientState::DataRequested => {
+                    let mut words = line.split(' ');
+                    let command = words.next().unwrap_or_default();
+                    if command.eq_ignore_ascii_case("AUTH") && words.count() == 1 {
+                        self.state = ClientState::AuthResponse;
+                    } else if command.eq_ignore_ascii_case("DATA") {
+                        self.state = ClientState::DataRequested;
+                    }
+                    self.record(format!("C: {}", redact::smtp_command(&line)));
+                }
+            }
+        }
+    }
+
+    fn server(&mut self, bytes: &[u8]) {
+        for line in take_lines(&mut self.server, bytes) {
+            // Only the last line of a reply, without a dash after the code, ends it.
+            let last = line.as_bytes().get(3) != Some(&b'-');
+            if last {
+                let code = line.get(..3).unwrap_or_default();
+                self.state = match (self.state, code) {
+                    (ClientState::AuthResponse, "334") => ClientState::AuthResponse,
+                    (ClientState::DataRequested, "354") => ClientState::Message { bytes: 0 },
+                    (ClientState::Message { bytes }, _) => ClientState::Message { bytes },
+                    _ => ClientState::Command,
+                };
+            }
+            self.record(format!("S: {line}"));
+        }
+    }
+}
+
+/// A stream that logs the SMTP conversation going through it, when enabled. It wraps
+/// the plaintext side of the connection, under TLS once STARTTLS is done.
+pub struct Transcript<S> {
+    stream: S,
+    log: Option<TranscriptLog>,
+}
+
+impl<S> Transcript<S> {
+    pub fn new(stream: S, enabled: bool) -> Self {
+        Self {
+            stream,
+            log: enabled.then(TranscriptLog::default),
+        }
+    }
+
+    /// The stream with the log of another one, to go on with it after STARTTLS.
+    pub fn with_log(stream: S, log: Option<TranscriptLog>) -> Self {
+        Self { stream, log }
+    }
+
+    pub fn into_parts(self) -> (S, Option<TranscriptLog>) {
+        (self.stream, self.log)
+    }
+
+    #[cfg(test)]
+    pub fn log(&self) -> Option<&TranscriptLog> {
+        self.log.as_ref()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Transcript<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.stream).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(log)) = (&poll, &mut this.log) {
+            log.server(&buf.filled()[before..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Transcript<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(log)) = (&poll, &mut this.log) {
+            log.client(&buf[..*written]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
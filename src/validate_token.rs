@@ -0,0 +1,315 @@
+// Standard libraries
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 3rd party crates
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{Map, Value};
+
+// My crates
+use crate::cloud::Cloud;
+use crate::error::{ErrorCodes, OAuth2Error, OAuth2Result};
+
+/// How far in the future the receive time of a token may be, for clock skew.
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// The app id of Exchange Online, the audience of some of its tokens instead of its URL.
+const EXCHANGE_APP_ID: &str = "00000002-0000-0ff1-ce00-000000000000";
+
+/// What the token has to be for, as given to `validate-token`.
+#[derive(Debug, Default)]
+pub struct Expected<'a> {
+    pub client_id: Option<&'a str>,
+    pub tenant_id: Option<&'a str>,
+    pub cloud: Cloud,
+}
+
+/// The claims of a Microsoft identity platform access token that say who it is for.
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    pub aud: Option<String>,
+    pub appid: Option<String>,
+    pub azp: Option<String>,
+    pub tid: Option<String>,
+    /// The version of the token, `1.0` or `2.0`.
+    pub ver: Option<String>,
+    /// The delegated scopes, separated by spaces.
+    pub scp: Option<String>,
+}
+
+/// A `Duration` as serde writes it, e.g. the receive time of the token.
+fn duration(value: &Value) -> Option<Duration> {
+    serde_json::from_value(value.clone()).ok()
+}
+
+/// Checks the optional field with `is_valid`, a missing or null field is valid.
+fn check_optional(
+    token: &Map<String, Value>,
+    name: &str,
+    is_valid: impl Fn(&Value) -> bool,
+    expected: &str,
+    problems: &mut Vec<String>,
+) {
+    match token.get(name) {
+        None | Some(Value::Null) => {}
+        Some(value) if is_valid(value) => {}
+        Some(_) => problems.push(format!("{name} is not {expected}.")),
+    }
+}
+
+/// The claims of the access token when it is a JWT, Microsoft personal accounts get
+/// opaque tokens.
+pub fn claims(access_token: &str) -> Option<serde_json::Result<Claims>> {
+    decode_jwt(access_token)
+}
+
+/// The payload of a JWT, e.g. of an access token or an id_token, none when the token is
+/// not a JWT. The signature is not checked.
+pub fn decode_jwt<T: DeserializeOwned>(jwt: &str) -> Option<serde_json::Result<T>> {
+    let mut parts = jwt.split('.');
+    let (Some(_), Some(payload), Some(_), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let payload = URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok()?;
+    Some(serde_json::from_slice(&payload))
+}
+
+fn check_claims(claims: &Claims, expected: &Expected<'_>, problems: &mut Vec<String>) {
+    let outlook = format!("https://{}", expected.cloud.outlook_host());
+    match claims.aud.as_deref() {
+        Some(aud) if aud.trim_end_matches('/') == outlook || aud == EXCHANGE_APP_ID => {}
+        aud => problems.push(format!(
+            "The access token is for {}, not for {outlook}.",
+            aud.unwrap_or("no audience")
+        )),
+    }
+    if let Some(client_id) = expected.client_id {
+        let issued_to = claims.appid.as_deref().or(claims.azp.as_deref());
+        if !issued_to.is_some_and(|issued_to| issued_to.eq_ignore_ascii_case(client_id)) {
+            problems.push(format!(
+                "The access token was issued to {}, not to the client id {client_id}.",
+                issued_to.unwrap_or("no client")
+            ));
+        }
+    }
+    if let Some(tenant_id) = expected.tenant_id {
+        let tid = claims.tid.as_deref();
+        if !tid.is_some_and(|tid| tid.eq_ignore_ascii_case(tenant_id)) {
+            problems.push(format!(
+                "The access token was issued by the tenant {}, not by {tenant_id}.",
+                tid.unwrap_or("-")
+            ));
+        }
+    }
+}
+
+/// The integrity problems of the JSON of a token file, at the time `now` since the
+/// epoch. None of the checks contact the network.
+pub fn validate(json: &str, expected: &Expected<'_>, now: Duration) -> Vec<String> {
+    let token = match serde_json::from_str::<Value>(json) {
+        Ok(Value::Object(token)) => token,
+        Ok(_) => return vec!["The token file is not a JSON object.".to_string()],
+        Err(e) => return vec![format!("The token file is not valid JSON: {e}")],
+    };
+    let mut problems = Vec::new();
+
+    let access_token = token.get("access_token").and_then(Value::as_str);
+    match access_token {
+        Some(access_token) if !access_token.trim().is_empty() => {}
+        Some(_) => problems.push("access_token is empty.".to_string()),
+        None => problems.push("access_token is missing or not a string.".to_string()),
+    }
+    check_optional(
+        &token,
+        "refresh_token",
+        Value::is_string,
+        "a string",
+        &mut problems,
+    );
+    check_optional(
+        &token,
+        "scopes",
+        |scopes| {
+            scopes
+                .as_array()
+                .is_some_and(|s| s.iter().all(Value::is_string))
+        },
+        "a list of strings",
+        &mut problems,
+    );
+    check_optional(
+        &token,
+        "expires_in",
+        |expires_in| duration(expires_in).is_some(),
+        "a duration",
+        &mut problems,
+    );
+
+    let receive_time = token.get("token_receive_time").and_then(duration);
+    let expires_in = token.get("expires_in").and_then(duration);
+    match receive_time {
+        None => problems.push("token_receive_time is missing or not a duration.".to_string()),
+        Some(receive_time) if receive_time.is_zero() => {
+            problems.push("token_receive_time is not set.".to_string())
+        }
+        Some(receive_time) if receive_time > now + CLOCK_SKEW => {
+            problems.push("token_receive_time is in the future.".to_string())
+        }
+        Some(receive_time) => match expires_in.and_then(|e| receive_time.checked_add(e)) {
+            None if expires_in.is_some() => {
+                problems.push("expires_in is not a valid expiry.".to_string())
+            }
+            None => log::warn!("The access token has no expiry, it is never used from the cache."),
+            Some(expiry) if expiry <= now => {
+                if token.get("refresh_token").is_some_and(Value::is_string) {
+                    log::info!("The access token has expired, the refresh token renews it.");
+                } else {
+                    problems.push(
+                        "The access token has expired and there is no refresh token.".to_string(),
+                    );
+                }
+            }
+            Some(expiry) => log::info!(
+                "The access token expires in {} seconds.",
+                (expiry - now).as_secs()
+            ),
+        },
+    }
+
+    match access_token.and_then(claims) {
+        None => log::info!("The access token is not a JWT, its client and tenant are not checked."),
+        Some(Err(e)) => problems.push(format!(
+            "The claims of the access token are not readable: {e}"
+        )),
+        Some(Ok(claims)) => check_claims(&claims, expected, &mut problems),
+    }
+    problems
+}
+
+/// Reads the token file and logs its integrity problems, an error if there is any.
+pub fn validate_token_file(path: &Path, expected: &Expected<'_>) -> OAuth2Result<()> {
+    let json = std::fs::read_to_string(path)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    let problems = validate(&json, expected, now);
+    for problem in &problems {
+        log::error!("{problem}");
+    }
+    if !problems.is_empty() {
+        return Err(OAuth2Error::new(
+            ErrorCodes::InvalidTokenFile,
+            format!(
+                "{} has {} integrity problem(s), login again.",
+                path.display(),
+                problems.len()
+            ),
+        ));
+    }
+    log::info!("{} is a valid token file.", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+
+    use super::{validate, validate_token_file, Expected};
+    use crate::cloud::Cloud;
+    use crate::error::ErrorCodes;
+
+    const CLIENT_ID: &str = "11111111-1111-1111-1111-111111111111";
+    const TENANT_ID: &str = "22222222-2222-2222-2222-222222222222";
+    const NOW: Duration = Duration::from_secs(1_700_000_000);
+
+    fn jwt(claims: &str) -> String {
+        format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"typ":"JWT","alg":"RS256"}"#),
+            URL_SAFE_NO_PAD.encode(claims)
+        )
+    }
+
+    fn token_file(access_token: &str) -> String {
+        format!(
+            r#"{{"access_token":"{access_token}","refresh_token":"refresh","scopes":["https://outlook.office.com/SMTP.Send"],"expires_in":{{"secs":3600,"nanos":0}},"token_receive_time":{{"secs":{},"nanos":0}}}}"#,
+            NOW.as_secs() - 60
+        )
+    }
+
+    fn expected() -> Expected<'static> {
+        Expected {
+            client_id: Some(CLIENT_ID),
+            tenant_id: Some(TENANT_ID),
+            cloud: Cloud::Commercial,
+        }
+    }
+
+    #[test]
+    fn test_valid_token_file() {
+        let access_token = jwt(&format!(
+            r#"{{"aud":"https://outlook.office.com","appid":"{CLIENT_ID}","tid":"{TENANT_ID}"}}"#
+        ));
+        assert_eq!(
+            validate(&token_file(&access_token), &expected(), NOW),
+            Vec::<String>::new()
+        );
+        // An opaque token is not checked against the client and tenant.
+        assert!(validate(&token_file("opaque"), &expected(), NOW).is_empty());
+    }
+
+    #[test]
+    fn test_corrupt_token_file() {
+        assert_eq!(validate("{\"access_token\":", &expected(), NOW).len(), 1);
+
+        let problems = validate(
+            r#"{"access_token":42,"scopes":"SMTP.Send","expires_in":-1,"token_receive_time":{"secs":0,"nanos":0}}"#,
+            &Expected::default(),
+            NOW,
+        );
+        assert_eq!(
+            problems,
+            [
+                "access_token is missing or not a string.",
+                "scopes is not a list of strings.",
+                "expires_in is not a duration.",
+                "token_receive_time is not set.",
+            ]
+        );
+
+        let expired = token_file("opaque").replace(r#""refresh_token":"refresh","#, "");
+        let problems = validate(
+            &expired,
+            &Expected::default(),
+            NOW + Duration::from_secs(3600),
+        );
+        assert_eq!(
+            problems,
+            ["The access token has expired and there is no refresh token."]
+        );
+    }
+
+    #[test]
+    fn test_mismatched_audience_token_file() {
+        let access_token = jwt(&format!(
+            r#"{{"aud":"https://graph.microsoft.com","azp":"33333333-3333-3333-3333-333333333333","tid":"{TENANT_ID}"}}"#
+        ));
+        let problems = validate(&token_file(&access_token), &expected(), NOW);
+        assert_eq!(problems.len(), 2, "{problems:?}");
+        assert!(problems[0].contains("https://graph.microsoft.com"));
+        assert!(problems[1].contains(CLIENT_ID));
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("token.json");
+        std::fs::write(&path, token_file(&access_token)).unwrap();
+        let error = validate_token_file(&path, &expected()).unwrap_err();
+        assert_eq!(error.error_code, ErrorCodes::InvalidTokenFile);
+        assert!(validate_token_file(Path::new("/nonexistent/token.json"), &expected()).is_err());
+    }
+}